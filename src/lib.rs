@@ -16,7 +16,28 @@ cfg_if::cfg_if! {
     if #[cfg(feature = "vmx")] {
         mod vmx;
         use vmx as vender;
-        pub use vmx::{VmxExitInfo, VmxExitReason, VmxInterruptInfo, VmxIoExitInfo};
+        pub use vmx::{
+            EntryCheckArea, EntryCheckError, VmcsFields, VmcsImage, VmxCapabilities,
+            check_vm_entry,
+        };
+        pub use vmx::{VcpuError, VcpuResult};
+        pub use vmx::{
+            ApicAccessExitInfo, ApicAccessExitType, CrAccessInfo, DebugExceptionQualification,
+            DescriptorTableInstruction, DrAccessInfo, EptViolationQualification,
+            ExitQualification, InstructionOperand, MemoryOperand, SegmentRegister,
+            SegmentTableInstruction, TaskSwitchInfo, TaskSwitchSource, VmxInstructionInfo,
+        };
+        pub use vmx::{
+            FlatBootState, LINUX_BOOT_CS, LINUX_BOOT_DS, LINUX_BOOT_GDT_SIZE, LinuxBootConfig,
+            LinuxBootEntry, VmxBootMode, VmxExitInfo, VmxExitReason, VmxInterruptInfo,
+            VmxIoExitInfo, VmxSetupConfig,
+        };
+        pub use vmx::{
+            EoiHandler, HyperVFeatures, Hypercall, HypercallAbi, HypercallAction,
+            HypercallDispatcher, HypercallHandler, MwaitMode, PleConfig, PostedInterruptHandle,
+            PvClock, PvOpsFeatures, PvOpsHandle, PvOpsHooks, TscModel, VcpuKickHandle,
+            VcpuRunState, VmxDeadline, VmxRunResult, WaitPkgConfig,
+        };
 
         pub use vender::VmxArchVCpu;
         pub use vender::VmxArchPerCpuState;
@@ -7,6 +7,8 @@ use x86::msr::{rdmsr, wrmsr};
 pub enum Msr {
     IA32_FEATURE_CONTROL = 0x3a,
 
+    IA32_UMWAIT_CONTROL = 0xe1,
+
     IA32_PAT = 0x277,
 
     IA32_VMX_BASIC = 0x480,
@@ -37,6 +39,7 @@ pub enum Msr {
     IA32_FS_BASE = 0xc000_0100,
     IA32_GS_BASE = 0xc000_0101,
     IA32_KERNEL_GSBASE = 0xc000_0102,
+    IA32_TSC_AUX = 0xc000_0103,
 }
 
 impl Msr {
@@ -0,0 +1,128 @@
+//! Emulated `IA32_APIC_BASE` MSR of the local APIC.
+//! (SDM Vol. 3A, Section 10.4.4 and 10.12.5)
+
+use bit_field::BitField;
+
+use super::apic_page::APIC_DEFAULT_BASE;
+use super::error::{VcpuError, VcpuResult};
+
+/// Bit 8: the processor is the bootstrap processor.
+const APIC_BASE_BSP: usize = 8;
+/// Bit 10: x2APIC mode enable.
+const APIC_BASE_EXTD: usize = 10;
+/// Bit 11: APIC global enable.
+const APIC_BASE_EN: usize = 11;
+
+/// The operating mode selected by `IA32_APIC_BASE.EN` and `IA32_APIC_BASE.EXTD`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicMode {
+    /// The local APIC is globally disabled.
+    Disabled,
+    /// Registers are accessed through MMIO at the APIC base.
+    XApic,
+    /// Registers are accessed through the MSRs 0x800 - 0x8ff.
+    X2Apic,
+}
+
+/// The value of the `IA32_APIC_BASE` MSR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApicBase(u64);
+
+impl ApicBase {
+    /// The value after reset: xAPIC mode at the default base.
+    pub fn new(bsp: bool) -> Self {
+        let mut value = APIC_DEFAULT_BASE as u64;
+        value.set_bit(APIC_BASE_EN, true);
+        value.set_bit(APIC_BASE_BSP, bsp);
+        Self(value)
+    }
+
+    /// The raw MSR value.
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// The current operating mode.
+    pub fn mode(self) -> ApicMode {
+        match (self.0.get_bit(APIC_BASE_EN), self.0.get_bit(APIC_BASE_EXTD)) {
+            (true, true) => ApicMode::X2Apic,
+            (true, false) => ApicMode::XApic,
+            _ => ApicMode::Disabled,
+        }
+    }
+
+    /// The guest-physical address of the xAPIC registers.
+    pub fn base(self) -> usize {
+        (self.0 & !0xfff) as usize
+    }
+
+    /// The value after a guest `WRMSR` of `value`, or #GP if it sets reserved
+    /// bits or makes an invalid mode transition. `phys_bits` is the guest
+    /// physical-address width, `x2apic` whether x2APIC mode is available.
+    /// The BSP flag is kept.
+    pub fn write(self, value: u64, phys_bits: u8, x2apic: bool) -> VcpuResult<Self> {
+        let mut reserved = !((1u64 << phys_bits) - 1) | 0x2ff;
+        if !x2apic {
+            reserved.set_bit(APIC_BASE_EXTD, true);
+        }
+        if value & reserved != 0 {
+            return Err(VcpuError::gp("reserved bits set in IA32_APIC_BASE"));
+        }
+
+        let mut new = value;
+        new.set_bit(APIC_BASE_BSP, self.0.get_bit(APIC_BASE_BSP));
+        let new = Self(new);
+        if new.0.get_bit(APIC_BASE_EXTD) && !new.0.get_bit(APIC_BASE_EN) {
+            return Err(VcpuError::gp("x2APIC mode enabled with the APIC disabled"));
+        }
+        match (self.mode(), new.mode()) {
+            (ApicMode::X2Apic, ApicMode::XApic) => Err(VcpuError::gp(
+                "x2APIC mode can only be left by disabling the APIC",
+            )),
+            (ApicMode::Disabled, ApicMode::X2Apic) => Err(VcpuError::gp(
+                "x2APIC mode can only be entered from xAPIC mode",
+            )),
+            _ => Ok(new),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reset_value() {
+        let base = ApicBase::new(true);
+        assert_eq!(base.bits(), 0xfee0_0900);
+        assert_eq!((base.mode(), base.base()), (ApicMode::XApic, 0xfee0_0000));
+    }
+
+    #[test]
+    fn test_move_base() {
+        // The BSP flag is read-only, the base can be moved.
+        let base = ApicBase::new(true);
+        let moved = base.write(0xfec0_0800, 36, true).unwrap();
+        assert_eq!((moved.bits(), moved.base()), (0xfec0_0900, 0xfec0_0000));
+        assert!(base.write(0x10_0000_0800, 36, true).is_err());
+        assert!(base.write(0xfee0_0801, 36, true).is_err());
+    }
+
+    #[test]
+    fn test_mode_transitions() {
+        // xAPIC -> x2APIC -> disabled -> xAPIC.
+        let base = ApicBase::new(true);
+        assert!(base.write(0xfee0_0c00, 36, false).is_err());
+        let x2apic = base.write(0xfee0_0c00, 36, true).unwrap();
+        assert_eq!(x2apic.mode(), ApicMode::X2Apic);
+        assert!(x2apic.write(0xfee0_0800, 36, true).is_err());
+        let disabled = x2apic.write(0xfee0_0000, 36, true).unwrap();
+        assert_eq!(disabled.mode(), ApicMode::Disabled);
+        assert!(disabled.write(0xfee0_0c00, 36, true).is_err());
+        assert!(disabled.write(0xfee0_0400, 36, true).is_err());
+        assert_eq!(
+            disabled.write(0xfee0_0800, 36, true).unwrap().mode(),
+            ApicMode::XApic
+        );
+    }
+}
@@ -0,0 +1,178 @@
+//! Interrupt state of the emulated local APIC in its virtual-APIC page.
+//! (SDM Vol. 3C, Section 10.8 and 29.1)
+//!
+//! The page has the xAPIC register layout: every register is 32 bits wide
+//! at a 16-byte aligned offset.
+
+use alloc::boxed::Box;
+use core::ptr::NonNull;
+
+/// Default guest-physical address of the xAPIC registers. (SDM Vol. 3A, Section 10.4.1)
+pub const APIC_DEFAULT_BASE: usize = 0xfee0_0000;
+
+/// Offset of the task-priority register (TPR).
+pub const APIC_TPR: usize = 0x80;
+/// Offset of the end-of-interrupt register (EOI).
+pub const APIC_EOI: usize = 0xb0;
+/// Offset of the processor-priority register (PPR).
+pub const APIC_PPR: usize = 0xa0;
+/// Offset of the in-service register (ISR), 8 registers for 256 vectors.
+pub const APIC_ISR: usize = 0x100;
+/// Offset of the interrupt-request register (IRR), 8 registers for 256 vectors.
+pub const APIC_IRR: usize = 0x200;
+/// Offset of the interrupt command register (ICR), low half first.
+pub const APIC_ICR: usize = 0x300;
+
+/// Called with the vector of a virtualized EOI that causes a VM exit, see
+/// `VmxVcpu::set_eoi_exit`.
+pub type EoiHandler = Box<dyn Fn(u8) + Send + Sync>;
+
+/// A virtual-APIC page.
+pub struct VirtualApicPage {
+    base: NonNull<u32>,
+}
+
+impl VirtualApicPage {
+    /// Access the 4 KiB page at `base`.
+    ///
+    /// # Safety
+    ///
+    /// `base` must point to a page-aligned, 4 KiB virtual-APIC page that stays
+    /// valid while the returned value is used.
+    pub unsafe fn new(base: *mut u8) -> Self {
+        Self {
+            base: NonNull::new(base.cast()).expect("null virtual-APIC page"),
+        }
+    }
+
+    /// Read the register at `offset`.
+    pub fn read(&self, offset: usize) -> u32 {
+        unsafe { self.base.byte_add(offset).read_volatile() }
+    }
+
+    /// Write the register at `offset`.
+    pub fn write(&mut self, offset: usize, value: u32) {
+        unsafe { self.base.byte_add(offset).write_volatile(value) }
+    }
+
+    fn highest_vector(&self, bank: usize) -> Option<u8> {
+        (0..8).rev().find_map(|i| {
+            let word = self.read(bank + i * 0x10);
+            (word != 0).then(|| (i * 32 + 31 - word.leading_zeros() as usize) as u8)
+        })
+    }
+
+    fn set_vector(&mut self, bank: usize, vector: u8, set: bool) {
+        let offset = bank + (vector as usize / 32) * 0x10;
+        let bit = 1 << (vector % 32);
+        let word = self.read(offset);
+        self.write(offset, if set { word | bit } else { word & !bit });
+    }
+
+    /// Add the requests, one bit per vector, to the IRR.
+    pub fn request_interrupts(&mut self, requests: &[u64; 4]) {
+        for (i, &bits) in requests.iter().enumerate() {
+            for (half, word) in [bits as u32, (bits >> 32) as u32].into_iter().enumerate() {
+                if word != 0 {
+                    let offset = APIC_IRR + (i * 2 + half) * 0x10;
+                    self.write(offset, self.read(offset) | word);
+                }
+            }
+        }
+    }
+
+    /// The task priority.
+    pub fn tpr(&self) -> u8 {
+        self.read(APIC_TPR) as u8
+    }
+
+    /// The highest requested vector.
+    pub fn highest_irr(&self) -> Option<u8> {
+        self.highest_vector(APIC_IRR)
+    }
+
+    /// The highest in-service vector.
+    pub fn highest_isr(&self) -> Option<u8> {
+        self.highest_vector(APIC_ISR)
+    }
+
+    /// The processor priority, from the task priority and the highest
+    /// in-service vector. (SDM Vol. 3A, Section 10.8.3.1)
+    pub fn ppr(&self) -> u8 {
+        let tpr = self.tpr();
+        let isrv = self.highest_isr().unwrap_or(0);
+        if tpr >> 4 >= isrv >> 4 {
+            tpr
+        } else {
+            isrv & 0xf0
+        }
+    }
+
+    /// The highest requested vector whose priority class is above the
+    /// processor priority class. (SDM Vol. 3A, Section 10.8.4)
+    pub fn pending_interrupt(&self) -> Option<u8> {
+        self.highest_irr()
+            .filter(|&vector| vector >> 4 > self.ppr() >> 4)
+    }
+
+    /// Accept the pending interrupt: move it from the IRR to the ISR and raise
+    /// the processor priority. Returns the vector to deliver.
+    pub fn accept_interrupt(&mut self) -> Option<u8> {
+        let vector = self.pending_interrupt()?;
+        self.set_vector(APIC_IRR, vector, false);
+        self.set_vector(APIC_ISR, vector, true);
+        let ppr = self.ppr();
+        self.write(APIC_PPR, ppr as u32);
+        Some(vector)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[repr(align(4096))]
+    struct Page([u32; 1024]);
+
+    #[test]
+    fn test_accept_highest_priority() {
+        let mut storage = Page([0; 1024]);
+        let mut page = unsafe { VirtualApicPage::new(storage.0.as_mut_ptr().cast()) };
+
+        // IRR: 0x31 and 0x62, TPR blocks class 3.
+        page.write(APIC_IRR + 0x10, 1 << 0x11);
+        page.write(APIC_IRR + 0x30, 1 << 0x2);
+        page.write(APIC_TPR, 0x30);
+        assert_eq!(page.highest_irr(), Some(0x62));
+        assert_eq!(page.accept_interrupt(), Some(0x62));
+        assert_eq!(
+            (page.highest_isr(), page.read(APIC_PPR)),
+            (Some(0x62), 0x60)
+        );
+    }
+
+    #[test]
+    fn test_masked_by_isr_and_tpr() {
+        let mut storage = Page([0; 1024]);
+        let mut page = unsafe { VirtualApicPage::new(storage.0.as_mut_ptr().cast()) };
+        page.write(APIC_IRR + 0x10, 1 << 0x11);
+        page.write(APIC_ISR + 0x30, 1 << 0x2);
+        page.write(APIC_TPR, 0x30);
+
+        // 0x31 is requested but masked by the in-service 0x62, then by the TPR.
+        assert_eq!(page.pending_interrupt(), None);
+        page.write(APIC_ISR + 0x30, 0);
+        assert_eq!(page.pending_interrupt(), None);
+        page.write(APIC_TPR, 0x20);
+        assert_eq!(page.accept_interrupt(), Some(0x31));
+        assert_eq!(page.highest_irr(), None);
+    }
+
+    #[test]
+    fn test_request_interrupts() {
+        let mut storage = Page([0; 1024]);
+        let mut page = unsafe { VirtualApicPage::new(storage.0.as_mut_ptr().cast()) };
+        page.request_interrupts(&[0, 1 << 40, 0, 0]);
+        assert_eq!(page.highest_irr(), Some(0x68));
+    }
+}
@@ -0,0 +1,482 @@
+use x86_64::registers::control::{Cr0Flags, Cr4Flags, EferFlags};
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err};
+
+use super::guest_mem::GuestMemory;
+use super::hyperv::HyperVFeatures;
+use super::idle::{MwaitMode, WaitPkgConfig};
+use super::ple::PleConfig;
+use super::pvops::PvOpsFeatures;
+use crate::regs::GeneralRegisters;
+
+/// Configuration consumed by [`VmxArchVCpu::setup`](crate::VmxArchVCpu).
+#[derive(Clone, Debug, Default)]
+pub struct VmxSetupConfig {
+    /// The processor state the guest starts in.
+    pub boot_mode: VmxBootMode,
+    /// Use APIC virtualization where the processor supports it: a TPR shadow,
+    /// x2APIC-mode and APIC-register virtualization and virtual-interrupt
+    /// delivery, so that most x2APIC MSR accesses no longer cause VM exits.
+    /// (SDM Vol. 3C, Chapter 29)
+    pub apicv: bool,
+    /// The host vector other CPUs send to notify the vCPU of posted
+    /// interrupts. The processor handles it in VMX non-root operation if it
+    /// supports posted-interrupt processing and virtual-interrupt delivery is in
+    /// use, otherwise it causes an external-interrupt VM exit.
+    /// (SDM Vol. 3C, Section 29.6)
+    pub posted_interrupt_vector: Option<u8>,
+    /// The guest TSC frequency in Hz. It needs TSC scaling, without it or if
+    /// `None` the guest TSC runs at the host rate. (SDM Vol. 3C, Section 25.3)
+    pub guest_tsc_hz: Option<u64>,
+    /// The host TSC value at which the guest TSC reads 0, by default the host
+    /// TSC at setup. The vCPUs of a VM should share it to keep their TSCs
+    /// synchronized.
+    pub tsc_origin: Option<u64>,
+    /// Offer a kvmclock-compatible paravirtual clock, advertised with the KVM
+    /// signature in the hypervisor CPUID leaves. It holds the wall-clock time,
+    /// in nanoseconds since the Unix epoch, at which the guest TSC reads 0.
+    /// Needs a known guest TSC frequency.
+    pub kvmclock: Option<u64>,
+    /// The Hyper-V enlightenments offered to the guest, with the Hyper-V
+    /// signature in CPUID leaf 0x4000_0000. The KVM leaves move to
+    /// 0x4000_0100 then. The reference TSC and synthetic timers need a known
+    /// guest TSC frequency.
+    pub hyperv: HyperVFeatures,
+    /// The paravirtual TLB flush and spinlock kick offered to the guest. The
+    /// kick is advertised with the KVM signature in the hypervisor CPUID
+    /// leaves, the TLB flush in the own leaves of this crate from 0x4000_0f00.
+    /// Their hypercalls exit to the VMM unless it provides hooks with
+    /// `VmxVcpu::set_pv_ops_hooks`. Cannot be combined with
+    /// `HyperVFeatures::HYPERCALL`, which decodes `VMCALL` differently.
+    pub pv_ops: PvOpsFeatures,
+    /// PAUSE-loop exiting, reported by `VmxVcpu::run_with_deadline` as
+    /// `VmxRunResult::YieldHint`. Ignored if the processor does not support
+    /// it. (SDM Vol. 3C, Section 25.1.3)
+    pub pause_loop_exiting: Option<PleConfig>,
+    /// What `MONITOR` and `MWAIT` do in the guest.
+    pub mwait: MwaitMode,
+    /// Offer `UMWAIT` and `TPAUSE` (CPUID WAITPKG) within these host limits.
+    /// Ignored if the processor does not support them.
+    pub waitpkg: Option<WaitPkgConfig>,
+}
+
+/// Processor state at the first VM entry.
+///
+/// The entry point is always the address given by `set_entry`, the presets only
+/// differ in the mode the guest is in when it reaches it.
+#[derive(Clone, Debug, Default)]
+pub enum VmxBootMode {
+    /// The state after RESET or INIT, with 16-bit segments based at 0.
+    /// (SDM Vol. 3A, Section 10.1.1)
+    #[default]
+    RealMode,
+    /// 32-bit protected mode with flat 4 GiB segments and paging disabled, as
+    /// expected by multiboot kernels.
+    ProtectedMode(FlatBootState),
+    /// 64-bit mode with 4-level paging through `cr3`, as expected by the Linux
+    /// 64-bit boot protocol.
+    LongMode {
+        /// Guest-physical address of the PML4 table.
+        cr3: u64,
+        /// Descriptor table and registers at the entry point.
+        state: FlatBootState,
+    },
+}
+
+/// Segment and register state shared by the protected-mode and long-mode presets.
+#[derive(Clone, Debug)]
+pub struct FlatBootState {
+    /// Guest-physical base address of the GDT.
+    pub gdt_base: u64,
+    /// Limit of the GDT.
+    pub gdt_limit: u16,
+    /// Selector loaded into CS. It should refer to a flat code descriptor in the GDT.
+    pub code_selector: u16,
+    /// Selector loaded into DS, ES, FS, GS and SS. It should refer to a flat
+    /// data descriptor in the GDT.
+    pub data_selector: u16,
+    /// General-purpose registers at the entry point, e.g. `rax`/`rbx` holding the
+    /// multiboot magic and information address, or `rsi` holding the address of
+    /// the Linux `boot_params`.
+    pub regs: GeneralRegisters,
+    /// Stack pointer at the entry point.
+    pub rsp: u64,
+}
+
+impl Default for FlatBootState {
+    fn default() -> Self {
+        Self {
+            gdt_base: 0,
+            gdt_limit: 0,
+            code_selector: 0x08,
+            data_selector: 0x10,
+            regs: GeneralRegisters::default(),
+            rsp: 0,
+        }
+    }
+}
+
+/// Segment register state of a boot preset.
+pub(super) struct BootSegment {
+    pub selector: u16,
+    pub limit: u32,
+    pub access_rights: u32,
+}
+
+impl VmxBootMode {
+    /// The flat register state, if the preset is not [`VmxBootMode::RealMode`].
+    pub(super) fn flat_state(&self) -> Option<&FlatBootState> {
+        match self {
+            Self::RealMode => None,
+            Self::ProtectedMode(state) | Self::LongMode { state, .. } => Some(state),
+        }
+    }
+
+    /// Whether the guest enters in IA-32e mode, i.e. the "IA-32e mode guest"
+    /// VM-entry control must be set. (SDM Vol. 3C, Section 24.8.1)
+    pub(super) fn is_long_mode(&self) -> bool {
+        matches!(self, Self::LongMode { .. })
+    }
+
+    pub(super) fn cr0(&self) -> Cr0Flags {
+        match self {
+            Self::RealMode => {
+                Cr0Flags::NOT_WRITE_THROUGH | Cr0Flags::CACHE_DISABLE | Cr0Flags::EXTENSION_TYPE
+            }
+            Self::ProtectedMode(_) => Cr0Flags::PROTECTED_MODE_ENABLE | Cr0Flags::EXTENSION_TYPE,
+            Self::LongMode { .. } => {
+                Cr0Flags::PROTECTED_MODE_ENABLE | Cr0Flags::EXTENSION_TYPE | Cr0Flags::PAGING
+            }
+        }
+    }
+
+    pub(super) fn cr3(&self) -> u64 {
+        match self {
+            Self::LongMode { cr3, .. } => *cr3,
+            _ => 0,
+        }
+    }
+
+    pub(super) fn cr4(&self) -> Cr4Flags {
+        match self {
+            Self::LongMode { .. } => Cr4Flags::PHYSICAL_ADDRESS_EXTENSION,
+            _ => Cr4Flags::empty(),
+        }
+    }
+
+    pub(super) fn efer(&self) -> EferFlags {
+        match self {
+            Self::LongMode { .. } => EferFlags::LONG_MODE_ENABLE | EferFlags::LONG_MODE_ACTIVE,
+            _ => EferFlags::empty(),
+        }
+    }
+
+    /// Code segment loaded into CS. (SDM Vol. 3C, Section 24.4.1 for the access-rights format)
+    pub(super) fn code_segment(&self) -> BootSegment {
+        match self {
+            // 16-bit, present, code, exec/read, accessed
+            Self::RealMode => BootSegment {
+                selector: 0,
+                limit: 0xffff,
+                access_rights: 0x9b,
+            },
+            // 32-bit (D), 4 KiB granularity (G), present, code, exec/read, accessed
+            Self::ProtectedMode(state) => BootSegment {
+                selector: state.code_selector,
+                limit: 0xffff_ffff,
+                access_rights: 0xc09b,
+            },
+            // 64-bit (L), 4 KiB granularity (G), present, code, exec/read, accessed
+            Self::LongMode { state, .. } => BootSegment {
+                selector: state.code_selector,
+                limit: 0xffff_ffff,
+                access_rights: 0xa09b,
+            },
+        }
+    }
+
+    /// Data segment loaded into DS, ES, FS, GS and SS.
+    pub(super) fn data_segment(&self) -> BootSegment {
+        match self.flat_state() {
+            // 16-bit, present, data, read/write, accessed
+            None => BootSegment {
+                selector: 0,
+                limit: 0xffff,
+                access_rights: 0x93,
+            },
+            // 32-bit (B), 4 KiB granularity (G), present, data, read/write, accessed
+            Some(state) => BootSegment {
+                selector: state.data_selector,
+                limit: 0xffff_ffff,
+                access_rights: 0xc093,
+            },
+        }
+    }
+}
+
+/// Entry point of the Linux x86 boot protocol to use.
+/// (Linux `Documentation/arch/x86/boot.rst`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinuxBootEntry {
+    /// The 32-bit entry at `code32_start`: flat protected mode, paging disabled.
+    Bits32,
+    /// The 64-bit entry at `code32_start + 0x200`: long mode with the kernel,
+    /// the zero page and the command line identity-mapped by `cr3`.
+    Bits64 {
+        /// Guest-physical address of the PML4 table.
+        cr3: u64,
+    },
+}
+
+/// Initial vCPU state for booting a Linux `bzImage` through its 32-bit or 64-bit entry.
+#[derive(Clone, Debug)]
+pub struct LinuxBootConfig {
+    /// Guest-physical address of the zero page (`struct boot_params`), passed in `rsi`.
+    pub zero_page: GuestPhysAddr,
+    /// Guest-physical address where the boot GDT is written, [`LINUX_BOOT_GDT_SIZE`] bytes.
+    pub gdt: GuestPhysAddr,
+    /// Which entry point the guest starts at.
+    pub entry: LinuxBootEntry,
+}
+
+/// Selector of the flat code segment required by the boot protocol (`__BOOT_CS`).
+pub const LINUX_BOOT_CS: u16 = 0x10;
+/// Selector of the flat data segment required by the boot protocol (`__BOOT_DS`).
+pub const LINUX_BOOT_DS: u16 = 0x18;
+/// Size in bytes of the boot GDT written by [`LinuxBootConfig::write_gdt`].
+pub const LINUX_BOOT_GDT_SIZE: usize = 4 * 8;
+
+const BOOT_PARAMS_SIZE: u64 = 0x1000;
+const SETUP_HEADER_BOOT_FLAG: usize = 0x1fe;
+const SETUP_HEADER_MAGIC: usize = 0x202;
+const SETUP_HEADER_VERSION: usize = 0x206;
+const SETUP_HEADER_XLOADFLAGS: usize = 0x236;
+const XLF_KERNEL_64: u16 = 1 << 0;
+
+impl LinuxBootConfig {
+    /// Check the configuration against the boot protocol and the setup header in
+    /// the zero page, which the VMM must have filled in already.
+    pub fn validate(&self, mem: &impl GuestMemory, entry: GuestPhysAddr) -> AxResult {
+        let zero_page = self.zero_page.as_usize() as u64;
+        let gdt = self.gdt.as_usize() as u64;
+        let entry = entry.as_usize() as u64;
+
+        if !gdt.is_multiple_of(8) {
+            return ax_err!(InvalidInput, "boot GDT is not 8-byte aligned");
+        }
+        if gdt < zero_page + BOOT_PARAMS_SIZE && zero_page < gdt + LINUX_BOOT_GDT_SIZE as u64 {
+            return ax_err!(InvalidInput, "boot GDT overlaps the zero page");
+        }
+        match self.entry {
+            LinuxBootEntry::Bits32 => {
+                // Everything is addressed through 32-bit registers.
+                let limit = 1 << 32;
+                if zero_page + BOOT_PARAMS_SIZE > limit
+                    || gdt + LINUX_BOOT_GDT_SIZE as u64 > limit
+                    || entry >= limit
+                {
+                    return ax_err!(
+                        InvalidInput,
+                        "32-bit boot requires the zero page, GDT and entry below 4 GiB"
+                    );
+                }
+            }
+            LinuxBootEntry::Bits64 { cr3 } => {
+                if !cr3.is_multiple_of(0x1000) {
+                    return ax_err!(InvalidInput, "CR3 is not 4 KiB aligned");
+                }
+            }
+        }
+
+        let read = |offset: usize, size: usize| {
+            mem.read_uint(
+                GuestPhysAddr::from(self.zero_page.as_usize() + offset),
+                size,
+            )
+        };
+        if read(SETUP_HEADER_BOOT_FLAG, 2)? != 0xaa55
+            || read(SETUP_HEADER_MAGIC, 4)? != u32::from_le_bytes(*b"HdrS") as u64
+        {
+            return ax_err!(InvalidData, "zero page does not contain a setup header");
+        }
+        let version = read(SETUP_HEADER_VERSION, 2)?;
+        if version < 0x0202 {
+            return ax_err!(
+                Unsupported,
+                format_args!("boot protocol {:#06x} predates the 32-bit entry", version)
+            );
+        }
+        if let LinuxBootEntry::Bits64 { .. } = self.entry
+            && (version < 0x020c || read(SETUP_HEADER_XLOADFLAGS, 2)? as u16 & XLF_KERNEL_64 == 0)
+        {
+            return ax_err!(Unsupported, "kernel does not have a 64-bit entry point");
+        }
+        Ok(())
+    }
+
+    /// Write the boot GDT with `__BOOT_CS` and `__BOOT_DS` at [`Self::gdt`].
+    pub fn write_gdt(&self, mem: &mut impl GuestMemory) -> AxResult {
+        let code: u64 = match self.entry {
+            LinuxBootEntry::Bits32 => 0x00cf_9b00_0000_ffff, // 32-bit, 4 GiB, exec/read
+            LinuxBootEntry::Bits64 { .. } => 0x00af_9b00_0000_ffff, // 64-bit, exec/read
+        };
+        let data: u64 = 0x00cf_9300_0000_ffff; // 32-bit, 4 GiB, read/write
+        for (i, desc) in [0, 0, code, data].into_iter().enumerate() {
+            mem.write_uint(GuestPhysAddr::from(self.gdt.as_usize() + i * 8), 8, desc)?;
+        }
+        Ok(())
+    }
+
+    /// The boot preset matching the protocol: `__BOOT_CS`/`__BOOT_DS` loaded,
+    /// `rsi` pointing at the zero page, `rbp`, `rdi` and `rbx` cleared and
+    /// interrupts disabled.
+    pub fn setup_config(&self) -> VmxSetupConfig {
+        let mut regs = GeneralRegisters::default();
+        regs.rsi = self.zero_page.as_usize() as u64;
+        let state = FlatBootState {
+            gdt_base: self.gdt.as_usize() as u64,
+            gdt_limit: LINUX_BOOT_GDT_SIZE as u16 - 1,
+            code_selector: LINUX_BOOT_CS,
+            data_selector: LINUX_BOOT_DS,
+            regs,
+            rsp: 0,
+        };
+        VmxSetupConfig {
+            boot_mode: match self.entry {
+                LinuxBootEntry::Bits32 => VmxBootMode::ProtectedMode(state),
+                LinuxBootEntry::Bits64 { cr3 } => VmxBootMode::LongMode { cr3, state },
+            },
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vmx::guest_mem::FlatMemory;
+    use alloc::vec;
+
+    const ZERO_PAGE: usize = 0x7000;
+    const GDT: usize = 0x6000;
+
+    fn memory_with_header(version: u16, xloadflags: u16) -> FlatMemory {
+        let mut mem = FlatMemory(vec![0; 0x10000]);
+        mem.write_uint((ZERO_PAGE + SETUP_HEADER_BOOT_FLAG).into(), 2, 0xaa55)
+            .unwrap();
+        mem.write((ZERO_PAGE + SETUP_HEADER_MAGIC).into(), b"HdrS")
+            .unwrap();
+        mem.write_uint((ZERO_PAGE + SETUP_HEADER_VERSION).into(), 2, version as u64)
+            .unwrap();
+        mem.write_uint(
+            (ZERO_PAGE + SETUP_HEADER_XLOADFLAGS).into(),
+            2,
+            xloadflags as u64,
+        )
+        .unwrap();
+        mem
+    }
+
+    fn config(entry: LinuxBootEntry) -> LinuxBootConfig {
+        LinuxBootConfig {
+            zero_page: ZERO_PAGE.into(),
+            gdt: GDT.into(),
+            entry,
+        }
+    }
+
+    #[test]
+    fn test_linux_32bit_boot_state() {
+        let mut mem = memory_with_header(0x020f, 0);
+        let boot = config(LinuxBootEntry::Bits32);
+        boot.validate(&mem, 0x10_0000.into()).unwrap();
+        boot.write_gdt(&mut mem).unwrap();
+
+        let cs = mem
+            .read_uint((GDT + LINUX_BOOT_CS as usize).into(), 8)
+            .unwrap();
+        let ds = mem
+            .read_uint((GDT + LINUX_BOOT_DS as usize).into(), 8)
+            .unwrap();
+        assert_eq!(cs, 0x00cf_9b00_0000_ffff);
+        assert_eq!(ds, 0x00cf_9300_0000_ffff);
+
+        let mode = boot.setup_config().boot_mode;
+        let state = mode.flat_state().unwrap();
+        assert_eq!(state.regs.rsi, ZERO_PAGE as u64);
+        assert_eq!(state.regs.rbp | state.regs.rdi | state.regs.rbx, 0);
+        assert_eq!(state.gdt_limit as usize, LINUX_BOOT_GDT_SIZE - 1);
+        assert!(!mode.is_long_mode());
+        assert!(mode.cr0().contains(Cr0Flags::PROTECTED_MODE_ENABLE));
+        assert!(!mode.cr0().contains(Cr0Flags::PAGING));
+        assert_eq!(mode.code_segment().selector, LINUX_BOOT_CS);
+        assert_eq!(mode.code_segment().access_rights, 0xc09b);
+        assert_eq!(mode.data_segment().selector, LINUX_BOOT_DS);
+    }
+
+    #[test]
+    fn test_linux_64bit_boot_state() {
+        let mut mem = memory_with_header(0x020f, XLF_KERNEL_64);
+        let boot = config(LinuxBootEntry::Bits64 { cr3: 0x9000 });
+        boot.validate(&mem, 0x10_0200.into()).unwrap();
+        boot.write_gdt(&mut mem).unwrap();
+
+        let cs = mem
+            .read_uint((GDT + LINUX_BOOT_CS as usize).into(), 8)
+            .unwrap();
+        assert_eq!(cs, 0x00af_9b00_0000_ffff);
+
+        let mode = boot.setup_config().boot_mode;
+        assert!(mode.is_long_mode());
+        assert_eq!(mode.cr3(), 0x9000);
+        assert!(
+            mode.cr0()
+                .contains(Cr0Flags::PROTECTED_MODE_ENABLE | Cr0Flags::PAGING)
+        );
+        assert!(mode.cr4().contains(Cr4Flags::PHYSICAL_ADDRESS_EXTENSION));
+        assert!(
+            mode.efer()
+                .contains(EferFlags::LONG_MODE_ENABLE | EferFlags::LONG_MODE_ACTIVE)
+        );
+        assert_eq!(mode.code_segment().access_rights, 0xa09b);
+        assert_eq!(mode.flat_state().unwrap().regs.rsi, ZERO_PAGE as u64);
+    }
+
+    #[test]
+    fn test_linux_boot_validation() {
+        let mem = memory_with_header(0x020f, 0);
+        // No 64-bit entry advertised in xloadflags.
+        assert!(
+            config(LinuxBootEntry::Bits64 { cr3: 0x9000 })
+                .validate(&mem, 0x10_0200.into())
+                .is_err()
+        );
+        // Misaligned page table root.
+        let mem = memory_with_header(0x020f, XLF_KERNEL_64);
+        assert!(
+            config(LinuxBootEntry::Bits64 { cr3: 0x9008 })
+                .validate(&mem, 0x10_0200.into())
+                .is_err()
+        );
+        // GDT inside the zero page.
+        let boot = LinuxBootConfig {
+            gdt: (ZERO_PAGE + 0x800).into(),
+            ..config(LinuxBootEntry::Bits32)
+        };
+        assert!(boot.validate(&mem, 0x10_0000.into()).is_err());
+        // Entry point out of reach of the 32-bit entry.
+        assert!(
+            config(LinuxBootEntry::Bits32)
+                .validate(&mem, 0x1_0000_0000.into())
+                .is_err()
+        );
+        // Missing setup header.
+        let mem = FlatMemory(vec![0; 0x10000]);
+        assert!(
+            config(LinuxBootEntry::Bits32)
+                .validate(&mem, 0x10_0000.into())
+                .is_err()
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use core::fmt::{Debug, Formatter, Result};
 
 /// VM instruction error numbers. (SDM Vol. 3C, Section 30.4)
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct VmxInstructionError(u32);
 
 impl VmxInstructionError {
@@ -272,3 +273,20 @@ impl VmxInterruptionType {
         )
     }
 }
+
+numeric_enum_macro::numeric_enum! {
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+/// The guest activity state. (SDM Vol. 3C, Section 24.4.2)
+pub enum VmxActivityState {
+    /// The logical processor is executing instructions normally.
+    Active = 0,
+    /// The logical processor is inactive because it executed the HLT instruction.
+    Hlt = 1,
+    /// The logical processor is inactive because it incurred a triple fault
+    /// or some other serious error.
+    Shutdown = 2,
+    /// The logical processor is inactive because it is waiting for a startup-IPI (SIPI).
+    WaitForSipi = 3,
+}
+}
@@ -0,0 +1,1795 @@
+//! Software implementation of the checks performed on VM entry. (SDM Vol. 3C, Sections 26.2 and 26.3)
+//!
+//! Hardware only reports that *some* control, host-state or guest-state field
+//! is invalid. Running the same checks in software tells which field and which
+//! rule it was.
+
+use alloc::collections::BTreeMap;
+use core::fmt::{Display, Formatter, Result as FmtResult};
+
+use bit_field::BitField;
+use x86::bits64::vmx;
+use x86_64::registers::control::{Cr0Flags, Cr4Flags, EferFlags};
+
+use super::vmcs::controls::{
+    EntryControls as EntryCtrl, ExitControls as ExitCtrl, PinbasedControls as PinCtrl,
+    PrimaryControls as CpuCtrl, SecondaryControls as CpuCtrl2,
+};
+use super::vmcs::{
+    VmcsControl16, VmcsControl32, VmcsControl64, VmcsGuest16, VmcsGuest32, VmcsGuest64,
+    VmcsGuestNW, VmcsHost16, VmcsHost64, VmcsHostNW,
+};
+use crate::msr::Msr;
+
+/// Read access to the fields of a VMCS.
+pub trait VmcsFields {
+    /// Read the field with the given encoding, `None` if it is not supported.
+    fn read_field(&self, encoding: u32) -> Option<u64>;
+}
+
+/// The current VMCS of this logical processor, read with VMREAD.
+pub struct CurrentVmcs;
+
+impl VmcsFields for CurrentVmcs {
+    fn read_field(&self, encoding: u32) -> Option<u64> {
+        unsafe { vmx::vmread(encoding).ok() }
+    }
+}
+
+/// A captured copy of VMCS fields, e.g. taken from a log of a failed entry.
+#[derive(Clone, Debug, Default)]
+pub struct VmcsImage {
+    fields: BTreeMap<u32, u64>,
+}
+
+impl VmcsImage {
+    /// Create an image with all fields missing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the field with the given encoding.
+    pub fn set(&mut self, encoding: u32, value: u64) -> &mut Self {
+        self.fields.insert(encoding, value);
+        self
+    }
+}
+
+impl FromIterator<(u32, u64)> for VmcsImage {
+    fn from_iter<T: IntoIterator<Item = (u32, u64)>>(iter: T) -> Self {
+        Self {
+            fields: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl VmcsFields for VmcsImage {
+    fn read_field(&self, encoding: u32) -> Option<u64> {
+        self.fields.get(&encoding).copied()
+    }
+}
+
+/// VMX capabilities the checks are made against. (SDM Vol. 3D, Appendix A)
+#[derive(Clone, Debug, Default)]
+pub struct VmxCapabilities {
+    /// IA32_VMX_BASIC.
+    pub basic: u64,
+    /// IA32_VMX_TRUE_PINBASED_CTLS, or IA32_VMX_PINBASED_CTLS without the true controls.
+    pub pinbased: u64,
+    /// IA32_VMX_TRUE_PROCBASED_CTLS, or IA32_VMX_PROCBASED_CTLS without the true controls.
+    pub procbased: u64,
+    /// IA32_VMX_PROCBASED_CTLS2, 0 if secondary controls are not supported.
+    pub procbased2: u64,
+    /// IA32_VMX_TRUE_EXIT_CTLS, or IA32_VMX_EXIT_CTLS without the true controls.
+    pub exit: u64,
+    /// IA32_VMX_TRUE_ENTRY_CTLS, or IA32_VMX_ENTRY_CTLS without the true controls.
+    pub entry: u64,
+    /// IA32_VMX_MISC.
+    pub misc: u64,
+    /// IA32_VMX_CR0_FIXED0.
+    pub cr0_fixed0: u64,
+    /// IA32_VMX_CR0_FIXED1.
+    pub cr0_fixed1: u64,
+    /// IA32_VMX_CR4_FIXED0.
+    pub cr4_fixed0: u64,
+    /// IA32_VMX_CR4_FIXED1.
+    pub cr4_fixed1: u64,
+    /// IA32_VMX_EPT_VPID_CAP, 0 if neither EPT nor VPID is supported.
+    pub ept_vpid_cap: u64,
+    /// The physical-address width (MAXPHYADDR).
+    pub phys_addr_width: u8,
+}
+
+impl VmxCapabilities {
+    /// Read the capabilities of the current processor.
+    pub fn read() -> Self {
+        let basic = Msr::IA32_VMX_BASIC.read();
+        let true_ctls = basic.get_bit(55);
+        let pick = |true_msr: Msr, msr: Msr| if true_ctls { true_msr } else { msr }.read();
+        let procbased = pick(
+            Msr::IA32_VMX_TRUE_PROCBASED_CTLS,
+            Msr::IA32_VMX_PROCBASED_CTLS,
+        );
+        let procbased2 = if procbased.get_bit(32 + 31) {
+            Msr::IA32_VMX_PROCBASED_CTLS2.read()
+        } else {
+            0
+        };
+        let ept_or_vpid = (CpuCtrl2::ENABLE_EPT | CpuCtrl2::ENABLE_VPID).bits() as u64;
+        let ept_vpid_cap = if (procbased2 >> 32) & ept_or_vpid != 0 {
+            Msr::IA32_VMX_EPT_VPID_CAP.read()
+        } else {
+            0
+        };
+        let phys_addr_width = raw_cpuid::CpuId::new()
+            .get_processor_capacity_feature_info()
+            .map_or(36, |info| info.physical_address_bits());
+        Self {
+            basic,
+            pinbased: pick(
+                Msr::IA32_VMX_TRUE_PINBASED_CTLS,
+                Msr::IA32_VMX_PINBASED_CTLS,
+            ),
+            procbased,
+            procbased2,
+            exit: pick(Msr::IA32_VMX_TRUE_EXIT_CTLS, Msr::IA32_VMX_EXIT_CTLS),
+            entry: pick(Msr::IA32_VMX_TRUE_ENTRY_CTLS, Msr::IA32_VMX_ENTRY_CTLS),
+            misc: Msr::IA32_VMX_MISC.read(),
+            cr0_fixed0: Msr::IA32_VMX_CR0_FIXED0.read(),
+            cr0_fixed1: Msr::IA32_VMX_CR0_FIXED1.read(),
+            cr4_fixed0: Msr::IA32_VMX_CR4_FIXED0.read(),
+            cr4_fixed1: Msr::IA32_VMX_CR4_FIXED1.read(),
+            ept_vpid_cap,
+            phys_addr_width,
+        }
+    }
+}
+
+/// The group of checks a failure belongs to, which also determines how the
+/// hardware reports it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryCheckArea {
+    /// VM-execution, VM-exit or VM-entry controls. Hardware fails the VMLAUNCH or
+    /// VMRESUME with VM-instruction error 7. (SDM Vol. 3C, Section 26.2.1)
+    Control,
+    /// Host-state area. Hardware fails the VMLAUNCH or VMRESUME with
+    /// VM-instruction error 8. (SDM Vol. 3C, Sections 26.2.2 to 26.2.4)
+    Host,
+    /// Guest-state area. Hardware performs a VM exit with basic exit reason 33
+    /// and the entry-failure bit set. (SDM Vol. 3C, Section 26.3.1)
+    Guest,
+}
+
+/// A VM-entry check that does not hold.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EntryCheckError {
+    /// The group of checks the rule belongs to.
+    pub area: EntryCheckArea,
+    /// Name of the offending VMCS field.
+    pub field: &'static str,
+    /// Encoding of the offending VMCS field.
+    pub encoding: u32,
+    /// Value of the field.
+    pub value: u64,
+    /// The rule that does not hold.
+    pub rule: &'static str,
+}
+
+impl Display for EntryCheckError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(
+            f,
+            "{:?} check failed on {} ({:#x}) = {:#x}: {}",
+            self.area, self.field, self.encoding, self.value, self.rule
+        )
+    }
+}
+
+/// Run all VM-entry checks over `vmcs`.
+pub fn check_vm_entry(
+    vmcs: &impl VmcsFields,
+    caps: &VmxCapabilities,
+) -> Result<(), EntryCheckError> {
+    let checker = Checker::new(vmcs, caps);
+    checker.check_controls()?;
+    checker.check_host_state()?;
+    checker.check_guest_state()
+}
+
+/// Run all VM-entry checks over the current VMCS against the capabilities of
+/// the current processor.
+pub fn check_current_vmcs() -> Result<(), EntryCheckError> {
+    check_vm_entry(&CurrentVmcs, &VmxCapabilities::read())
+}
+
+#[derive(Clone, Copy)]
+struct Field {
+    encoding: u32,
+    name: &'static str,
+}
+
+macro_rules! field {
+    ($ty:ident :: $name:ident) => {
+        Field {
+            encoding: $ty::$name as u32,
+            name: concat!(stringify!($ty), "::", stringify!($name)),
+        }
+    };
+}
+
+/// Defined bits of segment access rights. (SDM Vol. 3C, Section 24.4.1, Table 24-2)
+mod ar {
+    pub const TYPE: core::ops::Range<usize> = 0..4;
+    pub const S: usize = 4;
+    pub const DPL: core::ops::Range<usize> = 5..7;
+    pub const P: usize = 7;
+    pub const L: usize = 13;
+    pub const DB: usize = 14;
+    pub const G: usize = 15;
+    pub const UNUSABLE: usize = 16;
+    pub const RESERVED: u64 = 0xfffe_0f00;
+}
+
+struct Segment {
+    selector: u64,
+    base: u64,
+    limit: u64,
+    ar: u64,
+    selector_field: Field,
+    base_field: Field,
+    limit_field: Field,
+    ar_field: Field,
+}
+
+impl Segment {
+    fn usable(&self) -> bool {
+        !self.ar.get_bit(ar::UNUSABLE)
+    }
+
+    fn seg_type(&self) -> u64 {
+        self.ar.get_bits(ar::TYPE)
+    }
+
+    fn dpl(&self) -> u64 {
+        self.ar.get_bits(ar::DPL)
+    }
+
+    fn rpl(&self) -> u64 {
+        self.selector.get_bits(0..2)
+    }
+}
+
+struct Checker<'a, V: VmcsFields> {
+    vmcs: &'a V,
+    caps: &'a VmxCapabilities,
+    pin: u32,
+    cpu: u32,
+    cpu2: u32,
+    exit: u32,
+    entry: u32,
+}
+
+type CheckResult = Result<(), EntryCheckError>;
+
+fn is_canonical(addr: u64) -> bool {
+    let upper = (addr as i64) >> 47;
+    upper == 0 || upper == -1
+}
+
+fn is_valid_pat(pat: u64) -> bool {
+    // Each entry must be UC (0), WC (1), WT (4), WP (5), WB (6) or UC- (7).
+    (0..8).all(|i| matches!(pat.get_bits(i * 8..i * 8 + 8), 0 | 1 | 4..=7))
+}
+
+fn conforms(value: u32, cap: u64) -> bool {
+    let allowed0 = cap as u32;
+    let allowed1 = (cap >> 32) as u32;
+    value & allowed0 == allowed0 && value & !allowed1 == 0
+}
+
+impl<'a, V: VmcsFields> Checker<'a, V> {
+    fn new(vmcs: &'a V, caps: &'a VmxCapabilities) -> Self {
+        let mut checker = Self {
+            vmcs,
+            caps,
+            pin: 0,
+            cpu: 0,
+            cpu2: 0,
+            exit: 0,
+            entry: 0,
+        };
+        checker.pin = checker.read(field!(VmcsControl32::PINBASED_EXEC_CONTROLS)) as u32;
+        checker.cpu = checker.read(field!(VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS)) as u32;
+        // The secondary controls are treated as 0 if they are not activated.
+        if CpuCtrl::from_bits_truncate(checker.cpu).contains(CpuCtrl::SECONDARY_CONTROLS) {
+            checker.cpu2 =
+                checker.read(field!(VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS)) as u32;
+        }
+        checker.exit = checker.read(field!(VmcsControl32::VMEXIT_CONTROLS)) as u32;
+        checker.entry = checker.read(field!(VmcsControl32::VMENTRY_CONTROLS)) as u32;
+        checker
+    }
+
+    fn read(&self, field: Field) -> u64 {
+        self.vmcs.read_field(field.encoding).unwrap_or(0)
+    }
+
+    fn check(
+        &self,
+        ok: bool,
+        area: EntryCheckArea,
+        field: Field,
+        value: u64,
+        rule: &'static str,
+    ) -> CheckResult {
+        if ok {
+            Ok(())
+        } else {
+            Err(EntryCheckError {
+                area,
+                field: field.name,
+                encoding: field.encoding,
+                value,
+                rule,
+            })
+        }
+    }
+
+    fn pin(&self) -> PinCtrl {
+        PinCtrl::from_bits_truncate(self.pin)
+    }
+
+    fn cpu(&self) -> CpuCtrl {
+        CpuCtrl::from_bits_truncate(self.cpu)
+    }
+
+    fn cpu2(&self) -> CpuCtrl2 {
+        CpuCtrl2::from_bits_truncate(self.cpu2)
+    }
+
+    fn exit(&self) -> ExitCtrl {
+        ExitCtrl::from_bits_truncate(self.exit)
+    }
+
+    fn entry(&self) -> EntryCtrl {
+        EntryCtrl::from_bits_truncate(self.entry)
+    }
+
+    fn fits_phys_width(&self, addr: u64) -> bool {
+        self.caps.phys_addr_width >= 64 || addr >> self.caps.phys_addr_width == 0
+    }
+
+    /// Check that a control field points to a properly aligned physical address.
+    fn check_address(&self, field: Field, align: u64, rule: &'static str) -> CheckResult {
+        let addr = self.read(field);
+        self.check(
+            addr.is_multiple_of(align) && self.fits_phys_width(addr),
+            EntryCheckArea::Control,
+            field,
+            addr,
+            rule,
+        )
+    }
+
+    /// Checks on VM-execution, VM-exit and VM-entry control fields. (SDM Vol. 3C, Section 26.2.1)
+    fn check_controls(&self) -> CheckResult {
+        use EntryCheckArea::Control;
+
+        // VM-execution control fields. (SDM Vol. 3C, Section 26.2.1.1)
+        self.check(
+            conforms(self.pin, self.caps.pinbased),
+            Control,
+            field!(VmcsControl32::PINBASED_EXEC_CONTROLS),
+            self.pin as u64,
+            "reserved bits must be set according to IA32_VMX_(TRUE_)PINBASED_CTLS",
+        )?;
+        self.check(
+            conforms(self.cpu, self.caps.procbased),
+            Control,
+            field!(VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS),
+            self.cpu as u64,
+            "reserved bits must be set according to IA32_VMX_(TRUE_)PROCBASED_CTLS",
+        )?;
+        self.check(
+            conforms(self.cpu2, self.caps.procbased2),
+            Control,
+            field!(VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS),
+            self.cpu2 as u64,
+            "reserved bits must be set according to IA32_VMX_PROCBASED_CTLS2",
+        )?;
+
+        let cr3_target_count = self.read(field!(VmcsControl32::CR3_TARGET_COUNT));
+        self.check(
+            cr3_target_count <= self.caps.misc.get_bits(16..25),
+            Control,
+            field!(VmcsControl32::CR3_TARGET_COUNT),
+            cr3_target_count,
+            "CR3-target count must not exceed the value reported in IA32_VMX_MISC",
+        )?;
+
+        let (pin, cpu, cpu2) = (self.pin(), self.cpu(), self.cpu2());
+        if cpu.contains(CpuCtrl::USE_IO_BITMAPS) {
+            for field in [
+                field!(VmcsControl64::IO_BITMAP_A_ADDR),
+                field!(VmcsControl64::IO_BITMAP_B_ADDR),
+            ] {
+                self.check_address(
+                    field,
+                    0x1000,
+                    "I/O bitmap address must be 4-KByte aligned and within the physical-address width",
+                )?;
+            }
+        }
+        if cpu.contains(CpuCtrl::USE_MSR_BITMAPS) {
+            self.check_address(
+                field!(VmcsControl64::MSR_BITMAPS_ADDR),
+                0x1000,
+                "MSR bitmap address must be 4-KByte aligned and within the physical-address width",
+            )?;
+        }
+
+        let tpr_threshold = self.read(field!(VmcsControl32::TPR_THRESHOLD));
+        if cpu.contains(CpuCtrl::USE_TPR_SHADOW) {
+            self.check_address(
+                field!(VmcsControl64::VIRT_APIC_ADDR),
+                0x1000,
+                "virtual-APIC address must be 4-KByte aligned and within the physical-address width",
+            )?;
+            if !cpu2.contains(CpuCtrl2::VIRTUAL_INTERRUPT_DELIVERY) {
+                self.check(
+                    tpr_threshold.get_bits(4..32) == 0,
+                    Control,
+                    field!(VmcsControl32::TPR_THRESHOLD),
+                    tpr_threshold,
+                    "bits 31:4 of the TPR threshold must be 0",
+                )?;
+            }
+        } else {
+            self.check(
+                !cpu2.intersects(
+                    CpuCtrl2::VIRTUALIZE_X2APIC
+                        | CpuCtrl2::VIRTUALIZE_APIC_REGISTER
+                        | CpuCtrl2::VIRTUAL_INTERRUPT_DELIVERY,
+                ),
+                Control,
+                field!(VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS),
+                self.cpu2 as u64,
+                "x2APIC virtualization, APIC-register virtualization and virtual-interrupt delivery require \"use TPR shadow\"",
+            )?;
+        }
+
+        if !pin.contains(PinCtrl::NMI_EXITING) {
+            self.check(
+                !pin.contains(PinCtrl::VIRTUAL_NMIS),
+                Control,
+                field!(VmcsControl32::PINBASED_EXEC_CONTROLS),
+                self.pin as u64,
+                "\"virtual NMIs\" requires \"NMI exiting\"",
+            )?;
+        }
+        if !pin.contains(PinCtrl::VIRTUAL_NMIS) {
+            self.check(
+                !cpu.contains(CpuCtrl::NMI_WINDOW_EXITING),
+                Control,
+                field!(VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS),
+                self.cpu as u64,
+                "\"NMI-window exiting\" requires \"virtual NMIs\"",
+            )?;
+        }
+
+        if cpu2.contains(CpuCtrl2::VIRTUALIZE_APIC) {
+            self.check_address(
+                field!(VmcsControl64::APIC_ACCESS_ADDR),
+                0x1000,
+                "APIC-access address must be 4-KByte aligned and within the physical-address width",
+            )?;
+        }
+        if cpu2.contains(CpuCtrl2::VIRTUALIZE_X2APIC) {
+            self.check(
+                !cpu2.contains(CpuCtrl2::VIRTUALIZE_APIC),
+                Control,
+                field!(VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS),
+                self.cpu2 as u64,
+                "\"virtualize x2APIC mode\" and \"virtualize APIC accesses\" are mutually exclusive",
+            )?;
+        }
+        if cpu2.contains(CpuCtrl2::VIRTUAL_INTERRUPT_DELIVERY) {
+            self.check(
+                pin.contains(PinCtrl::EXTERNAL_INTERRUPT_EXITING),
+                Control,
+                field!(VmcsControl32::PINBASED_EXEC_CONTROLS),
+                self.pin as u64,
+                "\"virtual-interrupt delivery\" requires \"external-interrupt exiting\"",
+            )?;
+        }
+        if pin.contains(PinCtrl::POSTED_INTERRUPTS) {
+            self.check(
+                cpu2.contains(CpuCtrl2::VIRTUAL_INTERRUPT_DELIVERY)
+                    && self.exit().contains(ExitCtrl::ACK_INTERRUPT_ON_EXIT),
+                Control,
+                field!(VmcsControl32::PINBASED_EXEC_CONTROLS),
+                self.pin as u64,
+                "\"process posted interrupts\" requires \"virtual-interrupt delivery\" and \"acknowledge interrupt on exit\"",
+            )?;
+            let vector = self.read(field!(VmcsControl16::POSTED_INTERRUPT_NOTIFICATION_VECTOR));
+            self.check(
+                vector.get_bits(8..16) == 0,
+                Control,
+                field!(VmcsControl16::POSTED_INTERRUPT_NOTIFICATION_VECTOR),
+                vector,
+                "bits 15:8 of the posted-interrupt notification vector must be 0",
+            )?;
+            self.check_address(
+                field!(VmcsControl64::POSTED_INTERRUPT_DESC_ADDR),
+                64,
+                "posted-interrupt descriptor address must be 64-byte aligned and within the physical-address width",
+            )?;
+        }
+
+        if cpu2.contains(CpuCtrl2::ENABLE_VPID) {
+            let vpid = self.read(field!(VmcsControl16::VPID));
+            self.check(
+                vpid != 0,
+                Control,
+                field!(VmcsControl16::VPID),
+                vpid,
+                "VPID must not be 0 when \"enable VPID\" is 1",
+            )?;
+        }
+        if cpu2.contains(CpuCtrl2::ENABLE_EPT) {
+            self.check_eptp()?;
+        }
+        if cpu2.contains(CpuCtrl2::UNRESTRICTED_GUEST) {
+            self.check(
+                cpu2.contains(CpuCtrl2::ENABLE_EPT),
+                Control,
+                field!(VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS),
+                self.cpu2 as u64,
+                "\"unrestricted guest\" requires \"enable EPT\"",
+            )?;
+        }
+
+        // VM-exit control fields. (SDM Vol. 3C, Section 26.2.1.2)
+        self.check(
+            conforms(self.exit, self.caps.exit),
+            Control,
+            field!(VmcsControl32::VMEXIT_CONTROLS),
+            self.exit as u64,
+            "reserved bits must be set according to IA32_VMX_(TRUE_)EXIT_CTLS",
+        )?;
+        if !pin.contains(PinCtrl::VMX_PREEMPTION_TIMER) {
+            self.check(
+                !self.exit().contains(ExitCtrl::SAVE_VMX_PREEMPTION_TIMER),
+                Control,
+                field!(VmcsControl32::VMEXIT_CONTROLS),
+                self.exit as u64,
+                "\"save VMX-preemption timer value\" requires \"activate VMX-preemption timer\"",
+            )?;
+        }
+        for (count, addr) in [
+            (
+                field!(VmcsControl32::VMEXIT_MSR_STORE_COUNT),
+                field!(VmcsControl64::VMEXIT_MSR_STORE_ADDR),
+            ),
+            (
+                field!(VmcsControl32::VMEXIT_MSR_LOAD_COUNT),
+                field!(VmcsControl64::VMEXIT_MSR_LOAD_ADDR),
+            ),
+            (
+                field!(VmcsControl32::VMENTRY_MSR_LOAD_COUNT),
+                field!(VmcsControl64::VMENTRY_MSR_LOAD_ADDR),
+            ),
+        ] {
+            if self.read(count) != 0 {
+                self.check_address(
+                    addr,
+                    16,
+                    "MSR area address must be 16-byte aligned and within the physical-address width",
+                )?;
+            }
+        }
+
+        // VM-entry control fields. (SDM Vol. 3C, Section 26.2.1.3)
+        self.check(
+            conforms(self.entry, self.caps.entry),
+            Control,
+            field!(VmcsControl32::VMENTRY_CONTROLS),
+            self.entry as u64,
+            "reserved bits must be set according to IA32_VMX_(TRUE_)ENTRY_CTLS",
+        )?;
+        self.check(
+            !self
+                .entry()
+                .intersects(EntryCtrl::ENTRY_TO_SMM | EntryCtrl::DEACTIVATE_DUAL_MONITOR),
+            Control,
+            field!(VmcsControl32::VMENTRY_CONTROLS),
+            self.entry as u64,
+            "\"entry to SMM\" and \"deactivate dual-monitor treatment\" must be 0 outside SMM",
+        )?;
+        self.check_event_injection()
+    }
+
+    /// Checks on the EPT pointer. (SDM Vol. 3C, Section 26.2.1.1 and 24.6.11)
+    fn check_eptp(&self) -> CheckResult {
+        let field = field!(VmcsControl64::EPTP);
+        let eptp = self.read(field);
+        let cap = self.caps.ept_vpid_cap;
+        let mem_type_ok = match eptp.get_bits(0..3) {
+            0 => cap.get_bit(8),
+            6 => cap.get_bit(14),
+            _ => false,
+        };
+        self.check(
+            mem_type_ok,
+            EntryCheckArea::Control,
+            field,
+            eptp,
+            "EPT paging-structure memory type must be UC or WB and supported by IA32_VMX_EPT_VPID_CAP",
+        )?;
+        let walk_ok = match eptp.get_bits(3..6) {
+            3 => cap.get_bit(6),
+            4 => cap.get_bit(7),
+            _ => false,
+        };
+        self.check(
+            walk_ok,
+            EntryCheckArea::Control,
+            field,
+            eptp,
+            "EPT page-walk length minus 1 must be a length supported by IA32_VMX_EPT_VPID_CAP",
+        )?;
+        self.check(
+            !eptp.get_bit(6) || cap.get_bit(21),
+            EntryCheckArea::Control,
+            field,
+            eptp,
+            "EPT accessed and dirty flags are not supported",
+        )?;
+        self.check(
+            eptp.get_bits(8..12) == 0 && self.fits_phys_width(eptp & !0xfff),
+            EntryCheckArea::Control,
+            field,
+            eptp,
+            "reserved bits 11:8 and bits beyond the physical-address width of the EPT pointer must be 0",
+        )
+    }
+
+    /// Checks on the VM-entry interruption-information field. (SDM Vol. 3C, Section 26.2.1.3)
+    fn check_event_injection(&self) -> CheckResult {
+        use EntryCheckArea::Control;
+
+        let field = field!(VmcsControl32::VMENTRY_INTERRUPTION_INFO_FIELD);
+        let info = self.read(field);
+        if !info.get_bit(31) {
+            return Ok(());
+        }
+        let vector = info.get_bits(0..8);
+        let int_type = info.get_bits(8..11);
+        self.check(
+            int_type != 1,
+            Control,
+            field,
+            info,
+            "interruption type 1 is reserved",
+        )?;
+        self.check(
+            int_type != 7 || self.caps.procbased.get_bit(32 + 27),
+            Control,
+            field,
+            info,
+            "interruption type 7 (other event) requires support for monitor trap flag",
+        )?;
+        self.check(
+            int_type != 2 || vector == 2,
+            Control,
+            field,
+            info,
+            "an NMI must use vector 2",
+        )?;
+        self.check(
+            int_type != 3 || vector <= 31,
+            Control,
+            field,
+            info,
+            "a hardware exception must use a vector in 0..=31",
+        )?;
+        self.check(
+            int_type != 7 || vector == 0,
+            Control,
+            field,
+            info,
+            "an other event must use vector 0",
+        )?;
+
+        let deliver_error_code = info.get_bit(11);
+        let unrestricted_real = self.cpu2().contains(CpuCtrl2::UNRESTRICTED_GUEST)
+            && !Cr0Flags::from_bits_truncate(self.read(field!(VmcsGuestNW::CR0)))
+                .contains(Cr0Flags::PROTECTED_MODE_ENABLE);
+        let has_error_code =
+            int_type == 3 && matches!(vector, 8 | 10..=14 | 17) && !unrestricted_real;
+        self.check(
+            deliver_error_code == has_error_code,
+            Control,
+            field,
+            info,
+            "\"deliver error code\" must be 1 exactly for hardware exceptions #DF, #TS, #NP, #SS, #GP, #PF and #AC in protected mode",
+        )?;
+        self.check(
+            info.get_bits(12..31) == 0,
+            Control,
+            field,
+            info,
+            "reserved bits 30:12 must be 0",
+        )?;
+        if deliver_error_code {
+            let err_field = field!(VmcsControl32::VMENTRY_EXCEPTION_ERR_CODE);
+            let err_code = self.read(err_field);
+            self.check(
+                err_code.get_bits(16..32) == 0,
+                Control,
+                err_field,
+                err_code,
+                "bits 31:16 of the VM-entry exception error code must be 0",
+            )?;
+        }
+        if matches!(int_type, 4..=6) {
+            let len_field = field!(VmcsControl32::VMENTRY_INSTRUCTION_LEN);
+            let len = self.read(len_field);
+            self.check(
+                len <= 15 && (len > 0 || self.caps.misc.get_bit(30)),
+                Control,
+                len_field,
+                len,
+                "VM-entry instruction length of a software event must be in 1..=15",
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Checks on host control registers, MSRs, segment and descriptor-table
+    /// registers and address-space size. (SDM Vol. 3C, Sections 26.2.2 to 26.2.4)
+    fn check_host_state(&self) -> CheckResult {
+        use EntryCheckArea::Host;
+
+        let cr0 = self.read(field!(VmcsHostNW::CR0));
+        self.check(
+            cr0 & self.caps.cr0_fixed0 == self.caps.cr0_fixed0 && cr0 & !self.caps.cr0_fixed1 == 0,
+            Host,
+            field!(VmcsHostNW::CR0),
+            cr0,
+            "CR0 must be set according to IA32_VMX_CR0_FIXED0 and IA32_VMX_CR0_FIXED1",
+        )?;
+        let cr4 = self.read(field!(VmcsHostNW::CR4));
+        self.check(
+            cr4 & self.caps.cr4_fixed0 == self.caps.cr4_fixed0 && cr4 & !self.caps.cr4_fixed1 == 0,
+            Host,
+            field!(VmcsHostNW::CR4),
+            cr4,
+            "CR4 must be set according to IA32_VMX_CR4_FIXED0 and IA32_VMX_CR4_FIXED1",
+        )?;
+        let cr3 = self.read(field!(VmcsHostNW::CR3));
+        self.check(
+            self.fits_phys_width(cr3),
+            Host,
+            field!(VmcsHostNW::CR3),
+            cr3,
+            "bits of CR3 beyond the physical-address width must be 0",
+        )?;
+        for field in [
+            field!(VmcsHostNW::IA32_SYSENTER_ESP),
+            field!(VmcsHostNW::IA32_SYSENTER_EIP),
+            field!(VmcsHostNW::FS_BASE),
+            field!(VmcsHostNW::GS_BASE),
+            field!(VmcsHostNW::TR_BASE),
+            field!(VmcsHostNW::GDTR_BASE),
+            field!(VmcsHostNW::IDTR_BASE),
+        ] {
+            let addr = self.read(field);
+            self.check(
+                is_canonical(addr),
+                Host,
+                field,
+                addr,
+                "address must be canonical",
+            )?;
+        }
+
+        let exit = self.exit();
+        if exit.contains(ExitCtrl::LOAD_IA32_PAT) {
+            let pat = self.read(field!(VmcsHost64::IA32_PAT));
+            self.check(
+                is_valid_pat(pat),
+                Host,
+                field!(VmcsHost64::IA32_PAT),
+                pat,
+                "each PAT entry must be a valid memory type",
+            )?;
+        }
+        let host_64bit = exit.contains(ExitCtrl::HOST_ADDRESS_SPACE_SIZE);
+        if exit.contains(ExitCtrl::LOAD_IA32_EFER) {
+            let efer = self.read(field!(VmcsHost64::IA32_EFER));
+            let lma = efer & EferFlags::LONG_MODE_ACTIVE.bits() != 0;
+            let lme = efer & EferFlags::LONG_MODE_ENABLE.bits() != 0;
+            self.check(
+                efer_reserved_bits_clear(efer) && lma == host_64bit && lme == host_64bit,
+                Host,
+                field!(VmcsHost64::IA32_EFER),
+                efer,
+                "reserved bits of IA32_EFER must be 0, and LMA and LME must equal \"host address-space size\"",
+            )?;
+        }
+
+        for field in [
+            field!(VmcsHost16::ES_SELECTOR),
+            field!(VmcsHost16::CS_SELECTOR),
+            field!(VmcsHost16::SS_SELECTOR),
+            field!(VmcsHost16::DS_SELECTOR),
+            field!(VmcsHost16::FS_SELECTOR),
+            field!(VmcsHost16::GS_SELECTOR),
+            field!(VmcsHost16::TR_SELECTOR),
+        ] {
+            let selector = self.read(field);
+            self.check(
+                selector.get_bits(0..3) == 0,
+                Host,
+                field,
+                selector,
+                "RPL and TI of host selectors must be 0",
+            )?;
+        }
+        for field in [
+            field!(VmcsHost16::CS_SELECTOR),
+            field!(VmcsHost16::TR_SELECTOR),
+        ] {
+            let selector = self.read(field);
+            self.check(
+                selector != 0,
+                Host,
+                field,
+                selector,
+                "host CS and TR selectors must not be 0",
+            )?;
+        }
+
+        // This crate only runs on 64-bit hosts, which are in IA-32e mode.
+        self.check(
+            host_64bit,
+            Host,
+            field!(VmcsControl32::VMEXIT_CONTROLS),
+            self.exit as u64,
+            "\"host address-space size\" must be 1 on a processor in IA-32e mode",
+        )?;
+        self.check(
+            cr4 & Cr4Flags::PHYSICAL_ADDRESS_EXTENSION.bits() != 0,
+            Host,
+            field!(VmcsHostNW::CR4),
+            cr4,
+            "CR4.PAE must be 1 when \"host address-space size\" is 1",
+        )?;
+        let rip = self.read(field!(VmcsHostNW::RIP));
+        self.check(
+            is_canonical(rip),
+            Host,
+            field!(VmcsHostNW::RIP),
+            rip,
+            "RIP must be canonical when \"host address-space size\" is 1",
+        )
+    }
+
+    /// Checks on the guest-state area. (SDM Vol. 3C, Section 26.3.1)
+    fn check_guest_state(&self) -> CheckResult {
+        self.check_guest_registers()?;
+        self.check_guest_segments()?;
+        self.check_guest_descriptor_tables()?;
+        self.check_guest_rip_rflags()?;
+        self.check_guest_non_register_state()?;
+        self.check_guest_pdptes()
+    }
+
+    fn guest_ia32e(&self) -> bool {
+        self.entry().contains(EntryCtrl::IA32E_MODE_GUEST)
+    }
+
+    fn unrestricted(&self) -> bool {
+        self.cpu2().contains(CpuCtrl2::UNRESTRICTED_GUEST)
+    }
+
+    /// Checks on guest control registers, debug registers and MSRs. (SDM Vol. 3C, Section 26.3.1.1)
+    fn check_guest_registers(&self) -> CheckResult {
+        use EntryCheckArea::Guest;
+
+        let cr0 = self.read(field!(VmcsGuestNW::CR0));
+        let mut fixed0 = self.caps.cr0_fixed0;
+        if self.unrestricted() {
+            fixed0 &= !(Cr0Flags::PROTECTED_MODE_ENABLE | Cr0Flags::PAGING).bits();
+        }
+        self.check(
+            cr0 & fixed0 == fixed0 && cr0 & !self.caps.cr0_fixed1 == 0,
+            Guest,
+            field!(VmcsGuestNW::CR0),
+            cr0,
+            "CR0 must be set according to IA32_VMX_CR0_FIXED0 and IA32_VMX_CR0_FIXED1",
+        )?;
+        let cr0 = Cr0Flags::from_bits_truncate(cr0);
+        self.check(
+            !cr0.contains(Cr0Flags::PAGING) || cr0.contains(Cr0Flags::PROTECTED_MODE_ENABLE),
+            Guest,
+            field!(VmcsGuestNW::CR0),
+            cr0.bits(),
+            "CR0.PE must be 1 if CR0.PG is 1",
+        )?;
+        let cr4 = self.read(field!(VmcsGuestNW::CR4));
+        self.check(
+            cr4 & self.caps.cr4_fixed0 == self.caps.cr4_fixed0 && cr4 & !self.caps.cr4_fixed1 == 0,
+            Guest,
+            field!(VmcsGuestNW::CR4),
+            cr4,
+            "CR4 must be set according to IA32_VMX_CR4_FIXED0 and IA32_VMX_CR4_FIXED1",
+        )?;
+        let cr4 = Cr4Flags::from_bits_truncate(cr4);
+        if self.guest_ia32e() {
+            self.check(
+                cr0.contains(Cr0Flags::PAGING),
+                Guest,
+                field!(VmcsGuestNW::CR0),
+                cr0.bits(),
+                "CR0.PG must be 1 for an IA-32e mode guest",
+            )?;
+            self.check(
+                cr4.contains(Cr4Flags::PHYSICAL_ADDRESS_EXTENSION),
+                Guest,
+                field!(VmcsGuestNW::CR4),
+                cr4.bits(),
+                "CR4.PAE must be 1 for an IA-32e mode guest",
+            )?;
+        } else {
+            self.check(
+                !cr4.contains(Cr4Flags::PCID),
+                Guest,
+                field!(VmcsGuestNW::CR4),
+                cr4.bits(),
+                "CR4.PCIDE must be 0 outside IA-32e mode",
+            )?;
+        }
+        let cr3 = self.read(field!(VmcsGuestNW::CR3));
+        self.check(
+            self.fits_phys_width(cr3),
+            Guest,
+            field!(VmcsGuestNW::CR3),
+            cr3,
+            "bits of CR3 beyond the physical-address width must be 0",
+        )?;
+
+        let entry = self.entry();
+        if entry.contains(EntryCtrl::LOAD_DEBUG_CONTROLS) {
+            let debugctl = self.read(field!(VmcsGuest64::IA32_DEBUGCTL));
+            self.check(
+                debugctl.get_bits(2..6) == 0 && debugctl.get_bits(16..64) == 0,
+                Guest,
+                field!(VmcsGuest64::IA32_DEBUGCTL),
+                debugctl,
+                "reserved bits of IA32_DEBUGCTL must be 0",
+            )?;
+            let dr7 = self.read(field!(VmcsGuestNW::DR7));
+            self.check(
+                dr7.get_bits(32..64) == 0,
+                Guest,
+                field!(VmcsGuestNW::DR7),
+                dr7,
+                "bits 63:32 of DR7 must be 0",
+            )?;
+        }
+        for field in [
+            field!(VmcsGuestNW::IA32_SYSENTER_ESP),
+            field!(VmcsGuestNW::IA32_SYSENTER_EIP),
+        ] {
+            let addr = self.read(field);
+            self.check(
+                is_canonical(addr),
+                Guest,
+                field,
+                addr,
+                "address must be canonical",
+            )?;
+        }
+        if entry.contains(EntryCtrl::LOAD_IA32_PAT) {
+            let pat = self.read(field!(VmcsGuest64::IA32_PAT));
+            self.check(
+                is_valid_pat(pat),
+                Guest,
+                field!(VmcsGuest64::IA32_PAT),
+                pat,
+                "each PAT entry must be a valid memory type",
+            )?;
+        }
+        if entry.contains(EntryCtrl::LOAD_IA32_EFER) {
+            let efer = self.read(field!(VmcsGuest64::IA32_EFER));
+            self.check(
+                efer_reserved_bits_clear(efer),
+                Guest,
+                field!(VmcsGuest64::IA32_EFER),
+                efer,
+                "reserved bits of IA32_EFER must be 0",
+            )?;
+            let lma = efer & EferFlags::LONG_MODE_ACTIVE.bits() != 0;
+            let lme = efer & EferFlags::LONG_MODE_ENABLE.bits() != 0;
+            self.check(
+                lma == self.guest_ia32e(),
+                Guest,
+                field!(VmcsGuest64::IA32_EFER),
+                efer,
+                "IA32_EFER.LMA must equal \"IA-32e mode guest\"",
+            )?;
+            self.check(
+                !cr0.contains(Cr0Flags::PAGING) || lma == lme,
+                Guest,
+                field!(VmcsGuest64::IA32_EFER),
+                efer,
+                "IA32_EFER.LME must equal IA32_EFER.LMA when CR0.PG is 1",
+            )?;
+        }
+        Ok(())
+    }
+
+    fn segment(&self, selector: Field, base: Field, limit: Field, ar: Field) -> Segment {
+        Segment {
+            selector: self.read(selector),
+            base: self.read(base),
+            limit: self.read(limit),
+            ar: self.read(ar),
+            selector_field: selector,
+            base_field: base,
+            limit_field: limit,
+            ar_field: ar,
+        }
+    }
+
+    /// Checks common to all usable segments: reserved bits of the access rights,
+    /// and the granularity flag consistent with the limit.
+    fn check_segment_ar_common(&self, seg: &Segment) -> CheckResult {
+        use EntryCheckArea::Guest;
+
+        self.check(
+            seg.ar & ar::RESERVED == 0,
+            Guest,
+            seg.ar_field,
+            seg.ar,
+            "reserved bits 11:8 and 31:17 of the access rights must be 0",
+        )?;
+        self.check(
+            seg.ar.get_bit(ar::P),
+            Guest,
+            seg.ar_field,
+            seg.ar,
+            "a usable segment must be present",
+        )?;
+        let g = seg.ar.get_bit(ar::G);
+        self.check(
+            (seg.limit & 0xfff == 0xfff || !g) && (seg.limit.get_bits(20..32) == 0 || g),
+            Guest,
+            seg.limit_field,
+            seg.limit,
+            "the granularity flag must be consistent with the segment limit",
+        )
+    }
+
+    /// Checks on guest segment registers. (SDM Vol. 3C, Section 26.3.1.2)
+    fn check_guest_segments(&self) -> CheckResult {
+        use EntryCheckArea::Guest;
+
+        macro_rules! seg {
+            ($seg:ident) => {
+                paste::paste! {
+                    self.segment(
+                        field!(VmcsGuest16::[<$seg _SELECTOR>]),
+                        field!(VmcsGuestNW::[<$seg _BASE>]),
+                        field!(VmcsGuest32::[<$seg _LIMIT>]),
+                        field!(VmcsGuest32::[<$seg _ACCESS_RIGHTS>]),
+                    )
+                }
+            };
+        }
+        let (cs, ss) = (seg!(CS), seg!(SS));
+        let data_segs = [seg!(DS), seg!(ES), seg!(FS), seg!(GS)];
+        let (tr, ldtr) = (seg!(TR), seg!(LDTR));
+
+        let rflags = self.read(field!(VmcsGuestNW::RFLAGS));
+        let v8086 = rflags.get_bit(17);
+        let unrestricted = self.unrestricted();
+        let ia32e = self.guest_ia32e();
+
+        // Selector fields.
+        self.check(
+            !tr.selector.get_bit(2),
+            Guest,
+            tr.selector_field,
+            tr.selector,
+            "TR must refer to the GDT (TI = 0)",
+        )?;
+        if ldtr.usable() {
+            self.check(
+                !ldtr.selector.get_bit(2),
+                Guest,
+                ldtr.selector_field,
+                ldtr.selector,
+                "LDTR must refer to the GDT (TI = 0)",
+            )?;
+        }
+        if !v8086 && !unrestricted {
+            self.check(
+                ss.rpl() == cs.rpl(),
+                Guest,
+                ss.selector_field,
+                ss.selector,
+                "the RPL of SS must equal the RPL of CS",
+            )?;
+        }
+
+        if v8086 {
+            // Virtual-8086 mode: every segment is a 64-KByte read/write segment at selector * 16.
+            for seg in [&cs, &ss].into_iter().chain(data_segs.iter()) {
+                self.check(
+                    seg.base == seg.selector << 4,
+                    Guest,
+                    seg.base_field,
+                    seg.base,
+                    "in virtual-8086 mode the base must be the selector shifted left by 4",
+                )?;
+                self.check(
+                    seg.limit == 0xffff,
+                    Guest,
+                    seg.limit_field,
+                    seg.limit,
+                    "in virtual-8086 mode the limit must be 0xffff",
+                )?;
+                self.check(
+                    seg.ar == 0xf3,
+                    Guest,
+                    seg.ar_field,
+                    seg.ar,
+                    "in virtual-8086 mode the access rights must be 0xf3",
+                )?;
+            }
+        }
+
+        // Base-address fields.
+        for seg in [&tr, &data_segs[2], &data_segs[3]] {
+            self.check(
+                is_canonical(seg.base),
+                Guest,
+                seg.base_field,
+                seg.base,
+                "the base of TR, FS and GS must be canonical",
+            )?;
+        }
+        if ldtr.usable() {
+            self.check(
+                is_canonical(ldtr.base),
+                Guest,
+                ldtr.base_field,
+                ldtr.base,
+                "the base of a usable LDTR must be canonical",
+            )?;
+        }
+        self.check(
+            cs.base.get_bits(32..64) == 0,
+            Guest,
+            cs.base_field,
+            cs.base,
+            "bits 63:32 of the CS base must be 0",
+        )?;
+        for seg in [&ss, &data_segs[0], &data_segs[1]] {
+            if seg.usable() {
+                self.check(
+                    seg.base.get_bits(32..64) == 0,
+                    Guest,
+                    seg.base_field,
+                    seg.base,
+                    "bits 63:32 of the SS, DS and ES bases must be 0 when usable",
+                )?;
+            }
+        }
+        if v8086 {
+            return self.check_guest_system_segments(&tr, &ldtr, ia32e);
+        }
+
+        // CS access rights.
+        let cs_type = cs.seg_type();
+        let cs_type_ok = matches!(cs_type, 9 | 11 | 13 | 15) || (unrestricted && cs_type == 3);
+        self.check(
+            cs_type_ok,
+            Guest,
+            cs.ar_field,
+            cs.ar,
+            "CS must be an accessed code segment (or an accessed read/write data segment with unrestricted guest)",
+        )?;
+        self.check(
+            cs.ar.get_bit(ar::S),
+            Guest,
+            cs.ar_field,
+            cs.ar,
+            "CS must be a code or data segment (S = 1)",
+        )?;
+        let dpl_ok = match cs_type {
+            3 => cs.dpl() == 0,
+            9 | 11 => cs.dpl() == ss.dpl(),
+            _ => cs.dpl() <= ss.dpl(),
+        };
+        self.check(
+            dpl_ok,
+            Guest,
+            cs.ar_field,
+            cs.ar,
+            "the DPL of CS must be 0 (type 3), equal to (non-conforming) or at most (conforming) the DPL of SS",
+        )?;
+        self.check_segment_ar_common(&cs)?;
+        if ia32e && cs.ar.get_bit(ar::L) {
+            self.check(
+                !cs.ar.get_bit(ar::DB),
+                Guest,
+                cs.ar_field,
+                cs.ar,
+                "the D/B flag of a 64-bit CS must be 0",
+            )?;
+        }
+
+        // SS access rights.
+        if ss.usable() {
+            self.check(
+                matches!(ss.seg_type(), 3 | 7) && ss.ar.get_bit(ar::S),
+                Guest,
+                ss.ar_field,
+                ss.ar,
+                "SS must be a read/write accessed data segment",
+            )?;
+            if !unrestricted {
+                self.check(
+                    ss.dpl() == ss.rpl(),
+                    Guest,
+                    ss.ar_field,
+                    ss.ar,
+                    "the DPL of SS must equal the RPL of its selector",
+                )?;
+            }
+            self.check_segment_ar_common(&ss)?;
+        }
+        let cr0 = Cr0Flags::from_bits_truncate(self.read(field!(VmcsGuestNW::CR0)));
+        if cs_type == 3 || !cr0.contains(Cr0Flags::PROTECTED_MODE_ENABLE) {
+            self.check(
+                ss.dpl() == 0,
+                Guest,
+                ss.ar_field,
+                ss.ar,
+                "the DPL of SS must be 0 when CS is a data segment or CR0.PE is 0",
+            )?;
+        }
+
+        // DS, ES, FS and GS access rights.
+        for seg in data_segs.iter().filter(|seg| seg.usable()) {
+            let seg_type = seg.seg_type();
+            self.check(
+                seg_type.get_bit(0) && (!seg_type.get_bit(3) || seg_type.get_bit(1)),
+                Guest,
+                seg.ar_field,
+                seg.ar,
+                "a usable data segment must be accessed, and readable if it is a code segment",
+            )?;
+            self.check(
+                seg.ar.get_bit(ar::S),
+                Guest,
+                seg.ar_field,
+                seg.ar,
+                "a usable data segment must be a code or data segment (S = 1)",
+            )?;
+            if !unrestricted && seg_type <= 11 {
+                self.check(
+                    seg.dpl() >= seg.rpl(),
+                    Guest,
+                    seg.ar_field,
+                    seg.ar,
+                    "the DPL of a usable data or non-conforming code segment must not be less than the RPL",
+                )?;
+            }
+            self.check_segment_ar_common(seg)?;
+        }
+
+        self.check_guest_system_segments(&tr, &ldtr, ia32e)
+    }
+
+    fn check_guest_system_segments(
+        &self,
+        tr: &Segment,
+        ldtr: &Segment,
+        ia32e: bool,
+    ) -> CheckResult {
+        use EntryCheckArea::Guest;
+
+        let tr_type = tr.seg_type();
+        self.check(
+            tr_type == 11 || (tr_type == 3 && !ia32e),
+            Guest,
+            tr.ar_field,
+            tr.ar,
+            "TR must be a busy 32-bit TSS (or a busy 16-bit TSS outside IA-32e mode)",
+        )?;
+        self.check(
+            !tr.ar.get_bit(ar::S) && tr.usable(),
+            Guest,
+            tr.ar_field,
+            tr.ar,
+            "TR must be a usable system segment (S = 0)",
+        )?;
+        self.check_segment_ar_common(tr)?;
+
+        if ldtr.usable() {
+            self.check(
+                ldtr.seg_type() == 2 && !ldtr.ar.get_bit(ar::S),
+                Guest,
+                ldtr.ar_field,
+                ldtr.ar,
+                "a usable LDTR must be an LDT system segment",
+            )?;
+            self.check_segment_ar_common(ldtr)?;
+        }
+        Ok(())
+    }
+
+    /// Checks on guest GDTR and IDTR. (SDM Vol. 3C, Section 26.3.1.3)
+    fn check_guest_descriptor_tables(&self) -> CheckResult {
+        use EntryCheckArea::Guest;
+
+        for field in [
+            field!(VmcsGuestNW::GDTR_BASE),
+            field!(VmcsGuestNW::IDTR_BASE),
+        ] {
+            let base = self.read(field);
+            self.check(
+                is_canonical(base),
+                Guest,
+                field,
+                base,
+                "descriptor-table base must be canonical",
+            )?;
+        }
+        for field in [
+            field!(VmcsGuest32::GDTR_LIMIT),
+            field!(VmcsGuest32::IDTR_LIMIT),
+        ] {
+            let limit = self.read(field);
+            self.check(
+                limit.get_bits(16..32) == 0,
+                Guest,
+                field,
+                limit,
+                "bits 31:16 of a descriptor-table limit must be 0",
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Checks on guest RIP and RFLAGS. (SDM Vol. 3C, Section 26.3.1.4)
+    fn check_guest_rip_rflags(&self) -> CheckResult {
+        use EntryCheckArea::Guest;
+
+        let rip = self.read(field!(VmcsGuestNW::RIP));
+        let cs_ar = self.read(field!(VmcsGuest32::CS_ACCESS_RIGHTS));
+        if self.guest_ia32e() && cs_ar.get_bit(ar::L) {
+            self.check(
+                is_canonical(rip),
+                Guest,
+                field!(VmcsGuestNW::RIP),
+                rip,
+                "RIP must be canonical in 64-bit mode",
+            )?;
+        } else {
+            self.check(
+                rip.get_bits(32..64) == 0,
+                Guest,
+                field!(VmcsGuestNW::RIP),
+                rip,
+                "bits 63:32 of RIP must be 0 outside 64-bit mode",
+            )?;
+        }
+
+        let field = field!(VmcsGuestNW::RFLAGS);
+        let rflags = self.read(field);
+        self.check(
+            rflags.get_bits(22..64) == 0
+                && !rflags.get_bit(15)
+                && !rflags.get_bit(5)
+                && !rflags.get_bit(3)
+                && rflags.get_bit(1),
+            Guest,
+            field,
+            rflags,
+            "reserved bits 63:22, 15, 5 and 3 of RFLAGS must be 0 and bit 1 must be 1",
+        )?;
+        let cr0 = Cr0Flags::from_bits_truncate(self.read(field!(VmcsGuestNW::CR0)));
+        if self.guest_ia32e() || !cr0.contains(Cr0Flags::PROTECTED_MODE_ENABLE) {
+            self.check(
+                !rflags.get_bit(17),
+                Guest,
+                field,
+                rflags,
+                "RFLAGS.VM must be 0 in IA-32e mode or when CR0.PE is 0",
+            )?;
+        }
+        let info = self.read(field!(VmcsControl32::VMENTRY_INTERRUPTION_INFO_FIELD));
+        if info.get_bit(31) && info.get_bits(8..11) == 0 {
+            self.check(
+                rflags.get_bit(9),
+                Guest,
+                field,
+                rflags,
+                "RFLAGS.IF must be 1 when injecting an external interrupt",
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Checks on guest non-register state. (SDM Vol. 3C, Section 26.3.1.5)
+    fn check_guest_non_register_state(&self) -> CheckResult {
+        use EntryCheckArea::Guest;
+
+        let activity_field = field!(VmcsGuest32::ACTIVITY_STATE);
+        let activity = self.read(activity_field);
+        // IA32_VMX_MISC bits 8:6 report support for HLT, shutdown and wait-for-SIPI.
+        let activity_ok = match activity {
+            0 => true,
+            1..=3 => self.caps.misc.get_bit(5 + activity as usize),
+            _ => false,
+        };
+        self.check(
+            activity_ok,
+            Guest,
+            activity_field,
+            activity,
+            "activity state must be active or a state supported by IA32_VMX_MISC",
+        )?;
+        let ss_ar = self.read(field!(VmcsGuest32::SS_ACCESS_RIGHTS));
+        if activity == 1 {
+            self.check(
+                ss_ar.get_bits(ar::DPL) == 0,
+                Guest,
+                activity_field,
+                activity,
+                "the HLT activity state requires the DPL of SS to be 0",
+            )?;
+        }
+
+        let int_field = field!(VmcsGuest32::INTERRUPTIBILITY_STATE);
+        let interruptibility = self.read(int_field);
+        let blocking_by_sti = interruptibility.get_bit(0);
+        let blocking_by_mov_ss = interruptibility.get_bit(1);
+        self.check(
+            interruptibility.get_bits(5..32) == 0,
+            Guest,
+            int_field,
+            interruptibility,
+            "reserved bits 31:5 of the interruptibility state must be 0",
+        )?;
+        self.check(
+            !(blocking_by_sti && blocking_by_mov_ss),
+            Guest,
+            int_field,
+            interruptibility,
+            "blocking by STI and blocking by MOV SS must not both be set",
+        )?;
+        let rflags = self.read(field!(VmcsGuestNW::RFLAGS));
+        if !rflags.get_bit(9) {
+            self.check(
+                !blocking_by_sti,
+                Guest,
+                int_field,
+                interruptibility,
+                "blocking by STI must be 0 when RFLAGS.IF is 0",
+            )?;
+        }
+        self.check(
+            !interruptibility.get_bit(2),
+            Guest,
+            int_field,
+            interruptibility,
+            "blocking by SMI must be 0 outside SMM",
+        )?;
+        let info = self.read(field!(VmcsControl32::VMENTRY_INTERRUPTION_INFO_FIELD));
+        if info.get_bit(31) {
+            match info.get_bits(8..11) {
+                0 => self.check(
+                    !blocking_by_sti && !blocking_by_mov_ss,
+                    Guest,
+                    int_field,
+                    interruptibility,
+                    "blocking by STI and by MOV SS must be 0 when injecting an external interrupt",
+                )?,
+                2 => {
+                    self.check(
+                        !blocking_by_mov_ss,
+                        Guest,
+                        int_field,
+                        interruptibility,
+                        "blocking by MOV SS must be 0 when injecting an NMI",
+                    )?;
+                    if self.pin().contains(PinCtrl::VIRTUAL_NMIS) {
+                        self.check(
+                            !interruptibility.get_bit(3),
+                            Guest,
+                            int_field,
+                            interruptibility,
+                            "blocking by NMI must be 0 when injecting an NMI with virtual NMIs",
+                        )?;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let dbg_field = field!(VmcsGuestNW::PENDING_DBG_EXCEPTIONS);
+        let pending_dbg = self.read(dbg_field);
+        self.check(
+            pending_dbg.get_bits(4..12) == 0
+                && !pending_dbg.get_bit(13)
+                && !pending_dbg.get_bit(15)
+                && pending_dbg.get_bits(17..64) == 0,
+            Guest,
+            dbg_field,
+            pending_dbg,
+            "reserved bits 11:4, 13, 15 and 63:17 of the pending debug exceptions must be 0",
+        )?;
+        if blocking_by_sti || blocking_by_mov_ss || activity == 1 {
+            let debugctl = self.read(field!(VmcsGuest64::IA32_DEBUGCTL));
+            let single_step = rflags.get_bit(8) && !debugctl.get_bit(1);
+            self.check(
+                pending_dbg.get_bit(14) == single_step,
+                Guest,
+                dbg_field,
+                pending_dbg,
+                "the BS bit of the pending debug exceptions must equal RFLAGS.TF && !IA32_DEBUGCTL.BTF while events are blocked or halted",
+            )?;
+        }
+
+        let link_field = field!(VmcsGuest64::LINK_PTR);
+        let link = self.read(link_field);
+        if link != u64::MAX {
+            self.check(
+                link & 0xfff == 0 && self.fits_phys_width(link),
+                Guest,
+                link_field,
+                link,
+                "the VMCS link pointer must be all ones, or 4-KByte aligned and within the physical-address width",
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Checks on the guest PDPTEs used with PAE paging and EPT. (SDM Vol. 3C, Section 26.3.1.6)
+    fn check_guest_pdptes(&self) -> CheckResult {
+        let cr0 = Cr0Flags::from_bits_truncate(self.read(field!(VmcsGuestNW::CR0)));
+        let cr4 = Cr4Flags::from_bits_truncate(self.read(field!(VmcsGuestNW::CR4)));
+        if !self.cpu2().contains(CpuCtrl2::ENABLE_EPT)
+            || self.guest_ia32e()
+            || !cr0.contains(Cr0Flags::PAGING)
+            || !cr4.contains(Cr4Flags::PHYSICAL_ADDRESS_EXTENSION)
+        {
+            return Ok(());
+        }
+        for field in [
+            field!(VmcsGuest64::PDPTE0),
+            field!(VmcsGuest64::PDPTE1),
+            field!(VmcsGuest64::PDPTE2),
+            field!(VmcsGuest64::PDPTE3),
+        ] {
+            let pdpte = self.read(field);
+            if pdpte.get_bit(0) {
+                self.check(
+                    pdpte.get_bits(1..3) == 0
+                        && pdpte.get_bits(5..9) == 0
+                        && self.fits_phys_width(pdpte & !0xfff),
+                    EntryCheckArea::Guest,
+                    field,
+                    pdpte,
+                    "reserved bits of a present PDPTE must be 0",
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn efer_reserved_bits_clear(efer: u64) -> bool {
+    // Only SCE (0), LME (8), LMA (10) and NXE (11) are defined.
+    efer & !(0xd01) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn caps() -> VmxCapabilities {
+        VmxCapabilities {
+            basic: 1 << 55,
+            pinbased: 0x0000_007f_0000_0016,
+            procbased: 0xfff9_fffe_0401_e172,
+            procbased2: 0x0053_7fff_0000_0000,
+            exit: 0x01ff_ffff_0003_6dfb,
+            entry: 0x0003_ffff_0000_11fb,
+            misc: 0x0000_0000_0040_01e0 | (4 << 16),
+            cr0_fixed0: 0x8000_0021,
+            cr0_fixed1: 0xffff_ffff,
+            cr4_fixed0: 0x2000,
+            cr4_fixed1: 0x0037_27ff,
+            ept_vpid_cap: 0x0000_0f01_0633_4141,
+            phys_addr_width: 39,
+        }
+    }
+
+    /// A VMCS image like the one set up by `VmxVcpu` for a guest at the reset vector.
+    fn real_mode_image() -> VmcsImage {
+        let mut image = VmcsImage::new();
+        macro_rules! set {
+            ($($field:expr => $value:expr),+ $(,)?) => {
+                $(image.set($field as u32, $value);)+
+            };
+        }
+        let cpu2 = (CpuCtrl2::ENABLE_EPT | CpuCtrl2::UNRESTRICTED_GUEST).bits() as u64;
+        set! {
+            VmcsControl32::PINBASED_EXEC_CONTROLS => 0x16 | 0x9,
+            VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS => 0x0401_e172 | (1 << 25) | (1 << 28) | (1 << 31),
+            VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS => cpu2,
+            VmcsControl32::VMEXIT_CONTROLS => 0x0003_6dfb | (1 << 9) | (1 << 15) | (0xf << 18),
+            VmcsControl32::VMENTRY_CONTROLS => 0x11fb | (3 << 14),
+            VmcsControl64::IO_BITMAP_A_ADDR => 0x1_0000,
+            VmcsControl64::IO_BITMAP_B_ADDR => 0x1_1000,
+            VmcsControl64::MSR_BITMAPS_ADDR => 0x1_2000,
+            VmcsControl64::EPTP => 0x2_0000 | 6 | (3 << 3),
+            VmcsHostNW::CR0 => 0x8005_0033,
+            VmcsHostNW::CR3 => 0x3_0000,
+            VmcsHostNW::CR4 => 0x2020 | 0x200,
+            VmcsHost16::CS_SELECTOR => 0x8,
+            VmcsHost16::SS_SELECTOR => 0x10,
+            VmcsHost16::TR_SELECTOR => 0x28,
+            VmcsHost64::IA32_PAT => 0x0007_0406_0007_0406,
+            VmcsHost64::IA32_EFER => 0xd01,
+            VmcsHostNW::RIP => 0xffff_8000_0010_0000,
+            VmcsGuestNW::CR0 => 0x30,
+            VmcsGuestNW::CR4 => 0x2000,
+            VmcsGuestNW::RFLAGS => 0x2,
+            VmcsGuestNW::RIP => 0xfff0,
+            VmcsGuestNW::DR7 => 0x400,
+            VmcsGuest64::IA32_PAT => 0x0007_0406_0007_0406,
+            VmcsGuest64::LINK_PTR => u64::MAX,
+            VmcsGuest32::GDTR_LIMIT => 0xffff,
+            VmcsGuest32::IDTR_LIMIT => 0xffff,
+        }
+        for (limit, ar) in [
+            (VmcsGuest32::ES_LIMIT, VmcsGuest32::ES_ACCESS_RIGHTS),
+            (VmcsGuest32::SS_LIMIT, VmcsGuest32::SS_ACCESS_RIGHTS),
+            (VmcsGuest32::DS_LIMIT, VmcsGuest32::DS_ACCESS_RIGHTS),
+            (VmcsGuest32::FS_LIMIT, VmcsGuest32::FS_ACCESS_RIGHTS),
+            (VmcsGuest32::GS_LIMIT, VmcsGuest32::GS_ACCESS_RIGHTS),
+        ] {
+            set! { limit => 0xffff, ar => 0x93 };
+        }
+        set! {
+            VmcsGuest32::CS_LIMIT => 0xffff,
+            VmcsGuest32::CS_ACCESS_RIGHTS => 0x9b,
+            VmcsGuest32::TR_LIMIT => 0xffff,
+            VmcsGuest32::TR_ACCESS_RIGHTS => 0x8b,
+            VmcsGuest32::LDTR_LIMIT => 0xffff,
+            VmcsGuest32::LDTR_ACCESS_RIGHTS => 0x82,
+        }
+        image
+    }
+
+    fn long_mode_image() -> VmcsImage {
+        let mut image = real_mode_image();
+        let entry = 0x11fb | (3 << 14) | EntryCtrl::IA32E_MODE_GUEST.bits() as u64;
+        image
+            .set(VmcsControl32::VMENTRY_CONTROLS as u32, entry)
+            .set(VmcsGuestNW::CR0 as u32, 0x8000_0031)
+            .set(VmcsGuestNW::CR3 as u32, 0x9000)
+            .set(VmcsGuestNW::CR4 as u32, 0x2020)
+            .set(VmcsGuest64::IA32_EFER as u32, 0x500)
+            .set(VmcsGuest16::CS_SELECTOR as u32, 0x10)
+            .set(VmcsGuest32::CS_LIMIT as u32, 0xffff_ffff)
+            .set(VmcsGuest32::CS_ACCESS_RIGHTS as u32, 0xa09b)
+            .set(VmcsGuestNW::RIP as u32, 0x10_0200);
+        for (sel, limit, ar) in [
+            (
+                VmcsGuest16::ES_SELECTOR,
+                VmcsGuest32::ES_LIMIT,
+                VmcsGuest32::ES_ACCESS_RIGHTS,
+            ),
+            (
+                VmcsGuest16::SS_SELECTOR,
+                VmcsGuest32::SS_LIMIT,
+                VmcsGuest32::SS_ACCESS_RIGHTS,
+            ),
+            (
+                VmcsGuest16::DS_SELECTOR,
+                VmcsGuest32::DS_LIMIT,
+                VmcsGuest32::DS_ACCESS_RIGHTS,
+            ),
+        ] {
+            image
+                .set(sel as u32, 0x18)
+                .set(limit as u32, 0xffff_ffff)
+                .set(ar as u32, 0xc093);
+        }
+        image
+    }
+
+    #[test]
+    fn test_valid_images_pass() {
+        check_vm_entry(&real_mode_image(), &caps()).unwrap();
+        check_vm_entry(&long_mode_image(), &caps()).unwrap();
+    }
+
+    #[test]
+    fn test_reports_failing_field_and_area() {
+        // The IA-32e mode guest control without paging.
+        let mut image = long_mode_image();
+        image.set(VmcsGuestNW::CR0 as u32, 0x31);
+        let err = check_vm_entry(&image, &caps()).unwrap_err();
+        assert_eq!(err.area, EntryCheckArea::Guest);
+        assert_eq!(err.field, "VmcsGuestNW::CR0");
+        assert_eq!(err.rule, "CR0.PG must be 1 for an IA-32e mode guest");
+
+        // 64-bit code segment with the D/B flag set.
+        let mut image = long_mode_image();
+        image.set(VmcsGuest32::CS_ACCESS_RIGHTS as u32, 0xe09b);
+        let err = check_vm_entry(&image, &caps()).unwrap_err();
+        assert_eq!(err.field, "VmcsGuest32::CS_ACCESS_RIGHTS");
+        assert_eq!(err.value, 0xe09b);
+
+        // Granularity inconsistent with the limit.
+        let mut image = long_mode_image();
+        image.set(VmcsGuest32::DS_ACCESS_RIGHTS as u32, 0x4093);
+        let err = check_vm_entry(&image, &caps()).unwrap_err();
+        assert_eq!(err.field, "VmcsGuest32::DS_LIMIT");
+
+        // Host TR selector of 0.
+        let mut image = real_mode_image();
+        image.set(VmcsHost16::TR_SELECTOR as u32, 0);
+        let err = check_vm_entry(&image, &caps()).unwrap_err();
+        assert_eq!(err.area, EntryCheckArea::Host);
+        assert_eq!(err.field, "VmcsHost16::TR_SELECTOR");
+
+        // Unrestricted guest without EPT.
+        let mut image = real_mode_image();
+        image.set(
+            VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS as u32,
+            CpuCtrl2::UNRESTRICTED_GUEST.bits() as u64,
+        );
+        let err = check_vm_entry(&image, &caps()).unwrap_err();
+        assert_eq!(err.area, EntryCheckArea::Control);
+        assert_eq!(err.rule, "\"unrestricted guest\" requires \"enable EPT\"");
+    }
+
+    #[test]
+    fn test_event_injection_checks() {
+        // #GP without an error code.
+        let mut image = long_mode_image();
+        image.set(
+            VmcsControl32::VMENTRY_INTERRUPTION_INFO_FIELD as u32,
+            (1 << 31) | (3 << 8) | 13,
+        );
+        let err = check_vm_entry(&image, &caps()).unwrap_err();
+        assert_eq!(err.field, "VmcsControl32::VMENTRY_INTERRUPTION_INFO_FIELD");
+
+        // External interrupt while RFLAGS.IF is 0.
+        image.set(
+            VmcsControl32::VMENTRY_INTERRUPTION_INFO_FIELD as u32,
+            (1 << 31) | 0x20,
+        );
+        let err = check_vm_entry(&image, &caps()).unwrap_err();
+        assert_eq!(err.field, "VmcsGuestNW::RFLAGS");
+        assert_eq!(
+            err.rule,
+            "RFLAGS.IF must be 1 when injecting an external interrupt"
+        );
+
+        image.set(VmcsGuestNW::RFLAGS as u32, 0x202);
+        check_vm_entry(&image, &caps()).unwrap();
+    }
+}
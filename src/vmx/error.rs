@@ -0,0 +1,120 @@
+use core::fmt::{Display, Formatter, Result};
+
+use axerrno::{AxError, ax_err_type};
+use x86::vmx::VmFail;
+
+use super::definitions::VmxInstructionError;
+use super::entry_check::EntryCheckError;
+use super::vmcs;
+
+/// Errors that stop a [`VmxVcpu`](super::VmxArchVCpu) from running the guest.
+///
+/// They surface from `run` as [`AxError`]s, the typed error is available from
+/// [`VmxVcpu::inner_run`](super::VmxArchVCpu::inner_run).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VcpuError {
+    /// A VMX instruction failed with VMfailValid. (SDM Vol. 3C, Section 30.2)
+    VmxInstruction(VmxInstructionError),
+    /// A VMX instruction failed with VMfailInvalid, there is no current VMCS.
+    InvalidVmcsPointer,
+    /// VMLAUNCH or VMRESUME failed on the checks of the controls or the host
+    /// state. `check` names the offending field if the software checker finds it.
+    EntryFailure {
+        /// The VM-instruction error reported by the processor.
+        error: VmxInstructionError,
+        /// The first failing check found by [`check_vm_entry`](super::check_vm_entry).
+        check: Option<EntryCheckError>,
+    },
+    /// The processor reported a basic exit reason this crate does not know.
+    UnknownExit(u32),
+    /// The guest did something architecturally invalid. Builtin VM-exit
+    /// handlers return this to have the exception injected into the guest
+    /// instead of failing the run.
+    InvalidGuestAction {
+        /// Vector of the exception to inject.
+        vector: u8,
+        /// Error code of the exception, if it has one.
+        err_code: Option<u32>,
+        /// What the guest did.
+        reason: &'static str,
+    },
+    /// Any other failure inside the vCPU, e.g. an unexpected VMCS state.
+    Internal(AxError),
+}
+
+/// A [`Result`](core::result::Result) with [`VcpuError`] as the error type.
+pub type VcpuResult<T = ()> = core::result::Result<T, VcpuError>;
+
+impl VcpuError {
+    /// #GP(0) for an invalid guest action.
+    pub(super) fn gp(reason: &'static str) -> Self {
+        Self::InvalidGuestAction {
+            vector: 13,
+            err_code: Some(0),
+            reason,
+        }
+    }
+
+    /// #UD for an invalid guest action.
+    pub(super) fn ud(reason: &'static str) -> Self {
+        Self::InvalidGuestAction {
+            vector: 6,
+            err_code: None,
+            reason,
+        }
+    }
+}
+
+impl From<VmFail> for VcpuError {
+    fn from(err: VmFail) -> Self {
+        match err {
+            VmFail::VmFailValid => match vmcs::instruction_error() {
+                Ok(error) => Self::VmxInstruction(error),
+                Err(_) => Self::InvalidVmcsPointer,
+            },
+            VmFail::VmFailInvalid => Self::InvalidVmcsPointer,
+        }
+    }
+}
+
+impl From<AxError> for VcpuError {
+    fn from(err: AxError) -> Self {
+        Self::Internal(err)
+    }
+}
+
+impl From<VcpuError> for AxError {
+    fn from(err: VcpuError) -> Self {
+        match err {
+            VcpuError::Internal(err) => err,
+            VcpuError::UnknownExit(_) => ax_err_type!(Unsupported, err),
+            VcpuError::InvalidGuestAction { .. } => ax_err_type!(InvalidInput, err),
+            _ => ax_err_type!(BadState, err),
+        }
+    }
+}
+
+impl Display for VcpuError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            Self::VmxInstruction(error) => write!(f, "VMX instruction failed: {}", error.as_str()),
+            Self::InvalidVmcsPointer => write!(f, "VMCS pointer is not valid"),
+            Self::EntryFailure {
+                error,
+                check: Some(check),
+            } => write!(f, "{}: {}", error.as_str(), check),
+            Self::EntryFailure { error, check: None } => write!(f, "{}", error.as_str()),
+            Self::UnknownExit(reason) => write!(f, "unknown VM-exit reason {}", reason),
+            Self::InvalidGuestAction {
+                vector,
+                err_code,
+                reason,
+            } => write!(
+                f,
+                "invalid guest action ({}), exception {} ({:?})",
+                reason, vector, err_code
+            ),
+            Self::Internal(err) => write!(f, "internal error: {:?}", err),
+        }
+    }
+}
@@ -0,0 +1,524 @@
+//! Pending virtual events of a vCPU, ordered by architectural priority.
+//! (SDM Vol. 3A, Section 6.9 and 6.15)
+
+use axerrno::{AxResult, ax_err};
+use bit_field::BitField;
+use bitflags::bitflags;
+use x86::irq::{
+    BREAKPOINT_VECTOR, DEBUG_VECTOR, NONMASKABLE_INTERRUPT_VECTOR, OVERFLOW_VECTOR,
+    PAGE_FAULT_VECTOR,
+};
+
+use super::definitions::{VmxActivityState, VmxInterruptionType};
+
+/// An event waiting to be injected into the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PendingEvent {
+    /// Vector of the interrupt or exception.
+    pub vector: u8,
+    /// How the event is delivered.
+    pub int_type: VmxInterruptionType,
+    /// Error code pushed by the exception.
+    pub err_code: Option<u32>,
+    /// VM-entry instruction length for software interrupts and exceptions.
+    pub instr_len: Option<u32>,
+    /// Faulting linear address loaded into the guest CR2 for a #PF.
+    pub fault_addr: Option<u64>,
+}
+
+impl PendingEvent {
+    /// An NMI (vector 2), a hardware exception (vectors below 32) or an
+    /// external interrupt, by vector. Nothing is validated.
+    pub fn new(vector: u8, err_code: Option<u32>) -> Self {
+        let int_type = match vector {
+            NONMASKABLE_INTERRUPT_VECTOR => VmxInterruptionType::NMI,
+            0..32 => VmxInterruptionType::HardException,
+            _ => VmxInterruptionType::External,
+        };
+        Self {
+            vector,
+            int_type,
+            err_code,
+            instr_len: None,
+            fault_addr: None,
+        }
+    }
+
+    /// A hardware exception. The error code must be given exactly for the
+    /// exceptions that push one, the fault address only for a #PF.
+    /// (SDM Vol. 3C, Section 26.2.1.3)
+    pub fn exception(vector: u8, err_code: Option<u32>, fault_addr: Option<u64>) -> AxResult<Self> {
+        if vector >= 32 || vector == NONMASKABLE_INTERRUPT_VECTOR {
+            return ax_err!(InvalidInput, "not an exception vector");
+        }
+        if err_code.is_some() != VmxInterruptionType::vector_has_error_code(vector) {
+            return ax_err!(InvalidInput, "error code does not match the exception");
+        }
+        if fault_addr.is_some() && vector != PAGE_FAULT_VECTOR {
+            return ax_err!(InvalidInput, "only a #PF has a fault address");
+        }
+        Ok(Self {
+            fault_addr,
+            ..Self::new(vector, err_code)
+        })
+    }
+
+    /// A software interrupt (INT n), `instr_len` is the length of the INT
+    /// instruction the guest resumes after.
+    pub fn software_interrupt(vector: u8, instr_len: u32) -> AxResult<Self> {
+        Self::software(VmxInterruptionType::SoftIntr, vector, instr_len)
+    }
+
+    /// A software exception: #BP (INT3) or #OF (INTO).
+    pub fn software_exception(vector: u8, instr_len: u32) -> AxResult<Self> {
+        if vector != BREAKPOINT_VECTOR && vector != OVERFLOW_VECTOR {
+            return ax_err!(InvalidInput, "only #BP and #OF are software exceptions");
+        }
+        Self::software(VmxInterruptionType::SoftException, vector, instr_len)
+    }
+
+    /// A privileged software exception: #DB raised by INT1.
+    pub fn privileged_software_exception(instr_len: u32) -> AxResult<Self> {
+        Self::software(
+            VmxInterruptionType::PrivSoftException,
+            DEBUG_VECTOR,
+            instr_len,
+        )
+    }
+
+    fn software(int_type: VmxInterruptionType, vector: u8, instr_len: u32) -> AxResult<Self> {
+        // SDM Vol. 3C, Section 26.2.1.3: the VM-entry instruction length is 1 to 15.
+        if !(1..=15).contains(&instr_len) {
+            return ax_err!(InvalidInput, "instruction length must be 1 to 15");
+        }
+        Ok(Self {
+            vector,
+            int_type,
+            err_code: None,
+            instr_len: Some(instr_len),
+            fault_addr: None,
+        })
+    }
+
+    /// Decode an event from the IDT-vectoring information field.
+    /// (SDM Vol. 3C, Section 24.9.3)
+    ///
+    /// Returns `None` if the field is not valid.
+    pub fn from_idt_vectoring(info: u32, err_code: u32, instr_len: u32) -> Option<Self> {
+        if !info.get_bit(31) {
+            return None;
+        }
+        let int_type = VmxInterruptionType::try_from(info.get_bits(8..11) as u8).ok()?;
+        Some(Self {
+            vector: info.get_bits(0..8) as u8,
+            int_type,
+            err_code: info.get_bit(11).then_some(err_code),
+            instr_len: int_type.is_soft().then_some(instr_len),
+            // CR2 still holds the address of an interrupted #PF.
+            fault_addr: None,
+        })
+    }
+
+    fn is_exception(&self) -> bool {
+        matches!(
+            self.int_type,
+            VmxInterruptionType::HardException
+                | VmxInterruptionType::SoftException
+                | VmxInterruptionType::PrivSoftException
+        )
+    }
+}
+
+bitflags! {
+    /// Guest interruptibility state. (SDM Vol. 3C, Section 24.4.2, Table 24-3)
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct InterruptibilityState: u32 {
+        /// Blocking by STI: interrupts are blocked for one instruction after STI.
+        const BLOCKING_BY_STI = 1 << 0;
+        /// Blocking by MOV SS: interrupts, NMIs and debug exceptions are
+        /// blocked for one instruction after MOV SS or POP SS.
+        const BLOCKING_BY_MOV_SS = 1 << 1;
+        /// Blocking by SMI: the guest is in SMM.
+        const BLOCKING_BY_SMI = 1 << 2;
+        /// Blocking by NMI: an NMI handler runs, or virtual-NMI blocking.
+        const BLOCKING_BY_NMI = 1 << 3;
+        /// Enclave interruption.
+        const ENCLAVE_INTERRUPTION = 1 << 4;
+    }
+}
+
+/// Whether the guest can take an event at the next VM entry.
+/// (SDM Vol. 3C, Section 24.4.2 and 26.3.1.5)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EventWindow {
+    /// RFLAGS.IF of the guest.
+    pub interrupt_flag: bool,
+    /// The guest interruptibility state.
+    pub interruptibility: InterruptibilityState,
+    /// The guest activity state.
+    pub activity: VmxActivityState,
+    /// The VM-entry interruption-information field already holds an event.
+    pub entry_event_pending: bool,
+}
+
+impl EventWindow {
+    /// Whether any event can be injected: none is pending in the VMCS, and
+    /// the guest is not shut down or waiting for a SIPI.
+    pub fn event_allowed(&self) -> bool {
+        !self.entry_event_pending
+            && matches!(
+                self.activity,
+                VmxActivityState::Active | VmxActivityState::Hlt
+            )
+    }
+
+    /// Whether an external interrupt can be injected.
+    pub fn interrupt_allowed(&self) -> bool {
+        self.event_allowed()
+            && self.interrupt_flag
+            && !self.interruptibility.intersects(
+                InterruptibilityState::BLOCKING_BY_STI | InterruptibilityState::BLOCKING_BY_MOV_SS,
+            )
+    }
+
+    /// Whether an NMI can be injected. Blocking by STI is honored as well,
+    /// some processors block NMIs in the STI shadow.
+    pub fn nmi_allowed(&self) -> bool {
+        self.event_allowed()
+            && !self.interruptibility.intersects(
+                InterruptibilityState::BLOCKING_BY_STI
+                    | InterruptibilityState::BLOCKING_BY_MOV_SS
+                    | InterruptibilityState::BLOCKING_BY_SMI
+                    | InterruptibilityState::BLOCKING_BY_NMI,
+            )
+    }
+
+    /// The window of a guest without blocking beyond RFLAGS.IF, e.g. one run
+    /// by the legacy-mode interpreter.
+    pub fn open(interrupt_flag: bool) -> Self {
+        Self {
+            interrupt_flag,
+            interruptibility: InterruptibilityState::empty(),
+            activity: VmxActivityState::Active,
+            entry_event_pending: false,
+        }
+    }
+}
+
+/// Class of an exception for the double-fault rules. (SDM Vol. 3A, Section 6.15, Table 6-4)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ExceptionClass {
+    Benign,
+    Contributory,
+    PageFault,
+    DoubleFault,
+}
+
+impl ExceptionClass {
+    fn of(vector: u8) -> Self {
+        use x86::irq::*;
+        match vector {
+            DIVIDE_ERROR_VECTOR
+            | INVALID_TSS_VECTOR
+            | SEGMENT_NOT_PRESENT_VECTOR
+            | STACK_SEGEMENT_FAULT_VECTOR
+            | GENERAL_PROTECTION_FAULT_VECTOR
+            | CONTROL_PROTECTION_VECTOR => Self::Contributory,
+            PAGE_FAULT_VECTOR | VIRTUALIZATION_VECTOR => Self::PageFault,
+            DOUBLE_FAULT_VECTOR => Self::DoubleFault,
+            _ => Self::Benign,
+        }
+    }
+}
+
+/// The vector of the control-protection exception (#CP).
+const CONTROL_PROTECTION_VECTOR: u8 = 21;
+
+/// Events waiting for injection.
+///
+/// At most one exception is pending: a second one merges with it into a #DF
+/// or a triple fault, or replaces it when the two can be handled serially.
+/// Exceptions go first, then a software interrupt (INT n), then an NMI, then
+/// external interrupts from the highest vector down. An event whose delivery a VM exit interrupted is injected
+/// again before anything else.
+#[derive(Debug, Default)]
+pub struct EventQueue {
+    /// Event interrupted by the last VM exit, re-injected first.
+    reinject: Option<PendingEvent>,
+    /// The pending exception.
+    exception: Option<PendingEvent>,
+    /// The pending software interrupt, delivered after the exception.
+    software: Option<PendingEvent>,
+    /// Whether an NMI is pending.
+    nmi: bool,
+    /// Pending external interrupts, one bit per vector.
+    interrupts: [u64; 4],
+    /// A triple fault happened, the vCPU must shut down.
+    triple_fault: bool,
+}
+
+impl EventQueue {
+    /// Create an empty queue.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue an event by vector: exceptions, NMI (vector 2) and external interrupts.
+    pub fn push(&mut self, event: PendingEvent) {
+        match event.int_type {
+            VmxInterruptionType::NMI => self.nmi = true,
+            VmxInterruptionType::External => {
+                self.interrupts[event.vector as usize / 64]
+                    .set_bit(event.vector as usize % 64, true);
+            }
+            _ if event.is_exception() => self.push_exception(event),
+            // Software interrupts (INT n) never merge, and must not drop a
+            // pending exception.
+            _ => self.software = Some(event),
+        }
+    }
+
+    fn push_exception(&mut self, event: PendingEvent) {
+        use ExceptionClass::*;
+        let Some(first) = self.exception else {
+            self.exception = Some(event);
+            return;
+        };
+        match (
+            ExceptionClass::of(first.vector),
+            ExceptionClass::of(event.vector),
+        ) {
+            (DoubleFault, Contributory | PageFault) => {
+                self.exception = None;
+                self.triple_fault = true;
+            }
+            (Contributory, Contributory) | (PageFault, Contributory | PageFault) => {
+                self.exception = Some(PendingEvent::new(x86::irq::DOUBLE_FAULT_VECTOR, Some(0)));
+            }
+            // Handled serially: the first exception recurs when the guest
+            // re-executes the instruction.
+            _ => self.exception = Some(event),
+        }
+    }
+
+    /// Record the event whose delivery was interrupted by a VM exit, so it is
+    /// injected again on the next VM entry. (SDM Vol. 3C, Section 27.2.4)
+    pub fn push_interrupted(&mut self, event: PendingEvent) {
+        if event.is_exception() {
+            // Exceptions raised while handling the VM exit merge with it.
+            self.exception = Some(event);
+        } else {
+            self.reinject = Some(event);
+        }
+    }
+
+    /// Take the next event the guest can accept in `window`.
+    pub fn pop(&mut self, window: &EventWindow) -> Option<PendingEvent> {
+        if !window.event_allowed() {
+            return None;
+        }
+        if let Some(event) = self.reinject.take() {
+            return Some(event);
+        }
+        if let Some(event) = self.exception.take() {
+            return Some(event);
+        }
+        if let Some(event) = self.software.take() {
+            return Some(event);
+        }
+        if self.nmi && window.nmi_allowed() {
+            self.nmi = false;
+            return Some(PendingEvent::new(NONMASKABLE_INTERRUPT_VECTOR, None));
+        }
+        if window.interrupt_allowed() {
+            let vector = self.highest_interrupt()?;
+            self.interrupts[vector as usize / 64].set_bit(vector as usize % 64, false);
+            return Some(PendingEvent::new(vector, None));
+        }
+        None
+    }
+
+    /// The highest pending external interrupt vector.
+    pub fn highest_interrupt(&self) -> Option<u8> {
+        (0..4).rev().find_map(|i| {
+            let word = self.interrupts[i];
+            (word != 0).then(|| (i * 64 + 63 - word.leading_zeros() as usize) as u8)
+        })
+    }
+
+    /// Whether an external interrupt is pending.
+    pub fn has_interrupt(&self) -> bool {
+        self.interrupts.iter().any(|&word| word != 0)
+    }
+
+    /// Whether an NMI is pending.
+    pub fn has_nmi(&self) -> bool {
+        self.nmi
+    }
+
+    /// Whether an exception merged into a triple fault, clearing the condition.
+    pub fn take_triple_fault(&mut self) -> bool {
+        core::mem::take(&mut self.triple_fault)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use x86::irq::*;
+
+    #[test]
+    fn test_priority_order() {
+        let blocked = EventWindow {
+            interruptibility: InterruptibilityState::BLOCKING_BY_MOV_SS,
+            ..EventWindow::open(true)
+        };
+        let nmi_only = EventWindow::open(false);
+        let mut queue = EventQueue::new();
+        queue.push(PendingEvent::new(0x30, None));
+        queue.push(PendingEvent::new(0xe0, None));
+        queue.push(PendingEvent::new(NONMASKABLE_INTERRUPT_VECTOR, None));
+        queue.push(PendingEvent::new(GENERAL_PROTECTION_FAULT_VECTOR, Some(0)));
+
+        assert_eq!(queue.pop(&blocked).map(|e| e.vector), Some(13));
+        assert_eq!(queue.pop(&blocked), None);
+        assert_eq!(queue.pop(&nmi_only).map(|e| e.vector), Some(2));
+        assert_eq!(
+            queue.pop(&EventWindow::open(true)).map(|e| e.vector),
+            Some(0xe0)
+        );
+        assert_eq!(
+            queue.pop(&EventWindow::open(true)).map(|e| e.vector),
+            Some(0x30)
+        );
+        assert!(!queue.has_interrupt());
+    }
+
+    #[test]
+    fn test_interrupted_delivery_goes_first() {
+        // An interrupted delivery goes before a pending exception.
+        let blocked = EventWindow {
+            interruptibility: InterruptibilityState::BLOCKING_BY_MOV_SS,
+            ..EventWindow::open(true)
+        };
+        let info = (1 << 31) | ((VmxInterruptionType::External as u32) << 8) | 0x41;
+        let interrupted = PendingEvent::from_idt_vectoring(info, 0, 0).unwrap();
+        let mut queue = EventQueue::new();
+        queue.push(PendingEvent::new(DEBUG_VECTOR, None));
+        queue.push_interrupted(interrupted);
+        assert_eq!(queue.pop(&blocked), Some(interrupted));
+        assert_eq!(queue.pop(&blocked).map(|e| e.vector), Some(1));
+    }
+
+    #[test]
+    fn test_double_and_triple_fault() {
+        let mut queue = EventQueue::new();
+        queue.push(PendingEvent::new(PAGE_FAULT_VECTOR, Some(2)));
+        queue.push(PendingEvent::new(GENERAL_PROTECTION_FAULT_VECTOR, Some(0)));
+        assert_eq!(queue.exception.map(|e| e.vector), Some(DOUBLE_FAULT_VECTOR));
+
+        queue.push(PendingEvent::new(PAGE_FAULT_VECTOR, Some(0)));
+        assert!(queue.take_triple_fault());
+        assert_eq!(queue.pop(&EventWindow::open(true)), None);
+    }
+
+    #[test]
+    fn test_serial_exceptions() {
+        // After a benign exception, or a #PF after a contributory one, the
+        // exceptions are handled serially.
+        let mut queue = EventQueue::new();
+        queue.push(PendingEvent::new(BREAKPOINT_VECTOR, None));
+        queue.push(PendingEvent::new(GENERAL_PROTECTION_FAULT_VECTOR, Some(0)));
+        assert_eq!(queue.exception.map(|e| e.vector), Some(13));
+        queue.push(PendingEvent::new(PAGE_FAULT_VECTOR, Some(0)));
+        assert_eq!(queue.exception.map(|e| e.vector), Some(14));
+    }
+
+    #[test]
+    fn test_software_interrupt_keeps_exception() {
+        let mut queue = EventQueue::new();
+        queue.push(PendingEvent::new(GENERAL_PROTECTION_FAULT_VECTOR, Some(0)));
+        queue.push(PendingEvent::software_interrupt(0x80, 2).unwrap());
+        let window = EventWindow::open(false);
+        assert_eq!(queue.pop(&window).map(|e| e.vector), Some(13));
+        assert_eq!(queue.pop(&window).map(|e| e.vector), Some(0x80));
+        assert_eq!(queue.pop(&window), None);
+    }
+
+    #[test]
+    fn test_validate_exception() {
+        assert!(PendingEvent::exception(GENERAL_PROTECTION_FAULT_VECTOR, None, None).is_err());
+        assert!(PendingEvent::exception(BREAKPOINT_VECTOR, Some(0), None).is_err());
+        assert!(PendingEvent::exception(NONMASKABLE_INTERRUPT_VECTOR, None, None).is_err());
+        assert!(PendingEvent::exception(0x20, None, None).is_err());
+        assert!(
+            PendingEvent::exception(GENERAL_PROTECTION_FAULT_VECTOR, Some(0), Some(0x1000))
+                .is_err()
+        );
+        let pf = PendingEvent::exception(PAGE_FAULT_VECTOR, Some(2), Some(0xdead_b000)).unwrap();
+        assert_eq!(pf.int_type, VmxInterruptionType::HardException);
+        assert_eq!(pf.fault_addr, Some(0xdead_b000));
+    }
+
+    #[test]
+    fn test_validate_software_events() {
+        assert!(PendingEvent::software_interrupt(0x80, 0).is_err());
+        let int = PendingEvent::software_interrupt(0x80, 2).unwrap();
+        assert_eq!(
+            (int.int_type, int.instr_len),
+            (VmxInterruptionType::SoftIntr, Some(2))
+        );
+        assert!(PendingEvent::software_exception(GENERAL_PROTECTION_FAULT_VECTOR, 1).is_err());
+        assert_eq!(
+            PendingEvent::privileged_software_exception(1)
+                .unwrap()
+                .int_type,
+            VmxInterruptionType::PrivSoftException
+        );
+    }
+
+    #[test]
+    fn test_window_blocking() {
+        let sti = EventWindow {
+            interruptibility: InterruptibilityState::BLOCKING_BY_STI,
+            ..EventWindow::open(true)
+        };
+        assert!(!sti.interrupt_allowed() && !sti.nmi_allowed() && sti.event_allowed());
+
+        let in_nmi = EventWindow {
+            interruptibility: InterruptibilityState::BLOCKING_BY_NMI,
+            ..EventWindow::open(true)
+        };
+        assert!(in_nmi.interrupt_allowed() && !in_nmi.nmi_allowed());
+        assert!(!EventWindow::open(false).interrupt_allowed());
+    }
+
+    #[test]
+    fn test_window_halted() {
+        let halted = EventWindow {
+            activity: VmxActivityState::Hlt,
+            ..EventWindow::open(true)
+        };
+        assert!(halted.interrupt_allowed() && halted.nmi_allowed());
+    }
+
+    #[test]
+    fn test_no_event_while_pending_or_shut_down() {
+        let pending = EventWindow {
+            entry_event_pending: true,
+            ..EventWindow::open(true)
+        };
+        let shutdown = EventWindow {
+            activity: VmxActivityState::Shutdown,
+            ..EventWindow::open(true)
+        };
+        let mut queue = EventQueue::new();
+        queue.push(PendingEvent::new(GENERAL_PROTECTION_FAULT_VECTOR, Some(0)));
+        assert_eq!(queue.pop(&pending), None);
+        assert_eq!(queue.pop(&shutdown), None);
+        assert_eq!(
+            queue.pop(&EventWindow::open(false)).map(|e| e.vector),
+            Some(13)
+        );
+    }
+}
@@ -0,0 +1,283 @@
+use core::marker::PhantomData;
+
+use bit_field::BitField;
+use memory_addr::PAGE_SIZE_4K as PAGE_SIZE;
+
+use axaddrspace::{AxMmHal, GuestPhysAddr, HostPhysAddr};
+use axerrno::{AxResult, ax_err};
+
+use crate::ept::GuestPageWalkInfo;
+
+/// Access to the guest-physical address space from inside the vCPU.
+///
+/// Implemented over the EPT by [`EptGuestMemory`], and by plain buffers in
+/// host-side tests.
+pub trait GuestMemory {
+    /// Read `buf.len()` bytes starting at `gpa`.
+    fn read(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> AxResult;
+
+    /// Write `buf` starting at `gpa`.
+    fn write(&mut self, gpa: GuestPhysAddr, buf: &[u8]) -> AxResult;
+
+    /// Read a little-endian value of `size` (1, 2, 4 or 8) bytes.
+    fn read_uint(&self, gpa: GuestPhysAddr, size: usize) -> AxResult<u64> {
+        let mut bytes = [0u8; 8];
+        self.read(gpa, &mut bytes[..size])?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Write the low `size` (1, 2, 4 or 8) bytes of `value` in little-endian order.
+    fn write_uint(&mut self, gpa: GuestPhysAddr, size: usize, value: u64) -> AxResult {
+        self.write(gpa, &value.to_le_bytes()[..size])
+    }
+}
+
+/// Guest memory reached by walking the EPT paging structures. (SDM Vol. 3C, Section 29.3.2)
+pub struct EptGuestMemory<H: AxMmHal> {
+    ept_root: HostPhysAddr,
+    _phantom: PhantomData<H>,
+}
+
+impl<H: AxMmHal> EptGuestMemory<H> {
+    /// Create an accessor for the EPT hierarchy whose PML4 table is at `ept_root`.
+    pub fn new(ept_root: HostPhysAddr) -> Self {
+        Self {
+            ept_root,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Translate a guest-physical address into a host-physical address.
+    pub fn translate(&self, gpa: GuestPhysAddr) -> AxResult<HostPhysAddr> {
+        const ENTRY_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+        const ENTRY_PAGE_SIZE: usize = 7;
+
+        let gpa = gpa.as_usize() as u64;
+        let mut table = self.ept_root.as_usize() as u64 & ENTRY_ADDR_MASK;
+        for level in (0..4).rev() {
+            let shift = 12 + level * 9;
+            let index = gpa.get_bits(shift..shift + 9) as usize;
+            let entry = unsafe {
+                let vaddr = H::phys_to_virt(HostPhysAddr::from(table as usize));
+                *(vaddr.as_ptr() as *const u64).add(index)
+            };
+            // Bits 2:0 are the read/write/execute permissions, an entry with
+            // none of them set is not present.
+            if entry.get_bits(0..3) == 0 {
+                return ax_err!(
+                    NotFound,
+                    format_args!("guest physical address {:#x} is not mapped", gpa)
+                );
+            }
+            let addr = entry & ENTRY_ADDR_MASK;
+            if level == 0 || (level < 3 && entry.get_bit(ENTRY_PAGE_SIZE)) {
+                let offset = gpa & ((1 << shift) - 1);
+                return Ok(HostPhysAddr::from((addr + offset) as usize));
+            }
+            table = addr;
+        }
+        unreachable!()
+    }
+
+    fn for_each_page(
+        &self,
+        gpa: GuestPhysAddr,
+        len: usize,
+        mut f: impl FnMut(*mut u8, core::ops::Range<usize>),
+    ) -> AxResult {
+        let mut done = 0;
+        while done < len {
+            let cur = gpa.as_usize() + done;
+            let chunk = (PAGE_SIZE - cur % PAGE_SIZE).min(len - done);
+            let hpa = self.translate(GuestPhysAddr::from(cur))?;
+            f(H::phys_to_virt(hpa).as_mut_ptr(), done..done + chunk);
+            done += chunk;
+        }
+        Ok(())
+    }
+}
+
+impl<H: AxMmHal> GuestMemory for EptGuestMemory<H> {
+    fn read(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> AxResult {
+        self.for_each_page(gpa, buf.len(), |ptr, range| unsafe {
+            core::ptr::copy_nonoverlapping(ptr, buf[range.clone()].as_mut_ptr(), range.len());
+        })
+    }
+
+    fn write(&mut self, gpa: GuestPhysAddr, buf: &[u8]) -> AxResult {
+        self.for_each_page(gpa, buf.len(), |ptr, range| unsafe {
+            core::ptr::copy_nonoverlapping(buf[range.clone()].as_ptr(), ptr, range.len());
+        })
+    }
+}
+
+/// Translate the guest linear address `gla` through the guest paging
+/// structures described by `ptw`, without checking access rights.
+/// (SDM Vol. 3A, Section 4.3, 4.4 and 4.5)
+pub fn translate_linear<M: GuestMemory + ?Sized>(
+    mem: &M,
+    ptw: &GuestPageWalkInfo,
+    gla: u64,
+) -> AxResult<GuestPhysAddr> {
+    const ENTRY_PRESENT: usize = 0;
+    const ENTRY_PAGE_SIZE: usize = 7;
+
+    let top = ptw.top_entry as u64;
+    // (first table, levels, index bits, entry size, address mask)
+    let (mut table, levels, width, entry_size, addr_mask) = match ptw.level {
+        0 => return Ok(GuestPhysAddr::from(gla as u32 as usize)),
+        2 => (top & 0xffff_f000, 2, 10, 4, 0xffff_f000),
+        3 => (top & 0xffff_ffe0, 3, 9, 8, 0x000f_ffff_ffff_f000),
+        4 => (top & 0x000f_ffff_ffff_f000, 4, 9, 8, 0x000f_ffff_ffff_f000),
+        level => return ax_err!(BadState, format_args!("invalid paging level {}", level)),
+    };
+    let gla = if levels == 4 { gla } else { gla as u32 as u64 };
+    for level in (0..levels).rev() {
+        let shift = 12 + level * width;
+        let index = (gla >> shift) & ((1 << width) - 1);
+        let entry = mem.read_uint(
+            GuestPhysAddr::from((table + index * entry_size as u64) as usize),
+            entry_size,
+        )?;
+        if !entry.get_bit(ENTRY_PRESENT) {
+            return ax_err!(
+                NotFound,
+                format_args!("guest linear address {:#x} is not mapped", gla)
+            );
+        }
+        // The PAE PDPTEs have no page size bit, 32-bit paging needs CR4.PSE.
+        let large = level > 0
+            && !(levels == 3 && level == 2)
+            && (entry_size == 8 || ptw.pse)
+            && entry.get_bit(ENTRY_PAGE_SIZE);
+        if level == 0 || large {
+            let offset_mask = (1 << shift) - 1;
+            return Ok(GuestPhysAddr::from(
+                ((entry & addr_mask & !offset_mask) | (gla & offset_mask)) as usize,
+            ));
+        }
+        table = entry & addr_mask;
+    }
+    unreachable!()
+}
+
+/// Read `buf.len()` bytes starting at the guest linear address `gla`, which
+/// may span pages that are not contiguous in guest-physical memory.
+pub fn read_linear<M: GuestMemory + ?Sized>(
+    mem: &M,
+    ptw: &GuestPageWalkInfo,
+    gla: u64,
+    buf: &mut [u8],
+) -> AxResult {
+    let mut done = 0;
+    while done < buf.len() {
+        let cur = gla + done as u64;
+        let chunk = (PAGE_SIZE - cur as usize % PAGE_SIZE).min(buf.len() - done);
+        let gpa = translate_linear(mem, ptw, cur)?;
+        mem.read(gpa, &mut buf[done..done + chunk])?;
+        done += chunk;
+    }
+    Ok(())
+}
+
+/// Guest memory backed by a plain buffer starting at guest-physical address 0,
+/// for host-side tests.
+#[cfg(test)]
+pub(crate) struct FlatMemory(pub alloc::vec::Vec<u8>);
+
+#[cfg(test)]
+impl GuestMemory for FlatMemory {
+    fn read(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> AxResult {
+        let start = gpa.as_usize();
+        buf.copy_from_slice(&self.0[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write(&mut self, gpa: GuestPhysAddr, buf: &[u8]) -> AxResult {
+        let start = gpa.as_usize();
+        self.0[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const GLA: u64 = 0xffff_8000_0020_1ff8;
+
+    fn walk_info(top_entry: usize, level: usize) -> GuestPageWalkInfo {
+        GuestPageWalkInfo {
+            top_entry,
+            level,
+            width: 9,
+            is_user_mode_access: false,
+            is_write_access: false,
+            is_inst_fetch: false,
+            pse: true,
+            wp: false,
+            nxe: false,
+            is_smap_on: false,
+            is_smep_on: false,
+        }
+    }
+
+    fn write_entry(mem: &mut FlatMemory, table: u64, shift: u64, value: u64) {
+        let index = (GLA >> shift) & 0x1ff;
+        mem.write_uint(GuestPhysAddr::from((table + index * 8) as usize), 8, value)
+            .unwrap();
+    }
+
+    /// PML4 at 0x1000, PDPT at 0x2000, PD at 0x3000, PT at 0x4000. `GLA` maps
+    /// to 0x6ff8 and the next page to 0x5000.
+    fn four_level_memory() -> FlatMemory {
+        let mut mem = FlatMemory(alloc::vec![0; 0x8000]);
+        write_entry(&mut mem, 0x1000, 39, 0x2003);
+        write_entry(&mut mem, 0x2000, 30, 0x3003);
+        write_entry(&mut mem, 0x3000, 21, 0x4003);
+        write_entry(&mut mem, 0x4000, 12, 0x6003);
+        mem.write_uint(
+            GuestPhysAddr::from(0x4000 + (((GLA >> 12) + 1) & 0x1ff) as usize * 8),
+            8,
+            0x5003,
+        )
+        .unwrap();
+        mem.write(GuestPhysAddr::from(0x6ff8), &[1; 8]).unwrap();
+        mem.write(GuestPhysAddr::from(0x5000), &[2; 8]).unwrap();
+        mem
+    }
+
+    #[test]
+    fn test_translate_four_level() {
+        let mem = four_level_memory();
+        assert_eq!(
+            translate_linear(&mem, &walk_info(0x1000, 4), GLA).unwrap(),
+            GuestPhysAddr::from(0x6ff8)
+        );
+    }
+
+    #[test]
+    fn test_read_across_pages() {
+        let mem = four_level_memory();
+        let mut buf = [0; 16];
+        read_linear(&mem, &walk_info(0x1000, 4), GLA, &mut buf).unwrap();
+        assert_eq!(buf, [1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn test_translate_large_page() {
+        // A 2 MiB page in the PD.
+        let mut mem = four_level_memory();
+        write_entry(&mut mem, 0x3000, 21, 0x20_0083);
+        assert_eq!(
+            translate_linear(&mem, &walk_info(0x1000, 4), GLA).unwrap(),
+            GuestPhysAddr::from(0x20_1ff8)
+        );
+    }
+
+    #[test]
+    fn test_translate_not_present() {
+        let mem = four_level_memory();
+        assert!(translate_linear(&mem, &walk_info(0x1000, 4), 0x1000).is_err());
+    }
+}
@@ -0,0 +1,267 @@
+//! Hypercalls made with `VMCALL`, decoded by the calling convention of the
+//! guest and handled in the vCPU when a handler is registered for them.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+
+use bit_field::BitField;
+
+use axerrno::{AxResult, ax_err};
+
+use crate::regs::GeneralRegisters;
+
+/// Where the guest puts the hypercall number, arguments and result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HypercallAbi {
+    /// Number in RAX, arguments in RDI, RSI, RDX, RCX, R8 and R9, result in
+    /// RAX. 32-bit guests pass the arguments in EBX, ECX, EDX, ESI, EDI and EBP.
+    #[default]
+    Native,
+    /// KVM: number in RAX, arguments in RBX, RCX, RDX and RSI, result in RAX,
+    /// the same registers for 32-bit guests.
+    Kvm,
+    /// Xen-like: number in RAX, arguments in RDI, RSI, RDX, R10 and R8, result
+    /// in RAX. 32-bit guests pass the arguments in EBX, ECX, EDX, ESI and EDI.
+    Xen,
+    /// Hyper-V: the control word in RCX, the input and output in RDX and R8,
+    /// the result in RAX. 32-bit guests use EDX:EAX, EBX:ECX, EDI:ESI and
+    /// EDX:EAX for the result. (TLFS, Section 3.7)
+    HyperV,
+}
+
+/// A decoded hypercall.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hypercall {
+    /// The hypercall number, the call code for Hyper-V.
+    pub nr: u64,
+    /// The arguments, in order, zero if not passed. For Hyper-V: the input
+    /// and output, as parameters for fast calls or as guest-physical
+    /// addresses of the parameter pages otherwise, then the control word.
+    pub args: [u64; 6],
+    /// Hyper-V fast call, with the parameters in registers.
+    pub fast: bool,
+}
+
+/// What to do with a hypercall.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HypercallAction {
+    /// Complete it in the vCPU, returning the value to the guest.
+    Return(u64),
+    /// Pass it to the VMM as an `AxVCpuExitReason::Hypercall` exit.
+    Exit,
+}
+
+/// A hypercall handler run in the vCPU, without a VM exit to the VMM.
+pub type HypercallHandler = Box<dyn Fn(&Hypercall) -> HypercallAction + Send + Sync>;
+
+/// Decodes hypercalls and dispatches them to the registered handlers.
+pub struct HypercallDispatcher {
+    abi: HypercallAbi,
+    deny_user: bool,
+    handlers: BTreeMap<u64, HypercallHandler>,
+}
+
+impl core::fmt::Debug for HypercallDispatcher {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("HypercallDispatcher")
+            .field("abi", &self.abi)
+            .field("deny_user", &self.deny_user)
+            .field("handlers", &self.handlers.keys())
+            .finish()
+    }
+}
+
+impl HypercallDispatcher {
+    /// A dispatcher for `abi` without handlers: every hypercall exits to the VMM.
+    pub fn new(abi: HypercallAbi) -> Self {
+        Self {
+            abi,
+            deny_user: false,
+            handlers: BTreeMap::new(),
+        }
+    }
+
+    /// The calling convention.
+    pub fn abi(&self) -> HypercallAbi {
+        self.abi
+    }
+
+    /// Set the calling convention.
+    pub fn set_abi(&mut self, abi: HypercallAbi) {
+        self.abi = abi;
+    }
+
+    /// Whether `VMCALL` at CPL > 0 raises #UD instead of making a hypercall.
+    pub fn deny_user(&self) -> bool {
+        self.deny_user
+    }
+
+    /// Raise #UD for `VMCALL` at CPL > 0 if `deny`.
+    pub fn set_deny_user(&mut self, deny: bool) {
+        self.deny_user = deny;
+    }
+
+    /// Handle hypercall `nr` in the vCPU with `handler`.
+    pub fn register(&mut self, nr: u64, handler: HypercallHandler) -> AxResult {
+        if self.handlers.contains_key(&nr) {
+            return ax_err!(
+                AlreadyExists,
+                format_args!("hypercall {:#x} already has a handler", nr)
+            );
+        }
+        self.handlers.insert(nr, handler);
+        Ok(())
+    }
+
+    /// Remove the handler of hypercall `nr`. Returns whether there was one.
+    pub fn unregister(&mut self, nr: u64) -> bool {
+        self.handlers.remove(&nr).is_some()
+    }
+
+    /// Decode the hypercall in `regs`, made from 64-bit code if `long_mode`.
+    pub fn decode(&self, regs: &GeneralRegisters, long_mode: bool) -> Hypercall {
+        let r32 = |value: u64| value & 0xffff_ffff;
+        let pair = |high: u64, low: u64| r32(high) << 32 | r32(low);
+        let (nr, args, fast) = match (self.abi, long_mode) {
+            (HypercallAbi::Native, true) => (
+                regs.rax,
+                [regs.rdi, regs.rsi, regs.rdx, regs.rcx, regs.r8, regs.r9],
+                false,
+            ),
+            (HypercallAbi::Native, false) => (
+                r32(regs.rax),
+                [regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rbp].map(r32),
+                false,
+            ),
+            (HypercallAbi::Kvm, _) => {
+                let args = [regs.rbx, regs.rcx, regs.rdx, regs.rsi, 0, 0];
+                if long_mode {
+                    (regs.rax, args, false)
+                } else {
+                    (r32(regs.rax), args.map(r32), false)
+                }
+            }
+            (HypercallAbi::Xen, true) => (
+                regs.rax,
+                [regs.rdi, regs.rsi, regs.rdx, regs.r10, regs.r8, 0],
+                false,
+            ),
+            (HypercallAbi::Xen, false) => (
+                r32(regs.rax),
+                [regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, 0].map(r32),
+                false,
+            ),
+            (HypercallAbi::HyperV, _) => {
+                let (control, input, output) = if long_mode {
+                    (regs.rcx, regs.rdx, regs.r8)
+                } else {
+                    (
+                        pair(regs.rdx, regs.rax),
+                        pair(regs.rbx, regs.rcx),
+                        pair(regs.rdi, regs.rsi),
+                    )
+                };
+                (
+                    control.get_bits(0..16),
+                    [input, output, control, 0, 0, 0],
+                    control.get_bit(16),
+                )
+            }
+        };
+        Hypercall { nr, args, fast }
+    }
+
+    /// Run the handler of `call`, or ask for an exit if there is none.
+    pub fn dispatch(&self, call: &Hypercall) -> HypercallAction {
+        self.handlers
+            .get(&call.nr)
+            .map_or(HypercallAction::Exit, |handler| handler(call))
+    }
+
+    /// Return `value` to the guest in the result register of the convention.
+    pub fn write_result(&self, regs: &mut GeneralRegisters, long_mode: bool, value: u64) {
+        if long_mode {
+            regs.rax = value;
+        } else if self.abi == HypercallAbi::HyperV {
+            regs.rax = value & 0xffff_ffff;
+            regs.rdx = value >> 32;
+        } else {
+            regs.rax = value & 0xffff_ffff;
+        }
+    }
+}
+
+impl Default for HypercallDispatcher {
+    fn default() -> Self {
+        Self::new(HypercallAbi::Native)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let mut regs = GeneralRegisters::default();
+        regs.rax = 0x1_0000_0005;
+        regs.rbx = 0x1_0000_0001;
+        regs.rcx = 0x1_0001_0008;
+        regs.rdx = 3;
+        regs.rsi = 4;
+        regs.rdi = 5;
+        regs.r8 = 6;
+
+        let mut hc = HypercallDispatcher::default();
+        let call = hc.decode(&regs, true);
+        assert_eq!(
+            (call.nr, call.args),
+            (0x1_0000_0005, [5, 4, 3, 0x1_0001_0008, 6, 0])
+        );
+        // 32-bit guests: EBX/ECX first.
+        let call = hc.decode(&regs, false);
+        assert_eq!((call.nr, call.args), (5, [1, 0x1_0008, 3, 4, 5, 0]));
+
+        hc.set_abi(HypercallAbi::Kvm);
+        assert_eq!(
+            hc.decode(&regs, true).args,
+            [0x1_0000_0001, 0x1_0001_0008, 3, 4, 0, 0]
+        );
+
+        // Hyper-V fast call 8 with the fast bit set in the control word.
+        hc.set_abi(HypercallAbi::HyperV);
+        let call = hc.decode(&regs, true);
+        assert_eq!(
+            (call.nr, call.fast, call.args[0], call.args[1]),
+            (8, true, 3, 6)
+        );
+    }
+
+    #[test]
+    fn test_hyperv_result_32bit() {
+        let hc = HypercallDispatcher::new(HypercallAbi::HyperV);
+        let mut regs = GeneralRegisters::default();
+        hc.write_result(&mut regs, false, 0x2_0000_0001);
+        assert_eq!((regs.rax, regs.rdx), (1, 2));
+    }
+
+    #[test]
+    fn test_dispatch_registered() {
+        // Registered numbers are handled in the vCPU, the others exit.
+        let mut hc = HypercallDispatcher::new(HypercallAbi::HyperV);
+        let call = Hypercall {
+            nr: 8,
+            args: [3, 6, 0, 0, 0, 0],
+            fast: true,
+        };
+        hc.register(
+            8,
+            Box::new(|call| HypercallAction::Return(call.args[0] + 1)),
+        )
+        .unwrap();
+        assert!(hc.register(8, Box::new(|_| HypercallAction::Exit)).is_err());
+        assert_eq!(hc.dispatch(&call), HypercallAction::Return(4));
+        assert!(hc.unregister(8));
+        assert_eq!(hc.dispatch(&call), HypercallAction::Exit);
+    }
+}
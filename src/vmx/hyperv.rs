@@ -0,0 +1,446 @@
+//! A subset of the Hyper-V enlightenments, for Windows guests.
+//!
+//! The hypervisor CPUID leaves, the synthetic MSRs, the hypercall page and the
+//! reference TSC page follow the Hypervisor Top Level Functional Specification
+//! (TLFS). Synthetic timers are only supported in direct mode, in which they
+//! raise an interrupt through the local APIC instead of a SynIC message.
+
+use bit_field::BitField;
+use bitflags::bitflags;
+use raw_cpuid::CpuIdResult;
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::AxResult;
+
+use super::error::{VcpuError, VcpuResult};
+use super::guest_mem::GuestMemory;
+
+bitflags! {
+    /// The Hyper-V enlightenments offered to the guest.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct HyperVFeatures: u32 {
+        /// The guest OS ID and hypercall page MSRs.
+        const HYPERCALL = 1 << 0;
+        /// The VP index MSR.
+        const VP_INDEX = 1 << 1;
+        /// The partition reference counter, the reference TSC page and the
+        /// TSC frequency MSR.
+        const REFERENCE_TSC = 1 << 2;
+        /// Synthetic timers in direct mode. Needs `REFERENCE_TSC`.
+        const SYNTHETIC_TIMERS = 1 << 3;
+        /// The EOI, ICR and TPR MSRs, recommended over the APIC registers.
+        const APIC_ACCESS = 1 << 4;
+    }
+}
+
+const HV_X64_MSR_GUEST_OS_ID: u32 = 0x4000_0000;
+const HV_X64_MSR_HYPERCALL: u32 = 0x4000_0001;
+const HV_X64_MSR_VP_INDEX: u32 = 0x4000_0002;
+const HV_X64_MSR_TIME_REF_COUNT: u32 = 0x4000_0020;
+const HV_X64_MSR_REFERENCE_TSC: u32 = 0x4000_0021;
+const HV_X64_MSR_TSC_FREQUENCY: u32 = 0x4000_0022;
+/// Write-only, an EOI to the local APIC.
+pub const HV_X64_MSR_EOI: u32 = 0x4000_0070;
+/// The 64-bit interrupt command register of the local APIC.
+pub const HV_X64_MSR_ICR: u32 = 0x4000_0071;
+/// The task-priority register of the local APIC.
+pub const HV_X64_MSR_TPR: u32 = 0x4000_0072;
+/// `STIMER0_CONFIG`, followed by `STIMER0_COUNT`, `STIMER1_CONFIG`, ...
+const HV_X64_MSR_STIMER0_CONFIG: u32 = 0x4000_00b0;
+const STIMER_COUNT: usize = 4;
+
+/// "Microsoft Hv".
+const HV_SIGNATURE: [u32; 3] = [0x7263_694d, 0x666f_736f, 0x7648_2074];
+/// "Hv#1", the interface implemented.
+const HV_INTERFACE: u32 = 0x3123_7648;
+/// The highest Hyper-V CPUID leaf.
+const HV_CPUID_MAX_LEAF: u32 = 0x4000_000a;
+
+/// `VMCALL; RET`, the hypercall page content.
+const HYPERCALL_CODE: [u8; 4] = [0x0f, 0x01, 0xc1, 0xc3];
+/// The reference time counts in units of 100 ns.
+const REFERENCE_TIME_HZ: u128 = 10_000_000;
+
+/// The guest TSC at the time of an access, from which the reference time is
+/// derived: it starts at 0 when the guest TSC does, and guest writes to the
+/// TSC do not move it.
+#[derive(Debug, Clone, Copy)]
+pub struct HvClock {
+    /// The guest TSC frequency in Hz.
+    pub tsc_hz: u64,
+    /// The guest `IA32_TSC_ADJUST`.
+    pub tsc_adjust: u64,
+    /// The current guest TSC.
+    pub guest_tsc: u64,
+}
+
+impl HvClock {
+    /// The partition reference time, in units of 100 ns.
+    pub fn reference_time(&self) -> u64 {
+        (self.guest_tsc.wrapping_sub(self.tsc_adjust) as u128 * REFERENCE_TIME_HZ
+            / self.tsc_hz as u128) as u64
+    }
+
+    /// The guest TSC at reference time `time`.
+    pub fn guest_tsc_at(&self, time: u64) -> u64 {
+        ((time as u128 * self.tsc_hz as u128 / REFERENCE_TIME_HZ) as u64)
+            .wrapping_add(self.tsc_adjust)
+    }
+
+    /// The scale and offset of the reference TSC page:
+    /// `time = ((tsc * scale) >> 64) + offset`.
+    fn tsc_page_params(&self) -> (u64, u64) {
+        let scale = ((REFERENCE_TIME_HZ << 64) / self.tsc_hz as u128) as u64;
+        let adjust = ((self.tsc_adjust as u128 * scale as u128) >> 64) as u64;
+        (scale, adjust.wrapping_neg())
+    }
+}
+
+/// A synthetic timer. (TLFS, Section 12.4)
+#[derive(Debug, Clone, Copy, Default)]
+struct SyntheticTimer {
+    config: u64,
+    count: u64,
+    /// The reference time at which it fires.
+    expiry: Option<u64>,
+}
+
+impl SyntheticTimer {
+    const ENABLE: usize = 0;
+    const PERIODIC: usize = 1;
+    const AUTO_ENABLE: usize = 3;
+    const DIRECT_MODE: usize = 12;
+
+    fn vector(&self) -> u8 {
+        self.config.get_bits(4..12) as u8
+    }
+
+    fn arm(&mut self, now: u64) {
+        let enabled = self.config.get_bit(Self::ENABLE) && self.count != 0;
+        self.expiry = match enabled {
+            false => None,
+            true if self.config.get_bit(Self::PERIODIC) => Some(now + self.count),
+            true => Some(self.count),
+        };
+        if enabled && !self.config.get_bit(Self::DIRECT_MODE) {
+            warn!("Hyper-V: synthetic timers only fire in direct mode");
+            self.expiry = None;
+        }
+    }
+}
+
+/// The Hyper-V state of a vCPU.
+#[derive(Debug, Clone)]
+pub struct HyperV {
+    features: HyperVFeatures,
+    vp_index: u32,
+    guest_os_id: u64,
+    hypercall: u64,
+    reference_tsc: u64,
+    tsc_sequence: u32,
+    timers: [SyntheticTimer; STIMER_COUNT],
+}
+
+impl HyperV {
+    /// The state of the vCPU with index `vp_index`, offering `features`.
+    pub fn new(features: HyperVFeatures, vp_index: u32) -> Self {
+        Self {
+            features,
+            vp_index,
+            guest_os_id: 0,
+            hypercall: 0,
+            reference_tsc: 0,
+            tsc_sequence: 0,
+            timers: [SyntheticTimer::default(); STIMER_COUNT],
+        }
+    }
+
+    /// The enlightenments offered to the guest.
+    pub fn features(&self) -> HyperVFeatures {
+        self.features
+    }
+
+    /// Offer `features`, before the guest runs.
+    pub fn set_features(&mut self, features: HyperVFeatures) {
+        self.features = features;
+    }
+
+    /// The Hyper-V CPUID leaf `leaf`, or `None` if it is not one.
+    pub fn cpuid(&self, leaf: u32) -> Option<CpuIdResult> {
+        // Partition privileges, in EAX of leaf 0x4000_0003.
+        const ACCESS_PARTITION_REFERENCE_COUNTER: u32 = 1 << 1;
+        const ACCESS_SYNTHETIC_TIMER_REGS: u32 = 1 << 3;
+        const ACCESS_INTR_CTRL_REGS: u32 = 1 << 4;
+        const ACCESS_HYPERCALL_MSRS: u32 = 1 << 5;
+        const ACCESS_VP_INDEX: u32 = 1 << 6;
+        const ACCESS_PARTITION_REFERENCE_TSC: u32 = 1 << 9;
+        const ACCESS_FREQUENCY_MSRS: u32 = 1 << 11;
+        // EDX of leaf 0x4000_0003.
+        const STIMER_DIRECT_MODE_AVAILABLE: u32 = 1 << 19;
+        // EAX of leaf 0x4000_0004.
+        const APIC_ACCESS_RECOMMENDED: u32 = 1 << 3;
+
+        let mut res = CpuIdResult {
+            eax: 0,
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+        };
+        let features = self.features;
+        match leaf {
+            0x4000_0000 => {
+                res.eax = HV_CPUID_MAX_LEAF;
+                [res.ebx, res.ecx, res.edx] = HV_SIGNATURE;
+            }
+            0x4000_0001 => res.eax = HV_INTERFACE,
+            0x4000_0003 => {
+                if features.contains(HyperVFeatures::HYPERCALL) {
+                    res.eax |= ACCESS_HYPERCALL_MSRS;
+                }
+                if features.contains(HyperVFeatures::VP_INDEX) {
+                    res.eax |= ACCESS_VP_INDEX;
+                }
+                if features.contains(HyperVFeatures::REFERENCE_TSC) {
+                    res.eax |= ACCESS_PARTITION_REFERENCE_COUNTER
+                        | ACCESS_PARTITION_REFERENCE_TSC
+                        | ACCESS_FREQUENCY_MSRS;
+                }
+                if features.contains(HyperVFeatures::SYNTHETIC_TIMERS) {
+                    res.eax |= ACCESS_SYNTHETIC_TIMER_REGS;
+                    res.edx |= STIMER_DIRECT_MODE_AVAILABLE;
+                }
+                if features.contains(HyperVFeatures::APIC_ACCESS) {
+                    res.eax |= ACCESS_INTR_CTRL_REGS;
+                }
+            }
+            0x4000_0004 => {
+                if features.contains(HyperVFeatures::APIC_ACCESS) {
+                    res.eax |= APIC_ACCESS_RECOMMENDED;
+                }
+                // Never notify the hypervisor of long spin waits.
+                res.ebx = u32::MAX;
+            }
+            0x4000_0002 | 0x4000_0005..=HV_CPUID_MAX_LEAF => {}
+            _ => return None,
+        }
+        Some(res)
+    }
+
+    /// Whether `msr` is a synthetic MSR of an offered enlightenment, the APIC
+    /// access MSRs aside.
+    pub fn handles_msr(&self, msr: u32) -> bool {
+        let stimer_end = HV_X64_MSR_STIMER0_CONFIG + 2 * STIMER_COUNT as u32;
+        let feature = match msr {
+            HV_X64_MSR_GUEST_OS_ID | HV_X64_MSR_HYPERCALL => HyperVFeatures::HYPERCALL,
+            HV_X64_MSR_VP_INDEX => HyperVFeatures::VP_INDEX,
+            HV_X64_MSR_TIME_REF_COUNT | HV_X64_MSR_REFERENCE_TSC | HV_X64_MSR_TSC_FREQUENCY => {
+                HyperVFeatures::REFERENCE_TSC
+            }
+            _ if (HV_X64_MSR_STIMER0_CONFIG..stimer_end).contains(&msr) => {
+                HyperVFeatures::SYNTHETIC_TIMERS
+            }
+            _ => return false,
+        };
+        self.features.contains(feature)
+    }
+
+    /// A guest `RDMSR` of a synthetic MSR.
+    pub fn read_msr(&self, msr: u32, clock: &HvClock) -> VcpuResult<u64> {
+        Ok(match msr {
+            HV_X64_MSR_GUEST_OS_ID => self.guest_os_id,
+            HV_X64_MSR_HYPERCALL => self.hypercall,
+            HV_X64_MSR_VP_INDEX => self.vp_index as u64,
+            HV_X64_MSR_TIME_REF_COUNT => clock.reference_time(),
+            HV_X64_MSR_REFERENCE_TSC => self.reference_tsc,
+            HV_X64_MSR_TSC_FREQUENCY => clock.tsc_hz,
+            _ => {
+                let (timer, count) = Self::timer_of(msr);
+                let timer = &self.timers[timer];
+                if count { timer.count } else { timer.config }
+            }
+        })
+    }
+
+    /// A guest `WRMSR` of `value` to a synthetic MSR, filling in the hypercall
+    /// and reference TSC pages in `mem`.
+    pub fn write_msr<M: GuestMemory + ?Sized>(
+        &mut self,
+        mem: &mut M,
+        msr: u32,
+        value: u64,
+        clock: &HvClock,
+    ) -> VcpuResult {
+        const PAGE_ENABLE: usize = 0;
+        match msr {
+            HV_X64_MSR_GUEST_OS_ID => {
+                self.guest_os_id = value;
+                if value == 0 {
+                    // The hypercall page is disabled with the guest OS ID.
+                    self.hypercall.set_bit(PAGE_ENABLE, false);
+                }
+            }
+            HV_X64_MSR_HYPERCALL => {
+                if value.get_bit(PAGE_ENABLE) && self.guest_os_id == 0 {
+                    // Cannot be enabled before the guest OS identifies itself.
+                    return Ok(());
+                }
+                self.hypercall = value;
+                if value.get_bit(PAGE_ENABLE) {
+                    mem.write(Self::page_of(value), &HYPERCALL_CODE)?;
+                }
+            }
+            HV_X64_MSR_REFERENCE_TSC => {
+                self.reference_tsc = value;
+                self.update_reference_tsc(mem, clock)?;
+            }
+            HV_X64_MSR_VP_INDEX | HV_X64_MSR_TIME_REF_COUNT | HV_X64_MSR_TSC_FREQUENCY => {
+                return Err(VcpuError::gp("write to a read-only Hyper-V MSR"));
+            }
+            _ => {
+                let now = clock.reference_time();
+                let (timer, count) = Self::timer_of(msr);
+                let timer = &mut self.timers[timer];
+                if count {
+                    timer.count = value;
+                    if value == 0 {
+                        timer.config.set_bit(SyntheticTimer::ENABLE, false);
+                    } else if timer.config.get_bit(SyntheticTimer::AUTO_ENABLE) {
+                        timer.config.set_bit(SyntheticTimer::ENABLE, true);
+                    }
+                } else {
+                    timer.config = value;
+                }
+                timer.arm(now);
+            }
+        }
+        Ok(())
+    }
+
+    /// Refresh the reference TSC page, if enabled, after the guest TSC has
+    /// been moved. (TLFS, Section 12.7)
+    pub fn update_reference_tsc<M: GuestMemory + ?Sized>(
+        &mut self,
+        mem: &mut M,
+        clock: &HvClock,
+    ) -> AxResult {
+        if !self.reference_tsc.get_bit(0) {
+            return Ok(());
+        }
+        let gpa = Self::page_of(self.reference_tsc);
+        // 0 and 0xffff_ffff tell the guest to use the reference counter MSR.
+        self.tsc_sequence = match self.tsc_sequence.wrapping_add(1) {
+            0 | u32::MAX => 1,
+            sequence => sequence,
+        };
+        let (scale, offset) = clock.tsc_page_params();
+        mem.write_uint(gpa, 4, 0)?;
+        mem.write_uint(gpa + 8, 8, scale)?;
+        mem.write_uint(gpa + 16, 8, offset)?;
+        mem.write_uint(gpa, 4, self.tsc_sequence as u64)
+    }
+
+    /// Fire the synthetic timers that have expired at reference time `now`,
+    /// passing their vectors to `fire`.
+    pub fn poll_timers(&mut self, now: u64, mut fire: impl FnMut(u8)) {
+        for timer in &mut self.timers {
+            match timer.expiry {
+                Some(expiry) if expiry <= now => {
+                    fire(timer.vector());
+                    if timer.config.get_bit(SyntheticTimer::PERIODIC) {
+                        // Skip the periods that have passed already.
+                        let periods = (now - expiry) / timer.count + 1;
+                        timer.expiry = Some(expiry + periods * timer.count);
+                    } else {
+                        timer.config.set_bit(SyntheticTimer::ENABLE, false);
+                        timer.expiry = None;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// The reference time at which the next synthetic timer fires.
+    pub fn next_timer_expiry(&self) -> Option<u64> {
+        self.timers.iter().filter_map(|timer| timer.expiry).min()
+    }
+
+    fn timer_of(msr: u32) -> (usize, bool) {
+        let index = (msr - HV_X64_MSR_STIMER0_CONFIG) as usize;
+        (index / 2, index % 2 == 1)
+    }
+
+    fn page_of(value: u64) -> GuestPhysAddr {
+        GuestPhysAddr::from((value & !0xfff) as usize)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vmx::guest_mem::FlatMemory;
+
+    // 1 GHz, 1 ms after boot.
+    const CLOCK: HvClock = HvClock {
+        tsc_hz: 1_000_000_000,
+        tsc_adjust: 0,
+        guest_tsc: 1_000_000,
+    };
+
+    #[test]
+    fn test_time_ref_count() {
+        let hv = HyperV::new(HyperVFeatures::REFERENCE_TSC, 1);
+        assert!(!hv.handles_msr(HV_X64_MSR_VP_INDEX));
+        assert_eq!(
+            hv.read_msr(HV_X64_MSR_TIME_REF_COUNT, &CLOCK).unwrap(),
+            10_000
+        );
+    }
+
+    #[test]
+    fn test_hypercall_page_needs_guest_os_id() {
+        let mut hv = HyperV::new(HyperVFeatures::HYPERCALL, 1);
+        let mut mem = FlatMemory(alloc::vec![0; 0x2000]);
+        hv.write_msr(&mut mem, HV_X64_MSR_HYPERCALL, 0x1001, &CLOCK)
+            .unwrap();
+        assert_eq!(hv.read_msr(HV_X64_MSR_HYPERCALL, &CLOCK).unwrap(), 0);
+        hv.write_msr(&mut mem, HV_X64_MSR_GUEST_OS_ID, 1 << 63, &CLOCK)
+            .unwrap();
+        hv.write_msr(&mut mem, HV_X64_MSR_HYPERCALL, 0x1001, &CLOCK)
+            .unwrap();
+        let mut code = [0; 4];
+        mem.read(GuestPhysAddr::from(0x1000), &mut code).unwrap();
+        assert_eq!(code, HYPERCALL_CODE);
+    }
+
+    #[test]
+    fn test_reference_tsc_page() {
+        // The reference TSC page gives the same time as the counter MSR.
+        let mut hv = HyperV::new(HyperVFeatures::REFERENCE_TSC, 1);
+        let mut mem = FlatMemory(alloc::vec![0; 0x3000]);
+        hv.write_msr(&mut mem, HV_X64_MSR_REFERENCE_TSC, 0x2001, &CLOCK)
+            .unwrap();
+        let read = |gpa: usize, size| mem.read_uint(GuestPhysAddr::from(gpa), size).unwrap();
+        assert_eq!(read(0x2000, 4), 1);
+        let time = ((CLOCK.guest_tsc as u128 * read(0x2008, 8) as u128) >> 64) as u64;
+        assert!(time.wrapping_add(read(0x2010, 8)).abs_diff(10_000) <= 1);
+    }
+
+    #[test]
+    fn test_periodic_timer() {
+        // A periodic direct-mode timer with vector 0x40, every 500 us.
+        let mut hv = HyperV::new(HyperVFeatures::SYNTHETIC_TIMERS, 1);
+        let mut mem = FlatMemory(alloc::vec::Vec::new());
+        let config = 0x40 << 4 | 1 << 12 | 1 << 1 | 1;
+        hv.write_msr(&mut mem, HV_X64_MSR_STIMER0_CONFIG + 1, 5000, &CLOCK)
+            .unwrap();
+        hv.write_msr(&mut mem, HV_X64_MSR_STIMER0_CONFIG, config, &CLOCK)
+            .unwrap();
+        assert_eq!(hv.next_timer_expiry(), Some(15_000));
+        let mut fired = alloc::vec::Vec::new();
+        hv.poll_timers(14_999, |vector| fired.push(vector));
+        hv.poll_timers(21_000, |vector| fired.push(vector));
+        assert_eq!(fired, [0x40]);
+        assert_eq!(hv.next_timer_expiry(), Some(25_000));
+    }
+}
@@ -0,0 +1,157 @@
+//! How the guest idles: `MONITOR`/`MWAIT`, and `UMWAIT`/`TPAUSE` (WAITPKG).
+//!
+//! `MWAIT` executed in the guest puts the physical CPU in the requested
+//! C-state, which suits vCPUs pinned to dedicated CPUs. Otherwise, `MONITOR`
+//! and `MWAIT` cause VM exits and `MWAIT` is reported like `HLT`. (SDM Vol. 3C,
+//! Section 25.1.3)
+//!
+//! `UMWAIT` and `TPAUSE` always run in the guest, waiting at most as long as
+//! `IA32_UMWAIT_CONTROL` allows. The guest value of that MSR is emulated, and
+//! a value clamped to the host limits is loaded while the guest runs.
+//! (SDM Vol. 1, Section 16.13.1)
+
+use bit_field::BitField;
+
+use super::error::{VcpuError, VcpuResult};
+use crate::msr::Msr;
+
+/// What `MONITOR` and `MWAIT` do in the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MwaitMode {
+    /// They run in the guest and may put the physical CPU in a deep C-state.
+    #[default]
+    Passthrough,
+    /// `MONITOR` does nothing and `MWAIT` is reported to the VMM as
+    /// [`AxVCpuExitReason::Halt`](axvcpu::AxVCpuExitReason::Halt), to be
+    /// completed on the next interrupt.
+    Halt,
+}
+
+/// The host limits on `UMWAIT` and `TPAUSE` in the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitPkgConfig {
+    /// The longest wait in TSC cycles, 0 for no limit other than the
+    /// guest's. Bits 1:0 are ignored.
+    pub max_time: u32,
+    /// Whether the guest may enter the C0.2 state, slower to wake up from.
+    pub allow_c0_2: bool,
+}
+
+impl Default for WaitPkgConfig {
+    /// 100000 cycles and C0.2 allowed, as in Linux.
+    fn default() -> Self {
+        Self {
+            max_time: 100_000,
+            allow_c0_2: true,
+        }
+    }
+}
+
+/// `IA32_UMWAIT_CONTROL`: C0.2 is not allowed.
+const UMWAIT_C0_2_DISABLE: u64 = 1 << 0;
+/// `IA32_UMWAIT_CONTROL`: the longest wait in TSC cycles, a multiple of 4.
+const UMWAIT_MAX_TIME_MASK: u64 = 0xffff_fffc;
+
+/// The guest and host `IA32_UMWAIT_CONTROL` of a vCPU.
+#[derive(Debug, Clone)]
+pub struct UmwaitControl {
+    limits: WaitPkgConfig,
+    guest: u64,
+    host: u64,
+}
+
+impl UmwaitControl {
+    /// A guest MSR at its reset value, 0, limited by `limits`, on a host
+    /// whose MSR reads `host`.
+    pub fn new(limits: WaitPkgConfig, host: u64) -> Self {
+        Self {
+            limits,
+            guest: 0,
+            host,
+        }
+    }
+
+    /// The guest `IA32_UMWAIT_CONTROL`.
+    pub fn guest(&self) -> u64 {
+        self.guest
+    }
+
+    /// A guest write of `value` to `IA32_UMWAIT_CONTROL`.
+    pub fn write(&mut self, value: u64) -> VcpuResult {
+        if value & !(UMWAIT_MAX_TIME_MASK | UMWAIT_C0_2_DISABLE) != 0 {
+            return Err(VcpuError::gp("reserved bits set in IA32_UMWAIT_CONTROL"));
+        }
+        self.guest = value;
+        Ok(())
+    }
+
+    /// The value in effect while the guest runs: the shorter of the guest and
+    /// host wait limits, and C0.2 disabled if either disables it.
+    pub fn effective(&self) -> u64 {
+        let guest_max = self.guest & UMWAIT_MAX_TIME_MASK;
+        let host_max = self.limits.max_time as u64 & UMWAIT_MAX_TIME_MASK;
+        let max_time = match (guest_max, host_max) {
+            (0, limit) | (limit, 0) => limit,
+            (guest, host) => guest.min(host),
+        };
+        let mut value = max_time;
+        value.set_bit(
+            0,
+            self.guest & UMWAIT_C0_2_DISABLE != 0 || !self.limits.allow_c0_2,
+        );
+        value
+    }
+
+    /// Load the effective guest value before VM entry.
+    pub fn switch_to_guest(&self) {
+        let value = self.effective();
+        if value != self.host {
+            unsafe { Msr::IA32_UMWAIT_CONTROL.write(value) };
+        }
+    }
+
+    /// Restore the host value after VM exit.
+    pub fn switch_to_host(&self) {
+        if self.effective() != self.host {
+            unsafe { Msr::IA32_UMWAIT_CONTROL.write(self.host) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_umwait_host_limit() {
+        let mut umwait = UmwaitControl::new(WaitPkgConfig::default(), 0);
+        // Unlimited in the guest: the host limit.
+        assert_eq!(umwait.effective(), 100_000);
+
+        umwait.write(5000 | UMWAIT_C0_2_DISABLE).unwrap();
+        assert_eq!(umwait.guest(), 5001);
+        assert_eq!(umwait.effective(), 5001);
+        umwait.write(1_000_000).unwrap();
+        assert_eq!(umwait.effective(), 100_000);
+    }
+
+    #[test]
+    fn test_umwait_reserved_bits() {
+        let mut umwait = UmwaitControl::new(WaitPkgConfig::default(), 0);
+        assert!(umwait.write(1 << 1).is_err());
+        assert!(umwait.write(1 << 32).is_err());
+    }
+
+    #[test]
+    fn test_umwait_c0_2_denied() {
+        // No host limit, and C0.2 denied by the host.
+        let umwait = UmwaitControl::new(
+            WaitPkgConfig {
+                max_time: 0,
+                allow_c0_2: false,
+            },
+            0,
+        );
+        assert_eq!(umwait.effective(), UMWAIT_C0_2_DISABLE);
+    }
+}
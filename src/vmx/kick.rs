@@ -0,0 +1,136 @@
+//! Forcing a running vCPU out of guest mode from another CPU.
+//!
+//! The vCPU publishes its run state, a kicker publishes an exit request, and
+//! each then reads what the other published. With sequentially consistent
+//! accesses at least one of them sees the other's store, so either the vCPU
+//! skips the VM entry or the kicker learns that it has to send an IPI.
+//!
+//! The IPI causes an external-interrupt VM exit once the guest runs. For it not
+//! to be taken by the host between the check and the VM entry, the vCPU must
+//! run with host interrupts disabled (VM exits clear `RFLAGS.IF` anyway).
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+/// Where a vCPU is with respect to guest mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum VcpuRunState {
+    /// Not running the guest, an exit request is seen before the next entry.
+    OutsideGuest = 0,
+    /// Between the exit-request check and VMLAUNCH/VMRESUME.
+    Entering = 1,
+    /// Running the guest, until the next VM exit.
+    InGuest = 2,
+}
+
+#[derive(Debug)]
+struct KickState {
+    run_state: AtomicU8,
+    exit_requested: AtomicBool,
+}
+
+/// A thread-safe handle to make a vCPU exit guest mode promptly, obtained with
+/// [`VmxVcpu::kick_handle`](super::vcpu::VmxVcpu::kick_handle).
+#[derive(Debug, Clone)]
+pub struct VcpuKickHandle {
+    state: Arc<KickState>,
+}
+
+impl VcpuKickHandle {
+    pub(crate) fn new() -> Self {
+        Self {
+            state: Arc::new(KickState {
+                run_state: AtomicU8::new(VcpuRunState::OutsideGuest as u8),
+                exit_requested: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// The current run state of the vCPU.
+    pub fn run_state(&self) -> VcpuRunState {
+        match self.state.run_state.load(Ordering::SeqCst) {
+            0 => VcpuRunState::OutsideGuest,
+            1 => VcpuRunState::Entering,
+            _ => VcpuRunState::InGuest,
+        }
+    }
+
+    /// Request the vCPU to return from its next or current run. Returns whether
+    /// an IPI must be sent to the CPU running it to force the VM exit.
+    pub fn kick(&self) -> bool {
+        self.state.exit_requested.store(true, Ordering::SeqCst);
+        self.run_state() != VcpuRunState::OutsideGuest
+    }
+
+    /// Whether an exit has been requested and not yet taken by the vCPU.
+    pub fn exit_requested(&self) -> bool {
+        self.state.exit_requested.load(Ordering::SeqCst)
+    }
+
+    /// Called by the vCPU before entering the guest. Returns `false`, and
+    /// consumes the request, if an exit has been requested.
+    pub(crate) fn begin_entry(&self) -> bool {
+        self.set_run_state(VcpuRunState::Entering);
+        if self.state.exit_requested.swap(false, Ordering::SeqCst) {
+            self.set_run_state(VcpuRunState::OutsideGuest);
+            return false;
+        }
+        self.set_run_state(VcpuRunState::InGuest);
+        true
+    }
+
+    /// Called by the vCPU after a VM exit or a failed VM entry.
+    pub(crate) fn end_entry(&self) {
+        self.set_run_state(VcpuRunState::OutsideGuest);
+    }
+
+    fn set_run_state(&self, state: VcpuRunState) {
+        self.state.run_state.store(state as u8, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vmx::events::{EventQueue, EventWindow, PendingEvent};
+
+    #[test]
+    fn test_kick_outside_guest() {
+        // Outside guest mode, the request is taken at the next entry.
+        let vcpu = VcpuKickHandle::new();
+        let kicker = vcpu.clone();
+        assert!(!kicker.kick());
+        assert!(!vcpu.begin_entry());
+        assert_eq!(kicker.run_state(), VcpuRunState::OutsideGuest);
+        assert!(!kicker.exit_requested());
+    }
+
+    #[test]
+    fn test_kick_in_guest() {
+        // In guest mode, an IPI is needed, and the request stays pending.
+        let vcpu = VcpuKickHandle::new();
+        let kicker = vcpu.clone();
+        assert!(vcpu.begin_entry());
+        assert!(kicker.kick());
+        vcpu.end_entry();
+        assert!(!vcpu.begin_entry());
+        assert!(vcpu.begin_entry());
+    }
+
+    #[test]
+    fn test_kick_keeps_pending_event() {
+        let vcpu = VcpuKickHandle::new();
+        let mut events = EventQueue::new();
+        events.push(PendingEvent::new(0x30, None));
+
+        // As in `inner_run`, events are taken only once the entry goes ahead.
+        assert!(!vcpu.kick());
+        assert!(!vcpu.begin_entry());
+        assert!(events.has_interrupt());
+
+        assert!(vcpu.begin_entry());
+        let window = EventWindow::open(true);
+        assert_eq!(events.pop(&window).map(|e| e.vector), Some(0x30));
+    }
+}
@@ -0,0 +1,254 @@
+//! Decoder for the instructions that access emulated MMIO registers.
+//!
+//! APIC-access VM exits report neither the instruction length nor its operands
+//! (SDM Vol. 3C, Section 27.2.5), so the instruction at the guest `RIP` is
+//! decoded here. Only the `MOV` forms used to access device registers are
+//! supported.
+
+use bit_field::BitField;
+
+use axerrno::{AxResult, ax_err};
+
+/// The architectural limit of the instruction length.
+pub const MAX_INSN_LEN: usize = 15;
+
+/// The register or immediate side of an MMIO access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmioOperand {
+    /// A general-purpose register, in the encoding of the ModR/M `reg` field
+    /// extended by `REX.R`. `high_byte` selects `AH`, `CH`, `DH` or `BH`.
+    Register { index: u8, high_byte: bool },
+    /// An immediate, already sign-extended to the access size.
+    Immediate(u64),
+}
+
+/// A decoded `MOV` between a register or immediate and memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MmioInstruction {
+    /// The instruction length in bytes.
+    pub len: usize,
+    /// Whether memory is written.
+    pub write: bool,
+    /// The size of the memory access in bytes.
+    pub size: usize,
+    /// The size of the destination register of a read, larger than `size` for `MOVZX`.
+    pub reg_size: usize,
+    /// The source of a write or the destination of a read.
+    pub operand: MmioOperand,
+}
+
+impl MmioInstruction {
+    /// Decode the instruction in `bytes`. `long_mode` is set for 64-bit code,
+    /// `default_size` is the default operand and address size (2 or 4) otherwise.
+    pub fn decode(bytes: &[u8], long_mode: bool, default_size: usize) -> AxResult<Self> {
+        let default_size = if long_mode { 4 } else { default_size };
+        let mut pos = 0;
+        let mut next = || -> AxResult<u8> {
+            let byte = bytes.get(pos).copied();
+            pos += 1;
+            match byte {
+                Some(byte) if pos <= MAX_INSN_LEN => Ok(byte),
+                _ => ax_err!(InvalidInput, "truncated MMIO instruction"),
+            }
+        };
+
+        let mut osz = default_size;
+        let mut asz = if long_mode { 8 } else { default_size };
+        let mut rex = 0u8;
+        let mut opcode;
+        loop {
+            opcode = next()?;
+            match opcode {
+                0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 | 0xf0 | 0xf2 | 0xf3 => {}
+                0x66 => osz = 6 - default_size,
+                0x67 => asz = if long_mode { 4 } else { 6 - default_size },
+                _ => break,
+            }
+        }
+        if long_mode && opcode & 0xf0 == 0x40 {
+            // REX must be the last prefix.
+            rex = opcode;
+            opcode = next()?;
+        }
+        if rex.get_bit(3) {
+            osz = 8;
+        }
+
+        let (write, size, reg_size, imm) = match opcode {
+            0x88 => (true, 1, 1, false),
+            0x89 => (true, osz, osz, false),
+            0x8a => (false, 1, 1, false),
+            0x8b => (false, osz, osz, false),
+            0xc6 => (true, 1, 1, true),
+            0xc7 => (true, osz, osz, true),
+            0x0f => match next()? {
+                0xb6 => (false, 1, osz, false),
+                0xb7 => (false, 2, osz, false),
+                op => {
+                    return ax_err!(
+                        Unsupported,
+                        format_args!("unsupported MMIO instruction 0f {:02x}", op)
+                    );
+                }
+            },
+            op => {
+                return ax_err!(
+                    Unsupported,
+                    format_args!("unsupported MMIO instruction {:02x}", op)
+                );
+            }
+        };
+
+        // ModR/M, SIB and displacement. (SDM Vol. 2A, Section 2.1.5)
+        let modrm = next()?;
+        let (md, reg, rm) = (modrm >> 6, (modrm >> 3) & 7, modrm & 7);
+        if md == 3 {
+            return ax_err!(InvalidInput, "MMIO instruction without memory operand");
+        }
+        if imm && reg != 0 {
+            return ax_err!(InvalidInput, "invalid MOV immediate encoding");
+        }
+        let disp = if asz == 2 {
+            match (md, rm) {
+                (0, 6) | (2, _) => 2,
+                (1, _) => 1,
+                _ => 0,
+            }
+        } else {
+            let base = if rm == 4 { next()? & 7 } else { rm };
+            match (md, base) {
+                (0, 5) | (2, _) => 4,
+                (1, _) => 1,
+                _ => 0,
+            }
+        };
+        for _ in 0..disp {
+            next()?;
+        }
+
+        let operand = if imm {
+            // At most 32 bits, sign-extended to a 64-bit access.
+            let imm_size = size.min(4);
+            let mut value = 0u64;
+            for i in 0..imm_size {
+                value |= (next()? as u64) << (i * 8);
+            }
+            if size == 8 {
+                value = value as i32 as i64 as u64;
+            }
+            MmioOperand::Immediate(value)
+        } else {
+            let index = reg | (rex.get_bit(2) as u8) << 3;
+            MmioOperand::Register {
+                index: if size == 1 && rex == 0 {
+                    index & 3
+                } else {
+                    index
+                },
+                high_byte: size == 1 && rex == 0 && index >= 4,
+            }
+        };
+        Ok(Self {
+            len: pos,
+            write,
+            size,
+            reg_size,
+            operand,
+        })
+    }
+
+    /// The value written to memory, given the value of the source register.
+    pub fn write_value(&self, reg: u64) -> u64 {
+        match self.operand {
+            MmioOperand::Register {
+                high_byte: true, ..
+            } => reg.get_bits(8..16),
+            MmioOperand::Register { .. } => truncate(reg, self.size),
+            MmioOperand::Immediate(value) => truncate(value, self.size),
+        }
+    }
+
+    /// The new value of the destination register after reading `value` from
+    /// memory into it, whose old value is `old`. 32-bit destinations are
+    /// zero-extended, smaller ones keep the other bits.
+    pub fn read_result(&self, old: u64, value: u64) -> u64 {
+        let value = truncate(value, self.size);
+        let mut result = old;
+        match (self.operand, self.reg_size) {
+            (
+                MmioOperand::Register {
+                    high_byte: true, ..
+                },
+                _,
+            ) => result.set_bits(8..16, value),
+            (_, 4) | (_, 8) => return value,
+            (_, reg_size) => result.set_bits(0..reg_size * 8, value),
+        };
+        result
+    }
+}
+
+fn truncate(value: u64, size: usize) -> u64 {
+    if size >= 8 {
+        value
+    } else {
+        value.get_bits(0..size * 8)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_mov_immediate() {
+        // mov dword ptr [rip + 0x1234], 0x0b0 (EOI-style constant write)
+        let insn = MmioInstruction::decode(
+            &[0xc7, 0x05, 0x34, 0x12, 0x00, 0x00, 0xb0, 0x00, 0x00, 0x00],
+            true,
+            4,
+        )
+        .unwrap();
+        assert_eq!((insn.len, insn.write, insn.size), (10, true, 4));
+        assert_eq!(insn.write_value(0), 0xb0);
+    }
+
+    #[test]
+    fn test_decode_mov_load() {
+        // mov r9d, [rax + rcx * 4 + 0x20]
+        let insn = MmioInstruction::decode(&[0x44, 0x8b, 0x4c, 0x88, 0x20], true, 4).unwrap();
+        assert_eq!(
+            (insn.len, insn.write, insn.operand),
+            (
+                5,
+                false,
+                MmioOperand::Register {
+                    index: 9,
+                    high_byte: false
+                }
+            )
+        );
+        assert_eq!(insn.read_result(u64::MAX, 0x1234_5678), 0x1234_5678);
+    }
+
+    #[test]
+    fn test_decode_movzx_16bit() {
+        // movzx ax, byte ptr [bx + si] in 16-bit code
+        let insn = MmioInstruction::decode(&[0x0f, 0xb6, 0x00], false, 2).unwrap();
+        assert_eq!((insn.len, insn.size, insn.reg_size), (3, 1, 2));
+        assert_eq!(insn.read_result(0xdead_beef, 0x1ff), 0xdead_00ff);
+    }
+
+    #[test]
+    fn test_decode_high_byte_store() {
+        // mov [ebx], ch
+        let insn = MmioInstruction::decode(&[0x88, 0x2b], false, 4).unwrap();
+        assert_eq!(insn.write_value(0x1200), 0x12);
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert!(MmioInstruction::decode(&[0x8b, 0xc0], true, 4).is_err());
+        assert!(MmioInstruction::decode(&[0xc7, 0x05, 0x34], true, 4).is_err());
+    }
+}
@@ -1,17 +1,65 @@
+mod apic_base;
+mod apic_page;
+mod boot;
 mod definitions;
+mod entry_check;
+mod error;
+mod events;
+mod guest_mem;
+mod hypercall;
+mod hyperv;
+mod idle;
 mod instructions;
+mod kick;
+mod mmio;
 mod percpu;
+mod ple;
+mod posted;
+mod preemption;
+mod pvclock;
+mod pvops;
+mod qualification;
+mod realmode;
 mod structs;
+mod tsc;
 mod vcpu;
 mod vmcs;
 
 use self::structs::VmxBasic;
-use axerrno::ax_err_type;
 
+pub use self::apic_page::EoiHandler;
+pub use self::boot::{
+    FlatBootState, LINUX_BOOT_CS, LINUX_BOOT_DS, LINUX_BOOT_GDT_SIZE, LinuxBootConfig,
+    LinuxBootEntry, VmxBootMode, VmxSetupConfig,
+};
 pub use self::definitions::VmxExitReason;
+pub use self::entry_check::{
+    EntryCheckArea, EntryCheckError, VmcsFields, VmcsImage, VmxCapabilities, check_vm_entry,
+};
+pub use self::error::{VcpuError, VcpuResult};
+pub use self::hypercall::{
+    Hypercall, HypercallAbi, HypercallAction, HypercallDispatcher, HypercallHandler,
+};
+pub use self::hyperv::HyperVFeatures;
+pub use self::idle::{MwaitMode, WaitPkgConfig};
+pub use self::kick::{VcpuKickHandle, VcpuRunState};
 pub use self::percpu::VmxPerCpuState as VmxArchPerCpuState;
+pub use self::ple::PleConfig;
+pub use self::posted::PostedInterruptHandle;
+pub use self::preemption::{VmxDeadline, VmxRunResult};
+pub use self::pvclock::PvClock;
+pub use self::pvops::{PvOpsFeatures, PvOpsHandle, PvOpsHooks};
+pub use self::qualification::{
+    DebugExceptionQualification, DescriptorTableInstruction, DrAccessInfo,
+    EptViolationQualification, ExitQualification, InstructionOperand, MemoryOperand,
+    SegmentRegister, SegmentTableInstruction, TaskSwitchInfo, TaskSwitchSource, VmxInstructionInfo,
+};
+pub use self::tsc::TscModel;
 pub use self::vcpu::VmxVcpu as VmxArchVCpu;
-pub use self::vmcs::{VmxExitInfo, VmxInterruptInfo, VmxIoExitInfo};
+pub use self::vmcs::{
+    ApicAccessExitInfo, ApicAccessExitType, CrAccessInfo, VmxExitInfo, VmxInterruptInfo,
+    VmxIoExitInfo,
+};
 
 /// Return if current platform support virtualization extension.
 pub fn has_hardware_support() -> bool {
@@ -27,9 +75,5 @@ pub fn read_vmcs_revision_id() -> u32 {
 }
 
 fn as_axerr(err: x86::vmx::VmFail) -> axerrno::AxError {
-    use x86::vmx::VmFail;
-    match err {
-        VmFail::VmFailValid => ax_err_type!(BadState, vmcs::instruction_error().as_str()),
-        VmFail::VmFailInvalid => ax_err_type!(BadState, "VMCS pointer is not valid"),
-    }
+    VcpuError::from(err).into()
 }
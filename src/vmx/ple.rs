@@ -0,0 +1,141 @@
+//! PAUSE-loop exiting, to detect vCPUs spinning on a lock held by a
+//! descheduled vCPU. (SDM Vol. 3C, Section 25.1.3)
+//!
+//! At CPL 0, two `PAUSE`s at most `PLE_Gap` TSC cycles apart belong to the
+//! same spin loop, and a loop running longer than `PLE_Window` TSC cycles
+//! causes a VM exit. The window adapts as in KVM: it grows on every exit, so
+//! that a vCPU legitimately spinning for long stops exiting, and shrinks back
+//! towards its base value whenever the vCPU gets a new time slice.
+
+/// PAUSE-loop exiting parameters, in TSC cycles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PleConfig {
+    /// The longest time between two `PAUSE`s of the same loop.
+    pub gap: u32,
+    /// The initial and smallest window.
+    pub window: u32,
+    /// The largest window.
+    pub window_max: u32,
+    /// On a PAUSE-loop exit, the window is multiplied by `grow` if `grow` is
+    /// less than `window`, increased by `grow` otherwise. 0 or 1 keep it fixed.
+    pub grow: u32,
+    /// On a new time slice, the window is divided by `shrink` if `shrink` is
+    /// less than `window`, decreased by `shrink` otherwise. 0 resets it to
+    /// `window`.
+    pub shrink: u32,
+}
+
+impl Default for PleConfig {
+    /// The KVM defaults.
+    fn default() -> Self {
+        Self {
+            gap: 128,
+            window: 4096,
+            window_max: u32::MAX,
+            grow: 2,
+            shrink: 0,
+        }
+    }
+}
+
+/// The adaptive PAUSE-loop window of a vCPU.
+#[derive(Debug, Clone)]
+pub struct PauseLoopExiting {
+    config: PleConfig,
+    window: u32,
+}
+
+impl PauseLoopExiting {
+    /// Start with the base window of `config`.
+    pub fn new(config: PleConfig) -> Self {
+        let window_max = config.window_max.max(config.window);
+        Self {
+            config: PleConfig {
+                window_max,
+                ..config
+            },
+            window: config.window,
+        }
+    }
+
+    /// The value of the `PLE_Gap` VMCS field.
+    pub fn gap(&self) -> u32 {
+        self.config.gap
+    }
+
+    /// The value of the `PLE_Window` VMCS field.
+    pub fn window(&self) -> u32 {
+        self.window
+    }
+
+    /// After a PAUSE-loop exit. Returns the new window if it changed.
+    pub fn grow(&mut self) -> Option<u32> {
+        let PleConfig {
+            window: base,
+            window_max,
+            grow,
+            ..
+        } = self.config;
+        let window = match grow {
+            0 | 1 => self.window,
+            grow if grow < base => self.window.saturating_mul(grow),
+            grow => self.window.saturating_add(grow),
+        };
+        self.update(window.min(window_max))
+    }
+
+    /// When the vCPU gets a new time slice. Returns the new window if it
+    /// changed.
+    pub fn shrink(&mut self) -> Option<u32> {
+        let PleConfig {
+            window: base,
+            shrink,
+            ..
+        } = self.config;
+        let window = match shrink {
+            0 => base,
+            shrink if shrink < base => self.window / shrink,
+            shrink => self.window.saturating_sub(shrink),
+        };
+        self.update(window.max(base))
+    }
+
+    fn update(&mut self, window: u32) -> Option<u32> {
+        let changed = window != self.window;
+        self.window = window;
+        changed.then_some(window)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_window_doubles_up_to_max() {
+        let mut ple = PauseLoopExiting::new(PleConfig {
+            window_max: 10_000,
+            ..Default::default()
+        });
+        assert_eq!(ple.grow(), Some(8192));
+        assert_eq!(ple.grow(), Some(10_000));
+        assert_eq!(ple.grow(), None);
+        assert_eq!(ple.shrink(), Some(4096));
+    }
+
+    #[test]
+    fn test_window_additive_grow_gradual_shrink() {
+        let mut ple = PauseLoopExiting::new(PleConfig {
+            grow: 5000,
+            shrink: 2,
+            ..Default::default()
+        });
+        ple.grow();
+        ple.grow();
+        assert_eq!(ple.window(), 14_096);
+        ple.shrink();
+        assert_eq!(ple.window(), 7048);
+        assert_eq!(ple.shrink(), Some(4096));
+        assert_eq!(ple.shrink(), None);
+    }
+}
@@ -0,0 +1,168 @@
+//! Posted-interrupt descriptor. (SDM Vol. 3C, Section 29.6)
+//!
+//! Other CPUs request vLAPIC interrupts by setting bits in the posted-interrupt
+//! requests (PIR) and the outstanding-notification (ON) bit, then send the
+//! notification vector to the CPU running the vCPU. The processor moves the
+//! requests to the virtual-APIC page without a VM exit, requests posted while
+//! the vCPU is not in VMX non-root operation are moved before the next VM entry.
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use axaddrspace::{AxMmHal, HostPhysAddr, HostVirtAddr};
+use bit_field::BitField;
+
+/// Outstanding notification, a notification has been sent for the requests.
+const PI_ON: usize = 0;
+/// Suppress notification for non-urgent requests.
+const PI_SN: usize = 1;
+/// Bits of the notification vector.
+const PI_NV: core::ops::Range<usize> = 16..24;
+
+/// The 64-byte posted-interrupt descriptor.
+#[derive(Debug)]
+#[repr(C, align(64))]
+pub struct PostedInterruptDescriptor {
+    pir: [AtomicU64; 4],
+    control: AtomicU64,
+    _reserved: [u64; 3],
+}
+
+impl PostedInterruptDescriptor {
+    /// An empty descriptor.
+    pub const fn new() -> Self {
+        Self {
+            pir: [const { AtomicU64::new(0) }; 4],
+            control: AtomicU64::new(0),
+            _reserved: [0; 3],
+        }
+    }
+
+    /// Record the notification vector, used by remapping hardware that posts
+    /// interrupts to the descriptor.
+    pub fn set_notification_vector(&self, vector: u8) {
+        let mut control = self.control.load(Ordering::Acquire);
+        loop {
+            let mut new = control;
+            new.set_bits(PI_NV, vector as u64);
+            match self.control.compare_exchange_weak(
+                control,
+                new,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return,
+                Err(current) => control = current,
+            }
+        }
+    }
+
+    /// Request `vector`. Returns whether the caller must send the
+    /// notification, i.e. no notification is outstanding or suppressed yet.
+    pub fn post(&self, vector: u8) -> bool {
+        let bit = 1 << (vector % 64);
+        if self.pir[vector as usize / 64].fetch_or(bit, Ordering::AcqRel) & bit != 0 {
+            // Already requested, the pending notification covers it.
+            return false;
+        }
+        let control = self.control.fetch_or(1 << PI_ON, Ordering::AcqRel);
+        !control.get_bit(PI_ON) && !control.get_bit(PI_SN)
+    }
+
+    /// Take the outstanding requests, one bit per vector, if a notification
+    /// is outstanding.
+    pub fn take_requests(&self) -> Option<[u64; 4]> {
+        if !self
+            .control
+            .fetch_and(!(1 << PI_ON), Ordering::AcqRel)
+            .get_bit(PI_ON)
+        {
+            return None;
+        }
+        Some(core::array::from_fn(|i| {
+            self.pir[i].swap(0, Ordering::AcqRel)
+        }))
+    }
+}
+
+impl Default for PostedInterruptDescriptor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A thread-safe handle to the posted-interrupt descriptor of a vCPU, obtained
+/// with [`VmxVcpu::posted_interrupt_handle`](super::vcpu::VmxVcpu::posted_interrupt_handle).
+#[derive(Debug, Clone)]
+pub struct PostedInterruptHandle {
+    desc: Arc<PostedInterruptDescriptor>,
+}
+
+impl PostedInterruptHandle {
+    pub(crate) fn new() -> Self {
+        Self {
+            desc: Arc::new(PostedInterruptDescriptor::new()),
+        }
+    }
+
+    /// Request the vLAPIC interrupt `vector`, from any CPU and while the vCPU
+    /// may be running.
+    ///
+    /// Returns whether the caller must send the notification vector given in
+    /// [`VmxSetupConfig::posted_interrupt_vector`](super::VmxSetupConfig::posted_interrupt_vector)
+    /// to the CPU running the vCPU, so that the interrupt is delivered without
+    /// waiting for the next VM exit. A vCPU that is not running picks the
+    /// request up at the next VM entry.
+    pub fn post(&self, vector: u8) -> bool {
+        self.desc.post(vector)
+    }
+
+    pub(crate) fn descriptor(&self) -> &PostedInterruptDescriptor {
+        &self.desc
+    }
+
+    /// The host physical address of the descriptor, for the VMCS.
+    pub(crate) fn phys_addr<H: AxMmHal>(&self) -> HostPhysAddr {
+        // The descriptor is 64-byte aligned and never crosses a page, the
+        // host heap is linearly mapped.
+        H::virt_to_phys(HostVirtAddr::from(Arc::as_ptr(&self.desc) as usize))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_post_and_take() {
+        let desc = PostedInterruptDescriptor::new();
+        assert_eq!(desc.take_requests(), None);
+
+        // Only the first request needs a notification.
+        assert!(desc.post(0x31));
+        assert!(!desc.post(0x31));
+        assert!(!desc.post(0xc0));
+        assert_eq!(desc.take_requests(), Some([1 << 0x31, 0, 0, 1]));
+        assert_eq!(desc.take_requests(), None);
+        assert!(desc.post(0x20));
+    }
+
+    #[test]
+    fn test_notification_vector() {
+        let desc = PostedInterruptDescriptor::new();
+        desc.set_notification_vector(0xf2);
+        assert!(desc.post(0x20));
+        assert_eq!(desc.control.load(Ordering::Relaxed).get_bits(PI_NV), 0xf2);
+    }
+
+    #[test]
+    fn test_handle_posts_to_shared_descriptor() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<PostedInterruptHandle>();
+
+        let vcpu = PostedInterruptHandle::new();
+        let sender = vcpu.clone();
+        assert!(sender.post(0x41));
+        assert_eq!(vcpu.descriptor().take_requests(), Some([0, 1 << 1, 0, 0]));
+    }
+}
@@ -0,0 +1,95 @@
+//! Time slices enforced by the VMX-preemption timer. (SDM Vol. 3C, Section 25.5.1)
+//!
+//! The timer counts down by 1 every time bit X of the TSC changes, X being
+//! reported in `IA32_VMX_MISC[4:0]`. It is loaded on VM entry and, with the
+//! "save VMX-preemption timer value" VM-exit control, saved on VM exit, so a
+//! budget carries over the VM exits the vCPU handles itself. The timer also
+//! makes a running vCPU exit when its next Hyper-V synthetic timer fires.
+
+use axvcpu::AxVCpuExitReason;
+use bit_field::BitField;
+use raw_cpuid::CpuId;
+
+use crate::msr::Msr;
+
+/// CPU time a vCPU may run for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmxDeadline {
+    /// A budget in nanoseconds, converted with the TSC frequency.
+    Nanos(u64),
+    /// A budget in TSC cycles.
+    Tsc(u64),
+}
+
+impl VmxDeadline {
+    /// The budget in TSC cycles, for a TSC running at `tsc_hz`.
+    pub fn tsc_cycles(self, tsc_hz: u64) -> u64 {
+        match self {
+            Self::Nanos(nanos) => {
+                (nanos as u128 * tsc_hz as u128 / 1_000_000_000).min(u64::MAX as u128) as u64
+            }
+            Self::Tsc(cycles) => cycles,
+        }
+    }
+}
+
+/// The outcome of [`VmxVcpu::run_with_deadline`](super::vcpu::VmxVcpu::run_with_deadline).
+#[derive(Debug)]
+pub enum VmxRunResult {
+    /// A VM exit the caller has to handle.
+    Exit(AxVCpuExitReason),
+    /// The budget has been used up.
+    Preempted,
+    /// An exit was requested through the kick handle of the vCPU.
+    Kicked,
+    /// The guest spins in a PAUSE loop, probably waiting for a lock held by a
+    /// descheduled vCPU: running another vCPU of the VM may help it.
+    YieldHint,
+}
+
+/// The timer rate: the timer counts down once every `1 << shift` TSC cycles.
+pub fn timer_rate_shift() -> u32 {
+    Msr::IA32_VMX_MISC.read().get_bits(0..5) as u32
+}
+
+/// The initial timer value for `tsc_cycles`, saturated to the 32-bit field.
+pub fn timer_ticks(tsc_cycles: u64, rate_shift: u32) -> u32 {
+    (tsc_cycles >> rate_shift).min(u32::MAX as u64) as u32
+}
+
+/// The TSC frequency in Hz, from CPUID leaf 0x15, or the base frequency in
+/// leaf 0x16.
+pub fn tsc_frequency_hz() -> Option<u64> {
+    let cpuid = CpuId::new();
+    cpuid
+        .get_tsc_info()
+        .and_then(|info| info.tsc_frequency())
+        .or_else(|| {
+            cpuid
+                .get_processor_frequency_info()
+                .map(|info| info.processor_base_frequency() as u64 * 1_000_000)
+        })
+        .filter(|&hz| hz != 0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_deadline_to_cycles() {
+        // 2 ms at 3 GHz.
+        assert_eq!(
+            VmxDeadline::Nanos(2_000_000).tsc_cycles(3_000_000_000),
+            6_000_000
+        );
+        assert_eq!(VmxDeadline::Tsc(1000).tsc_cycles(0), 1000);
+    }
+
+    #[test]
+    fn test_cycles_to_ticks() {
+        // Counting every 32 cycles, saturating at the 32-bit timer.
+        assert_eq!(timer_ticks(6_000_000, 5), 187_500);
+        assert_eq!(timer_ticks(u64::MAX, 0), u32::MAX);
+    }
+}
@@ -0,0 +1,214 @@
+//! Paravirtual clock compatible with the KVM clock source (kvmclock).
+//!
+//! The guest registers a `pvclock_vcpu_time_info` structure per vCPU through
+//! `MSR_KVM_SYSTEM_TIME_NEW`, and reads the wall-clock time at boot through
+//! `MSR_KVM_WALL_CLOCK_NEW`. It computes the time since boot from the TSC with
+//! the scale in the structure. Both structures are protected by a version
+//! that is odd while they are being updated.
+//! (Linux `Documentation/virt/kvm/x86/msr.rst`)
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::AxResult;
+
+use super::guest_mem::GuestMemory;
+
+/// Signature in CPUID leaf 0x4000_0000: "KVMKVMKVM\0\0\0".
+pub const KVM_SIGNATURE: [u32; 3] = [0x4b4d_564b, 0x564b_4d56, 0x0000_004d];
+/// CPUID 0x4000_0001.EAX: the `*_NEW` clock MSRs are available.
+pub const KVM_FEATURE_CLOCKSOURCE2: u32 = 1 << 3;
+/// CPUID 0x4000_0001.EAX: the `PVCLOCK_TSC_STABLE_BIT` flag may be set.
+pub const KVM_FEATURE_CLOCKSOURCE_STABLE_BIT: u32 = 1 << 24;
+
+/// Guest-physical address of the wall-clock structure, written by the guest.
+pub const MSR_KVM_WALL_CLOCK_NEW: u32 = 0x4b56_4d00;
+/// Guest-physical address of the time structure of the vCPU, bit 0 enables it.
+pub const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b56_4d01;
+
+/// The TSC is synchronized across vCPUs and never goes backwards.
+const PVCLOCK_TSC_STABLE_BIT: u8 = 1 << 0;
+
+/// The kvmclock state of a vCPU.
+#[derive(Debug, Clone)]
+pub struct PvClock {
+    tsc_hz: u64,
+    tsc_to_system_mul: u32,
+    tsc_shift: i8,
+    wall_clock_ns: u64,
+    wall_clock: u64,
+    system_time: u64,
+}
+
+impl PvClock {
+    /// A clock for a guest TSC running at `tsc_hz`, the guest having booted at
+    /// `wall_clock_ns` nanoseconds since the Unix epoch.
+    pub fn new(tsc_hz: u64, wall_clock_ns: u64) -> Self {
+        let (tsc_to_system_mul, tsc_shift) = time_scale(tsc_hz);
+        Self {
+            tsc_hz,
+            tsc_to_system_mul,
+            tsc_shift,
+            wall_clock_ns,
+            wall_clock: 0,
+            system_time: 0,
+        }
+    }
+
+    /// The value of `MSR_KVM_WALL_CLOCK_NEW`.
+    pub fn wall_clock_msr(&self) -> u64 {
+        self.wall_clock
+    }
+
+    /// The value of `MSR_KVM_SYSTEM_TIME_NEW`.
+    pub fn system_time_msr(&self) -> u64 {
+        self.system_time
+    }
+
+    /// A guest write of `value` to `MSR_KVM_SYSTEM_TIME_NEW`. Returns whether
+    /// the time structure is enabled and must be updated.
+    pub fn set_system_time_msr(&mut self, value: u64) -> bool {
+        self.system_time = value;
+        value & 1 != 0
+    }
+
+    /// A guest write of `gpa` to `MSR_KVM_WALL_CLOCK_NEW`: write the
+    /// wall-clock time at boot to the structure there.
+    pub fn write_wall_clock<M: GuestMemory + ?Sized>(
+        &mut self,
+        mem: &mut M,
+        gpa: GuestPhysAddr,
+    ) -> AxResult {
+        self.wall_clock = gpa.as_usize() as u64;
+        let version = mem.read_uint(gpa, 4)? as u32;
+        mem.write_uint(gpa, 4, (version | 1) as u64)?;
+        mem.write_uint(gpa + 4, 4, self.wall_clock_ns / 1_000_000_000)?;
+        mem.write_uint(gpa + 8, 4, self.wall_clock_ns % 1_000_000_000)?;
+        mem.write_uint(gpa, 4, (version | 1).wrapping_add(1) as u64)
+    }
+
+    /// Update the time structure of the vCPU, if enabled, when the guest TSC
+    /// reads `guest_tsc`, `elapsed` TSC cycles after boot.
+    pub fn update<M: GuestMemory + ?Sized>(
+        &self,
+        mem: &mut M,
+        guest_tsc: u64,
+        elapsed: u64,
+    ) -> AxResult {
+        if self.system_time & 1 == 0 {
+            return Ok(());
+        }
+        let gpa = GuestPhysAddr::from((self.system_time & !1) as usize);
+        let version = mem.read_uint(gpa, 4)? as u32;
+        // struct pvclock_vcpu_time_info
+        mem.write_uint(gpa, 4, (version | 1) as u64)?;
+        mem.write_uint(gpa + 8, 8, guest_tsc)?;
+        mem.write_uint(gpa + 16, 8, self.nanos(elapsed))?;
+        mem.write_uint(gpa + 24, 4, self.tsc_to_system_mul as u64)?;
+        mem.write_uint(gpa + 28, 1, self.tsc_shift as u8 as u64)?;
+        mem.write_uint(gpa + 29, 1, PVCLOCK_TSC_STABLE_BIT as u64)?;
+        mem.write_uint(gpa, 4, (version | 1).wrapping_add(1) as u64)
+    }
+
+    /// `tsc` TSC cycles in nanoseconds.
+    fn nanos(&self, tsc: u64) -> u64 {
+        (tsc as u128 * 1_000_000_000 / self.tsc_hz as u128) as u64
+    }
+}
+
+/// The multiplier and shift that convert TSC cycles at `tsc_hz` to
+/// nanoseconds as `((delta << shift) * mul) >> 32`, a negative shift meaning
+/// a right shift. (Linux `kvm_get_time_scale`)
+fn time_scale(tsc_hz: u64) -> (u32, i8) {
+    let mut scaled: u64 = 1_000_000_000;
+    let mut tps = tsc_hz;
+    let mut shift = 0i8;
+    while tps > scaled * 2 || tps >> 32 != 0 {
+        tps >>= 1;
+        shift -= 1;
+    }
+    let mut tps = tps as u32;
+    while tps as u64 <= scaled || scaled >> 32 != 0 {
+        if scaled >> 32 != 0 || tps & 0x8000_0000 != 0 {
+            scaled >>= 1;
+        } else {
+            tps <<= 1;
+        }
+        shift += 1;
+    }
+    (((scaled << 32) / tps as u64) as u32, shift)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vmx::guest_mem::FlatMemory;
+
+    /// The guest side of the conversion. (Linux `pvclock_scale_delta`)
+    fn scale_delta(delta: u64, mul: u32, shift: i8) -> u64 {
+        let delta = if shift < 0 {
+            delta >> -shift
+        } else {
+            delta << shift
+        };
+        ((delta as u128 * mul as u128) >> 32) as u64
+    }
+
+    fn read(mem: &FlatMemory, gpa: usize, size: usize) -> u64 {
+        mem.read_uint(GuestPhysAddr::from(gpa), size).unwrap()
+    }
+
+    #[test]
+    fn test_wall_clock() {
+        let mut mem = FlatMemory(alloc::vec![0; 0x2000]);
+        let mut clock = PvClock::new(2_500_000_000, 1_700_000_000_123_456_789);
+        clock
+            .write_wall_clock(&mut mem, GuestPhysAddr::from(0x100))
+            .unwrap();
+        assert_eq!(
+            (
+                read(&mem, 0x100, 4),
+                read(&mem, 0x104, 4),
+                read(&mem, 0x108, 4)
+            ),
+            (2, 1_700_000_000, 123_456_789)
+        );
+    }
+
+    #[test]
+    fn test_system_time_disabled() {
+        // Disabled until bit 0 is set.
+        let mut mem = FlatMemory(alloc::vec![0; 0x2000]);
+        let mut clock = PvClock::new(2_500_000_000, 1_700_000_000_123_456_789);
+        assert!(!clock.set_system_time_msr(0x1000));
+        clock.update(&mut mem, 1000, 1000).unwrap();
+        assert_eq!(read(&mem, 0x1000, 4), 0);
+    }
+
+    #[test]
+    fn test_system_time_update() {
+        let mut mem = FlatMemory(alloc::vec![0; 0x2000]);
+        let mut clock = PvClock::new(2_500_000_000, 1_700_000_000_123_456_789);
+        assert!(clock.set_system_time_msr(0x1001));
+        clock
+            .update(&mut mem, 5_000_000_000, 2_500_000_000)
+            .unwrap();
+        assert_eq!(read(&mem, 0x1000, 4), 2);
+        assert_eq!(read(&mem, 0x1008, 8), 5_000_000_000);
+        assert_eq!(read(&mem, 0x1010, 8), 1_000_000_000);
+        assert_eq!(read(&mem, 0x101d, 1), PVCLOCK_TSC_STABLE_BIT as u64);
+    }
+
+    #[test]
+    fn test_scale_to_nanos() {
+        let mut mem = FlatMemory(alloc::vec![0; 0x2000]);
+        let mut clock = PvClock::new(2_500_000_000, 1_700_000_000_123_456_789);
+        assert!(clock.set_system_time_msr(0x1001));
+        clock
+            .update(&mut mem, 5_000_000_000, 2_500_000_000)
+            .unwrap();
+
+        // One second of TSC cycles is one second, to the nanosecond.
+        let (mul, shift) = (read(&mem, 0x1018, 4) as u32, read(&mem, 0x101c, 1) as i8);
+        let nanos = scale_delta(2_500_000_000, mul, shift);
+        assert!(nanos.abs_diff(1_000_000_000) <= 1);
+    }
+}
@@ -0,0 +1,276 @@
+//! Paravirtual TLB flush and spinlock kick for SMP guests.
+//!
+//! Before sending TLB-shootdown IPIs, the guest asks with a hypercall for the
+//! TLBs of other vCPUs to be flushed. Those outside guest mode, e.g. preempted
+//! ones, flush at their next VM entry, so the guest does not wait for them to
+//! run. The hypercall returns those still in guest mode, which the guest
+//! flushes with IPIs as usual, as KVM guests do for vCPUs that are not
+//! preempted. The hypercall is specific to this crate, and advertised in its
+//! own CPUID leaves rather than with the KVM ones. A vCPU that releases a lock
+//! kicks a waiter halted in `HLT` with `KVM_HC_KICK_CPU`.
+//! (Linux `Documentation/virt/kvm/x86/hypercalls.rst`)
+//!
+//! The requests reach the target vCPUs through the [`PvOpsHooks`] of the VMM,
+//! which knows how APIC IDs map to vCPUs, and their [`PvOpsHandle`]s.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use bit_field::BitField;
+use bitflags::bitflags;
+
+use axerrno::AxResult;
+
+use super::hypercall::{HypercallAction, HypercallDispatcher};
+use super::kick::{VcpuKickHandle, VcpuRunState};
+
+/// CPUID 0x4000_0001.EAX: `KVM_HC_KICK_CPU` wakes halted vCPUs.
+pub const KVM_FEATURE_PV_UNHALT: u32 = 1 << 7;
+
+/// The CPUID leaves of this crate: EAX holds the highest one, EBX, ECX and EDX
+/// the `"RVMRVMRVMRVM"` signature. Out of the ranges of KVM and Hyper-V.
+pub const PV_OPS_CPUID_LEAF: u32 = 0x4000_0f00;
+/// The signature in [`PV_OPS_CPUID_LEAF`].
+pub const PV_OPS_SIGNATURE: [u32; 3] = [0x524d_5652, 0x5652_4d56, 0x4d56_524d];
+/// CPUID 0x4000_0f01.EAX: remote TLB flushes with [`PV_HC_FLUSH_TLB`].
+pub const PV_OPS_FEATURE_TLB_FLUSH: u32 = 1 << 0;
+
+/// Wake the vCPU with APIC ID `args[1]`. `args[0]` holds flags, none defined.
+pub const KVM_HC_KICK_CPU: u64 = 5;
+/// Flush the TLBs of the vCPUs in the APIC ID bitmap `args[0]`, bit 0
+/// standing for APIC ID `args[1]`. Returns the bitmap of those still in guest
+/// mode, whose TLBs the guest must flush itself. Not a KVM hypercall, the
+/// number is out of the KVM range.
+pub const PV_HC_FLUSH_TLB: u64 = 0x5256_0001;
+
+bitflags! {
+    /// The paravirtual operations offered to the guest.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct PvOpsFeatures: u32 {
+        /// Remote TLB flushes with [`PV_HC_FLUSH_TLB`].
+        const TLB_FLUSH = 1 << 0;
+        /// Waking halted vCPUs with [`KVM_HC_KICK_CPU`]. It makes `HLT` cause
+        /// VM exits, reported as [`AxVCpuExitReason::Halt`](axvcpu::AxVCpuExitReason::Halt).
+        const SPINLOCK_KICK = 1 << 1;
+    }
+}
+
+impl PvOpsFeatures {
+    /// The feature bits in CPUID 0x4000_0001.EAX.
+    pub fn kvm_features(self) -> u32 {
+        if self.contains(Self::SPINLOCK_KICK) {
+            KVM_FEATURE_PV_UNHALT
+        } else {
+            0
+        }
+    }
+
+    /// The leaves of [`PV_OPS_CPUID_LEAF`], if any feature is offered there.
+    pub fn cpuid(self, function: u32) -> Option<[u32; 4]> {
+        if !self.contains(Self::TLB_FLUSH) {
+            return None;
+        }
+        match function {
+            PV_OPS_CPUID_LEAF => {
+                let [ebx, ecx, edx] = PV_OPS_SIGNATURE;
+                Some([PV_OPS_CPUID_LEAF + 1, ebx, ecx, edx])
+            }
+            0x4000_0f01 => Some([PV_OPS_FEATURE_TLB_FLUSH, 0, 0, 0]),
+            _ => None,
+        }
+    }
+}
+
+/// How the VMM delivers paravirtual requests to the other vCPUs of the VM.
+pub trait PvOpsHooks: Send + Sync {
+    /// Flush the TLB of the vCPU with APIC ID `apic_id` before it next runs
+    /// the guest, usually with [`PvOpsHandle::request_tlb_flush`]. Returns
+    /// whether it is in guest mode, so that the guest must flush its TLB with
+    /// an IPI, `false` if there is no such vCPU.
+    fn flush_tlb(&self, apic_id: u32) -> bool;
+
+    /// Wake the vCPU with APIC ID `apic_id`, usually with
+    /// [`PvOpsHandle::unhalt`], and make it runnable if the VMM blocked it on
+    /// [`AxVCpuExitReason::Halt`](axvcpu::AxVCpuExitReason::Halt). Returns
+    /// whether there is such a vCPU.
+    fn kick_cpu(&self, apic_id: u32) -> bool;
+}
+
+#[derive(Debug)]
+struct PvOpsState {
+    tlb_flush: AtomicBool,
+    unhalt: AtomicBool,
+}
+
+/// A thread-safe handle to the paravirtual state of a vCPU, obtained with
+/// [`VmxVcpu::pv_ops_handle`](super::vcpu::VmxVcpu::pv_ops_handle).
+#[derive(Debug, Clone)]
+pub struct PvOpsHandle {
+    state: Arc<PvOpsState>,
+    kick: VcpuKickHandle,
+}
+
+impl PvOpsHandle {
+    pub(crate) fn new(kick: VcpuKickHandle) -> Self {
+        Self {
+            state: Arc::new(PvOpsState {
+                tlb_flush: AtomicBool::new(false),
+                unhalt: AtomicBool::new(false),
+            }),
+            kick,
+        }
+    }
+
+    /// Flush the TLB of the vCPU before it next runs the guest. Returns
+    /// whether it is in guest mode, and may run on stale translations until
+    /// its TLB is flushed some other way. The vCPU is not kicked.
+    pub fn request_tlb_flush(&self) -> bool {
+        self.state.tlb_flush.store(true, Ordering::SeqCst);
+        // Read after the store: the vCPU takes the request after publishing
+        // its run state, so one of the two sees the other.
+        self.kick.run_state() != VcpuRunState::OutsideGuest
+    }
+
+    /// Complete the next or current `HLT` of the vCPU at once.
+    pub fn unhalt(&self) {
+        self.state.unhalt.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether a TLB flush is pending.
+    pub fn tlb_flush_pending(&self) -> bool {
+        self.state.tlb_flush.load(Ordering::SeqCst)
+    }
+
+    /// Called by the vCPU after [`VcpuKickHandle::begin_entry`]. Returns
+    /// whether the TLB must be flushed.
+    pub(crate) fn take_tlb_flush(&self) -> bool {
+        self.state.tlb_flush.swap(false, Ordering::SeqCst)
+    }
+
+    /// Called by the vCPU on `HLT`. Returns whether it has been woken up.
+    pub(crate) fn take_unhalt(&self) -> bool {
+        self.state.unhalt.swap(false, Ordering::SeqCst)
+    }
+}
+
+/// Register the handlers of the hypercalls of `features`, calling `hooks`.
+pub(crate) fn register_hypercalls(
+    hypercalls: &mut HypercallDispatcher,
+    features: PvOpsFeatures,
+    hooks: Arc<dyn PvOpsHooks>,
+) -> AxResult {
+    if features.contains(PvOpsFeatures::TLB_FLUSH) {
+        let hooks = hooks.clone();
+        hypercalls.register(
+            PV_HC_FLUSH_TLB,
+            Box::new(move |call| {
+                let [bitmap, min, ..] = call.args;
+                let running = (0..64)
+                    .filter(|&bit| bitmap.get_bit(bit))
+                    .filter(|&bit| hooks.flush_tlb((min as u32).wrapping_add(bit as u32)))
+                    .fold(0u64, |running, bit| running | 1 << bit);
+                HypercallAction::Return(running)
+            }),
+        )?;
+    }
+    if features.contains(PvOpsFeatures::SPINLOCK_KICK) {
+        hypercalls.register(
+            KVM_HC_KICK_CPU,
+            Box::new(move |call| {
+                hooks.kick_cpu(call.args[1] as u32);
+                HypercallAction::Return(0)
+            }),
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::regs::GeneralRegisters;
+    use crate::vmx::hypercall::HypercallAbi;
+    use alloc::vec::Vec;
+    use core::sync::atomic::AtomicU32;
+
+    struct Vm {
+        vcpus: Vec<PvOpsHandle>,
+        kicked: AtomicU32,
+    }
+
+    impl PvOpsHooks for Vm {
+        fn flush_tlb(&self, apic_id: u32) -> bool {
+            self.vcpus
+                .get(apic_id as usize)
+                .is_some_and(PvOpsHandle::request_tlb_flush)
+        }
+
+        fn kick_cpu(&self, apic_id: u32) -> bool {
+            self.kicked.store(apic_id, Ordering::SeqCst);
+            self.vcpus
+                .get(apic_id as usize)
+                .map(PvOpsHandle::unhalt)
+                .is_some()
+        }
+    }
+
+    fn setup() -> (Vec<VcpuKickHandle>, Arc<Vm>, HypercallDispatcher) {
+        let kicks: Vec<_> = (0..3).map(|_| VcpuKickHandle::new()).collect();
+        let vm = Arc::new(Vm {
+            vcpus: kicks.iter().cloned().map(PvOpsHandle::new).collect(),
+            kicked: AtomicU32::new(u32::MAX),
+        });
+        let mut hypercalls = HypercallDispatcher::new(HypercallAbi::Kvm);
+        register_hypercalls(&mut hypercalls, PvOpsFeatures::all(), vm.clone()).unwrap();
+        (kicks, vm, hypercalls)
+    }
+
+    #[test]
+    fn test_flush_tlb_hypercall() {
+        let (kicks, vm, hypercalls) = setup();
+
+        // vCPU 2 is in guest mode and left to the guest, vCPU 1 is not, and
+        // APIC ID 3 does not exist. Nothing is kicked.
+        assert!(kicks[2].begin_entry());
+        let mut regs = GeneralRegisters::default();
+        regs.rax = PV_HC_FLUSH_TLB;
+        regs.rbx = 0b111;
+        regs.rcx = 1;
+        let call = hypercalls.decode(&regs, true);
+        assert_eq!(hypercalls.dispatch(&call), HypercallAction::Return(0b10));
+        assert!(!vm.vcpus[0].tlb_flush_pending());
+        assert!(vm.vcpus[1].take_tlb_flush());
+        assert!(kicks.iter().all(|kick| !kick.exit_requested()));
+    }
+
+    #[test]
+    fn test_kick_cpu_hypercall() {
+        let (_kicks, vm, hypercalls) = setup();
+        let mut regs = GeneralRegisters::default();
+        regs.rax = KVM_HC_KICK_CPU;
+        regs.rcx = 1;
+        let call = hypercalls.decode(&regs, true);
+        assert_eq!(hypercalls.dispatch(&call), HypercallAction::Return(0));
+        assert_eq!(vm.kicked.load(Ordering::SeqCst), 1);
+        assert!(vm.vcpus[1].take_unhalt() && !vm.vcpus[1].take_unhalt());
+    }
+
+    #[test]
+    fn test_tlb_flush_in_own_cpuid_leaves() {
+        let features = PvOpsFeatures::all();
+        assert_eq!(features.kvm_features(), KVM_FEATURE_PV_UNHALT);
+        let [max, ebx, ecx, edx] = features.cpuid(PV_OPS_CPUID_LEAF).unwrap();
+        assert_eq!(max, 0x4000_0f01);
+        let signature: Vec<u8> = [ebx, ecx, edx]
+            .iter()
+            .flat_map(|r| r.to_le_bytes())
+            .collect();
+        assert_eq!(signature, b"RVMRVMRVMRVM");
+        assert_eq!(
+            features.cpuid(0x4000_0f01),
+            Some([PV_OPS_FEATURE_TLB_FLUSH, 0, 0, 0])
+        );
+        assert_eq!(PvOpsFeatures::SPINLOCK_KICK.cpuid(PV_OPS_CPUID_LEAF), None);
+    }
+}
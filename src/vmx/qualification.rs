@@ -0,0 +1,594 @@
+//! Decoders for the exit qualification and the VM-exit instruction-information
+//! field. (SDM Vol. 3C, Section 27.2.1 and 27.2.5)
+//!
+//! All decoders work on raw field values, [`VmxExitInfo`](super::VmxExitInfo)
+//! carries both fields of the last VM exit.
+
+use bit_field::BitField;
+use bitflags::bitflags;
+use page_table_entry::MappingFlags;
+
+use super::definitions::VmxExitReason;
+use super::vmcs::{ApicAccessExitInfo, CrAccessInfo, VmxIoExitInfo};
+use crate::regs::GeneralRegisters;
+
+bitflags! {
+    /// Exit Qualification for Debug Exceptions. (SDM Vol. 3C, Section 27.2.1, Table 27-1)
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct DebugExceptionQualification: u64 {
+        /// Breakpoint condition 0 met.
+        const B0 = 1 << 0;
+        /// Breakpoint condition 1 met.
+        const B1 = 1 << 1;
+        /// Breakpoint condition 2 met.
+        const B2 = 1 << 2;
+        /// Breakpoint condition 3 met.
+        const B3 = 1 << 3;
+        /// A debug register access was detected (DR7.GD).
+        const BD = 1 << 13;
+        /// A single step or branch was trapped.
+        const BS = 1 << 14;
+        /// The debug exception occurred inside an RTM region.
+        const RTM = 1 << 16;
+    }
+}
+
+bitflags! {
+    /// Exit Qualification for EPT Violations. (SDM Vol. 3C, Section 27.2.1, Table 27-7)
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct EptViolationQualification: u64 {
+        /// The access was a data read.
+        const READ = 1 << 0;
+        /// The access was a data write.
+        const WRITE = 1 << 1;
+        /// The access was an instruction fetch.
+        const INSTRUCTION_FETCH = 1 << 2;
+        /// The guest-physical address was readable.
+        const READABLE = 1 << 3;
+        /// The guest-physical address was writable.
+        const WRITABLE = 1 << 4;
+        /// The guest-physical address was executable (for supervisor-mode
+        /// linear addresses if mode-based execute control is enabled).
+        const EXECUTABLE = 1 << 5;
+        /// The guest-physical address was executable for user-mode linear
+        /// addresses, only with mode-based execute control.
+        const USER_EXECUTABLE = 1 << 6;
+        /// The guest linear-address field is valid.
+        const GLA_VALID = 1 << 7;
+        /// With [`Self::GLA_VALID`]: the access was the translation of the
+        /// linear address, not an access to a guest paging structure.
+        const GLA_TRANSLATION = 1 << 8;
+        /// The linear address is user-mode (advanced VM-exit information).
+        const USER_MODE_LINEAR = 1 << 9;
+        /// The linear address is writable (advanced VM-exit information).
+        const WRITABLE_LINEAR = 1 << 10;
+        /// The linear address is execute-disabled (advanced VM-exit information).
+        const EXECUTE_DISABLE_LINEAR = 1 << 11;
+        /// NMI blocking was lifted by an IRET that caused the violation.
+        const NMI_UNBLOCKING = 1 << 12;
+        /// The access was a shadow-stack access.
+        const SHADOW_STACK = 1 << 13;
+        /// The guest-physical address maps a supervisor shadow-stack page.
+        const SUPERVISOR_SHADOW_STACK = 1 << 14;
+        /// The access was a guest-paging verification.
+        const GUEST_PAGING_VERIFICATION = 1 << 15;
+        /// The access was asynchronous to instruction execution.
+        const ASYNCHRONOUS = 1 << 16;
+    }
+}
+
+impl EptViolationQualification {
+    /// The kind of access that caused the violation.
+    pub fn access_flags(&self) -> MappingFlags {
+        let mut flags = MappingFlags::empty();
+        flags.set(MappingFlags::READ, self.contains(Self::READ));
+        flags.set(MappingFlags::WRITE, self.contains(Self::WRITE));
+        flags.set(
+            MappingFlags::EXECUTE,
+            self.contains(Self::INSTRUCTION_FETCH),
+        );
+        flags
+    }
+}
+
+/// Source of a task switch. (SDM Vol. 3C, Section 27.2.1, Table 27-2)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskSwitchSource {
+    /// CALL instruction.
+    Call = 0,
+    /// IRET instruction.
+    Iret = 1,
+    /// JMP instruction.
+    Jmp = 2,
+    /// Task gate in the IDT.
+    TaskGate = 3,
+}
+
+/// Exit Qualification for Task Switches. (SDM Vol. 3C, Section 27.2.1, Table 27-2)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TaskSwitchInfo {
+    /// Selector of the task-state segment to switch to.
+    pub selector: u16,
+    /// What initiated the task switch.
+    pub source: TaskSwitchSource,
+}
+
+impl TaskSwitchInfo {
+    /// Decode from the raw exit qualification.
+    pub fn from_qualification(qualification: u64) -> Self {
+        Self {
+            selector: qualification.get_bits(0..16) as u16,
+            source: match qualification.get_bits(30..32) {
+                0 => TaskSwitchSource::Call,
+                1 => TaskSwitchSource::Iret,
+                2 => TaskSwitchSource::Jmp,
+                _ => TaskSwitchSource::TaskGate,
+            },
+        }
+    }
+}
+
+/// Exit Qualification for MOV DR. (SDM Vol. 3C, Section 27.2.1, Table 27-4)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DrAccessInfo {
+    /// [2:0] Number of the debug register.
+    pub dr_number: u8,
+    /// [4] Direction of access (0 = MOV to DR; 1 = MOV from DR).
+    pub is_read: bool,
+    /// [11:8] The general-purpose register, encoded as in [`CrAccessInfo::gpr`].
+    pub gpr: u8,
+}
+
+impl DrAccessInfo {
+    /// Decode from the raw exit qualification.
+    pub fn from_qualification(qualification: u64) -> Self {
+        Self {
+            dr_number: qualification.get_bits(0..3) as u8,
+            is_read: qualification.get_bit(4),
+            gpr: qualification.get_bits(8..12) as u8,
+        }
+    }
+}
+
+/// Typed exit qualification of a VM exit. (SDM Vol. 3C, Section 27.2.1)
+#[derive(Debug)]
+pub enum ExitQualification {
+    /// Exception or NMI. For a #DB this is a [`DebugExceptionQualification`],
+    /// for a #PF the faulting linear address, otherwise zero.
+    Exception(u64),
+    /// SIPI, with the vector of the start-up IPI.
+    StartupIpi(u8),
+    /// Task switch.
+    TaskSwitch(TaskSwitchInfo),
+    /// INVLPG, with the linear-address operand.
+    LinearAddress(u64),
+    /// Instructions with a memory operand described by the instruction
+    /// information, with the sign-extended displacement of the operand.
+    Displacement(i64),
+    /// Control-register access.
+    CrAccess(CrAccessInfo),
+    /// MOV DR.
+    DrAccess(DrAccessInfo),
+    /// I/O instruction.
+    Io(VmxIoExitInfo),
+    /// APIC access.
+    ApicAccess(ApicAccessExitInfo),
+    /// EPT violation.
+    EptViolation(EptViolationQualification),
+    /// Virtualized EOI, with the vector of the EOI.
+    VirtualizedEoi(u8),
+    /// APIC write, with the offset of the write in the APIC page.
+    ApicWrite(u16),
+    /// Any other exit reason, the qualification is undefined or not decoded.
+    Raw(u64),
+}
+
+impl ExitQualification {
+    /// Decode the raw exit qualification of a VM exit caused by `reason`.
+    pub fn decode(reason: VmxExitReason, qualification: u64) -> Self {
+        use VmxExitReason::*;
+        match reason {
+            EXCEPTION_NMI => Self::Exception(qualification),
+            SIPI => Self::StartupIpi(qualification.get_bits(0..8) as u8),
+            TASK_SWITCH => Self::TaskSwitch(TaskSwitchInfo::from_qualification(qualification)),
+            INVLPG => Self::LinearAddress(qualification),
+            INVEPT | INVPCID | INVVPID | VMCLEAR | VMPTRLD | VMPTRST | VMREAD | VMWRITE | VMON
+            | GDTR_IDTR | LDTR_TR | XSAVES | XRSTORS => Self::Displacement(qualification as i64),
+            CR_ACCESS => Self::CrAccess(CrAccessInfo::from_qualification(qualification)),
+            DR_ACCESS => Self::DrAccess(DrAccessInfo::from_qualification(qualification)),
+            IO_INSTRUCTION => Self::Io(VmxIoExitInfo::from_qualification(qualification)),
+            APIC_ACCESS => match ApicAccessExitInfo::from_qualification(qualification) {
+                Some(info) => Self::ApicAccess(info),
+                None => Self::Raw(qualification),
+            },
+            EPT_VIOLATION => {
+                Self::EptViolation(EptViolationQualification::from_bits_retain(qualification))
+            }
+            VIRTUALIZED_EOI => Self::VirtualizedEoi(qualification.get_bits(0..8) as u8),
+            APIC_WRITE => Self::ApicWrite(qualification.get_bits(0..12) as u16),
+            _ => Self::Raw(qualification),
+        }
+    }
+}
+
+/// Segment register of a memory operand in the instruction information.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SegmentRegister {
+    /// ES.
+    ES = 0,
+    /// CS.
+    CS = 1,
+    /// SS.
+    SS = 2,
+    /// DS.
+    DS = 3,
+    /// FS.
+    FS = 4,
+    /// GS.
+    GS = 5,
+}
+
+impl SegmentRegister {
+    fn from_bits(bits: u32) -> Option<Self> {
+        Some(match bits {
+            0 => Self::ES,
+            1 => Self::CS,
+            2 => Self::SS,
+            3 => Self::DS,
+            4 => Self::FS,
+            5 => Self::GS,
+            _ => return None,
+        })
+    }
+}
+
+/// Size in bytes of a 2-bit or 3-bit size encoding (0 = 16-bit, 1 = 32-bit, 2 = 64-bit).
+fn size_in_bytes(bits: u32) -> Option<u8> {
+    match bits {
+        0 => Some(2),
+        1 => Some(4),
+        2 => Some(8),
+        _ => None,
+    }
+}
+
+/// A memory operand described by the instruction information and the
+/// displacement in the exit qualification. (SDM Vol. 3C, Section 27.2.5)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryOperand {
+    /// Segment register of the operand.
+    pub segment: SegmentRegister,
+    /// Base register, encoded as in [`CrAccessInfo::gpr`].
+    pub base: Option<u8>,
+    /// Index register, encoded as in [`CrAccessInfo::gpr`].
+    pub index: Option<u8>,
+    /// Scale of the index register (1, 2, 4 or 8).
+    pub scale: u8,
+    /// Address size in bytes (2, 4 or 8).
+    pub address_size: u8,
+    /// Sign-extended displacement.
+    pub displacement: i64,
+}
+
+impl MemoryOperand {
+    fn decode(info: u32, qualification: u64) -> Option<Self> {
+        Some(Self {
+            segment: SegmentRegister::from_bits(info.get_bits(15..18))?,
+            base: (!info.get_bit(27)).then(|| info.get_bits(23..27) as u8),
+            index: (!info.get_bit(22)).then(|| info.get_bits(18..22) as u8),
+            scale: 1 << info.get_bits(0..2),
+            address_size: size_in_bytes(info.get_bits(7..10))?,
+            displacement: qualification as i64,
+        })
+    }
+
+    /// Offset of the operand within its segment. `rsp` is the guest RSP,
+    /// which is not part of [`GeneralRegisters`].
+    pub fn offset(&self, regs: &GeneralRegisters, rsp: u64) -> u64 {
+        let reg = |index: u8| {
+            if index == 4 {
+                rsp
+            } else {
+                regs.get_reg_of_index(index)
+            }
+        };
+        let base = self.base.map_or(0, reg);
+        let index = self.index.map_or(0, reg);
+        let offset = base
+            .wrapping_add(index.wrapping_mul(self.scale as u64))
+            .wrapping_add(self.displacement as u64);
+        match self.address_size {
+            2 => offset & 0xffff,
+            4 => offset & 0xffff_ffff,
+            _ => offset,
+        }
+    }
+}
+
+/// A register or memory operand of the instruction information.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InstructionOperand {
+    /// A general-purpose register, encoded as in [`CrAccessInfo::gpr`].
+    Register(u8),
+    /// A memory operand.
+    Memory(MemoryOperand),
+}
+
+impl InstructionOperand {
+    fn decode(info: u32, qualification: u64) -> Option<Self> {
+        if info.get_bit(10) {
+            Some(Self::Register(info.get_bits(3..7) as u8))
+        } else {
+            MemoryOperand::decode(info, qualification).map(Self::Memory)
+        }
+    }
+}
+
+/// Instruction identity of a GDTR or IDTR access.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DescriptorTableInstruction {
+    /// SGDT.
+    Sgdt = 0,
+    /// SIDT.
+    Sidt = 1,
+    /// LGDT.
+    Lgdt = 2,
+    /// LIDT.
+    Lidt = 3,
+}
+
+/// Instruction identity of an LDTR or TR access.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SegmentTableInstruction {
+    /// SLDT.
+    Sldt = 0,
+    /// STR.
+    Str = 1,
+    /// LLDT.
+    Lldt = 2,
+    /// LTR.
+    Ltr = 3,
+}
+
+/// VM-Exit Instruction-Information Field. (SDM Vol. 3C, Section 27.2.5, Table 27-8 to 27-15)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VmxInstructionInfo {
+    /// INS and OUTS. The segment is undefined for INS, which always uses ES.
+    StringIo {
+        /// Address size in bytes.
+        address_size: u8,
+        /// Segment register of the source operand of OUTS.
+        segment: Option<SegmentRegister>,
+    },
+    /// INVEPT, INVPCID and INVVPID.
+    Invalidate {
+        /// Register holding the invalidation type.
+        type_register: u8,
+        /// The descriptor operand.
+        operand: MemoryOperand,
+    },
+    /// LGDT, LIDT, SGDT and SIDT.
+    DescriptorTable {
+        /// Which instruction caused the VM exit.
+        instruction: DescriptorTableInstruction,
+        /// Operand size in bytes (2 or 4), ignored in 64-bit mode.
+        operand_size: u8,
+        /// The pseudo-descriptor operand.
+        operand: MemoryOperand,
+    },
+    /// LLDT, LTR, SLDT and STR.
+    SegmentTable {
+        /// Which instruction caused the VM exit.
+        instruction: SegmentTableInstruction,
+        /// The selector operand.
+        operand: InstructionOperand,
+    },
+    /// RDRAND, RDSEED, TPAUSE and UMWAIT.
+    Register {
+        /// The destination (RDRAND, RDSEED) or source (TPAUSE, UMWAIT) register.
+        register: u8,
+        /// Operand size in bytes (2, 4 or 8).
+        operand_size: u8,
+    },
+    /// VMCLEAR, VMPTRLD, VMPTRST, VMXON, XRSTORS and XSAVES.
+    Memory(MemoryOperand),
+    /// VMREAD and VMWRITE.
+    VmreadVmwrite {
+        /// Register holding the VMCS field encoding.
+        field_register: u8,
+        /// The source (VMWRITE) or destination (VMREAD) operand.
+        operand: InstructionOperand,
+    },
+    /// LOADIWKEY.
+    LoadIwKey {
+        /// The first source register (an XMM register).
+        register1: u8,
+        /// The second source register (an XMM register).
+        register2: u8,
+    },
+}
+
+impl VmxInstructionInfo {
+    /// Decode the raw instruction information of a VM exit caused by `reason`.
+    /// `qualification` supplies the displacement of memory operands.
+    ///
+    /// Returns `None` if `reason` does not report instruction information, or
+    /// the field holds an undefined encoding.
+    pub fn decode(reason: VmxExitReason, info: u32, qualification: u64) -> Option<Self> {
+        use VmxExitReason::*;
+        let reg1 = info.get_bits(3..7) as u8;
+        let reg2 = info.get_bits(28..32) as u8;
+        Some(match reason {
+            IO_INSTRUCTION => Self::StringIo {
+                address_size: size_in_bytes(info.get_bits(7..10))?,
+                segment: SegmentRegister::from_bits(info.get_bits(15..18)),
+            },
+            INVEPT | INVPCID | INVVPID => Self::Invalidate {
+                type_register: reg2,
+                operand: MemoryOperand::decode(info, qualification)?,
+            },
+            GDTR_IDTR => Self::DescriptorTable {
+                instruction: match info.get_bits(28..30) {
+                    0 => DescriptorTableInstruction::Sgdt,
+                    1 => DescriptorTableInstruction::Sidt,
+                    2 => DescriptorTableInstruction::Lgdt,
+                    _ => DescriptorTableInstruction::Lidt,
+                },
+                operand_size: if info.get_bit(11) { 4 } else { 2 },
+                operand: MemoryOperand::decode(info, qualification)?,
+            },
+            LDTR_TR => Self::SegmentTable {
+                instruction: match info.get_bits(28..30) {
+                    0 => SegmentTableInstruction::Sldt,
+                    1 => SegmentTableInstruction::Str,
+                    2 => SegmentTableInstruction::Lldt,
+                    _ => SegmentTableInstruction::Ltr,
+                },
+                operand: InstructionOperand::decode(info, qualification)?,
+            },
+            RDRAND | RDSEED | TPAUSE | UMWAIT => Self::Register {
+                register: reg1,
+                operand_size: size_in_bytes(info.get_bits(11..13))?,
+            },
+            VMCLEAR | VMPTRLD | VMPTRST | VMON | XRSTORS | XSAVES => {
+                Self::Memory(MemoryOperand::decode(info, qualification)?)
+            }
+            VMREAD | VMWRITE => Self::VmreadVmwrite {
+                field_register: reg2,
+                operand: InstructionOperand::decode(info, qualification)?,
+            },
+            LOADIWKEY => Self::LoadIwKey {
+                register1: reg1,
+                register2: reg2,
+            },
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_lmsw_cr_access() {
+        // LMSW 0x8011 from memory: source data is 16 bits wide.
+        let ExitQualification::CrAccess(cr) =
+            ExitQualification::decode(VmxExitReason::CR_ACCESS, 0x8011_0070)
+        else {
+            panic!("not a CR access");
+        };
+        assert_eq!((cr.cr_number, cr.access_type, cr.lmsw_op_type), (0, 3, 1));
+        assert_eq!(cr.lmsw_source_data, 0x8011);
+    }
+
+    #[test]
+    fn test_decode_ept_violation() {
+        let ExitQualification::EptViolation(ept) =
+            ExitQualification::decode(VmxExitReason::EPT_VIOLATION, 0x1182)
+        else {
+            panic!("not an EPT violation");
+        };
+        assert!(ept.contains(
+            EptViolationQualification::WRITE
+                | EptViolationQualification::GLA_VALID
+                | EptViolationQualification::GLA_TRANSLATION
+                | EptViolationQualification::NMI_UNBLOCKING
+        ));
+        assert_eq!(ept.access_flags(), MappingFlags::WRITE);
+    }
+
+    #[test]
+    fn test_decode_task_switch() {
+        assert_eq!(
+            TaskSwitchInfo::from_qualification(0xc000_0028),
+            TaskSwitchInfo {
+                selector: 0x28,
+                source: TaskSwitchSource::TaskGate,
+            }
+        );
+    }
+
+    #[test]
+    fn test_decode_dr_access() {
+        assert_eq!(
+            DrAccessInfo::from_qualification(0x313),
+            DrAccessInfo {
+                dr_number: 3,
+                is_read: true,
+                gpr: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn test_decode_displacement() {
+        assert!(matches!(
+            ExitQualification::decode(VmxExitReason::VMREAD, (-8i64) as u64),
+            ExitQualification::Displacement(-8)
+        ));
+    }
+
+    #[test]
+    fn test_decode_vmread_memory_operand() {
+        // VMREAD [rsi + rcx * 2 - 8], rax: 64-bit addressing through DS.
+        let info = VmxInstructionInfo::decode(VmxExitReason::VMREAD, 0x0305_8101, (-8i64) as u64);
+        let Some(VmxInstructionInfo::VmreadVmwrite {
+            field_register: 0,
+            operand: InstructionOperand::Memory(mem),
+        }) = info
+        else {
+            panic!("unexpected {:?}", info);
+        };
+        assert_eq!(mem.segment, SegmentRegister::DS);
+        assert_eq!((mem.base, mem.index, mem.scale), (Some(6), Some(1), 2));
+        let mut regs = GeneralRegisters::default();
+        regs.rsi = 0x1000;
+        regs.rcx = 0x10;
+        assert_eq!(mem.offset(&regs, 0), 0x1018);
+    }
+
+    #[test]
+    fn test_decode_register_operand() {
+        // RDRAND rbx.
+        assert_eq!(
+            VmxInstructionInfo::decode(VmxExitReason::RDRAND, 0x1018, 0),
+            Some(VmxInstructionInfo::Register {
+                register: 3,
+                operand_size: 8,
+            })
+        );
+    }
+
+    #[test]
+    fn test_decode_descriptor_table_without_base_or_index() {
+        // LGDT [0x1234] with 32-bit addressing and operand size, no base or index.
+        let Some(VmxInstructionInfo::DescriptorTable {
+            instruction: DescriptorTableInstruction::Lgdt,
+            operand_size: 4,
+            operand: mem,
+        }) = VmxInstructionInfo::decode(VmxExitReason::GDTR_IDTR, 0x2841_8880, 0x1234)
+        else {
+            panic!("not an LGDT");
+        };
+        assert_eq!((mem.base, mem.index, mem.address_size), (None, None, 4));
+        assert_eq!(mem.offset(&GeneralRegisters::default(), 0), 0x1234);
+    }
+
+    #[test]
+    fn test_decode_segment_table_register() {
+        // STR eax.
+        assert_eq!(
+            VmxInstructionInfo::decode(VmxExitReason::LDTR_TR, 0x1000_0400, 0),
+            Some(VmxInstructionInfo::SegmentTable {
+                instruction: SegmentTableInstruction::Str,
+                operand: InstructionOperand::Register(0),
+            })
+        );
+    }
+
+    #[test]
+    fn test_no_instruction_info_for_other_exits() {
+        assert_eq!(VmxInstructionInfo::decode(VmxExitReason::CPUID, 0, 0), None);
+    }
+}
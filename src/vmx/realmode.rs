@@ -0,0 +1,2533 @@
+use bit_field::BitField;
+
+use axaddrspace::GuestPhysAddr;
+use axaddrspace::device::AccessWidth;
+use axerrno::{AxResult, ax_err};
+
+use super::guest_mem::GuestMemory;
+use crate::regs::GeneralRegisters;
+
+const FLAG_CF: u64 = 1 << 0;
+const FLAG_PF: u64 = 1 << 2;
+const FLAG_AF: u64 = 1 << 4;
+const FLAG_ZF: u64 = 1 << 6;
+const FLAG_SF: u64 = 1 << 7;
+const FLAG_TF: u64 = 1 << 8;
+const FLAG_IF: u64 = 1 << 9;
+const FLAG_DF: u64 = 1 << 10;
+const FLAG_OF: u64 = 1 << 11;
+const FLAG_IOPL: u64 = 3 << 12;
+const FLAG_NT: u64 = 1 << 14;
+const FLAG_RF: u64 = 1 << 16;
+const FLAG_VM: u64 = 1 << 17;
+/// Flags that `POPF`/`IRET` may change in real mode.
+const FLAGS_WRITABLE: u64 = 0x7fd5;
+
+const CR0_PE: u64 = 1 << 0;
+const CR0_PG: u64 = 1 << 31;
+const MSR_IA32_EFER: u32 = 0xc000_0080;
+
+const INVALID_OPCODE_VECTOR: u8 = 6;
+const GENERAL_PROTECTION_VECTOR: u8 = 13;
+
+/// Segment register indexes, in the order of their instruction encoding.
+pub const SEG_ES: usize = 0;
+/// Code segment.
+pub const SEG_CS: usize = 1;
+/// Stack segment.
+pub const SEG_SS: usize = 2;
+/// Data segment.
+pub const SEG_DS: usize = 3;
+/// Extra data segment FS.
+pub const SEG_FS: usize = 4;
+/// Extra data segment GS.
+pub const SEG_GS: usize = 5;
+
+/// Access-rights bit 14: default operation size (code) or stack size (SS).
+const AR_DB: usize = 14;
+/// Access-rights bit 16: segment unusable.
+const AR_UNUSABLE: u32 = 1 << 16;
+
+/// The hidden part of a segment register, in VMCS access-rights format.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentCache {
+    /// Visible selector.
+    pub selector: u16,
+    /// Segment base address.
+    pub base: u64,
+    /// Segment limit, in bytes.
+    pub limit: u32,
+    /// Access rights. (SDM Vol. 3C, Section 25.4.1, Table 25-2)
+    pub access_rights: u32,
+}
+
+impl SegmentCache {
+    /// The hidden part loaded from the segment descriptor `desc`.
+    fn from_descriptor(selector: u16, desc: u64) -> Self {
+        let mut limit = (desc.get_bits(0..16) | (desc.get_bits(48..52) << 16)) as u32;
+        if desc.get_bit(55) {
+            limit = (limit << 12) | 0xfff;
+        }
+        Self {
+            selector,
+            base: desc.get_bits(16..40) | (desc.get_bits(56..64) << 24),
+            limit,
+            access_rights: (desc.get_bits(40..48) | (desc.get_bits(52..56) << 12)) as u32,
+        }
+    }
+}
+
+/// Architectural state of a guest running without paging, operated on by the
+/// legacy-mode interpreter.
+///
+/// This is used when the processor does not support the unrestricted-guest
+/// VMX control, so real-mode, big-real-mode and unpaged protected-mode code
+/// can not be run by the hardware. (SDM Vol. 3C, Section 26.3.1.1)
+#[derive(Debug, Default, Clone)]
+pub struct LegacyCpuState {
+    /// General-purpose registers, except `RSP`.
+    pub regs: GeneralRegisters,
+    /// Stack pointer.
+    pub rsp: u64,
+    /// Instruction pointer.
+    pub rip: u64,
+    /// Flags register.
+    pub rflags: u64,
+    /// ES, CS, SS, DS, FS and GS, indexed by `SEG_*`.
+    pub segs: [SegmentCache; 6],
+    /// LDTR.
+    pub ldtr: SegmentCache,
+    /// TR, the TSS holding the inner stacks for interrupts in protected mode.
+    pub tr: SegmentCache,
+    /// CR0 as the guest sees it.
+    pub cr0: u64,
+    /// CR3.
+    pub cr3: u64,
+    /// CR4 as the guest sees it.
+    pub cr4: u64,
+    /// IA32_EFER.
+    pub efer: u64,
+    /// GDTR base.
+    pub gdtr_base: u64,
+    /// GDTR limit.
+    pub gdtr_limit: u32,
+    /// IDTR base.
+    pub idtr_base: u64,
+    /// IDTR limit.
+    pub idtr_limit: u32,
+    /// The accumulator while `INS` waits for the caller to complete its
+    /// [`LegacyExit::IoRead`], which returns the input there.
+    pub pending_input: Option<u64>,
+}
+
+/// Reasons for the interpreter to hand control back to its caller.
+#[derive(Debug, PartialEq, Eq)]
+pub enum LegacyExit {
+    /// The guest executed `HLT`.
+    Halt,
+    /// The guest executed `IN` or `INS`. The result is expected in
+    /// `AL`/`AX`/`EAX`. `RIP` is left at `INS`, which stores the result when
+    /// run again.
+    IoRead {
+        /// Port number.
+        port: u16,
+        /// Access width.
+        width: AccessWidth,
+    },
+    /// The guest executed `OUT`, or `OUTS` for one element.
+    IoWrite {
+        /// Port number.
+        port: u16,
+        /// Access width.
+        width: AccessWidth,
+        /// Data written.
+        data: u64,
+    },
+    /// The guest turned on protected mode with paging, the hardware can take over.
+    PagingEnabled,
+    /// The guest executed `CPUID`. `RIP` is left at the 2-byte instruction,
+    /// for the caller to complete it with the leaves of the virtual CPU.
+    Cpuid,
+    /// The guest executed `RDMSR` of an MSR other than `IA32_EFER`. `RIP` is
+    /// left at the 2-byte instruction, as for [`Self::Cpuid`].
+    MsrRead,
+    /// The guest executed `WRMSR` of an MSR other than `IA32_EFER`. `RIP` is
+    /// left at the 2-byte instruction, as for [`Self::Cpuid`].
+    MsrWrite,
+}
+
+/// A decoded memory or register operand.
+#[derive(Clone, Copy)]
+enum Operand {
+    Reg(u8),
+    Mem(usize, u64),
+}
+
+/// Per-instruction decoding context.
+struct Insn {
+    /// Offset of the next byte to fetch.
+    ip: u64,
+    /// Segment override prefix.
+    seg: Option<usize>,
+    /// Operand size in bytes, 2 or 4.
+    osz: usize,
+    /// Address size in bytes, 2 or 4.
+    asz: usize,
+    /// `F3` (`Some(true)`) or `F2` (`Some(false)`) prefix.
+    rep: Option<bool>,
+}
+
+fn mask(size: usize) -> u64 {
+    if size >= 8 {
+        u64::MAX
+    } else {
+        (1u64 << (size * 8)) - 1
+    }
+}
+
+fn sign_bit(size: usize) -> u64 {
+    1u64 << (size * 8 - 1)
+}
+
+fn sign_extend(value: u64, size: usize) -> u64 {
+    let shift = 64 - size * 8;
+    (((value << shift) as i64) >> shift) as u64
+}
+
+fn access_width(size: usize) -> AccessWidth {
+    match size {
+        1 => AccessWidth::Byte,
+        2 => AccessWidth::Word,
+        _ => AccessWidth::Dword,
+    }
+}
+
+impl LegacyCpuState {
+    /// Whether the guest has enabled both protected mode and paging.
+    pub fn paging_enabled(&self) -> bool {
+        self.cr0 & (CR0_PE | CR0_PG) == (CR0_PE | CR0_PG)
+    }
+
+    /// Whether the guest is in real mode (`CR0.PE` = 0).
+    pub fn is_real_mode(&self) -> bool {
+        self.cr0 & CR0_PE == 0
+    }
+
+    /// Whether maskable interrupts are enabled (`RFLAGS.IF` = 1).
+    pub fn interrupts_enabled(&self) -> bool {
+        self.rflags & FLAG_IF != 0
+    }
+
+    /// Execute up to `budget` instructions.
+    ///
+    /// Returns `None` if the budget ran out before anything required the
+    /// attention of the caller.
+    pub fn run<M: GuestMemory + ?Sized>(
+        &mut self,
+        mem: &mut M,
+        budget: usize,
+    ) -> AxResult<Option<LegacyExit>> {
+        for _ in 0..budget {
+            if let Some(exit) = self.step(mem)? {
+                return Ok(Some(exit));
+            }
+            if self.paging_enabled() {
+                return Ok(Some(LegacyExit::PagingEnabled));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Deliver an interrupt or exception through the real-mode interrupt
+    /// vector table, or through the IDT in protected mode with `err_code`
+    /// pushed if given. (SDM Vol. 3A, Section 21.1.4 and 6.12)
+    pub fn deliver_interrupt<M: GuestMemory + ?Sized>(
+        &mut self,
+        mem: &mut M,
+        vector: u8,
+        err_code: Option<u32>,
+    ) -> AxResult {
+        self.deliver(mem, vector, err_code, None)
+    }
+
+    /// Deliver an event. `next_ip` is given for `INT n` and `INT3`, the
+    /// instruction they return to. In protected mode they are checked against
+    /// the privilege level of the gate first, and fault at `RIP` otherwise.
+    fn deliver<M: GuestMemory + ?Sized>(
+        &mut self,
+        mem: &mut M,
+        vector: u8,
+        err_code: Option<u32>,
+        next_ip: Option<u64>,
+    ) -> AxResult {
+        if !self.is_real_mode() {
+            return self.deliver_through_gate(mem, vector, err_code, next_ip);
+        }
+        if let Some(ip) = next_ip {
+            self.rip = ip;
+        }
+        let entry = self.idtr_base + vector as u64 * 4;
+        if entry + 3 > self.idtr_base + self.idtr_limit as u64 {
+            return ax_err!(InvalidInput, "interrupt vector beyond IVT limit");
+        }
+        let target = mem.read_uint(GuestPhysAddr::from(entry as usize), 4)?;
+        let (flags, cs, ip) = (self.rflags, self.segs[SEG_CS].selector, self.rip);
+        self.push(mem, flags & 0xffff, 2)?;
+        self.push(mem, cs as u64, 2)?;
+        self.push(mem, ip & 0xffff, 2)?;
+        self.rflags &= !(FLAG_IF | FLAG_TF);
+        self.load_segment(mem, SEG_CS, target.get_bits(16..32) as u16)?;
+        self.rip = target.get_bits(0..16);
+        Ok(())
+    }
+
+    /// Deliver an event through an interrupt or trap gate of the IDT,
+    /// switching to the stack in the TSS for a more privileged handler.
+    /// (SDM Vol. 3A, Section 6.12.1)
+    fn deliver_through_gate<M: GuestMemory + ?Sized>(
+        &mut self,
+        mem: &mut M,
+        vector: u8,
+        err_code: Option<u32>,
+        next_ip: Option<u64>,
+    ) -> AxResult {
+        let entry = vector as u64 * 8;
+        if entry + 7 > self.idtr_limit as u64 {
+            return ax_err!(InvalidInput, "interrupt vector beyond IDT limit");
+        }
+        let gate = mem.read_uint(GuestPhysAddr::from((self.idtr_base + entry) as usize), 8)?;
+        if !gate.get_bit(47) {
+            return ax_err!(InvalidInput, "IDT gate not present");
+        }
+        let size = match gate.get_bits(40..44) {
+            0x6 | 0x7 => 2,
+            0xe | 0xf => 4,
+            0x5 => return ax_err!(Unsupported, "task gates in emulated protected mode"),
+            _ => return ax_err!(InvalidInput, "invalid IDT gate type"),
+        };
+        let cpl = self.cpl();
+        if let Some(ip) = next_ip {
+            if (gate.get_bits(45..47) as u8) < cpl {
+                let err_code = vector as u32 * 8 + 2;
+                return self.deliver_through_gate(
+                    mem,
+                    GENERAL_PROTECTION_VECTOR,
+                    Some(err_code),
+                    None,
+                );
+            }
+            self.rip = ip;
+        }
+        let selector = gate.get_bits(16..32) as u16;
+        let code = self.descriptor(mem, selector)?;
+        // A conforming handler runs at the current privilege level.
+        let dpl = if code.get_bit(42) {
+            cpl
+        } else {
+            code.get_bits(45..47) as u8
+        };
+        if dpl > cpl {
+            return ax_err!(
+                InvalidInput,
+                "interrupt handler less privileged than the program"
+            );
+        }
+
+        let (flags, cs, ip) = (self.rflags, self.segs[SEG_CS].selector, self.rip);
+        if dpl < cpl {
+            let (ss, sp) = (self.segs[SEG_SS].selector, self.rsp);
+            self.load_inner_stack(mem, dpl)?;
+            self.push(mem, ss as u64, size)?;
+            self.push(mem, sp & mask(size), size)?;
+        }
+        self.push(mem, flags & mask(size), size)?;
+        self.push(mem, cs as u64, size)?;
+        self.push(mem, ip & mask(size), size)?;
+        if let Some(err_code) = err_code {
+            self.push(mem, err_code as u64, size)?;
+        }
+        self.rflags &= !(FLAG_TF | FLAG_NT | FLAG_RF);
+        // Interrupt gates, unlike trap gates, disable interrupts.
+        if !gate.get_bit(40) {
+            self.rflags &= !FLAG_IF;
+        }
+        self.load_segment(mem, SEG_CS, (selector & !3) | dpl as u16)?;
+        self.rip = gate.get_bits(0..16) | (gate.get_bits(48..64) << 16);
+        Ok(())
+    }
+
+    /// Load the stack of privilege level `dpl` from the TSS.
+    /// (SDM Vol. 3A, Section 7.2.1 and 7.6)
+    fn load_inner_stack<M: GuestMemory + ?Sized>(&mut self, mem: &mut M, dpl: u8) -> AxResult {
+        let tss = self.tr;
+        // A 32-bit TSS holds ESP0 at 4, a 16-bit one SP0 at 2.
+        let (offset, size) = if tss.access_rights.get_bit(3) {
+            (4 + 8 * dpl as u64, 4)
+        } else {
+            (2 + 4 * dpl as u64, 2)
+        };
+        if tss.access_rights & AR_UNUSABLE != 0 || offset + size + 1 > tss.limit as u64 {
+            return ax_err!(InvalidInput, "no inner stack in the TSS");
+        }
+        let read =
+            |offset, size| mem.read_uint(GuestPhysAddr::from((tss.base + offset) as usize), size);
+        let sp = read(offset, size as usize)?;
+        let ss = read(offset + size, 2)?;
+        self.load_segment(mem, SEG_SS, ss as u16)?;
+        self.rsp = sp;
+        Ok(())
+    }
+
+    /// `IRET` in protected mode, to the same or an outer privilege level.
+    /// Returns the new instruction pointer. (SDM Vol. 2A, IRET)
+    fn iret_protected<M: GuestMemory + ?Sized>(
+        &mut self,
+        mem: &mut M,
+        osz: usize,
+    ) -> AxResult<u64> {
+        if self.rflags & FLAG_NT != 0 {
+            return ax_err!(Unsupported, "task return in emulated protected mode");
+        }
+        let cpl = self.cpl();
+        let ip = self.pop(mem, osz)?;
+        let cs = self.pop(mem, osz)? as u16;
+        let flags = self.pop(mem, osz)?;
+        if osz == 4 && flags & FLAG_VM != 0 {
+            return ax_err!(Unsupported, "virtual-8086 mode in emulated protected mode");
+        }
+        let rpl = (cs & 3) as u8;
+        if rpl < cpl {
+            return ax_err!(InvalidInput, "IRET to an inner privilege level");
+        }
+        if rpl > cpl {
+            let sp = self.pop(mem, osz)?;
+            let ss = self.pop(mem, osz)?;
+            self.load_segment(mem, SEG_CS, cs)?;
+            self.load_segment(mem, SEG_SS, ss as u16)?;
+            self.set_reg(4, self.stack_size(), sp);
+            // Segments the outer level may not access are nulled.
+            for seg in [SEG_ES, SEG_DS, SEG_FS, SEG_GS] {
+                let ar = self.segs[seg].access_rights;
+                let conforming_code = ar.get_bit(3) && ar.get_bit(2);
+                if ar & AR_UNUSABLE == 0 && !conforming_code && (ar.get_bits(5..7) as u8) < rpl {
+                    self.segs[seg] = SegmentCache {
+                        access_rights: AR_UNUSABLE,
+                        ..Default::default()
+                    };
+                }
+            }
+        } else {
+            self.load_segment(mem, SEG_CS, cs)?;
+        }
+        // IOPL is changed only at CPL 0, IF only at or below IOPL.
+        let mut writable = FLAGS_WRITABLE & mask(osz);
+        if cpl > 0 {
+            writable &= !FLAG_IOPL;
+        }
+        if cpl as u64 > self.rflags.get_bits(12..14) {
+            writable &= !FLAG_IF;
+        }
+        self.rflags = (self.rflags & !writable) | (flags & writable) | 0x2;
+        Ok(ip)
+    }
+
+    /// The current privilege level, the DPL of SS.
+    fn cpl(&self) -> u8 {
+        self.segs[SEG_SS].access_rights.get_bits(5..7) as u8
+    }
+
+    /// Execute a single instruction.
+    pub fn step<M: GuestMemory + ?Sized>(&mut self, mem: &mut M) -> AxResult<Option<LegacyExit>> {
+        let def = if self.segs[SEG_CS].access_rights.get_bit(AR_DB) {
+            4
+        } else {
+            2
+        };
+        let mut insn = Insn {
+            ip: self.rip,
+            seg: None,
+            osz: def,
+            asz: def,
+            rep: None,
+        };
+
+        let mut opcode;
+        loop {
+            opcode = self.fetch(mem, &mut insn, 1)? as u8;
+            match opcode {
+                0x26 => insn.seg = Some(SEG_ES),
+                0x2e => insn.seg = Some(SEG_CS),
+                0x36 => insn.seg = Some(SEG_SS),
+                0x3e => insn.seg = Some(SEG_DS),
+                0x64 => insn.seg = Some(SEG_FS),
+                0x65 => insn.seg = Some(SEG_GS),
+                0x66 => insn.osz = 6 - def,
+                0x67 => insn.asz = 6 - def,
+                0xf0 => {}
+                0xf2 => insn.rep = Some(false),
+                0xf3 => insn.rep = Some(true),
+                _ => break,
+            }
+        }
+
+        let osz = insn.osz;
+        let mut exit = None;
+        let mut next_ip = None;
+
+        match opcode {
+            // ALU r/m, reg / reg, r/m / acc, imm.
+            0x00..=0x3f if opcode & 7 < 6 => {
+                let op = opcode >> 3;
+                let size = if opcode & 1 == 0 { 1 } else { osz };
+                match opcode & 7 {
+                    0 | 1 => {
+                        let (reg, rm) = self.modrm(mem, &mut insn)?;
+                        let a = self.read_op(mem, rm, size)?;
+                        let b = self.reg(reg, size);
+                        let res = self.alu(op, a, b, size);
+                        if op != 7 {
+                            self.write_op(mem, rm, size, res)?;
+                        }
+                    }
+                    2 | 3 => {
+                        let (reg, rm) = self.modrm(mem, &mut insn)?;
+                        let a = self.reg(reg, size);
+                        let b = self.read_op(mem, rm, size)?;
+                        let res = self.alu(op, a, b, size);
+                        if op != 7 {
+                            self.set_reg(reg, size, res);
+                        }
+                    }
+                    _ => {
+                        let b = self.fetch(mem, &mut insn, size)?;
+                        let a = self.reg(0, size);
+                        let res = self.alu(op, a, b, size);
+                        if op != 7 {
+                            self.set_reg(0, size, res);
+                        }
+                    }
+                }
+            }
+            // PUSH/POP ES, CS, SS, DS.
+            0x06 | 0x0e | 0x16 | 0x1e => {
+                let sel = self.segs[(opcode >> 3) as usize].selector;
+                self.push(mem, sel as u64, osz)?;
+            }
+            0x07 | 0x17 | 0x1f => {
+                let sel = self.pop(mem, osz)?;
+                self.load_segment(mem, (opcode >> 3) as usize, sel as u16)?;
+            }
+            0x0f => return self.step_0f(mem, &mut insn),
+            // INC/DEC reg.
+            0x40..=0x4f => {
+                let reg = opcode & 7;
+                let a = self.reg(reg, osz);
+                let res = self.inc_dec(a, opcode >= 0x48, osz);
+                self.set_reg(reg, osz, res);
+            }
+            // PUSH/POP reg.
+            0x50..=0x57 => {
+                let val = self.reg(opcode & 7, osz);
+                self.push(mem, val, osz)?;
+            }
+            0x58..=0x5f => {
+                let val = self.pop(mem, osz)?;
+                self.set_reg(opcode & 7, osz, val);
+            }
+            // PUSHA/POPA.
+            0x60 => {
+                let sp = self.reg(4, osz);
+                for reg in 0..8 {
+                    let val = if reg == 4 { sp } else { self.reg(reg, osz) };
+                    self.push(mem, val, osz)?;
+                }
+            }
+            0x61 => {
+                for reg in (0..8).rev() {
+                    let val = self.pop(mem, osz)?;
+                    if reg != 4 {
+                        self.set_reg(reg, osz, val);
+                    }
+                }
+            }
+            // INS/OUTS.
+            0x6c..=0x6f => {
+                exit = self.string_io(mem, &insn, opcode)?;
+                // Stay at the instruction for its input or its next element.
+                let asz = insn.asz;
+                if self.pending_input.is_some() || (insn.rep.is_some() && self.reg(1, asz) != 0) {
+                    next_ip = Some(self.rip);
+                }
+            }
+            // PUSH imm.
+            0x68 => {
+                let val = self.fetch(mem, &mut insn, osz)?;
+                self.push(mem, val, osz)?;
+            }
+            0x6a => {
+                let val = sign_extend(self.fetch(mem, &mut insn, 1)?, 1);
+                self.push(mem, val, osz)?;
+            }
+            // IMUL reg, r/m, imm.
+            0x69 | 0x6b => {
+                let (reg, rm) = self.modrm(mem, &mut insn)?;
+                let a = self.read_op(mem, rm, osz)?;
+                let b = if opcode == 0x6b {
+                    sign_extend(self.fetch(mem, &mut insn, 1)?, 1)
+                } else {
+                    self.fetch(mem, &mut insn, osz)?
+                };
+                let res = self.imul(a, b, osz);
+                self.set_reg(reg, osz, res);
+            }
+            // Jcc rel8.
+            0x70..=0x7f => {
+                let rel = sign_extend(self.fetch(mem, &mut insn, 1)?, 1);
+                if self.condition(opcode & 0xf) {
+                    next_ip = Some(insn.ip.wrapping_add(rel) & mask(osz));
+                }
+            }
+            // Group 1: ALU r/m, imm.
+            0x80 | 0x81 | 0x83 => {
+                let size = if opcode == 0x80 { 1 } else { osz };
+                let (op, rm) = self.modrm(mem, &mut insn)?;
+                let a = self.read_op(mem, rm, size)?;
+                let b = if opcode == 0x83 {
+                    sign_extend(self.fetch(mem, &mut insn, 1)?, 1) & mask(size)
+                } else {
+                    self.fetch(mem, &mut insn, size)?
+                };
+                let res = self.alu(op, a, b, size);
+                if op != 7 {
+                    self.write_op(mem, rm, size, res)?;
+                }
+            }
+            // TEST r/m, reg.
+            0x84 | 0x85 => {
+                let size = if opcode == 0x84 { 1 } else { osz };
+                let (reg, rm) = self.modrm(mem, &mut insn)?;
+                let a = self.read_op(mem, rm, size)?;
+                let b = self.reg(reg, size);
+                self.alu(4, a, b, size);
+            }
+            // XCHG r/m, reg.
+            0x86 | 0x87 => {
+                let size = if opcode == 0x86 { 1 } else { osz };
+                let (reg, rm) = self.modrm(mem, &mut insn)?;
+                let a = self.read_op(mem, rm, size)?;
+                let b = self.reg(reg, size);
+                self.write_op(mem, rm, size, b)?;
+                self.set_reg(reg, size, a);
+            }
+            // MOV r/m, reg / reg, r/m.
+            0x88..=0x8b => {
+                let size = if opcode & 1 == 0 { 1 } else { osz };
+                let (reg, rm) = self.modrm(mem, &mut insn)?;
+                if opcode & 2 == 0 {
+                    let val = self.reg(reg, size);
+                    self.write_op(mem, rm, size, val)?;
+                } else {
+                    let val = self.read_op(mem, rm, size)?;
+                    self.set_reg(reg, size, val);
+                }
+            }
+            // MOV r/m, sreg.
+            0x8c => {
+                let (sreg, rm) = self.modrm(mem, &mut insn)?;
+                if sreg as usize > SEG_GS {
+                    return self.invalid_opcode(mem);
+                }
+                let sel = self.segs[sreg as usize].selector as u64;
+                let size = if matches!(rm, Operand::Reg(_)) {
+                    osz
+                } else {
+                    2
+                };
+                self.write_op(mem, rm, size, sel)?;
+            }
+            // LEA.
+            0x8d => {
+                let (reg, rm) = self.modrm(mem, &mut insn)?;
+                match rm {
+                    Operand::Mem(_, offset) => self.set_reg(reg, osz, offset),
+                    Operand::Reg(_) => return self.invalid_opcode(mem),
+                }
+            }
+            // MOV sreg, r/m.
+            0x8e => {
+                let (sreg, rm) = self.modrm(mem, &mut insn)?;
+                let sreg = sreg as usize;
+                if sreg == SEG_CS || sreg > SEG_GS {
+                    return self.invalid_opcode(mem);
+                }
+                let sel = self.read_op(mem, rm, 2)?;
+                self.load_segment(mem, sreg, sel as u16)?;
+            }
+            // POP r/m.
+            0x8f => {
+                let (_, rm) = self.modrm(mem, &mut insn)?;
+                let val = self.pop(mem, osz)?;
+                self.write_op(mem, rm, osz, val)?;
+            }
+            // NOP / XCHG acc, reg.
+            0x90 => {}
+            0x91..=0x97 => {
+                let reg = opcode & 7;
+                let (a, b) = (self.reg(0, osz), self.reg(reg, osz));
+                self.set_reg(0, osz, b);
+                self.set_reg(reg, osz, a);
+            }
+            // CBW/CWDE, CWD/CDQ.
+            0x98 => {
+                let half = osz / 2;
+                let val = sign_extend(self.reg(0, half), half);
+                self.set_reg(0, osz, val);
+            }
+            0x99 => {
+                let high = if self.reg(0, osz) & sign_bit(osz) != 0 {
+                    mask(osz)
+                } else {
+                    0
+                };
+                self.set_reg(2, osz, high);
+            }
+            // CALL far ptr.
+            0x9a => {
+                let ip = self.fetch(mem, &mut insn, osz)?;
+                let cs = self.fetch(mem, &mut insn, 2)?;
+                let old_cs = self.segs[SEG_CS].selector as u64;
+                self.push(mem, old_cs, osz)?;
+                self.push(mem, insn.ip, osz)?;
+                self.load_segment(mem, SEG_CS, cs as u16)?;
+                next_ip = Some(ip);
+            }
+            // PUSHF/POPF.
+            0x9c => {
+                let flags = self.rflags & mask(osz);
+                self.push(mem, flags, osz)?;
+            }
+            0x9d => {
+                let flags = self.pop(mem, osz)?;
+                self.set_flags(flags, osz);
+            }
+            // SAHF/LAHF.
+            0x9e => {
+                let ah = self.reg(4, 1);
+                self.rflags = (self.rflags & !0xd5) | (ah & 0xd5) | 0x2;
+            }
+            0x9f => {
+                let flags = self.rflags & 0xff;
+                self.set_reg(4, 1, flags);
+            }
+            // MOV acc, moffs / moffs, acc.
+            0xa0..=0xa3 => {
+                let size = if opcode & 1 == 0 { 1 } else { osz };
+                let asz = insn.asz;
+                let offset = self.fetch(mem, &mut insn, asz)?;
+                let seg = insn.seg.unwrap_or(SEG_DS);
+                if opcode & 2 == 0 {
+                    let val = self.read_mem(mem, seg, offset, size)?;
+                    self.set_reg(0, size, val);
+                } else {
+                    let val = self.reg(0, size);
+                    self.write_mem(mem, seg, offset, size, val)?;
+                }
+            }
+            // String instructions.
+            0xa4..=0xa7 | 0xaa..=0xaf => {
+                self.string_op(mem, &insn, opcode)?;
+            }
+            // TEST acc, imm.
+            0xa8 | 0xa9 => {
+                let size = if opcode == 0xa8 { 1 } else { osz };
+                let b = self.fetch(mem, &mut insn, size)?;
+                let a = self.reg(0, size);
+                self.alu(4, a, b, size);
+            }
+            // MOV reg, imm.
+            0xb0..=0xbf => {
+                let size = if opcode < 0xb8 { 1 } else { osz };
+                let val = self.fetch(mem, &mut insn, size)?;
+                self.set_reg(opcode & 7, size, val);
+            }
+            // Group 2: shifts and rotates.
+            0xc0 | 0xc1 | 0xd0..=0xd3 => {
+                let size = if opcode & 1 == 0 { 1 } else { osz };
+                let (op, rm) = self.modrm(mem, &mut insn)?;
+                let count = match opcode {
+                    0xc0 | 0xc1 => self.fetch(mem, &mut insn, 1)?,
+                    0xd0 | 0xd1 => 1,
+                    _ => self.reg(1, 1),
+                };
+                let a = self.read_op(mem, rm, size)?;
+                let res = self.shift(op, a, count & 0x1f, size);
+                self.write_op(mem, rm, size, res)?;
+            }
+            // RET near.
+            0xc2 | 0xc3 => {
+                let release = if opcode == 0xc2 {
+                    self.fetch(mem, &mut insn, 2)?
+                } else {
+                    0
+                };
+                let ip = self.pop(mem, osz)?;
+                self.release_stack(release);
+                next_ip = Some(ip);
+            }
+            // LES/LDS.
+            0xc4 | 0xc5 => {
+                let seg = if opcode == 0xc4 { SEG_ES } else { SEG_DS };
+                if !self.load_far_pointer(mem, &mut insn, seg)? {
+                    return self.invalid_opcode(mem);
+                }
+            }
+            // MOV r/m, imm.
+            0xc6 | 0xc7 => {
+                let size = if opcode == 0xc6 { 1 } else { osz };
+                let (_, rm) = self.modrm(mem, &mut insn)?;
+                let val = self.fetch(mem, &mut insn, size)?;
+                self.write_op(mem, rm, size, val)?;
+            }
+            // ENTER.
+            0xc8 => {
+                let bytes = self.fetch(mem, &mut insn, 2)?;
+                let level = self.fetch(mem, &mut insn, 1)? % 32;
+                let ssz = self.stack_size();
+                let bp = self.reg(5, osz);
+                self.push(mem, bp, osz)?;
+                let frame = self.reg(4, ssz);
+                // Copy the frame pointers of the enclosing levels.
+                let mut bp = self.reg(5, ssz);
+                for _ in 1..level {
+                    bp = bp.wrapping_sub(osz as u64) & mask(ssz);
+                    let val = self.read_mem(mem, SEG_SS, bp, osz)?;
+                    self.push(mem, val, osz)?;
+                }
+                if level > 0 {
+                    self.push(mem, frame, osz)?;
+                }
+                self.set_reg(5, osz, frame);
+                let sp = self.reg(4, ssz).wrapping_sub(bytes) & mask(ssz);
+                self.set_reg(4, ssz, sp);
+            }
+            // LEAVE.
+            0xc9 => {
+                let ssz = self.stack_size();
+                let bp = self.reg(5, ssz);
+                self.set_reg(4, ssz, bp);
+                let val = self.pop(mem, osz)?;
+                self.set_reg(5, osz, val);
+            }
+            // RET far.
+            0xca | 0xcb => {
+                let release = if opcode == 0xca {
+                    self.fetch(mem, &mut insn, 2)?
+                } else {
+                    0
+                };
+                let ip = self.pop(mem, osz)?;
+                let cs = self.pop(mem, osz)?;
+                self.release_stack(release);
+                self.load_segment(mem, SEG_CS, cs as u16)?;
+                next_ip = Some(ip);
+            }
+            // INT3, INT n.
+            0xcc | 0xcd => {
+                let vector = if opcode == 0xcc {
+                    3
+                } else {
+                    self.fetch(mem, &mut insn, 1)? as u8
+                };
+                self.deliver(mem, vector, None, Some(insn.ip))?;
+                return Ok(None);
+            }
+            // IRET.
+            0xcf => {
+                if !self.is_real_mode() {
+                    next_ip = Some(self.iret_protected(mem, osz)?);
+                } else {
+                    let ip = self.pop(mem, osz)?;
+                    let cs = self.pop(mem, osz)?;
+                    let flags = self.pop(mem, osz)?;
+                    self.load_segment(mem, SEG_CS, cs as u16)?;
+                    self.set_flags(flags, osz);
+                    next_ip = Some(ip);
+                }
+            }
+            // XLAT.
+            0xd7 => {
+                let asz = insn.asz;
+                let offset = self.reg(3, asz).wrapping_add(self.reg(0, 1)) & mask(asz);
+                let val = self.read_mem(mem, insn.seg.unwrap_or(SEG_DS), offset, 1)?;
+                self.set_reg(0, 1, val);
+            }
+            // LOOPNE, LOOPE, LOOP, JCXZ.
+            0xe0..=0xe3 => {
+                let rel = sign_extend(self.fetch(mem, &mut insn, 1)?, 1);
+                let asz = insn.asz;
+                let taken = if opcode == 0xe3 {
+                    self.reg(1, asz) == 0
+                } else {
+                    let count = self.reg(1, asz).wrapping_sub(1) & mask(asz);
+                    self.set_reg(1, asz, count);
+                    let zf = self.rflags & FLAG_ZF != 0;
+                    count != 0
+                        && match opcode {
+                            0xe0 => !zf,
+                            0xe1 => zf,
+                            _ => true,
+                        }
+                };
+                if taken {
+                    next_ip = Some(insn.ip.wrapping_add(rel) & mask(osz));
+                }
+            }
+            // IN/OUT.
+            0xe4..=0xe7 | 0xec..=0xef => {
+                let size = if opcode & 1 == 0 { 1 } else { osz };
+                let port = if opcode < 0xe8 {
+                    self.fetch(mem, &mut insn, 1)? as u16
+                } else {
+                    self.reg(2, 2) as u16
+                };
+                let width = access_width(size);
+                exit = Some(if opcode & 2 == 0 {
+                    LegacyExit::IoRead { port, width }
+                } else {
+                    LegacyExit::IoWrite {
+                        port,
+                        width,
+                        data: self.reg(0, size),
+                    }
+                });
+            }
+            // CALL rel.
+            0xe8 => {
+                let rel = sign_extend(self.fetch(mem, &mut insn, osz)?, osz);
+                self.push(mem, insn.ip, osz)?;
+                next_ip = Some(insn.ip.wrapping_add(rel) & mask(osz));
+            }
+            // JMP rel.
+            0xe9 | 0xeb => {
+                let size = if opcode == 0xeb { 1 } else { osz };
+                let rel = sign_extend(self.fetch(mem, &mut insn, size)?, size);
+                next_ip = Some(insn.ip.wrapping_add(rel) & mask(osz));
+            }
+            // JMP far ptr.
+            0xea => {
+                let ip = self.fetch(mem, &mut insn, osz)?;
+                let cs = self.fetch(mem, &mut insn, 2)?;
+                self.load_segment(mem, SEG_CS, cs as u16)?;
+                next_ip = Some(ip);
+            }
+            0xf4 => exit = Some(LegacyExit::Halt),
+            0xf5 => self.rflags ^= FLAG_CF,
+            // Group 3.
+            0xf6 | 0xf7 => {
+                let size = if opcode == 0xf6 { 1 } else { osz };
+                let (op, rm) = self.modrm(mem, &mut insn)?;
+                if !self.group3(mem, &mut insn, op, rm, size)? {
+                    // Divide error, the faulting instruction is restarted.
+                    self.deliver_interrupt(mem, 0, None)?;
+                    return Ok(None);
+                }
+            }
+            0xf8 => self.rflags &= !FLAG_CF,
+            0xf9 => self.rflags |= FLAG_CF,
+            0xfa => self.rflags &= !FLAG_IF,
+            0xfb => self.rflags |= FLAG_IF,
+            0xfc => self.rflags &= !FLAG_DF,
+            0xfd => self.rflags |= FLAG_DF,
+            // Group 4 and 5.
+            0xfe | 0xff => {
+                let size = if opcode == 0xfe { 1 } else { osz };
+                let (op, rm) = self.modrm(mem, &mut insn)?;
+                match op {
+                    0 | 1 => {
+                        let a = self.read_op(mem, rm, size)?;
+                        let res = self.inc_dec(a, op == 1, size);
+                        self.write_op(mem, rm, size, res)?;
+                    }
+                    2 | 4 if opcode == 0xff => {
+                        let target = self.read_op(mem, rm, osz)?;
+                        if op == 2 {
+                            self.push(mem, insn.ip, osz)?;
+                        }
+                        next_ip = Some(target);
+                    }
+                    3 | 5 if opcode == 0xff => {
+                        let Operand::Mem(seg, offset) = rm else {
+                            return self.invalid_opcode(mem);
+                        };
+                        let ip = self.read_mem(mem, seg, offset, osz)?;
+                        let cs = self.read_mem(mem, seg, offset + osz as u64, 2)?;
+                        if op == 3 {
+                            let old_cs = self.segs[SEG_CS].selector as u64;
+                            self.push(mem, old_cs, osz)?;
+                            self.push(mem, insn.ip, osz)?;
+                        }
+                        self.load_segment(mem, SEG_CS, cs as u16)?;
+                        next_ip = Some(ip);
+                    }
+                    6 if opcode == 0xff => {
+                        let val = self.read_op(mem, rm, osz)?;
+                        self.push(mem, val, osz)?;
+                    }
+                    _ => return self.invalid_opcode(mem),
+                }
+            }
+            _ => return self.invalid_opcode(mem),
+        }
+
+        self.rip = next_ip.unwrap_or(insn.ip);
+        Ok(exit)
+    }
+
+    fn step_0f<M: GuestMemory + ?Sized>(
+        &mut self,
+        mem: &mut M,
+        insn: &mut Insn,
+    ) -> AxResult<Option<LegacyExit>> {
+        let osz = insn.osz;
+        let opcode = self.fetch(mem, insn, 1)? as u8;
+        let mut next_ip = None;
+        match opcode {
+            // Group 6: SLDT, STR, LLDT, LTR.
+            0x00 => {
+                let (op, rm) = self.modrm(mem, insn)?;
+                if self.is_real_mode() || op > 3 {
+                    return self.invalid_opcode(mem);
+                }
+                if op < 2 {
+                    let sel = if op == 0 {
+                        self.ldtr.selector
+                    } else {
+                        self.tr.selector
+                    };
+                    let size = if matches!(rm, Operand::Reg(_)) {
+                        osz
+                    } else {
+                        2
+                    };
+                    self.write_op(mem, rm, size, sel as u64)?;
+                } else {
+                    if self.cpl() != 0 {
+                        return self.fault(mem, GENERAL_PROTECTION_VECTOR, Some(0));
+                    }
+                    let sel = self.read_op(mem, rm, 2)?;
+                    self.load_system_segment(mem, sel as u16, op == 3)?;
+                }
+            }
+            // Group 7: LGDT, LIDT, SMSW, LMSW, INVLPG.
+            0x01 => {
+                let (op, rm) = self.modrm(mem, insn)?;
+                match (op, rm) {
+                    (2 | 3, Operand::Mem(seg, offset)) => {
+                        let limit = self.read_mem(mem, seg, offset, 2)? as u32;
+                        let mut base = self.read_mem(mem, seg, offset + 2, 4)?;
+                        if osz == 2 {
+                            base &= 0xff_ffff;
+                        }
+                        if op == 2 {
+                            (self.gdtr_base, self.gdtr_limit) = (base, limit);
+                        } else {
+                            (self.idtr_base, self.idtr_limit) = (base, limit);
+                        }
+                    }
+                    (4, _) => {
+                        let size = if matches!(rm, Operand::Reg(_)) {
+                            osz
+                        } else {
+                            2
+                        };
+                        self.write_op(mem, rm, size, self.cr0)?;
+                    }
+                    (6, _) => {
+                        // LMSW can set but never clear PE.
+                        let val = self.read_op(mem, rm, 2)? & 0xf;
+                        self.cr0 = (self.cr0 & !0xe) | val;
+                    }
+                    (7, Operand::Mem(..)) => {}
+                    _ => return self.invalid_opcode(mem),
+                }
+            }
+            // CLTS.
+            0x06 => self.cr0 &= !(1 << 3),
+            // INVD, WBINVD.
+            0x08 | 0x09 => {}
+            // MOV reg, CRn / CRn, reg.
+            0x20 | 0x22 => {
+                let modrm = self.fetch(mem, insn, 1)? as u8;
+                let (cr, reg) = ((modrm >> 3) & 7, modrm & 7);
+                if opcode == 0x20 {
+                    let val = match cr {
+                        0 => self.cr0,
+                        2 => 0,
+                        3 => self.cr3,
+                        4 => self.cr4,
+                        _ => return self.invalid_opcode(mem),
+                    };
+                    self.set_reg(reg, 4, val);
+                } else {
+                    let val = self.reg(reg, 4);
+                    match cr {
+                        0 => self.cr0 = val,
+                        3 => self.cr3 = val,
+                        4 => self.cr4 = val,
+                        _ => return self.invalid_opcode(mem),
+                    }
+                }
+            }
+            // WRMSR/RDMSR. IA32_EFER is part of the emulated state, the
+            // others are left to the caller.
+            0x30 | 0x32 => {
+                if self.reg(1, 4) as u32 != MSR_IA32_EFER {
+                    // Skip the prefixes, the caller completes the 2-byte instruction.
+                    self.rip = insn.ip - 2;
+                    return Ok(Some(if opcode == 0x30 {
+                        LegacyExit::MsrWrite
+                    } else {
+                        LegacyExit::MsrRead
+                    }));
+                }
+                if opcode == 0x30 {
+                    self.efer = self.reg(0, 4) | (self.reg(2, 4) << 32);
+                } else {
+                    let efer = self.efer;
+                    self.set_reg(0, 4, efer & 0xffff_ffff);
+                    self.set_reg(2, 4, efer >> 32);
+                }
+            }
+            // CMOVcc.
+            0x40..=0x4f => {
+                let (reg, rm) = self.modrm(mem, insn)?;
+                let val = self.read_op(mem, rm, osz)?;
+                if self.condition(opcode & 0xf) {
+                    self.set_reg(reg, osz, val);
+                }
+            }
+            // Jcc rel16/32.
+            0x80..=0x8f => {
+                let rel = sign_extend(self.fetch(mem, insn, osz)?, osz);
+                if self.condition(opcode & 0xf) {
+                    next_ip = Some(insn.ip.wrapping_add(rel) & mask(osz));
+                }
+            }
+            // SETcc.
+            0x90..=0x9f => {
+                let (_, rm) = self.modrm(mem, insn)?;
+                let val = self.condition(opcode & 0xf) as u64;
+                self.write_op(mem, rm, 1, val)?;
+            }
+            // CPUID, completed by the caller like RDMSR/WRMSR.
+            0xa2 => {
+                self.rip = insn.ip - 2;
+                return Ok(Some(LegacyExit::Cpuid));
+            }
+            // PUSH/POP FS, GS.
+            0xa0 | 0xa8 => {
+                let seg = if opcode == 0xa0 { SEG_FS } else { SEG_GS };
+                let sel = self.segs[seg].selector as u64;
+                self.push(mem, sel, osz)?;
+            }
+            0xa1 | 0xa9 => {
+                let seg = if opcode == 0xa1 { SEG_FS } else { SEG_GS };
+                let sel = self.pop(mem, osz)?;
+                self.load_segment(mem, seg, sel as u16)?;
+            }
+            // BT, BTS, BTR, BTC r/m, reg.
+            0xa3 | 0xab | 0xb3 | 0xbb => {
+                let (reg, rm) = self.modrm(mem, insn)?;
+                let bit = self.reg(reg, osz);
+                self.bit_test(mem, rm, (opcode >> 3) & 3, bit, false, osz)?;
+            }
+            // SHLD, SHRD r/m, reg, imm8 / CL.
+            0xa4 | 0xa5 | 0xac | 0xad => {
+                let (reg, rm) = self.modrm(mem, insn)?;
+                let count = if opcode & 1 == 0 {
+                    self.fetch(mem, insn, 1)?
+                } else {
+                    self.reg(1, 1)
+                };
+                let a = self.read_op(mem, rm, osz)?;
+                let b = self.reg(reg, osz);
+                let res = self.double_shift(a, b, count & 0x1f, opcode < 0xac, osz);
+                self.write_op(mem, rm, osz, res)?;
+            }
+            // IMUL reg, r/m.
+            0xaf => {
+                let (reg, rm) = self.modrm(mem, insn)?;
+                let a = self.reg(reg, osz);
+                let b = self.read_op(mem, rm, osz)?;
+                let res = self.imul(a, b, osz);
+                self.set_reg(reg, osz, res);
+            }
+            // CMPXCHG r/m, reg.
+            0xb0 | 0xb1 => {
+                let size = if opcode == 0xb0 { 1 } else { osz };
+                let (reg, rm) = self.modrm(mem, insn)?;
+                let dest = self.read_op(mem, rm, size)?;
+                let acc = self.reg(0, size);
+                self.alu(7, acc, dest, size);
+                if acc == dest {
+                    let src = self.reg(reg, size);
+                    self.write_op(mem, rm, size, src)?;
+                } else {
+                    // The destination is written back even if unchanged.
+                    self.write_op(mem, rm, size, dest)?;
+                    self.set_reg(0, size, dest);
+                }
+            }
+            // LSS, LFS, LGS.
+            0xb2 | 0xb4 | 0xb5 => {
+                let seg = match opcode {
+                    0xb2 => SEG_SS,
+                    0xb4 => SEG_FS,
+                    _ => SEG_GS,
+                };
+                if !self.load_far_pointer(mem, insn, seg)? {
+                    return self.invalid_opcode(mem);
+                }
+            }
+            // MOVZX/MOVSX.
+            0xb6 | 0xb7 | 0xbe | 0xbf => {
+                let src_size = if opcode & 1 == 0 { 1 } else { 2 };
+                let (reg, rm) = self.modrm(mem, insn)?;
+                let mut val = self.read_op(mem, rm, src_size)?;
+                if opcode >= 0xbe {
+                    val = sign_extend(val, src_size);
+                }
+                self.set_reg(reg, osz, val & mask(osz));
+            }
+            // Group 8: BT, BTS, BTR, BTC r/m, imm8.
+            0xba => {
+                let (op, rm) = self.modrm(mem, insn)?;
+                let bit = self.fetch(mem, insn, 1)?;
+                if op < 4 {
+                    return self.invalid_opcode(mem);
+                }
+                self.bit_test(mem, rm, op & 3, bit, true, osz)?;
+            }
+            // BSF, BSR.
+            0xbc | 0xbd => {
+                let (reg, rm) = self.modrm(mem, insn)?;
+                let val = self.read_op(mem, rm, osz)?;
+                // The destination is left alone for a zero source.
+                self.rflags &= !FLAG_ZF;
+                if val == 0 {
+                    self.rflags |= FLAG_ZF;
+                } else if opcode == 0xbc {
+                    self.set_reg(reg, osz, val.trailing_zeros() as u64);
+                } else {
+                    self.set_reg(reg, osz, 63 - val.leading_zeros() as u64);
+                }
+            }
+            _ => return self.invalid_opcode(mem),
+        }
+        self.rip = next_ip.unwrap_or(insn.ip);
+        Ok(None)
+    }
+
+    /// Raise the fault `vector` at the instruction being executed.
+    fn fault<M: GuestMemory + ?Sized>(
+        &mut self,
+        mem: &mut M,
+        vector: u8,
+        err_code: Option<u32>,
+    ) -> AxResult<Option<LegacyExit>> {
+        self.deliver_interrupt(mem, vector, err_code)?;
+        Ok(None)
+    }
+
+    /// Raise #UD for an opcode that is invalid or not emulated, as the
+    /// hardware would for an invalid one.
+    fn invalid_opcode<M: GuestMemory + ?Sized>(
+        &mut self,
+        mem: &mut M,
+    ) -> AxResult<Option<LegacyExit>> {
+        debug!(
+            "legacy-mode interpreter raises #UD at {:04x}:{:x}",
+            self.segs[SEG_CS].selector, self.rip
+        );
+        self.fault(mem, INVALID_OPCODE_VECTOR, None)
+    }
+
+    fn gpr(&self, index: u8) -> u64 {
+        if index == 4 {
+            self.rsp
+        } else {
+            self.regs.get_reg_of_index(index)
+        }
+    }
+
+    fn set_gpr(&mut self, index: u8, value: u64) {
+        if index == 4 {
+            self.rsp = value;
+        } else {
+            self.regs.set_reg_of_index(index, value);
+        }
+    }
+
+    /// Read a register by its encoding. Byte registers 4-7 are AH, CH, DH and BH.
+    fn reg(&self, index: u8, size: usize) -> u64 {
+        if size == 1 && index >= 4 {
+            self.gpr(index - 4).get_bits(8..16)
+        } else {
+            self.gpr(index) & mask(size)
+        }
+    }
+
+    fn set_reg(&mut self, index: u8, size: usize, value: u64) {
+        if size == 1 && index >= 4 {
+            let mut reg = self.gpr(index - 4);
+            reg.set_bits(8..16, value & 0xff);
+            self.set_gpr(index - 4, reg);
+        } else if size == 4 {
+            self.set_gpr(index, value & 0xffff_ffff);
+        } else {
+            let mut reg = self.gpr(index);
+            reg.set_bits(0..size * 8, value & mask(size));
+            self.set_gpr(index, reg);
+        }
+    }
+
+    fn set_flags(&mut self, value: u64, size: usize) {
+        let writable = FLAGS_WRITABLE & mask(size);
+        self.rflags = (self.rflags & !writable) | (value & writable) | 0x2;
+    }
+
+    fn linear(&self, seg: usize, offset: u64) -> GuestPhysAddr {
+        // Without paging, linear addresses are guest-physical addresses.
+        GuestPhysAddr::from((self.segs[seg].base.wrapping_add(offset) & 0xffff_ffff) as usize)
+    }
+
+    fn read_mem<M: GuestMemory + ?Sized>(
+        &self,
+        mem: &M,
+        seg: usize,
+        offset: u64,
+        size: usize,
+    ) -> AxResult<u64> {
+        mem.read_uint(self.linear(seg, offset), size)
+    }
+
+    fn write_mem<M: GuestMemory + ?Sized>(
+        &self,
+        mem: &mut M,
+        seg: usize,
+        offset: u64,
+        size: usize,
+        value: u64,
+    ) -> AxResult {
+        mem.write_uint(self.linear(seg, offset), size, value)
+    }
+
+    fn fetch<M: GuestMemory + ?Sized>(
+        &self,
+        mem: &M,
+        insn: &mut Insn,
+        size: usize,
+    ) -> AxResult<u64> {
+        let val = self.read_mem(mem, SEG_CS, insn.ip, size)?;
+        insn.ip = insn.ip.wrapping_add(size as u64);
+        Ok(val)
+    }
+
+    fn stack_size(&self) -> usize {
+        if self.segs[SEG_SS].access_rights.get_bit(AR_DB) {
+            4
+        } else {
+            2
+        }
+    }
+
+    fn push<M: GuestMemory + ?Sized>(&mut self, mem: &mut M, value: u64, size: usize) -> AxResult {
+        let ssz = self.stack_size();
+        let sp = self.reg(4, ssz).wrapping_sub(size as u64) & mask(ssz);
+        self.write_mem(mem, SEG_SS, sp, size, value)?;
+        self.set_reg(4, ssz, sp);
+        Ok(())
+    }
+
+    fn pop<M: GuestMemory + ?Sized>(&mut self, mem: &mut M, size: usize) -> AxResult<u64> {
+        let ssz = self.stack_size();
+        let sp = self.reg(4, ssz);
+        let value = self.read_mem(mem, SEG_SS, sp, size)?;
+        self.set_reg(4, ssz, sp.wrapping_add(size as u64) & mask(ssz));
+        Ok(value)
+    }
+
+    fn release_stack(&mut self, bytes: u64) {
+        let ssz = self.stack_size();
+        let sp = self.reg(4, ssz).wrapping_add(bytes) & mask(ssz);
+        self.set_reg(4, ssz, sp);
+    }
+
+    /// Load a segment register.
+    ///
+    /// In real mode only the selector and base change, which keeps the limit
+    /// set up by protected mode and gives "big real mode".
+    fn load_segment<M: GuestMemory + ?Sized>(
+        &mut self,
+        mem: &M,
+        seg: usize,
+        selector: u16,
+    ) -> AxResult {
+        if self.cr0 & CR0_PE == 0 {
+            let cache = &mut self.segs[seg];
+            cache.selector = selector;
+            cache.base = (selector as u64) << 4;
+            cache.access_rights &= !AR_UNUSABLE;
+            return Ok(());
+        }
+        if selector & !3 == 0 {
+            if seg == SEG_CS || seg == SEG_SS {
+                return ax_err!(InvalidInput, "null selector loaded into CS or SS");
+            }
+            self.segs[seg] = SegmentCache {
+                selector,
+                access_rights: AR_UNUSABLE,
+                ..Default::default()
+            };
+            return Ok(());
+        }
+        let desc = self.descriptor(mem, selector)?;
+        let mut cache = SegmentCache::from_descriptor(selector, desc);
+        // Code and data segments are marked accessed when loaded.
+        if cache.access_rights.get_bit(4) {
+            cache.access_rights.set_bit(0, true);
+        }
+        self.segs[seg] = cache;
+        Ok(())
+    }
+
+    /// Read the descriptor of `selector` from the GDT, or from the LDT if its
+    /// table indicator is set.
+    fn descriptor<M: GuestMemory + ?Sized>(&self, mem: &M, selector: u16) -> AxResult<u64> {
+        let (base, limit) = if selector.get_bit(2) {
+            if self.ldtr.access_rights & AR_UNUSABLE != 0 {
+                return ax_err!(InvalidInput, "LDT selector without an LDT");
+            }
+            (self.ldtr.base, self.ldtr.limit)
+        } else {
+            (self.gdtr_base, self.gdtr_limit)
+        };
+        let index = (selector & !7) as u64;
+        if index + 7 > limit as u64 {
+            return ax_err!(InvalidInput, "selector beyond descriptor table limit");
+        }
+        mem.read_uint(GuestPhysAddr::from((base + index) as usize), 8)
+    }
+
+    /// LLDT, or LTR if `task`, loading a system segment from the GDT. LTR
+    /// marks the TSS busy. (SDM Vol. 3A, Section 7.2.4)
+    fn load_system_segment<M: GuestMemory + ?Sized>(
+        &mut self,
+        mem: &mut M,
+        selector: u16,
+        task: bool,
+    ) -> AxResult {
+        if selector & !3 == 0 && !task {
+            self.ldtr = SegmentCache {
+                selector,
+                access_rights: AR_UNUSABLE,
+                ..Default::default()
+            };
+            return Ok(());
+        }
+        if selector & !3 == 0 || selector.get_bit(2) {
+            return ax_err!(InvalidInput, "system segment selector not in the GDT");
+        }
+        let mut desc = self.descriptor(mem, selector)?;
+        // An available 16-bit or 32-bit TSS, or an LDT.
+        let valid = match desc.get_bits(40..45) {
+            0x1 | 0x9 => task,
+            0x2 => !task,
+            _ => false,
+        };
+        if !valid || !desc.get_bit(47) {
+            return ax_err!(InvalidInput, "invalid system segment descriptor");
+        }
+        if task {
+            desc.set_bit(41, true);
+            let entry = self.gdtr_base + (selector & !7) as u64;
+            mem.write_uint(GuestPhysAddr::from(entry as usize), 8, desc)?;
+            self.tr = SegmentCache::from_descriptor(selector, desc);
+        } else {
+            self.ldtr = SegmentCache::from_descriptor(selector, desc);
+        }
+        Ok(())
+    }
+
+    /// LDS, LES, LSS, LFS and LGS.
+    ///
+    /// Returns `false` for a register operand, which is invalid.
+    fn load_far_pointer<M: GuestMemory + ?Sized>(
+        &mut self,
+        mem: &mut M,
+        insn: &mut Insn,
+        seg: usize,
+    ) -> AxResult<bool> {
+        let (reg, rm) = self.modrm(mem, insn)?;
+        let Operand::Mem(src_seg, offset) = rm else {
+            return Ok(false);
+        };
+        let val = self.read_mem(mem, src_seg, offset, insn.osz)?;
+        let sel = self.read_mem(mem, src_seg, offset + insn.osz as u64, 2)?;
+        self.load_segment(mem, seg, sel as u16)?;
+        self.set_reg(reg, insn.osz, val);
+        Ok(true)
+    }
+
+    /// Decode a ModR/M byte (and SIB/displacement), returning the `reg`
+    /// field and the r/m operand. (SDM Vol. 2A, Section 2.1.5)
+    fn modrm<M: GuestMemory + ?Sized>(&self, mem: &M, insn: &mut Insn) -> AxResult<(u8, Operand)> {
+        let modrm = self.fetch(mem, insn, 1)? as u8;
+        let (md, reg, rm) = (modrm >> 6, (modrm >> 3) & 7, modrm & 7);
+        if md == 3 {
+            return Ok((reg, Operand::Reg(rm)));
+        }
+
+        let mut seg = SEG_DS;
+        let offset = if insn.asz == 2 {
+            let (base, default_ss) = match rm {
+                0 => (self.reg(3, 2) + self.reg(6, 2), false),
+                1 => (self.reg(3, 2) + self.reg(7, 2), false),
+                2 => (self.reg(5, 2) + self.reg(6, 2), true),
+                3 => (self.reg(5, 2) + self.reg(7, 2), true),
+                4 => (self.reg(6, 2), false),
+                5 => (self.reg(7, 2), false),
+                6 if md == 0 => (self.fetch(mem, insn, 2)?, false),
+                6 => (self.reg(5, 2), true),
+                _ => (self.reg(3, 2), false),
+            };
+            if default_ss {
+                seg = SEG_SS;
+            }
+            let disp = match md {
+                1 => sign_extend(self.fetch(mem, insn, 1)?, 1),
+                2 => self.fetch(mem, insn, 2)?,
+                _ => 0,
+            };
+            base.wrapping_add(disp) & 0xffff
+        } else {
+            let base = if rm == 4 {
+                let sib = self.fetch(mem, insn, 1)? as u8;
+                let (scale, index, base) = (sib >> 6, (sib >> 3) & 7, sib & 7);
+                let index = if index == 4 {
+                    0
+                } else {
+                    self.reg(index, 4) << scale
+                };
+                let base = if base == 5 && md == 0 {
+                    self.fetch(mem, insn, 4)?
+                } else {
+                    if base == 4 || base == 5 {
+                        seg = SEG_SS;
+                    }
+                    self.reg(base, 4)
+                };
+                base.wrapping_add(index)
+            } else if rm == 5 && md == 0 {
+                self.fetch(mem, insn, 4)?
+            } else {
+                if rm == 5 {
+                    seg = SEG_SS;
+                }
+                self.reg(rm, 4)
+            };
+            let disp = match md {
+                1 => sign_extend(self.fetch(mem, insn, 1)?, 1),
+                2 => self.fetch(mem, insn, 4)?,
+                _ => 0,
+            };
+            base.wrapping_add(disp) & 0xffff_ffff
+        };
+        Ok((reg, Operand::Mem(insn.seg.unwrap_or(seg), offset)))
+    }
+
+    fn read_op<M: GuestMemory + ?Sized>(&self, mem: &M, op: Operand, size: usize) -> AxResult<u64> {
+        match op {
+            Operand::Reg(reg) => Ok(self.reg(reg, size)),
+            Operand::Mem(seg, offset) => self.read_mem(mem, seg, offset, size),
+        }
+    }
+
+    fn write_op<M: GuestMemory + ?Sized>(
+        &mut self,
+        mem: &mut M,
+        op: Operand,
+        size: usize,
+        value: u64,
+    ) -> AxResult {
+        match op {
+            Operand::Reg(reg) => {
+                self.set_reg(reg, size, value);
+                Ok(())
+            }
+            Operand::Mem(seg, offset) => self.write_mem(mem, seg, offset, size, value),
+        }
+    }
+
+    fn set_result_flags(&mut self, res: u64, size: usize) {
+        let res = res & mask(size);
+        self.rflags &= !(FLAG_ZF | FLAG_SF | FLAG_PF);
+        if res == 0 {
+            self.rflags |= FLAG_ZF;
+        }
+        if res & sign_bit(size) != 0 {
+            self.rflags |= FLAG_SF;
+        }
+        if (res as u8).count_ones().is_multiple_of(2) {
+            self.rflags |= FLAG_PF;
+        }
+    }
+
+    /// ADD, OR, ADC, SBB, AND, SUB, XOR and CMP, selected by `op` (0-7).
+    fn alu(&mut self, op: u8, a: u64, b: u64, size: usize) -> u64 {
+        let carry = self.rflags & FLAG_CF;
+        let (res, cf, of) = match op {
+            0 | 2 => {
+                let c = if op == 2 { carry } else { 0 };
+                let res = a + b + c;
+                let of = (a ^ res) & (b ^ res) & sign_bit(size) != 0;
+                (res, res > mask(size), of)
+            }
+            3 | 5 | 7 => {
+                let c = if op == 3 { carry } else { 0 };
+                let res = a.wrapping_sub(b).wrapping_sub(c);
+                let of = (a ^ b) & (a ^ res) & sign_bit(size) != 0;
+                (res, b + c > a, of)
+            }
+            1 => (a | b, false, false),
+            4 => (a & b, false, false),
+            _ => (a ^ b, false, false),
+        };
+        self.rflags &= !(FLAG_CF | FLAG_OF | FLAG_AF);
+        if cf {
+            self.rflags |= FLAG_CF;
+        }
+        if of {
+            self.rflags |= FLAG_OF;
+        }
+        if (a ^ b ^ res) & 0x10 != 0 && !matches!(op, 1 | 4 | 6) {
+            self.rflags |= FLAG_AF;
+        }
+        self.set_result_flags(res, size);
+        if op == 7 { a } else { res & mask(size) }
+    }
+
+    fn inc_dec(&mut self, a: u64, dec: bool, size: usize) -> u64 {
+        let carry = self.rflags & FLAG_CF;
+        let res = self.alu(if dec { 5 } else { 0 }, a, 1, size);
+        self.rflags = (self.rflags & !FLAG_CF) | carry;
+        res
+    }
+
+    /// ROL, ROR, RCL, RCR, SHL, SHR, SAL and SAR, selected by `op` (0-7).
+    fn shift(&mut self, op: u8, a: u64, count: u64, size: usize) -> u64 {
+        if count == 0 {
+            return a;
+        }
+        let bits = size as u64 * 8;
+        let msb = |v: u64| v & sign_bit(size) != 0;
+        let (res, cf) = match op {
+            0 => {
+                let n = count % bits;
+                let res = ((a << n) | (a >> ((bits - n) % bits))) & mask(size);
+                (res, res & 1 != 0)
+            }
+            1 => {
+                let n = count % bits;
+                let res = ((a >> n) | (a << ((bits - n) % bits))) & mask(size);
+                (res, msb(res))
+            }
+            2 | 3 => {
+                let mut res = a;
+                let mut cf = self.rflags & FLAG_CF != 0;
+                for _ in 0..count % (bits + 1) {
+                    if op == 2 {
+                        let out = msb(res);
+                        res = ((res << 1) | cf as u64) & mask(size);
+                        cf = out;
+                    } else {
+                        let out = res & 1 != 0;
+                        res = (res >> 1) | if cf { sign_bit(size) } else { 0 };
+                        cf = out;
+                    }
+                }
+                (res, cf)
+            }
+            4 | 6 => {
+                let res = (a << count) & mask(size);
+                (res, count <= bits && (a >> (bits - count)) & 1 != 0)
+            }
+            5 => (a >> count, (a >> (count - 1)) & 1 != 0),
+            _ => {
+                let res = (sign_extend(a, size) as i64 >> count.min(63)) as u64 & mask(size);
+                let cf = (sign_extend(a, size) as i64 >> (count - 1).min(63)) & 1 != 0;
+                (res, cf)
+            }
+        };
+        let of = match op {
+            0 | 2 | 4 | 6 => msb(res) != cf,
+            1 | 3 => msb(res) != (res & (sign_bit(size) >> 1) != 0),
+            5 => msb(a),
+            _ => false,
+        };
+        if op >= 4 {
+            self.set_result_flags(res, size);
+        }
+        self.rflags &= !(FLAG_CF | FLAG_OF);
+        if cf {
+            self.rflags |= FLAG_CF;
+        }
+        if of {
+            self.rflags |= FLAG_OF;
+        }
+        res
+    }
+
+    /// SHLD and SHRD, shifting the bits of `b` into `a`.
+    fn double_shift(&mut self, a: u64, b: u64, count: u64, left: bool, size: usize) -> u64 {
+        if count == 0 {
+            return a;
+        }
+        let bits = size as u64 * 8;
+        let (res, cf) = if left {
+            let wide = (a << bits) | b;
+            (
+                (wide << count) >> bits,
+                (wide >> (2 * bits - count)) & 1 != 0,
+            )
+        } else {
+            let wide = (b << bits) | a;
+            (wide >> count, (wide >> (count - 1)) & 1 != 0)
+        };
+        let res = res & mask(size);
+        self.set_result_flags(res, size);
+        self.rflags &= !(FLAG_CF | FLAG_OF);
+        if cf {
+            self.rflags |= FLAG_CF;
+        }
+        if (res ^ a) & sign_bit(size) != 0 {
+            self.rflags |= FLAG_OF;
+        }
+        res
+    }
+
+    /// BT, BTS, BTR and BTC, selected by `op` (0-3).
+    ///
+    /// A bit offset from a register addresses a bit string in memory, other
+    /// offsets wrap around in the operand.
+    fn bit_test<M: GuestMemory + ?Sized>(
+        &mut self,
+        mem: &mut M,
+        rm: Operand,
+        op: u8,
+        bit: u64,
+        imm: bool,
+        size: usize,
+    ) -> AxResult {
+        let bits = size as i64 * 8;
+        let (rm, bit) = match rm {
+            Operand::Mem(seg, offset) if !imm => {
+                let bit = sign_extend(bit, size) as i64;
+                let offset = offset.wrapping_add((bit.div_euclid(bits) * size as i64) as u64);
+                (Operand::Mem(seg, offset), bit.rem_euclid(bits) as u64)
+            }
+            _ => (rm, bit % bits as u64),
+        };
+        let a = self.read_op(mem, rm, size)?;
+        self.rflags &= !FLAG_CF;
+        if a & (1 << bit) != 0 {
+            self.rflags |= FLAG_CF;
+        }
+        let res = match op {
+            1 => a | (1 << bit),
+            2 => a & !(1 << bit),
+            3 => a ^ (1 << bit),
+            _ => return Ok(()),
+        };
+        self.write_op(mem, rm, size, res)
+    }
+
+    /// The two- and three-operand IMUL, truncated to `size`.
+    fn imul(&mut self, a: u64, b: u64, size: usize) -> u64 {
+        let res = sign_extend(a, size) as i64 * sign_extend(b, size) as i64;
+        self.rflags &= !(FLAG_CF | FLAG_OF);
+        if sign_extend(res as u64, size) as i64 != res {
+            self.rflags |= FLAG_CF | FLAG_OF;
+        }
+        res as u64 & mask(size)
+    }
+
+    /// TEST, NOT, NEG, MUL, IMUL, DIV and IDIV with an r/m operand.
+    ///
+    /// Returns `false` on a divide error.
+    fn group3<M: GuestMemory + ?Sized>(
+        &mut self,
+        mem: &mut M,
+        insn: &mut Insn,
+        op: u8,
+        rm: Operand,
+        size: usize,
+    ) -> AxResult<bool> {
+        let a = self.read_op(mem, rm, size)?;
+        let bits = size * 8;
+        // The double-width accumulator: AX, DX:AX or EDX:EAX.
+        let (lo_reg, hi_reg) = if size == 1 { (0, 4) } else { (0, 2) };
+        match op {
+            0 | 1 => {
+                let b = self.fetch(mem, insn, size)?;
+                self.alu(4, a, b, size);
+            }
+            2 => self.write_op(mem, rm, size, !a & mask(size))?,
+            3 => {
+                let res = self.alu(5, 0, a, size);
+                self.write_op(mem, rm, size, res)?;
+            }
+            4 | 5 => {
+                let acc = self.reg(0, size);
+                let (res, overflow) = if op == 4 {
+                    let res = acc * a;
+                    (res, res >> bits != 0)
+                } else {
+                    let res = sign_extend(acc, size) as i64 * sign_extend(a, size) as i64;
+                    (res as u64, sign_extend(res as u64, size) as i64 != res)
+                };
+                let (lo, hi) = (res & mask(size), (res >> bits) & mask(size));
+                self.set_reg(lo_reg, size, lo);
+                self.set_reg(hi_reg, size, hi);
+                self.rflags &= !(FLAG_CF | FLAG_OF);
+                if overflow {
+                    self.rflags |= FLAG_CF | FLAG_OF;
+                }
+            }
+            _ => {
+                if a == 0 {
+                    return Ok(false);
+                }
+                let dividend = (self.reg(hi_reg, size) << bits) | self.reg(lo_reg, size);
+                let (quot, rem) = if op == 6 {
+                    let (q, r) = (dividend / a, dividend % a);
+                    if q > mask(size) {
+                        return Ok(false);
+                    }
+                    (q, r)
+                } else {
+                    let n = sign_extend(dividend, size * 2) as i64;
+                    let d = sign_extend(a, size) as i64;
+                    let (q, r) = (n.wrapping_div(d), n.wrapping_rem(d));
+                    let limit = 1i64 << (bits - 1);
+                    if q >= limit || q < -limit {
+                        return Ok(false);
+                    }
+                    (q as u64, r as u64)
+                };
+                self.set_reg(lo_reg, size, quot & mask(size));
+                self.set_reg(hi_reg, size, rem & mask(size));
+            }
+        }
+        Ok(true)
+    }
+
+    /// Evaluate the condition encoded in the low 4 bits of a Jcc opcode.
+    fn condition(&self, cc: u8) -> bool {
+        let f = |flag| self.rflags & flag != 0;
+        let res = match cc >> 1 {
+            0 => f(FLAG_OF),
+            1 => f(FLAG_CF),
+            2 => f(FLAG_ZF),
+            3 => f(FLAG_CF) || f(FLAG_ZF),
+            4 => f(FLAG_SF),
+            5 => f(FLAG_PF),
+            6 => f(FLAG_SF) != f(FLAG_OF),
+            _ => f(FLAG_ZF) || f(FLAG_SF) != f(FLAG_OF),
+        };
+        res != (cc & 1 != 0)
+    }
+
+    /// MOVS, CMPS, STOS, LODS and SCAS, with optional REP prefixes.
+    fn string_op<M: GuestMemory + ?Sized>(
+        &mut self,
+        mem: &mut M,
+        insn: &Insn,
+        opcode: u8,
+    ) -> AxResult {
+        let size = if opcode & 1 == 0 { 1 } else { insn.osz };
+        let asz = insn.asz;
+        let src_seg = insn.seg.unwrap_or(SEG_DS);
+        let delta = self.string_delta(size);
+        let compares = matches!(opcode, 0xa6 | 0xa7 | 0xae | 0xaf);
+
+        loop {
+            if insn.rep.is_some() && self.reg(1, asz) == 0 {
+                break;
+            }
+            let (si, di) = (self.reg(6, asz), self.reg(7, asz));
+            match opcode {
+                0xa4 | 0xa5 => {
+                    let val = self.read_mem(mem, src_seg, si, size)?;
+                    self.write_mem(mem, SEG_ES, di, size, val)?;
+                }
+                0xa6 | 0xa7 => {
+                    let a = self.read_mem(mem, src_seg, si, size)?;
+                    let b = self.read_mem(mem, SEG_ES, di, size)?;
+                    self.alu(7, a, b, size);
+                }
+                0xaa | 0xab => {
+                    let val = self.reg(0, size);
+                    self.write_mem(mem, SEG_ES, di, size, val)?;
+                }
+                0xac | 0xad => {
+                    let val = self.read_mem(mem, src_seg, si, size)?;
+                    self.set_reg(0, size, val);
+                }
+                _ => {
+                    let a = self.reg(0, size);
+                    let b = self.read_mem(mem, SEG_ES, di, size)?;
+                    self.alu(7, a, b, size);
+                }
+            }
+            if matches!(opcode, 0xa4 | 0xa5 | 0xa6 | 0xa7 | 0xac | 0xad) {
+                self.set_reg(6, asz, si.wrapping_add(delta) & mask(asz));
+            }
+            if !matches!(opcode, 0xac | 0xad) {
+                self.set_reg(7, asz, di.wrapping_add(delta) & mask(asz));
+            }
+            let Some(repe) = insn.rep else {
+                break;
+            };
+            let count = self.reg(1, asz).wrapping_sub(1) & mask(asz);
+            self.set_reg(1, asz, count);
+            if compares && (self.rflags & FLAG_ZF != 0) != repe {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// INS and OUTS, one element at a time.
+    ///
+    /// `INS` first exits for the input, keeping the accumulator in
+    /// [`Self::pending_input`], and stores the input when run again.
+    fn string_io<M: GuestMemory + ?Sized>(
+        &mut self,
+        mem: &mut M,
+        insn: &Insn,
+        opcode: u8,
+    ) -> AxResult<Option<LegacyExit>> {
+        let size = if opcode & 1 == 0 { 1 } else { insn.osz };
+        let asz = insn.asz;
+        if insn.rep.is_some() && self.reg(1, asz) == 0 {
+            return Ok(None);
+        }
+        let port = self.reg(2, 2) as u16;
+        let width = access_width(size);
+        let delta = self.string_delta(size);
+        let exit = if opcode < 0x6e {
+            let Some(acc) = self.pending_input.take() else {
+                self.pending_input = Some(self.gpr(0));
+                return Ok(Some(LegacyExit::IoRead { port, width }));
+            };
+            let val = self.reg(0, size);
+            self.set_gpr(0, acc);
+            let di = self.reg(7, asz);
+            self.write_mem(mem, SEG_ES, di, size, val)?;
+            self.set_reg(7, asz, di.wrapping_add(delta) & mask(asz));
+            None
+        } else {
+            let si = self.reg(6, asz);
+            let data = self.read_mem(mem, insn.seg.unwrap_or(SEG_DS), si, size)?;
+            self.set_reg(6, asz, si.wrapping_add(delta) & mask(asz));
+            Some(LegacyExit::IoWrite { port, width, data })
+        };
+        if insn.rep.is_some() {
+            let count = self.reg(1, asz).wrapping_sub(1) & mask(asz);
+            self.set_reg(1, asz, count);
+        }
+        Ok(exit)
+    }
+
+    /// How string instructions move `SI`/`DI` per element, by `RFLAGS.DF`.
+    fn string_delta(&self, size: usize) -> u64 {
+        if self.rflags & FLAG_DF != 0 {
+            (size as u64).wrapping_neg()
+        } else {
+            size as u64
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vmx::guest_mem::FlatMemory;
+    use alloc::vec;
+
+    fn reset_state(cs: u16, ip: u64) -> LegacyCpuState {
+        let mut cpu = LegacyCpuState {
+            rip: ip,
+            rflags: 0x2,
+            idtr_limit: 0x3ff,
+            ..Default::default()
+        };
+        for seg in cpu.segs.iter_mut() {
+            *seg = SegmentCache {
+                selector: 0,
+                base: 0,
+                limit: 0xffff,
+                access_rights: 0x93,
+            };
+        }
+        cpu.segs[SEG_CS] = SegmentCache {
+            selector: cs,
+            base: (cs as u64) << 4,
+            limit: 0xffff,
+            access_rights: 0x9b,
+        };
+        cpu
+    }
+
+    fn load(code: &[u8], at: usize) -> FlatMemory {
+        let mut mem = FlatMemory(vec![0; 0x10_0000]);
+        mem.0[at..at + code.len()].copy_from_slice(code);
+        mem
+    }
+
+    #[test]
+    fn test_arith_and_io_exit() {
+        // mov ax, 0x1234; add ax, 0x0100; mov dx, 0x3f8; out dx, al
+        let code = [0xb8, 0x34, 0x12, 0x05, 0x00, 0x01, 0xba, 0xf8, 0x03, 0xee];
+        let mut mem = load(&code, 0x7c00);
+        let mut cpu = reset_state(0, 0x7c00);
+        let exit = cpu.run(&mut mem, 16).unwrap();
+        assert_eq!(
+            exit,
+            Some(LegacyExit::IoWrite {
+                port: 0x3f8,
+                width: AccessWidth::Byte,
+                data: 0x34,
+            })
+        );
+        assert_eq!(cpu.regs.rax, 0x1334);
+        assert_eq!(cpu.rip, 0x7c00 + code.len() as u64);
+    }
+
+    #[test]
+    fn test_call_ret_and_stack() {
+        // mov sp, 0x8000; call +1; hlt; push 0x55; pop bx; ret
+        let code = [
+            0xbc, 0x00, 0x80, 0xe8, 0x01, 0x00, 0xf4, 0x6a, 0x55, 0x5b, 0xc3,
+        ];
+        let mut mem = load(&code, 0x7c00);
+        let mut cpu = reset_state(0, 0x7c00);
+        assert_eq!(cpu.run(&mut mem, 16).unwrap(), Some(LegacyExit::Halt));
+        assert_eq!(cpu.regs.rbx, 0x55);
+        assert_eq!(cpu.rsp, 0x8000);
+        assert_eq!(cpu.rip, 0x7c07);
+    }
+
+    #[test]
+    fn test_rep_movsb_and_loop() {
+        // mov si, 0x100; mov di, 0x200; mov cx, 4; rep movsb; hlt
+        let code = [
+            0xbe, 0x00, 0x01, 0xbf, 0x00, 0x02, 0xb9, 0x04, 0x00, 0xf3, 0xa4, 0xf4,
+        ];
+        let mut mem = load(&code, 0x7c00);
+        mem.0[0x100..0x104].copy_from_slice(b"boot");
+        let mut cpu = reset_state(0, 0x7c00);
+        assert_eq!(cpu.run(&mut mem, 16).unwrap(), Some(LegacyExit::Halt));
+        assert_eq!(&mem.0[0x200..0x204], b"boot");
+        assert_eq!(cpu.regs.rcx, 0);
+        assert_eq!(cpu.regs.rdi, 0x204);
+    }
+
+    #[test]
+    fn test_software_interrupt_through_ivt() {
+        // int 0x10 at 0000:7c00, handler at f000:0100 is `iret`.
+        let mut mem = load(&[0xcd, 0x10, 0xf4], 0x7c00);
+        mem.0[0x40..0x44].copy_from_slice(&[0x00, 0x01, 0x00, 0xf0]);
+        mem.0[0xf0100] = 0xcf;
+        let mut cpu = reset_state(0, 0x7c00);
+        cpu.rsp = 0x7000;
+        cpu.rflags |= FLAG_IF;
+        cpu.step(&mut mem).unwrap();
+        assert_eq!(cpu.segs[SEG_CS].base, 0xf0000);
+        assert_eq!(cpu.rip, 0x100);
+        assert!(!cpu.interrupts_enabled());
+        assert_eq!(cpu.run(&mut mem, 4).unwrap(), Some(LegacyExit::Halt));
+        assert!(cpu.interrupts_enabled());
+        assert_eq!(cpu.rsp, 0x7000);
+    }
+
+    #[test]
+    fn test_big_real_mode_and_paging_switch() {
+        // GDT at 0x500: null, flat 4G data (0x08), flat 4G 32-bit code (0x10).
+        let gdt: [u64; 3] = [0, 0x00cf_9300_0000_ffff, 0x00cf_9b00_0000_ffff];
+        let code = [
+            0x0f, 0x01, 0x16, 0x00, 0x06, // lgdt [0x600]
+            0x0f, 0x20, 0xc0, // mov eax, cr0
+            0x0c, 0x01, // or al, 1
+            0x0f, 0x22, 0xc0, // mov cr0, eax
+            0xbb, 0x08, 0x00, // mov bx, 0x08
+            0x8e, 0xdb, // mov ds, bx
+            0x24, 0xfe, // and al, 0xfe
+            0x0f, 0x22, 0xc0, // mov cr0, eax
+            0x31, 0xdb, // xor bx, bx
+            0x8e, 0xdb, // mov ds, bx
+            0x66, 0x67, 0xc7, 0x05, 0x00, 0x00, 0x01, 0x00, 0x78, 0x56, 0x34, 0x12,
+            // mov dword [0x10000], 0x12345678 (above 64K in big real mode)
+            0xf4,
+        ];
+        let mut mem = load(&code, 0x7c00);
+        for (i, desc) in gdt.iter().enumerate() {
+            mem.0[0x500 + i * 8..0x508 + i * 8].copy_from_slice(&desc.to_le_bytes());
+        }
+        mem.0[0x600..0x606].copy_from_slice(&[0x17, 0x00, 0x00, 0x05, 0x00, 0x00]);
+        let mut cpu = reset_state(0, 0x7c00);
+        assert_eq!(cpu.run(&mut mem, 32).unwrap(), Some(LegacyExit::Halt));
+        assert!(cpu.is_real_mode());
+        assert_eq!(cpu.segs[SEG_DS].limit, 0xffff_ffff);
+        assert_eq!(&mem.0[0x10000..0x10004], &0x12345678u32.to_le_bytes());
+
+        // mov eax, 0x80000001; mov cr0, eax
+        let mut mem = load(
+            &[0x66, 0xb8, 0x01, 0x00, 0x00, 0x80, 0x0f, 0x22, 0xc0],
+            0x7c00,
+        );
+        let mut cpu = reset_state(0, 0x7c00);
+        assert_eq!(
+            cpu.run(&mut mem, 8).unwrap(),
+            Some(LegacyExit::PagingEnabled)
+        );
+    }
+
+    /// Flat 32-bit ring-0 code (0x08) and data (0x10), ring-3 code (0x1b) and
+    /// data (0x23), and a TSS (0x28) at 0x2000 with the ring-0 stack at
+    /// 0x10:0x9000. In the IDT at 0x1000, vector 0x20 has an interrupt gate
+    /// usable from ring 3 to 0x3000 (`iret`), vector 13 one to 0x3100
+    /// (`add esp, 4; iret`).
+    fn protected_mode(code: &[u8], at: usize) -> (LegacyCpuState, FlatMemory) {
+        let mut mem = load(code, at);
+        let gdt: [u64; 6] = [
+            0,
+            0x00cf_9b00_0000_ffff,
+            0x00cf_9300_0000_ffff,
+            0x00cf_fb00_0000_ffff,
+            0x00cf_f300_0000_ffff,
+            0x0000_8b00_2000_0067,
+        ];
+        for (i, desc) in gdt.iter().enumerate() {
+            mem.0[0x500 + i * 8..0x508 + i * 8].copy_from_slice(&desc.to_le_bytes());
+        }
+        mem.0[0x2004..0x2008].copy_from_slice(&0x9000u32.to_le_bytes());
+        mem.0[0x2008..0x200a].copy_from_slice(&0x10u16.to_le_bytes());
+        let gates = [
+            (0x20, 0x0000_ee00_0008_3000u64),
+            (13, 0x0000_8e00_0008_3100),
+        ];
+        for (vector, gate) in gates {
+            mem.0[0x1000 + vector * 8..0x1008 + vector * 8].copy_from_slice(&gate.to_le_bytes());
+        }
+        mem.0[0x3000] = 0xcf;
+        mem.0[0x3100..0x3104].copy_from_slice(&[0x83, 0xc4, 0x04, 0xcf]);
+
+        let mut cpu = LegacyCpuState {
+            rip: at as u64,
+            rsp: 0x8000,
+            rflags: 0x202,
+            cr0: CR0_PE,
+            gdtr_base: 0x500,
+            gdtr_limit: 0x2f,
+            idtr_base: 0x1000,
+            idtr_limit: 0x7ff,
+            ldtr: SegmentCache {
+                access_rights: AR_UNUSABLE,
+                ..Default::default()
+            },
+            tr: SegmentCache {
+                selector: 0x28,
+                base: 0x2000,
+                limit: 0x67,
+                access_rights: 0x8b,
+            },
+            ..Default::default()
+        };
+        cpu.load_segment(&mem, SEG_CS, 0x08).unwrap();
+        for seg in [SEG_ES, SEG_SS, SEG_DS, SEG_FS, SEG_GS] {
+            cpu.load_segment(&mem, seg, 0x10).unwrap();
+        }
+        (cpu, mem)
+    }
+
+    fn enter_ring3(cpu: &mut LegacyCpuState, mem: &FlatMemory) {
+        cpu.load_segment(mem, SEG_CS, 0x1b).unwrap();
+        cpu.load_segment(mem, SEG_SS, 0x23).unwrap();
+        cpu.load_segment(mem, SEG_DS, 0x23).unwrap();
+        cpu.rsp = 0x7000;
+    }
+
+    #[test]
+    fn test_protected_mode_software_interrupt() {
+        // int 0x20; hlt
+        let (mut cpu, mut mem) = protected_mode(&[0xcd, 0x20, 0xf4], 0x4000);
+        cpu.step(&mut mem).unwrap();
+        assert_eq!((cpu.segs[SEG_CS].selector, cpu.rip), (0x08, 0x3000));
+        assert!(!cpu.interrupts_enabled());
+        assert_eq!(cpu.rsp, 0x8000 - 12);
+        let frame = |i: usize| mem.0[cpu.rsp as usize + i * 4];
+        assert_eq!((frame(0), frame(1), frame(2)), (0x02, 0x08, 0x02));
+
+        assert_eq!(cpu.run(&mut mem, 4).unwrap(), Some(LegacyExit::Halt));
+        assert!(cpu.interrupts_enabled());
+        assert_eq!((cpu.rsp, cpu.rip), (0x8000, 0x4003));
+    }
+
+    #[test]
+    fn test_protected_mode_interrupt_from_ring3() {
+        // An external interrupt at a ring-3 `hlt` switches to the TSS stack.
+        let (mut cpu, mut mem) = protected_mode(&[0xf4], 0x4000);
+        enter_ring3(&mut cpu, &mem);
+        cpu.deliver_interrupt(&mut mem, 0x20, None).unwrap();
+        assert_eq!(cpu.cpl(), 0);
+        assert_eq!((cpu.segs[SEG_SS].selector, cpu.rsp), (0x10, 0x9000 - 20));
+        let frame = |i: usize| {
+            mem.read_uint(GuestPhysAddr::from(0x9000 - 20 + i * 4), 4)
+                .unwrap()
+        };
+        assert_eq!(
+            [frame(0), frame(1), frame(2), frame(3), frame(4)],
+            [0x4000, 0x1b, 0x202, 0x7000, 0x23]
+        );
+
+        // IRET goes back to ring 3 and its stack.
+        assert_eq!(cpu.run(&mut mem, 4).unwrap(), Some(LegacyExit::Halt));
+        assert_eq!(cpu.cpl(), 3);
+        assert_eq!(
+            (cpu.segs[SEG_CS].selector, cpu.segs[SEG_SS].selector),
+            (0x1b, 0x23)
+        );
+        assert_eq!((cpu.rsp, cpu.rip), (0x7000, 0x4001));
+    }
+
+    #[test]
+    fn test_protected_mode_privileged_gate_faults() {
+        // `int 13` from ring 3 to a ring-0 gate raises #GP at the instruction,
+        // with the IDT entry as error code.
+        let (mut cpu, mut mem) = protected_mode(&[0xcd, 0x0d], 0x4000);
+        enter_ring3(&mut cpu, &mem);
+        cpu.step(&mut mem).unwrap();
+        assert_eq!(cpu.rip, 0x3100);
+        assert_eq!(
+            mem.read_uint(GuestPhysAddr::from(0x9000 - 24), 4).unwrap(),
+            13 * 8 + 2
+        );
+        assert_eq!(
+            mem.read_uint(GuestPhysAddr::from(0x9000 - 20), 4).unwrap(),
+            0x4000
+        );
+
+        // The handler drops the error code and returns to the `int`.
+        cpu.run(&mut mem, 2).unwrap();
+        assert_eq!((cpu.cpl(), cpu.rip, cpu.rsp), (3, 0x4000, 0x7000));
+    }
+
+    #[test]
+    fn test_protected_mode_ldt_selector() {
+        // mov ax, 0x0c; mov ds, ax; hlt, with an LDT at 0x600 whose entry 1
+        // is a data segment based at 0x10000.
+        let (mut cpu, mut mem) = protected_mode(&[0xb8, 0x0c, 0x00, 0x8e, 0xd8, 0xf4], 0x4000);
+        cpu.segs[SEG_CS].access_rights.set_bit(AR_DB, false);
+        mem.0[0x608..0x610].copy_from_slice(&0x0000_9301_0000_ffffu64.to_le_bytes());
+        cpu.ldtr = SegmentCache {
+            selector: 0x30,
+            base: 0x600,
+            limit: 0xf,
+            access_rights: 0x82,
+        };
+        assert_eq!(cpu.run(&mut mem, 4).unwrap(), Some(LegacyExit::Halt));
+        assert_eq!(cpu.segs[SEG_DS].selector, 0x0c);
+        assert_eq!(cpu.segs[SEG_DS].base, 0x10000);
+
+        // Without an LDT, LDT selectors are not valid.
+        cpu.ldtr.access_rights = AR_UNUSABLE;
+        assert!(cpu.load_segment(&mem, SEG_ES, 0x0c).is_err());
+    }
+
+    #[test]
+    fn test_cpuid_exit_leaves_rip_at_instruction() {
+        // xor eax, eax; cpuid (with a redundant operand-size prefix)
+        let mut mem = load(&[0x66, 0x31, 0xc0, 0x66, 0x0f, 0xa2], 0x7c00);
+        let mut cpu = reset_state(0, 0x7c00);
+        assert_eq!(cpu.run(&mut mem, 8).unwrap(), Some(LegacyExit::Cpuid));
+        assert_eq!(cpu.rip, 0x7c04);
+    }
+
+    #[test]
+    fn test_msr_exits_except_efer() {
+        // mov ecx, 0xc0000080; mov eax, 0x100; wrmsr; mov ecx, 0x1b; rdmsr
+        let code = [
+            0x66, 0xb9, 0x80, 0x00, 0x00, 0xc0, 0x66, 0xb8, 0x00, 0x01, 0x00, 0x00, 0x0f, 0x30,
+            0x66, 0xb9, 0x1b, 0x00, 0x00, 0x00, 0x0f, 0x32,
+        ];
+        let mut mem = load(&code, 0x7c00);
+        let mut cpu = reset_state(0, 0x7c00);
+        assert_eq!(cpu.run(&mut mem, 8).unwrap(), Some(LegacyExit::MsrRead));
+        assert_eq!(cpu.efer, 0x100);
+        assert_eq!(cpu.rip, 0x7c14);
+
+        // mov ecx, 0x1b; wrmsr
+        let mut mem = load(&[0x66, 0xb9, 0x1b, 0x00, 0x00, 0x00, 0x0f, 0x30], 0x7c00);
+        let mut cpu = reset_state(0, 0x7c00);
+        assert_eq!(cpu.run(&mut mem, 8).unwrap(), Some(LegacyExit::MsrWrite));
+        assert_eq!(cpu.rip, 0x7c06);
+    }
+
+    #[test]
+    fn test_invalid_opcode_raises_ud() {
+        // ud2, lea ax, ax and lldt ax (invalid in real mode), with the #UD
+        // handler at f000:0200.
+        let codes: [&[u8]; 3] = [&[0x0f, 0x0b], &[0x8d, 0xc0], &[0x0f, 0x00, 0xd0]];
+        for code in codes {
+            let mut mem = load(code, 0x7c00);
+            mem.0[0x18..0x1c].copy_from_slice(&[0x00, 0x02, 0x00, 0xf0]);
+            let mut cpu = reset_state(0, 0x7c00);
+            cpu.rsp = 0x7000;
+            assert_eq!(cpu.step(&mut mem).unwrap(), None);
+            assert_eq!((cpu.segs[SEG_CS].base, cpu.rip), (0xf0000, 0x200));
+            // The return address is the faulting instruction.
+            assert_eq!(&mem.0[0x6ffa..0x6ffe], &[0x00, 0x7c, 0x00, 0x00]);
+        }
+    }
+
+    #[test]
+    fn test_enter_leave() {
+        // mov sp, 0x8000; mov bp, 0x1234; enter 8, 1; hlt; leave; hlt
+        let code = [
+            0xbc, 0x00, 0x80, 0xbd, 0x34, 0x12, 0xc8, 0x08, 0x00, 0x01, 0xf4, 0xc9, 0xf4,
+        ];
+        let mut mem = load(&code, 0x7c00);
+        let mut cpu = reset_state(0, 0x7c00);
+        assert_eq!(cpu.run(&mut mem, 8).unwrap(), Some(LegacyExit::Halt));
+        assert_eq!((cpu.regs.rbp, cpu.rsp), (0x7ffe, 0x7ff4));
+        // The old BP, then the new frame pointer for level 1.
+        assert_eq!(&mem.0[0x7ffc..0x8000], &[0xfe, 0x7f, 0x34, 0x12]);
+
+        assert_eq!(cpu.run(&mut mem, 8).unwrap(), Some(LegacyExit::Halt));
+        assert_eq!((cpu.regs.rbp, cpu.rsp), (0x1234, 0x8000));
+    }
+
+    #[test]
+    fn test_ins_outs_one_element_per_exit() {
+        // mov si, 0x100; mov dx, 0x3f8; mov cx, 2; rep outsb; hlt
+        let code = [
+            0xbe, 0x00, 0x01, 0xba, 0xf8, 0x03, 0xb9, 0x02, 0x00, 0xf3, 0x6e, 0xf4,
+        ];
+        let mut mem = load(&code, 0x7c00);
+        mem.0[0x100..0x102].copy_from_slice(b"hi");
+        let mut cpu = reset_state(0, 0x7c00);
+        for (data, rip) in [(b'h', 0x7c09), (b'i', 0x7c0b)] {
+            let exit = cpu.run(&mut mem, 8).unwrap();
+            assert_eq!(
+                exit,
+                Some(LegacyExit::IoWrite {
+                    port: 0x3f8,
+                    width: AccessWidth::Byte,
+                    data: data as u64,
+                })
+            );
+            assert_eq!(cpu.rip, rip);
+        }
+        assert_eq!(cpu.run(&mut mem, 8).unwrap(), Some(LegacyExit::Halt));
+
+        // mov di, 0x200; mov dx, 0x60; mov ax, 0xaaaa; insb; hlt
+        let code = [
+            0xbf, 0x00, 0x02, 0xba, 0x60, 0x00, 0xb8, 0xaa, 0xaa, 0x6c, 0xf4,
+        ];
+        let mut mem = load(&code, 0x7c00);
+        let mut cpu = reset_state(0, 0x7c00);
+        let exit = cpu.run(&mut mem, 8).unwrap();
+        assert_eq!(
+            exit,
+            Some(LegacyExit::IoRead {
+                port: 0x60,
+                width: AccessWidth::Byte,
+            })
+        );
+        assert_eq!(cpu.rip, 0x7c09);
+        assert_eq!(cpu.pending_input, Some(0xaaaa));
+
+        // The input arrives in AL, INS stores it and restores AX.
+        cpu.regs.rax = 0xaa42;
+        assert_eq!(cpu.run(&mut mem, 8).unwrap(), Some(LegacyExit::Halt));
+        assert_eq!(mem.0[0x200], 0x42);
+        assert_eq!((cpu.regs.rax, cpu.regs.rdi), (0xaaaa, 0x201));
+        assert_eq!(cpu.pending_input, None);
+    }
+
+    #[test]
+    fn test_imul_forms() {
+        let code = [
+            0xbb, 0x2c, 0x01, // mov bx, 300
+            0x69, 0xc3, 0xc8, 0x00, // imul ax, bx, 200
+            0x6b, 0xcb, 0xfe, // imul cx, bx, -2
+            0xba, 0x03, 0x00, // mov dx, 3
+            0x0f, 0xaf, 0xd1, // imul dx, cx
+            0xf4,
+        ];
+        let mut mem = load(&code, 0x7c00);
+        let mut cpu = reset_state(0, 0x7c00);
+        assert_eq!(cpu.run(&mut mem, 2).unwrap(), None);
+        // 60000 does not fit in 16 signed bits.
+        assert_eq!(cpu.regs.rax, 0xea60);
+        assert_eq!(cpu.rflags & (FLAG_CF | FLAG_OF), FLAG_CF | FLAG_OF);
+
+        assert_eq!(cpu.run(&mut mem, 8).unwrap(), Some(LegacyExit::Halt));
+        assert_eq!(cpu.regs.rcx, 0xfda8);
+        assert_eq!(cpu.regs.rdx, 0xf8f8);
+        assert_eq!(cpu.rflags & (FLAG_CF | FLAG_OF), 0);
+    }
+
+    #[test]
+    fn test_setcc_cmovcc() {
+        // mov ax, 5; cmp ax, 5; sete bl; setne bh; mov cx, 7; cmove dx, cx;
+        // cmovne si, cx; hlt
+        let code = [
+            0xb8, 0x05, 0x00, 0x3d, 0x05, 0x00, 0x0f, 0x94, 0xc3, 0x0f, 0x95, 0xc7, 0xb9, 0x07,
+            0x00, 0x0f, 0x44, 0xd1, 0x0f, 0x45, 0xf1, 0xf4,
+        ];
+        let mut mem = load(&code, 0x7c00);
+        let mut cpu = reset_state(0, 0x7c00);
+        cpu.regs.rbx = 0xffff;
+        assert_eq!(cpu.run(&mut mem, 16).unwrap(), Some(LegacyExit::Halt));
+        assert_eq!(cpu.regs.rbx, 0x0001);
+        assert_eq!((cpu.regs.rdx, cpu.regs.rsi), (7, 0));
+    }
+
+    #[test]
+    fn test_bit_scan_and_test() {
+        let code = [
+            0xb8, 0x10, 0x08, // mov ax, 0x0810
+            0x0f, 0xbc, 0xc8, // bsf cx, ax
+            0x0f, 0xbd, 0xd0, // bsr dx, ax
+            0x0f, 0xba, 0xe8, 0x00, // bts ax, 0
+            0xbe, 0x00, 0x00, // mov si, 0
+            0xbf, 0x09, 0x00, // mov di, 9
+            0x0f, 0xbc, 0xfe, // bsf di, si
+            0xbb, 0x11, 0x00, // mov bx, 17
+            0x0f, 0xa3, 0x1e, 0x00, 0x01, // bt [0x100], bx
+            0x0f, 0xb3, 0x1e, 0x00, 0x01, // btr [0x100], bx
+            0xf4,
+        ];
+        let mut mem = load(&code, 0x7c00);
+        mem.0[0x102] = 0x03;
+        let mut cpu = reset_state(0, 0x7c00);
+        assert_eq!(cpu.run(&mut mem, 16).unwrap(), Some(LegacyExit::Halt));
+        assert_eq!((cpu.regs.rcx, cpu.regs.rdx), (4, 11));
+        assert_eq!(cpu.regs.rax, 0x0811);
+        // A zero source sets ZF and leaves the destination alone.
+        assert_eq!(cpu.regs.rdi, 9);
+        assert_ne!(cpu.rflags & FLAG_ZF, 0);
+        // Bit 17 of the bit string at 0x100 is bit 1 of the word at 0x102.
+        assert_eq!(mem.0[0x102], 0x01);
+        assert_ne!(cpu.rflags & FLAG_CF, 0);
+    }
+
+    #[test]
+    fn test_xlat() {
+        // mov bx, 0x100; mov al, 3; xlat; hlt
+        let mut mem = load(&[0xbb, 0x00, 0x01, 0xb0, 0x03, 0xd7, 0xf4], 0x7c00);
+        mem.0[0x103] = 0x5a;
+        let mut cpu = reset_state(0, 0x7c00);
+        assert_eq!(cpu.run(&mut mem, 8).unwrap(), Some(LegacyExit::Halt));
+        assert_eq!(cpu.regs.rax, 0x5a);
+    }
+
+    #[test]
+    fn test_shld_shrd() {
+        let code = [
+            0xb8, 0x34, 0x12, // mov ax, 0x1234
+            0xba, 0xcd, 0xab, // mov dx, 0xabcd
+            0x0f, 0xa4, 0xd0, 0x04, // shld ax, dx, 4
+            0xf4, //
+            0xbb, 0x34, 0x12, // mov bx, 0x1234
+            0xb1, 0x08, // mov cl, 8
+            0x0f, 0xad, 0xd3, // shrd bx, dx, cl
+            0xf4,
+        ];
+        let mut mem = load(&code, 0x7c00);
+        let mut cpu = reset_state(0, 0x7c00);
+        assert_eq!(cpu.run(&mut mem, 8).unwrap(), Some(LegacyExit::Halt));
+        assert_eq!(cpu.regs.rax, 0x234a);
+        // The last bit shifted out is bit 12 of 0x1234.
+        assert_ne!(cpu.rflags & FLAG_CF, 0);
+
+        assert_eq!(cpu.run(&mut mem, 8).unwrap(), Some(LegacyExit::Halt));
+        assert_eq!(cpu.regs.rbx, 0xcd12);
+        assert_eq!(cpu.rflags & FLAG_CF, 0);
+    }
+
+    #[test]
+    fn test_cmpxchg() {
+        let code = [
+            0xc7, 0x06, 0x00, 0x01, 0x05, 0x00, // mov word [0x100], 5
+            0xb8, 0x05, 0x00, // mov ax, 5
+            0xb9, 0x09, 0x00, // mov cx, 9
+            0x0f, 0xb1, 0x0e, 0x00, 0x01, // cmpxchg [0x100], cx
+            0xf4, //
+            0x0f, 0xb1, 0x0e, 0x00, 0x01, // cmpxchg [0x100], cx
+            0xf4,
+        ];
+        let mut mem = load(&code, 0x7c00);
+        let mut cpu = reset_state(0, 0x7c00);
+        assert_eq!(cpu.run(&mut mem, 8).unwrap(), Some(LegacyExit::Halt));
+        assert_eq!(&mem.0[0x100..0x102], &[0x09, 0x00]);
+        assert_ne!(cpu.rflags & FLAG_ZF, 0);
+
+        // A mismatch loads the accumulator instead.
+        assert_eq!(cpu.run(&mut mem, 8).unwrap(), Some(LegacyExit::Halt));
+        assert_eq!(cpu.regs.rax, 9);
+        assert_eq!(cpu.rflags & FLAG_ZF, 0);
+    }
+
+    #[test]
+    fn test_lldt_ltr() {
+        let code = [
+            0x66, 0xb8, 0x28, 0x00, // mov ax, 0x28
+            0x0f, 0x00, 0xd8, // ltr ax
+            0x66, 0xb8, 0x30, 0x00, // mov ax, 0x30
+            0x0f, 0x00, 0xd0, // lldt ax
+            0x0f, 0x00, 0xcb, // str ebx
+            0x0f, 0x00, 0xc1, // sldt ecx
+            0xf4,
+        ];
+        let (mut cpu, mut mem) = protected_mode(&code, 0x4000);
+        // An available TSS, and an LDT at 0x600.
+        mem.0[0x528..0x530].copy_from_slice(&0x0000_8900_2000_0067u64.to_le_bytes());
+        mem.0[0x530..0x538].copy_from_slice(&0x0000_8200_0600_000fu64.to_le_bytes());
+        cpu.gdtr_limit = 0x37;
+        assert_eq!(cpu.run(&mut mem, 8).unwrap(), Some(LegacyExit::Halt));
+        assert_eq!((cpu.tr.selector, cpu.tr.base), (0x28, 0x2000));
+        // The TSS is marked busy, in TR and in the GDT.
+        assert_eq!(cpu.tr.access_rights, 0x8b);
+        assert_eq!(mem.0[0x52d], 0x8b);
+        assert_eq!((cpu.ldtr.base, cpu.ldtr.limit), (0x600, 0xf));
+        assert_eq!((cpu.regs.rbx, cpu.regs.rcx), (0x28, 0x30));
+
+        // ltr ax is privileged, #GP(0) at ring 3.
+        let (mut cpu, mut mem) = protected_mode(&[0x0f, 0x00, 0xd8], 0x4000);
+        enter_ring3(&mut cpu, &mem);
+        cpu.step(&mut mem).unwrap();
+        assert_eq!(cpu.rip, 0x3100);
+        assert_eq!(
+            mem.read_uint(GuestPhysAddr::from(0x9000 - 24), 4).unwrap(),
+            0
+        );
+        assert_eq!(
+            mem.read_uint(GuestPhysAddr::from(0x9000 - 20), 4).unwrap(),
+            0x4000
+        );
+    }
+}
@@ -0,0 +1,173 @@
+//! Per-vCPU model of the guest time-stamp counter.
+//!
+//! With TSC offsetting and scaling, `RDTSC` in the guest returns the host TSC
+//! multiplied by the TSC multiplier, a fixed-point value with 48 fractional
+//! bits, plus the TSC offset. (SDM Vol. 3C, Section 25.3)
+
+/// The TSC multiplier that leaves the rate unchanged.
+pub const TSC_MULTIPLIER_ONE: u64 = 1 << 48;
+
+/// The guest TSC rate, its offset from the host TSC, and the guest-visible
+/// `IA32_TSC_ADJUST` and `IA32_TSC_DEADLINE` MSRs.
+#[derive(Debug, Clone)]
+pub struct TscModel {
+    guest_hz: Option<u64>,
+    multiplier: u64,
+    offset: u64,
+    adjust: u64,
+    deadline: u64,
+}
+
+impl TscModel {
+    /// A guest TSC running at `guest_hz` if `scaling` is available and both
+    /// frequencies are known, at the host rate `host_hz` otherwise.
+    pub fn new(host_hz: Option<u64>, guest_hz: Option<u64>, scaling: bool) -> Self {
+        let multiplier = match (host_hz, guest_hz) {
+            (Some(host_hz), Some(guest_hz)) if scaling && host_hz != guest_hz => {
+                ((guest_hz as u128) << 48) / host_hz as u128
+            }
+            _ => TSC_MULTIPLIER_ONE as u128,
+        };
+        let multiplier = multiplier.clamp(1, u64::MAX as u128) as u64;
+        Self {
+            guest_hz: if multiplier == TSC_MULTIPLIER_ONE {
+                host_hz
+            } else {
+                guest_hz
+            },
+            multiplier,
+            offset: 0,
+            adjust: 0,
+            deadline: 0,
+        }
+    }
+
+    /// The guest TSC frequency in Hz, if known.
+    pub fn guest_hz(&self) -> Option<u64> {
+        self.guest_hz
+    }
+
+    /// The value of the `TSC multiplier` VMCS field.
+    pub fn multiplier(&self) -> u64 {
+        self.multiplier
+    }
+
+    /// Whether the guest TSC runs at a different rate than the host TSC.
+    pub fn is_scaled(&self) -> bool {
+        self.multiplier != TSC_MULTIPLIER_ONE
+    }
+
+    /// The value of the `TSC offset` VMCS field.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    fn scale(&self, host_tsc: u64) -> u64 {
+        ((host_tsc as u128 * self.multiplier as u128) >> 48) as u64
+    }
+
+    /// The guest TSC when the host TSC reads `host_tsc`.
+    pub fn guest_tsc(&self, host_tsc: u64) -> u64 {
+        self.scale(host_tsc).wrapping_add(self.offset)
+    }
+
+    /// The host TSC when the guest TSC reads `guest_tsc`.
+    pub fn host_tsc(&self, guest_tsc: u64) -> u64 {
+        (((guest_tsc.wrapping_sub(self.offset) as u128) << 48) / self.multiplier as u128) as u64
+    }
+
+    /// Make the guest TSC read `value` when the host TSC reads `host_tsc`,
+    /// without a guest-visible change of `IA32_TSC_ADJUST`. Used to start the
+    /// guest TSC at zero and to continue it after a restore or migration.
+    pub fn set_guest_tsc(&mut self, host_tsc: u64, value: u64) {
+        self.offset = value.wrapping_sub(self.scale(host_tsc));
+    }
+
+    /// A guest write of `value` to `IA32_TIME_STAMP_COUNTER`, which also adds
+    /// the change to `IA32_TSC_ADJUST`. (SDM Vol. 3B, Section 17.17.3)
+    pub fn write_tsc(&mut self, host_tsc: u64, value: u64) {
+        let delta = value.wrapping_sub(self.guest_tsc(host_tsc));
+        self.adjust = self.adjust.wrapping_add(delta);
+        self.offset = self.offset.wrapping_add(delta);
+    }
+
+    /// The guest `IA32_TSC_ADJUST`.
+    pub fn tsc_adjust(&self) -> u64 {
+        self.adjust
+    }
+
+    /// A guest write of `value` to `IA32_TSC_ADJUST`, which also adds the
+    /// change to the TSC.
+    pub fn write_tsc_adjust(&mut self, value: u64) {
+        self.offset = self.offset.wrapping_add(value.wrapping_sub(self.adjust));
+        self.adjust = value;
+    }
+
+    /// The guest `IA32_TSC_DEADLINE` when the host TSC reads `host_tsc`: the
+    /// armed deadline, or 0 once it has passed. (SDM Vol. 3A, Section 10.5.4.1)
+    pub fn tsc_deadline(&self, host_tsc: u64) -> u64 {
+        if self.deadline != 0 && self.guest_tsc(host_tsc) < self.deadline {
+            self.deadline
+        } else {
+            0
+        }
+    }
+
+    /// A guest write of `value` to `IA32_TSC_DEADLINE`. Returns the deadline
+    /// in host TSC cycles, or 0 to disarm the timer.
+    pub fn write_tsc_deadline(&mut self, value: u64) -> u64 {
+        self.deadline = value;
+        if value == 0 {
+            0
+        } else {
+            self.host_tsc(value).max(1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_offset_and_scale() {
+        // A 1 GHz guest on a 2 GHz host, starting at 0.
+        let mut tsc = TscModel::new(Some(2_000_000_000), Some(1_000_000_000), true);
+        tsc.set_guest_tsc(10_000, 0);
+        assert_eq!(tsc.multiplier(), 1 << 47);
+        assert_eq!(tsc.guest_tsc(10_000), 0);
+        assert_eq!(tsc.guest_tsc(30_000), 10_000);
+        assert_eq!(tsc.host_tsc(10_000), 30_000);
+    }
+
+    #[test]
+    fn test_tsc_and_tsc_adjust() {
+        // Writing the TSC moves IA32_TSC_ADJUST along, and the other way round.
+        let mut tsc = TscModel::new(Some(2_000_000_000), Some(1_000_000_000), true);
+        tsc.set_guest_tsc(10_000, 0);
+        tsc.write_tsc(30_000, 15_000);
+        assert_eq!((tsc.guest_tsc(30_000), tsc.tsc_adjust()), (15_000, 5_000));
+        tsc.write_tsc_adjust(0);
+        assert_eq!(tsc.guest_tsc(30_000), 10_000);
+    }
+
+    #[test]
+    fn test_tsc_deadline() {
+        // The deadline reads 0 once it has passed.
+        let mut tsc = TscModel::new(Some(2_000_000_000), Some(1_000_000_000), true);
+        tsc.set_guest_tsc(10_000, 0);
+        assert_eq!(tsc.write_tsc_deadline(20_000), 50_000);
+        assert_eq!(tsc.tsc_deadline(40_000), 20_000);
+        assert_eq!(tsc.tsc_deadline(50_000), 0);
+    }
+
+    #[test]
+    fn test_unscaled() {
+        // Without scaling the guest runs at the host rate.
+        let tsc = TscModel::new(Some(2_000_000_000), Some(1_000_000_000), false);
+        assert_eq!(
+            (tsc.is_scaled(), tsc.guest_hz()),
+            (false, Some(2_000_000_000))
+        );
+    }
+}
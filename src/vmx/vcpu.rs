@@ -1,10 +1,11 @@
-use alloc::collections::VecDeque;
+use alloc::sync::Arc;
 use bit_field::BitField;
 use core::{
     arch::naked_asm,
     fmt::{Debug, Formatter, Result},
     mem::size_of,
 };
+use memory_addr::PAGE_SIZE_4K;
 use raw_cpuid::CpuId;
 use x86::{
     bits64::vmx,
@@ -13,11 +14,12 @@ use x86::{
     segmentation::SegmentSelector,
 };
 use x86_64::registers::control::{Cr0, Cr0Flags, Cr3, Cr4, Cr4Flags, EferFlags};
+use x86_64::registers::rflags::RFlags;
 use x86_vlapic::EmulatedLocalApic;
 
 use axaddrspace::{
-    GuestPhysAddr, GuestVirtAddr, HostPhysAddr, NestedPageFaultInfo,
-    device::{AccessWidth, Port, SysRegAddr, SysRegAddrRange},
+    AxMmHal, GuestPhysAddr, GuestVirtAddr, HostPhysAddr, NestedPageFaultInfo,
+    device::{AccessWidth, GuestPhysAddrRange, Port, SysRegAddr, SysRegAddrRange},
 };
 use axdevice_base::BaseDeviceOps;
 use axerrno::{AxResult, ax_err, ax_err_type};
@@ -25,16 +27,48 @@ use axvcpu::{AxArchVCpu, AxVCpuExitReason, AxVCpuHal};
 use axvisor_api::vmm::{VCpuId, VMId};
 
 use super::VmxExitInfo;
+use super::apic_base::{ApicBase, ApicMode};
+use super::apic_page::{APIC_EOI, APIC_ICR, APIC_TPR, EoiHandler, VirtualApicPage};
 use super::as_axerr;
-use super::definitions::VmxExitReason;
+use super::boot::{LinuxBootConfig, VmxBootMode, VmxSetupConfig};
+use super::definitions::{VmxActivityState, VmxExitReason};
+use super::entry_check;
+use super::error::{VcpuError, VcpuResult};
+use super::events::{EventQueue, EventWindow, InterruptibilityState, PendingEvent};
+use super::guest_mem::{EptGuestMemory, read_linear};
+use super::hypercall::{HypercallAbi, HypercallAction, HypercallDispatcher};
+use super::hyperv::{self, HvClock, HyperV, HyperVFeatures};
+use super::idle::{MwaitMode, UmwaitControl};
+use super::kick::VcpuKickHandle;
+use super::mmio::{MAX_INSN_LEN, MmioInstruction, MmioOperand};
+use super::ple::PauseLoopExiting;
+use super::posted::PostedInterruptHandle;
+use super::preemption::{self, VmxDeadline, VmxRunResult};
+use super::pvclock::{self, PvClock};
+use super::pvops::{self, PvOpsFeatures, PvOpsHandle, PvOpsHooks};
+use super::realmode::{
+    LegacyCpuState, LegacyExit, SEG_CS, SEG_DS, SEG_ES, SEG_FS, SEG_GS, SEG_SS, SegmentCache,
+};
 use super::structs::{IOBitmap, MsrBitmap, VmxRegion};
+use super::tsc::TscModel;
 use super::vmcs::{
-    self, ApicAccessExitType, VmcsControl32, VmcsControl64, VmcsControlNW, VmcsGuest16,
-    VmcsGuest32, VmcsGuest64, VmcsGuestNW, VmcsHost16, VmcsHost32, VmcsHost64, VmcsHostNW,
+    self, ApicAccessExitType, VmcsControl16, VmcsControl32, VmcsControl64, VmcsControlNW,
+    VmcsGuest16, VmcsGuest32, VmcsGuest64, VmcsGuestNW, VmcsHost16, VmcsHost32, VmcsHost64,
+    VmcsHostNW, VmcsReadOnly32, VmcsReadOnlyNW,
 };
 use crate::{ept::GuestPageWalkInfo, msr::Msr, regs::GeneralRegisters};
 
-const VMX_PREEMPTION_TIMER_SET_VALUE: u32 = 1_000_000;
+/// Number of instructions interpreted per [`VmxVcpu::run`] call while the guest
+/// is in a mode the hardware can not run without unrestricted guest support.
+const LEGACY_EMULATION_BUDGET: usize = 4096;
+
+/// The EOI-exit bitmap, one bit per vector. (SDM Vol. 3C, Section 24.6.8)
+const EOI_EXIT_BITMAP: [VmcsControl64; 4] = [
+    VmcsControl64::EOI_EXIT0,
+    VmcsControl64::EOI_EXIT1,
+    VmcsControl64::EOI_EXIT2,
+    VmcsControl64::EOI_EXIT3,
+];
 
 const QEMU_EXIT_PORT: u16 = 0x604;
 const QEMU_EXIT_MAGIC: u64 = 0x2000;
@@ -170,6 +204,57 @@ pub struct VmxVcpu<H: AxVCpuHal> {
     entry: Option<GuestPhysAddr>,
     /// The EPT root address.
     ept_root: Option<HostPhysAddr>,
+    /// The guest CR2, not part of the VMCS guest state. It is loaded right
+    /// before VM entry and saved right after VM exit.
+    guest_cr2: usize,
+    /// Whether the processor supports the unrestricted-guest VMX control. If not,
+    /// guest code running without paging is interpreted by [`LegacyCpuState`].
+    unrestricted_guest: bool,
+    /// The accumulator of an `INS` waiting for its input in the legacy-mode
+    /// interpreter, see [`LegacyCpuState::pending_input`].
+    legacy_pending_input: Option<u64>,
+    /// Whether the processor supports the virtual-NMIs VMX control, which
+    /// tracks guest NMI blocking and allows NMI-window exiting.
+    virtual_nmi: bool,
+    /// The APIC-virtualization controls in use, all of them on top of the TPR
+    /// shadow. Empty if every access to the emulated local APIC causes a VM exit.
+    apicv: vmcs::controls::SecondaryControls,
+    /// The notification vector of posted interrupts, see [`Self::posted_interrupt_handle`].
+    posted_interrupt_vector: Option<u8>,
+    /// Where the EOIs selected with [`Self::set_eoi_exit`] are forwarded.
+    eoi_handler: Option<EoiHandler>,
+    /// The emulated `IA32_APIC_BASE` MSR, selecting the APIC mode.
+    apic_base: ApicBase,
+    /// Whether the VMX-preemption timer armed by [`Self::run_with_deadline`] expired.
+    timer_expired: bool,
+    /// The VMX-preemption timer ticks left in the time slice of [`Self::run_with_deadline`].
+    slice_ticks: Option<u32>,
+    /// The VMX-preemption timer value loaded by the last VM entry, if armed.
+    preemption_timer_armed: Option<u32>,
+    /// Rate and offset of the guest TSC, and the TSC MSRs.
+    tsc: TscModel,
+    /// The kvmclock state, if offered to the guest.
+    pvclock: Option<PvClock>,
+    /// The Hyper-V enlightenment state.
+    hyperv: HyperV,
+    /// Calling convention and in-vCPU handlers of hypercalls.
+    hypercalls: HypercallDispatcher,
+    /// The paravirtual operations offered to the guest.
+    pv_ops: PvOpsFeatures,
+    /// TLB flush and wake-up requests from other vCPUs.
+    pv_ops_handle: PvOpsHandle,
+    /// Run state and exit requests shared with other CPUs.
+    kick: VcpuKickHandle,
+    /// Whether the last run returned without entering the guest because of a kick.
+    kicked: bool,
+    /// What `MONITOR` and `MWAIT` do in the guest.
+    mwait: MwaitMode,
+    /// The guest `IA32_UMWAIT_CONTROL`, if WAITPKG is offered.
+    umwait: Option<UmwaitControl>,
+    /// PAUSE-loop exiting, if enabled.
+    ple: Option<PauseLoopExiting>,
+    /// Whether the last run returned because the guest spins in a PAUSE loop.
+    yield_hint: bool,
     // /// Whether this VCPU is a host VCpu. Used in type 1.5 hypervisor.
     // is_host: bool, temporary removed because we don't care about type 1.5 now
 
@@ -180,10 +265,12 @@ pub struct VmxVcpu<H: AxVCpuHal> {
     io_bitmap: IOBitmap<H::MmHal>,
     /// The MSR bitmap for the VMCS.
     msr_bitmap: MsrBitmap<H::MmHal>,
+    /// The posted-interrupt descriptor, shared with the senders.
+    posted_interrupts: PostedInterruptHandle,
 
     // Interrupt-related fields
     /// Pending events to be injected to the guest.
-    pending_events: VecDeque<(u8, Option<u32>)>,
+    events: EventQueue,
     /// Emulated Local APIC.
     vlapic: EmulatedLocalApic,
 
@@ -201,17 +288,48 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
     /// Create a new [`VmxVcpu`].
     pub fn new(vm_id: VMId, vcpu_id: VCpuId) -> AxResult<Self> {
         let vmcs_revision_id = super::read_vmcs_revision_id();
+        let kick = VcpuKickHandle::new();
         let vcpu = Self {
             guest_regs: GeneralRegisters::default(),
             host_stack_top: 0,
             launched: false,
             entry: None,
             ept_root: None,
+            guest_cr2: 0,
+            unrestricted_guest: vmcs::control_allowed1(
+                Msr::IA32_VMX_PROCBASED_CTLS2,
+                vmcs::controls::SecondaryControls::UNRESTRICTED_GUEST.bits(),
+            ),
+            legacy_pending_input: None,
+            virtual_nmi: vmcs::control_allowed1(
+                Msr::IA32_VMX_TRUE_PINBASED_CTLS,
+                vmcs::controls::PinbasedControls::VIRTUAL_NMIS.bits(),
+            ),
+            apicv: vmcs::controls::SecondaryControls::empty(),
+            posted_interrupt_vector: None,
+            eoi_handler: None,
+            apic_base: ApicBase::new(vcpu_id == 0),
+            timer_expired: false,
+            slice_ticks: None,
+            preemption_timer_armed: None,
+            tsc: TscModel::new(None, None, false),
+            pvclock: None,
+            hyperv: HyperV::new(HyperVFeatures::empty(), vcpu_id as u32),
+            hypercalls: HypercallDispatcher::default(),
+            pv_ops: PvOpsFeatures::empty(),
+            pv_ops_handle: PvOpsHandle::new(kick.clone()),
+            kick,
+            kicked: false,
+            mwait: MwaitMode::Passthrough,
+            umwait: None,
+            ple: None,
+            yield_hint: false,
             // is_host: false,
             vmcs: VmxRegion::new(vmcs_revision_id, false)?,
             io_bitmap: IOBitmap::passthrough_all()?,
             msr_bitmap: MsrBitmap::passthrough_all()?,
-            pending_events: VecDeque::with_capacity(8),
+            posted_interrupts: PostedInterruptHandle::new(),
+            events: EventQueue::new(),
             vlapic: EmulatedLocalApic::new(vm_id, vcpu_id),
             xstate: XState::new(),
             #[cfg(feature = "tracing")]
@@ -222,11 +340,33 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
     }
 
     /// Set the new [`VmxVcpu`] context from guest OS.
-    pub fn setup(&mut self, ept_root: HostPhysAddr, entry: GuestPhysAddr) -> AxResult {
-        self.setup_vmcs(entry, ept_root)?;
+    pub fn setup(
+        &mut self,
+        ept_root: HostPhysAddr,
+        entry: GuestPhysAddr,
+        config: &VmxSetupConfig,
+    ) -> AxResult {
+        self.setup_vmcs(entry, ept_root, config)?;
         Ok(())
     }
 
+    /// Set up the [`VmxVcpu`] to boot a Linux kernel at `entry` through the
+    /// 32-bit or 64-bit boot protocol.
+    ///
+    /// The zero page must already be filled in. The boot GDT is written to
+    /// guest memory here.
+    pub fn setup_linux(
+        &mut self,
+        ept_root: HostPhysAddr,
+        entry: GuestPhysAddr,
+        boot: &LinuxBootConfig,
+    ) -> AxResult {
+        let mut mem = EptGuestMemory::<H::MmHal>::new(ept_root);
+        boot.validate(&mem, entry)?;
+        boot.write_gdt(&mut mem)?;
+        self.setup(ept_root, entry, &boot.setup_config())
+    }
+
     // /// Get the identifier of this [`VmxVcpu`].
     // pub fn vcpu_id(&self) -> usize {
     //     get_current_vcpu::<Self>().unwrap().id()
@@ -258,6 +398,11 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
         Ok(())
     }
 
+    /// Whether the VMCS of this [`VmxVcpu`] is current on this logical processor.
+    fn is_bound_to_current_processor(&self) -> bool {
+        unsafe { vmx::vmptrst() }.is_ok_and(|vmcs| vmcs == self.vmcs.phys_addr().as_usize() as u64)
+    }
+
     /// Get CPU mode of the guest.
     pub fn get_cpu_mode(&self) -> VmCpuMode {
         let ia32_efer = Msr::IA32_EFER.read();
@@ -278,11 +423,37 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
     }
 
     /// Run the guest. It returns when a vm-exit happens and returns the vm-exit if it cannot be handled by this [`VmxVcpu`] itself.
-    pub fn inner_run(&mut self) -> Option<VmxExitInfo> {
-        self.inject_pending_events().unwrap();
+    pub fn inner_run(&mut self) -> VcpuResult<Option<VmxExitInfo>> {
+        if !self.launched {
+            VmcsHostNW::RSP.write(&self.host_stack_top as *const _ as usize)?;
+        }
+
+        if !self.kick.begin_entry() {
+            // An exit was requested through the kick handle, skip the VM entry.
+            // Nothing is injected yet, pending events stay queued.
+            self.kicked = true;
+            return Ok(None);
+        }
+        if let Err(err) = self
+            .inject_pending_events()
+            .and_then(|_| self.arm_preemption_timer())
+        {
+            self.kick.end_entry();
+            return Err(err.into());
+        }
+        if self.pv_ops_handle.take_tlb_flush() {
+            // Requested by another vCPU instead of a TLB-shootdown IPI.
+            if let Err(err) = vmcs::flush_guest_tlb() {
+                self.kick.end_entry();
+                return Err(err.into());
+            }
+        }
 
         // Run guest
         self.load_guest_xstate();
+        if let Some(umwait) = &self.umwait {
+            umwait.switch_to_guest();
+        }
 
         #[cfg(feature = "tracing")]
         {
@@ -299,49 +470,90 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
             }
         }
 
-        unsafe {
-            if self.launched {
-                self.vmx_resume();
+        let entry_failed = unsafe {
+            // Nothing may fault between here and the VM entry, or after the
+            // VM exit until the guest CR2 is saved.
+            x86::controlregs::cr2_write(self.guest_cr2 as u64);
+            let failed = if self.launched {
+                self.vmx_resume()
             } else {
-                self.launched = true;
-                VmcsHostNW::RSP
-                    .write(&self.host_stack_top as *const _ as usize)
-                    .unwrap();
-
-                self.vmx_launch();
-            }
+                self.vmx_launch()
+            };
+            self.guest_cr2 = x86::controlregs::cr2();
+            failed
+        } != 0;
+        self.kick.end_entry();
+        if let Some(umwait) = &self.umwait {
+            umwait.switch_to_host();
         }
         self.load_host_xstate();
 
+        if entry_failed {
+            return Err(Self::entry_failure());
+        }
+        self.launched = true;
+        self.charge_time_slice()?;
+
         #[cfg(feature = "tracing")]
         {
             self.guest_regs_exiting = self.guest_regs;
         }
 
         // Handle vm-exits
-        let exit_info = self.exit_info().unwrap();
+        let exit_info = vmcs::exit_info()?;
         // debug!("VM exit: {:#x?}", exit_info);
+        match vmcs::idt_vectoring_event()? {
+            Some(event) => self.events.push_interrupted(event),
+            None => self.restore_nmi_blocking(&exit_info)?,
+        }
 
-        match self.builtin_vmexit_handler(&exit_info) {
-            Some(result) => {
-                if result.is_err() {
-                    panic!(
-                        "VmxVcpu failed to handle a VM-exit that should be handled by itself: {:?}, error {:?}, vcpu: {:#x?}",
-                        exit_info.exit_reason,
-                        result.unwrap_err(),
-                        self
-                    );
-                }
+        self.dispatch_exit(exit_info)
+    }
 
-                None
+    /// Handle `exit_info` with [`Self::builtin_vmexit_handler`], queueing the
+    /// exception of an invalid guest action.
+    ///
+    /// Returns the exit back if it is left to the caller.
+    fn dispatch_exit(&mut self, exit_info: VmxExitInfo) -> VcpuResult<Option<VmxExitInfo>> {
+        match self.builtin_vmexit_handler(&exit_info) {
+            Some(Ok(())) => Ok(None),
+            Some(Err(VcpuError::InvalidGuestAction {
+                vector,
+                err_code,
+                reason,
+            })) => {
+                debug!(
+                    "Injecting exception {} into the guest on {:?}: {}",
+                    vector, exit_info.exit_reason, reason
+                );
+                self.queue_event(vector, err_code)?;
+                Ok(None)
+            }
+            Some(Err(err)) => {
+                warn!(
+                    "VmxVcpu failed to handle a VM-exit that should be handled by itself: {:?}, error {}, vcpu: {:#x?}",
+                    exit_info.exit_reason, err, self
+                );
+                Err(err)
             }
-            None => Some(exit_info),
+            None => Ok(Some(exit_info)),
+        }
+    }
+
+    /// Describe why VMLAUNCH or VMRESUME failed. (SDM Vol. 3C, Section 26.1 - 26.3)
+    fn entry_failure() -> VcpuError {
+        match vmcs::instruction_error() {
+            Ok(error) => VcpuError::EntryFailure {
+                error,
+                check: entry_check::check_current_vmcs().err(),
+            },
+            Err(_) => VcpuError::InvalidVmcsPointer,
         }
     }
 
     /// Basic information about VM exits.
     pub fn exit_info(&self) -> AxResult<vmcs::VmxExitInfo> {
-        vmcs::exit_info()
+        Ok(vmcs::exit_info()?)
     }
 
     /// Raw information for VM Exits Due to Vectored Events, See SDM 25.9.2
@@ -464,8 +676,83 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
 
     /// Add a virtual interrupt or exception to the pending events list,
     /// and try to inject it before later VM entries.
-    pub fn queue_event(&mut self, vector: u8, err_code: Option<u32>) {
-        self.pending_events.push_back((vector, err_code));
+    ///
+    /// Vectors below 32 are hardware exceptions (see [`Self::inject_exception`]),
+    /// vector 2 is an NMI, the others are external interrupts. Exceptions merge
+    /// into a #DF or a triple fault by the SDM rules, and events are injected by
+    /// priority, not in the order they are queued.
+    pub fn queue_event(&mut self, vector: u8, err_code: Option<u32>) -> AxResult {
+        match vector {
+            x86::irq::NONMASKABLE_INTERRUPT_VECTOR if err_code.is_none() => self.queue_nmi(),
+            0..32 => self.inject_exception(vector, err_code, None)?,
+            _ if err_code.is_none() => self.events.push(PendingEvent::new(vector, None)),
+            _ => return ax_err!(InvalidInput, "only exceptions push an error code"),
+        }
+        Ok(())
+    }
+
+    /// Queue a hardware exception. `err_code` must be given exactly for the
+    /// exceptions that push one, `fault_addr` is loaded into the guest CR2 and
+    /// only valid for a #PF.
+    pub fn inject_exception(
+        &mut self,
+        vector: u8,
+        err_code: Option<u32>,
+        fault_addr: Option<u64>,
+    ) -> AxResult {
+        self.events
+            .push(PendingEvent::exception(vector, err_code, fault_addr)?);
+        Ok(())
+    }
+
+    /// Queue a software interrupt (INT n). The guest resumes after the
+    /// instruction of `instr_len` bytes at the current `RIP`.
+    pub fn inject_soft_interrupt(&mut self, vector: u8, instr_len: u32) -> AxResult {
+        self.events
+            .push(PendingEvent::software_interrupt(vector, instr_len)?);
+        Ok(())
+    }
+
+    /// Queue a software exception, #BP (INT3) or #OF (INTO). The guest resumes
+    /// after the instruction of `instr_len` bytes at the current `RIP`.
+    pub fn inject_soft_exception(&mut self, vector: u8, instr_len: u32) -> AxResult {
+        self.events
+            .push(PendingEvent::software_exception(vector, instr_len)?);
+        Ok(())
+    }
+
+    /// Queue a privileged software exception, the #DB raised by INT1. The guest
+    /// resumes after the instruction of `instr_len` bytes at the current `RIP`.
+    pub fn inject_privileged_soft_exception(&mut self, instr_len: u32) -> AxResult {
+        self.events
+            .push(PendingEvent::privileged_software_exception(instr_len)?);
+        Ok(())
+    }
+
+    /// Add an NMI to the pending events. It is injected once the guest no
+    /// longer blocks NMIs, using NMI-window exiting if virtual NMIs are supported.
+    pub fn queue_nmi(&mut self) {
+        self.events.push(PendingEvent::new(
+            x86::irq::NONMASKABLE_INTERRUPT_VECTOR,
+            None,
+        ));
+    }
+
+    /// If enable, a VM exit occurs at the beginning of any instruction if
+    /// there is no virtual-NMI blocking and no blocking by MOV SS.
+    /// (see SDM, Vol. 3C, Section 24.4.2)
+    ///
+    /// Requires virtual NMIs.
+    pub fn set_nmi_window(&mut self, enable: bool) -> AxResult {
+        let mut ctrl = VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS.read()?;
+        let bits = vmcs::controls::PrimaryControls::NMI_WINDOW_EXITING.bits();
+        if enable {
+            ctrl |= bits
+        } else {
+            ctrl &= !bits
+        }
+        VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS.write(ctrl)?;
+        Ok(())
     }
 
     /// If enable, a VM exit occurs at the beginning of any instruction if
@@ -483,6 +770,136 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
         Ok(())
     }
 
+    /// A handle other CPUs can use to request vLAPIC interrupts while this
+    /// vCPU may be running, see [`PostedInterruptHandle::post`].
+    pub fn posted_interrupt_handle(&self) -> PostedInterruptHandle {
+        self.posted_interrupts.clone()
+    }
+
+    /// A handle other CPUs can use to make this vCPU exit guest mode promptly.
+    /// A kicked [`AxArchVCpu::run`] returns [`AxVCpuExitReason::Nothing`].
+    pub fn kick_handle(&self) -> VcpuKickHandle {
+        self.kick.clone()
+    }
+
+    /// A handle other vCPUs can use, through the [`PvOpsHooks`] of the VMM, to
+    /// flush the TLB of this vCPU or wake it up.
+    pub fn pv_ops_handle(&self) -> PvOpsHandle {
+        self.pv_ops_handle.clone()
+    }
+
+    /// Handle the hypercalls of [`VmxSetupConfig::pv_ops`] in the vCPU,
+    /// delivering the requests to the other vCPUs through `hooks`.
+    pub fn set_pv_ops_hooks(&mut self, hooks: Arc<dyn PvOpsHooks>) -> AxResult {
+        pvops::register_hypercalls(&mut self.hypercalls, self.pv_ops, hooks)
+    }
+
+    /// Run the guest for at most `deadline`, across the VM exits handled by the
+    /// vCPU itself. Returns the first VM exit the caller has to handle,
+    /// [`VmxRunResult::Preempted`] once the budget is used up, or
+    /// [`VmxRunResult::Kicked`] when kicked through [`Self::kick_handle`].
+    pub fn run_with_deadline(&mut self, deadline: VmxDeadline) -> AxResult<VmxRunResult> {
+        let tsc_hz = match deadline {
+            VmxDeadline::Nanos(_) => preemption::tsc_frequency_hz()
+                .ok_or_else(|| ax_err_type!(Unsupported, "TSC frequency is unknown"))?,
+            VmxDeadline::Tsc(_) => 0,
+        };
+        if !Self::supports_preemption_timer() {
+            return ax_err!(Unsupported, "VMX-preemption timer is not supported");
+        }
+
+        self.slice_ticks = Some(preemption::timer_ticks(
+            deadline.tsc_cycles(tsc_hz),
+            preemption::timer_rate_shift(),
+        ));
+        self.timer_expired = false;
+        self.kicked = false;
+        self.yield_hint = false;
+        // A new time slice.
+        if let Some(window) = self.ple.as_mut().and_then(PauseLoopExiting::shrink) {
+            VmcsControl32::PLE_WINDOW.write(window)?;
+        }
+        let result = loop {
+            match self.run() {
+                Err(err) => break Err(err),
+                Ok(_) if self.kicked => break Ok(VmxRunResult::Kicked),
+                Ok(_) if self.yield_hint => break Ok(VmxRunResult::YieldHint),
+                Ok(_) if self.timer_expired => break Ok(VmxRunResult::Preempted),
+                Ok(AxVCpuExitReason::Nothing) => continue,
+                Ok(exit) => break Ok(VmxRunResult::Exit(exit)),
+            }
+        };
+        self.slice_ticks = None;
+        result
+    }
+
+    /// The current guest TSC, e.g. to save it for a restore or migration.
+    pub fn guest_tsc(&self) -> u64 {
+        self.tsc.guest_tsc(unsafe { x86::time::rdtsc() })
+    }
+
+    /// Make the guest TSC continue from `value`, e.g. from the value saved
+    /// before a restore or migration, so that it never goes backwards. The
+    /// guest `IA32_TSC_ADJUST` is not changed.
+    ///
+    /// The vCPU must be bound to the calling processor, the new offset is
+    /// written to its VMCS.
+    pub fn set_guest_tsc(&mut self, value: u64) -> AxResult {
+        if !self.is_bound_to_current_processor() {
+            return ax_err!(BadState, "vCPU is not bound to the current processor");
+        }
+        self.tsc.set_guest_tsc(unsafe { x86::time::rdtsc() }, value);
+        VmcsControl64::TSC_OFFSET.write(self.tsc.offset())?;
+        self.update_pv_clocks()
+    }
+
+    /// The hypercall dispatcher, to pick the calling convention and register
+    /// handlers run in the vCPU. Hypercalls without a handler are returned as
+    /// [`AxVCpuExitReason::Hypercall`].
+    pub fn hypercalls_mut(&mut self) -> &mut HypercallDispatcher {
+        &mut self.hypercalls
+    }
+
+    /// The host TSC at which the next Hyper-V synthetic timer fires. A running
+    /// vCPU exits at that time to deliver it, a halted one must be woken up
+    /// by then, e.g. by the VMM blocking it on HLT.
+    pub fn hyperv_timer_deadline(&self) -> Option<u64> {
+        let clock = self.hv_clock()?;
+        let expiry = self.hyperv.next_timer_expiry()?;
+        Some(self.tsc.host_tsc(clock.guest_tsc_at(expiry)))
+    }
+
+    /// The emulated `IA32_APIC_BASE` MSR. The guest xAPIC page at its base
+    /// must be mapped to the APIC-access page in the EPT.
+    pub fn apic_base(&self) -> u64 {
+        self.apic_base.bits()
+    }
+
+    /// If `exit`, a virtualized EOI of `vector` causes a VM exit after the
+    /// virtual-APIC page has been updated, and the vCPU passes the vector to
+    /// the handler of [`Self::set_eoi_handler`], e.g. to deassert the remote
+    /// IRR of a level-triggered interrupt. (SDM Vol. 3C, Section 29.1.4)
+    ///
+    /// Requires virtual-interrupt delivery.
+    pub fn set_eoi_exit(&mut self, vector: u8, exit: bool) -> AxResult {
+        if !self
+            .apicv
+            .contains(vmcs::controls::SecondaryControls::VIRTUAL_INTERRUPT_DELIVERY)
+        {
+            return ax_err!(Unsupported, "virtual-interrupt delivery is not in use");
+        }
+        let field = EOI_EXIT_BITMAP[vector as usize / 64];
+        let mut bitmap = field.read()?;
+        bitmap.set_bit(vector as usize % 64, exit);
+        field.write(bitmap)
+    }
+
+    /// Forward the EOIs selected with [`Self::set_eoi_exit`] to `handler`,
+    /// called on the vCPU before it resumes the guest.
+    pub fn set_eoi_handler(&mut self, handler: EoiHandler) {
+        self.eoi_handler = Some(handler);
+    }
+
     /// Set I/O intercept by modifying I/O bitmap.
     pub fn set_io_intercept_of_range(&mut self, port_base: u32, count: u32, intercept: bool) {
         self.io_bitmap
@@ -542,42 +959,276 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
         Ok(())
     }
 
-    #[allow(dead_code)]
     fn setup_msr_bitmap(&mut self) -> AxResult {
         // Intercept IA32_APIC_BASE MSR accesses
-        // let msr = x86::msr::IA32_APIC_BASE;
-        // self.msr_bitmap.set_read_intercept(msr, true);
-        // self.msr_bitmap.set_write_intercept(msr, true);
-
-        // This is strange, guest Linux's access to `IA32_UMWAIT_CONTROL` will cause an exception.
-        // But if we intercept it, it seems okay.
-        const IA32_UMWAIT_CONTROL: u32 = 0xe1;
+        let msr = x86::msr::IA32_APIC_BASE;
+        self.msr_bitmap.set_read_intercept(msr, true);
+        self.msr_bitmap.set_write_intercept(msr, true);
+
+        // Writes to the TSC and the TSC_ADJUST and TSC_DEADLINE MSRs are
+        // emulated through the TSC model, TSC reads are offset and scaled by
+        // the processor.
+        use x86::msr::{IA32_TIME_STAMP_COUNTER, IA32_TSC_ADJUST, IA32_TSC_DEADLINE};
         self.msr_bitmap
-            .set_write_intercept(IA32_UMWAIT_CONTROL, true);
-        self.msr_bitmap
-            .set_read_intercept(IA32_UMWAIT_CONTROL, true);
+            .set_write_intercept(IA32_TIME_STAMP_COUNTER, true);
+        for msr in [IA32_TSC_ADJUST, IA32_TSC_DEADLINE] {
+            self.msr_bitmap.set_read_intercept(msr, true);
+            self.msr_bitmap.set_write_intercept(msr, true);
+        }
+
+        // The guest `IA32_UMWAIT_CONTROL` is emulated, the host one must not
+        // change. Without WAITPKG, accesses raise #GP.
+        let msr = Msr::IA32_UMWAIT_CONTROL as u32;
+        self.msr_bitmap.set_write_intercept(msr, true);
+        self.msr_bitmap.set_read_intercept(msr, true);
+
+        self.update_x2apic_msr_intercepts();
+        Ok(())
+    }
 
-        // Intercept all x2APIC MSR accesses
+    /// Intercept all x2APIC MSR accesses, except those the processor
+    /// virtualizes in x2APIC mode.
+    fn update_x2apic_msr_intercepts(&mut self) {
         for msr in 0x800..=0x83f {
             self.msr_bitmap.set_read_intercept(msr, true);
             self.msr_bitmap.set_write_intercept(msr, true);
         }
-        Ok(())
+        if self.apic_base.mode() != ApicMode::X2Apic {
+            return;
+        }
+
+        // Let the processor virtualize the x2APIC MSRs it can, the others behave
+        // as on the physical x2APIC. (SDM Vol. 3C, Section 29.5)
+        use vmcs::controls::SecondaryControls as CpuCtrl2;
+        const X2APIC_TPR: u32 = 0x808;
+        const X2APIC_EOI: u32 = 0x80b;
+        const X2APIC_TMCCT: u32 = 0x839;
+        const X2APIC_SELF_IPI: u32 = 0x83f;
+        if self.apicv.contains(CpuCtrl2::VIRTUALIZE_X2APIC) {
+            self.msr_bitmap.set_read_intercept(X2APIC_TPR, false);
+            self.msr_bitmap.set_write_intercept(X2APIC_TPR, false);
+        }
+        if self.apicv.contains(CpuCtrl2::VIRTUALIZE_APIC_REGISTER) {
+            // The timer current count is not kept up to date in the page.
+            for msr in (0x800..=0x83f).filter(|&msr| msr != X2APIC_TMCCT) {
+                self.msr_bitmap.set_read_intercept(msr, false);
+            }
+        }
+        if self.apicv.contains(CpuCtrl2::VIRTUAL_INTERRUPT_DELIVERY) {
+            self.msr_bitmap.set_write_intercept(X2APIC_EOI, false);
+            self.msr_bitmap.set_write_intercept(X2APIC_SELF_IPI, false);
+        }
+    }
+
+    /// The APIC-virtualization controls for the current APIC mode: x2APIC
+    /// virtualization in x2APIC mode, APIC-access exits in xAPIC mode.
+    fn apic_controls(&self) -> vmcs::controls::SecondaryControls {
+        use vmcs::controls::SecondaryControls as CpuCtrl2;
+        let common = self.apicv - CpuCtrl2::VIRTUALIZE_X2APIC;
+        match self.apic_base.mode() {
+            ApicMode::X2Apic => self.apicv,
+            ApicMode::XApic if Self::supports_apic_accesses() => common | CpuCtrl2::VIRTUALIZE_APIC,
+            _ => common,
+        }
     }
 
-    fn setup_vmcs(&mut self, entry: GuestPhysAddr, ept_root: HostPhysAddr) -> AxResult {
+    fn supports_apic_accesses() -> bool {
+        vmcs::control_allowed1(
+            Msr::IA32_VMX_PROCBASED_CTLS2,
+            vmcs::controls::SecondaryControls::VIRTUALIZE_APIC.bits(),
+        )
+    }
+
+    /// Switch the MSR intercepts and the APIC-virtualization controls to the
+    /// current APIC mode.
+    fn switch_apic_mode(&mut self) -> AxResult {
+        use vmcs::controls::SecondaryControls as CpuCtrl2;
+        self.update_x2apic_msr_intercepts();
+        let apic_bits = CpuCtrl2::VIRTUALIZE_APIC
+            | CpuCtrl2::VIRTUALIZE_X2APIC
+            | CpuCtrl2::VIRTUALIZE_APIC_REGISTER
+            | CpuCtrl2::VIRTUAL_INTERRUPT_DELIVERY;
+        let ctrl = VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS.read()?;
+        VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS
+            .write((ctrl & !apic_bits.bits()) | self.apic_controls().bits())
+    }
+
+    /// The APIC-virtualization controls supported by the processor, none if it
+    /// cannot shadow the TPR or virtualize x2APIC mode. (SDM Vol. 3C, Section 29.1)
+    fn supported_apicv() -> vmcs::controls::SecondaryControls {
+        use vmcs::controls::{PrimaryControls as CpuCtrl, SecondaryControls as CpuCtrl2};
+        let allowed =
+            |ctrl: CpuCtrl2| vmcs::control_allowed1(Msr::IA32_VMX_PROCBASED_CTLS2, ctrl.bits());
+        if !vmcs::control_allowed1(
+            Msr::IA32_VMX_TRUE_PROCBASED_CTLS,
+            CpuCtrl::USE_TPR_SHADOW.bits(),
+        ) || !allowed(CpuCtrl2::VIRTUALIZE_X2APIC)
+        {
+            return CpuCtrl2::empty();
+        }
+        [
+            CpuCtrl2::VIRTUALIZE_APIC_REGISTER,
+            CpuCtrl2::VIRTUAL_INTERRUPT_DELIVERY,
+        ]
+        .into_iter()
+        .filter(|&ctrl| allowed(ctrl))
+        .fold(CpuCtrl2::VIRTUALIZE_X2APIC, |val, ctrl| val | ctrl)
+    }
+
+    fn setup_vmcs(
+        &mut self,
+        entry: GuestPhysAddr,
+        ept_root: HostPhysAddr,
+        config: &VmxSetupConfig,
+    ) -> AxResult {
+        if !config.pv_ops.is_empty() && config.hyperv.contains(HyperVFeatures::HYPERCALL) {
+            // Both are decoded from VMCALL, each with its own calling convention.
+            return ax_err!(
+                InvalidInput,
+                "Hyper-V hypercalls cannot be combined with paravirtual operations"
+            );
+        }
+        self.ept_root = Some(ept_root);
         let paddr = self.vmcs.phys_addr().as_usize() as u64;
         unsafe {
             vmx::vmclear(paddr).map_err(as_axerr)?;
         }
         self.bind_to_current_processor()?;
+        self.apicv = if config.apicv {
+            Self::supported_apicv()
+        } else {
+            vmcs::controls::SecondaryControls::empty()
+        };
+        self.posted_interrupt_vector = config.posted_interrupt_vector;
+        self.setup_tsc(config);
+        self.pv_ops = config.pv_ops;
+        self.mwait = config.mwait;
+        self.umwait = config.waitpkg.and_then(|limits| {
+            let supported = CpuId::new()
+                .get_extended_feature_info()
+                .is_some_and(|features| features.has_waitpkg())
+                && vmcs::control_allowed1(
+                    Msr::IA32_VMX_PROCBASED_CTLS2,
+                    vmcs::controls::SecondaryControls::ENABLE_USER_WAIT_PAUSE.bits(),
+                );
+            if !supported {
+                warn!("VmxVcpu: UMWAIT and TPAUSE are not supported, WAITPKG disabled");
+            }
+            supported.then(|| UmwaitControl::new(limits, Msr::IA32_UMWAIT_CONTROL.read()))
+        });
+        self.ple = config.pause_loop_exiting.and_then(|ple| {
+            let supported = vmcs::control_allowed1(
+                Msr::IA32_VMX_PROCBASED_CTLS2,
+                vmcs::controls::SecondaryControls::PAUSE_LOOP_EXITING.bits(),
+            );
+            if !supported {
+                warn!("VmxVcpu: PAUSE-loop exiting is not supported, disabled");
+            }
+            supported.then(|| PauseLoopExiting::new(ple))
+        });
+        if !self.pv_ops.is_empty() && self.hypercalls.abi() == HypercallAbi::Native {
+            // Linux makes the KVM hypercalls with the KVM convention. The
+            // Hyper-V one is ruled out above.
+            self.hypercalls.set_abi(HypercallAbi::Kvm);
+        }
+        if let Some(vector) = self.posted_interrupt_vector {
+            self.posted_interrupts
+                .descriptor()
+                .set_notification_vector(vector);
+        }
         self.setup_msr_bitmap()?;
-        self.setup_vmcs_guest(entry)?;
-        self.setup_vmcs_control(ept_root, true)?;
+        self.setup_vmcs_guest(entry, &config.boot_mode)?;
+        self.setup_vmcs_control(ept_root, config.boot_mode.is_long_mode())?;
         self.unbind_from_current_processor()?;
         Ok(())
     }
 
+    /// Set up the guest TSC: its rate, scaled if requested and supported, and
+    /// its offset, starting at 0 at the configured origin.
+    fn setup_tsc(&mut self, config: &VmxSetupConfig) {
+        let scaling = vmcs::control_allowed1(
+            Msr::IA32_VMX_PROCBASED_CTLS2,
+            vmcs::controls::SecondaryControls::USE_TSC_SCALING.bits(),
+        );
+        self.tsc = TscModel::new(preemption::tsc_frequency_hz(), config.guest_tsc_hz, scaling);
+        if config.guest_tsc_hz.is_some() && self.tsc.guest_hz() != config.guest_tsc_hz {
+            warn!(
+                "VmxVcpu: cannot scale the guest TSC to {:?} Hz, it runs at {:?} Hz",
+                config.guest_tsc_hz,
+                self.tsc.guest_hz()
+            );
+        }
+        let origin = config
+            .tsc_origin
+            .unwrap_or_else(|| unsafe { x86::time::rdtsc() });
+        self.tsc.set_guest_tsc(origin, 0);
+
+        self.pvclock = config.kvmclock.and_then(|wall_clock_ns| {
+            let clock = self
+                .tsc
+                .guest_hz()
+                .map(|hz| PvClock::new(hz, wall_clock_ns));
+            if clock.is_none() {
+                warn!("VmxVcpu: kvmclock disabled, the guest TSC frequency is unknown");
+            }
+            clock
+        });
+
+        let mut hyperv = config.hyperv;
+        let timing = HyperVFeatures::REFERENCE_TSC | HyperVFeatures::SYNTHETIC_TIMERS;
+        if hyperv.intersects(timing) && self.tsc.guest_hz().is_none() {
+            warn!(
+                "VmxVcpu: Hyper-V reference TSC and timers disabled, the guest TSC frequency is unknown"
+            );
+            hyperv -= timing;
+        }
+        self.hyperv.set_features(hyperv);
+        if hyperv.contains(HyperVFeatures::HYPERCALL) {
+            // The hypercall page makes calls with the Hyper-V convention.
+            self.hypercalls.set_abi(HypercallAbi::HyperV);
+        }
+    }
+
+    /// The guest TSC as seen by the Hyper-V reference time, if its frequency
+    /// is known.
+    fn hv_clock(&self) -> Option<HvClock> {
+        Some(HvClock {
+            tsc_hz: self.tsc.guest_hz()?,
+            tsc_adjust: self.tsc.tsc_adjust(),
+            guest_tsc: self.guest_tsc(),
+        })
+    }
+
+    /// Refresh the kvmclock and Hyper-V reference TSC pages after the guest
+    /// TSC has been moved.
+    fn update_pv_clocks(&mut self) -> AxResult {
+        self.update_pvclock()?;
+        let Some(clock) = self.hv_clock() else {
+            return Ok(());
+        };
+        let ept_root = self
+            .ept_root
+            .ok_or_else(|| ax_err_type!(BadState, "EPT root is not set"))?;
+        let mut mem = EptGuestMemory::<H::MmHal>::new(ept_root);
+        self.hyperv.update_reference_tsc(&mut mem, &clock)
+    }
+
+    /// Refresh the kvmclock time structure of the vCPU after the guest TSC
+    /// has been moved.
+    fn update_pvclock(&self) -> AxResult {
+        let Some(clock) = &self.pvclock else {
+            return Ok(());
+        };
+        let ept_root = self
+            .ept_root
+            .ok_or_else(|| ax_err_type!(BadState, "EPT root is not set"))?;
+        let mut mem = EptGuestMemory::<H::MmHal>::new(ept_root);
+        let guest_tsc = self.guest_tsc();
+        // Writes to the TSC by the guest are not time passing.
+        let elapsed = guest_tsc.wrapping_sub(self.tsc.tsc_adjust());
+        clock.update(&mut mem, guest_tsc, elapsed)
+    }
+
     fn setup_vmcs_host(&self) -> AxResult {
         VmcsHost64::IA32_PAT.write(Msr::IA32_PAT.read())?;
         VmcsHost64::IA32_EFER.write(Msr::IA32_EFER.read())?;
@@ -615,43 +1266,66 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
         Ok(())
     }
 
-    fn setup_vmcs_guest(&mut self, entry: GuestPhysAddr) -> AxResult {
-        let cr0_val: Cr0Flags =
-            Cr0Flags::NOT_WRITE_THROUGH | Cr0Flags::CACHE_DISABLE | Cr0Flags::EXTENSION_TYPE;
-        self.set_cr(0, cr0_val.bits());
-        self.set_cr(4, 0);
+    fn setup_vmcs_guest(&mut self, entry: GuestPhysAddr, boot_mode: &VmxBootMode) -> AxResult {
+        // Set EFER before CR0 so that the read shadow is consistent with LMA.
+        VmcsGuest64::IA32_EFER.write(boot_mode.efer().bits())?;
+        self.set_cr(0, boot_mode.cr0().bits())?;
+        self.set_cr(4, boot_mode.cr4().bits())?;
 
         macro_rules! set_guest_segment {
-            ($seg: ident, $access_rights: expr) => {{
+            ($seg: ident, $selector: expr, $limit: expr, $access_rights: expr) => {{
                 use VmcsGuest16::*;
                 use VmcsGuest32::*;
                 use VmcsGuestNW::*;
                 paste::paste! {
-                    [<$seg _SELECTOR>].write(0)?;
+                    [<$seg _SELECTOR>].write($selector)?;
                     [<$seg _BASE>].write(0)?;
-                    [<$seg _LIMIT>].write(0xffff)?;
+                    [<$seg _LIMIT>].write($limit)?;
                     [<$seg _ACCESS_RIGHTS>].write($access_rights)?;
                 }
             }};
+            ($seg: ident, $segment: expr) => {
+                set_guest_segment!(
+                    $seg,
+                    $segment.selector,
+                    $segment.limit,
+                    $segment.access_rights
+                )
+            };
         }
 
-        set_guest_segment!(ES, 0x93); // 16-bit, present, data, read/write, accessed
-        set_guest_segment!(CS, 0x9b); // 16-bit, present, code, exec/read, accessed
-        set_guest_segment!(SS, 0x93);
-        set_guest_segment!(DS, 0x93);
-        set_guest_segment!(FS, 0x93);
-        set_guest_segment!(GS, 0x93);
-        set_guest_segment!(TR, 0x8b); // present, system, 32-bit TSS busy
-        set_guest_segment!(LDTR, 0x82); // present, system, LDT
-
-        VmcsGuestNW::GDTR_BASE.write(0)?;
-        VmcsGuest32::GDTR_LIMIT.write(0xffff)?;
-        VmcsGuestNW::IDTR_BASE.write(0)?;
-        VmcsGuest32::IDTR_LIMIT.write(0xffff)?;
+        let code = boot_mode.code_segment();
+        let data = boot_mode.data_segment();
+        set_guest_segment!(ES, data);
+        set_guest_segment!(CS, code);
+        set_guest_segment!(SS, data);
+        set_guest_segment!(DS, data);
+        set_guest_segment!(FS, data);
+        set_guest_segment!(GS, data);
+        set_guest_segment!(TR, 0, 0xffff, 0x8b); // present, system, 32-bit TSS busy
+        set_guest_segment!(LDTR, 0, 0xffff, 0x82); // present, system, LDT
+
+        match boot_mode.flat_state() {
+            None => {
+                VmcsGuestNW::GDTR_BASE.write(0)?;
+                VmcsGuest32::GDTR_LIMIT.write(0xffff)?;
+                VmcsGuestNW::IDTR_BASE.write(0)?;
+                VmcsGuest32::IDTR_LIMIT.write(0xffff)?;
+                VmcsGuestNW::RSP.write(0)?;
+            }
+            Some(state) => {
+                // The guest installs its own IDT before enabling interrupts.
+                VmcsGuestNW::GDTR_BASE.write(state.gdt_base as _)?;
+                VmcsGuest32::GDTR_LIMIT.write(state.gdt_limit as _)?;
+                VmcsGuestNW::IDTR_BASE.write(0)?;
+                VmcsGuest32::IDTR_LIMIT.write(0)?;
+                VmcsGuestNW::RSP.write(state.rsp as _)?;
+                self.guest_regs = state.regs;
+            }
+        }
 
-        VmcsGuestNW::CR3.write(0)?;
+        VmcsGuestNW::CR3.write(boot_mode.cr3() as _)?;
         VmcsGuestNW::DR7.write(0x400)?;
-        VmcsGuestNW::RSP.write(0)?;
         VmcsGuestNW::RIP.write(entry.as_usize())?;
         VmcsGuestNW::RFLAGS.write(0x2)?;
         VmcsGuestNW::PENDING_DBG_EXCEPTIONS.write(0)?;
@@ -662,52 +1336,97 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
         VmcsGuest32::INTERRUPTIBILITY_STATE.write(0)?;
         VmcsGuest32::ACTIVITY_STATE.write(0)?;
 
-        VmcsGuest32::VMX_PREEMPTION_TIMER_VALUE.write(VMX_PREEMPTION_TIMER_SET_VALUE)?;
-
         VmcsGuest64::LINK_PTR.write(u64::MAX)?; // SDM Vol. 3C, Section 24.4.2
         VmcsGuest64::IA32_DEBUGCTL.write(0)?;
         VmcsGuest64::IA32_PAT.write(Msr::IA32_PAT.read())?;
-        VmcsGuest64::IA32_EFER.write(0)?;
         Ok(())
     }
 
-    fn setup_vmcs_control(&mut self, ept_root: HostPhysAddr, is_guest: bool) -> AxResult {
+    fn setup_vmcs_control(&mut self, ept_root: HostPhysAddr, long_mode: bool) -> AxResult {
         // Intercept NMI and external interrupts.
         use super::vmcs::controls::*;
         use PinbasedControls as PinCtrl;
         let raw_cpuid = CpuId::new();
 
+        let mut val = PinCtrl::NMI_EXITING | PinCtrl::EXTERNAL_INTERRUPT_EXITING;
+        if self.virtual_nmi {
+            val |= PinCtrl::VIRTUAL_NMIS;
+        }
+        let posted_interrupts = self.posted_interrupt_vector.is_some()
+            && self
+                .apicv
+                .contains(SecondaryControls::VIRTUAL_INTERRUPT_DELIVERY)
+            && vmcs::control_allowed1(
+                Msr::IA32_VMX_TRUE_PINBASED_CTLS,
+                PinCtrl::POSTED_INTERRUPTS.bits(),
+            );
+        if posted_interrupts {
+            val |= PinCtrl::POSTED_INTERRUPTS;
+        }
         vmcs::set_control(
             VmcsControl32::PINBASED_EXEC_CONTROLS,
             Msr::IA32_VMX_TRUE_PINBASED_CTLS,
             Msr::IA32_VMX_PINBASED_CTLS.read() as u32,
-            (PinCtrl::NMI_EXITING | PinCtrl::EXTERNAL_INTERRUPT_EXITING).bits(),
-            // (PinCtrl::NMI_EXITING | PinCtrl::VMX_PREEMPTION_TIMER).bits(),
+            val.bits(),
+            // The VMX-preemption timer is armed by `run_with_deadline` only.
             // PinCtrl::NMI_EXITING.bits(),
             0,
         )?;
 
         // Intercept all I/O instructions, use MSR bitmaps, activate secondary controls,
         // disable CR3 load/store interception.
+        // CR8 accesses reach the TPR of the vLAPIC: through the TPR shadow in the
+        // virtual-APIC page if APIC virtualization is used, by VM exits otherwise.
         use PrimaryControls as CpuCtrl;
+        let mut val = CpuCtrl::USE_IO_BITMAPS
+            | CpuCtrl::USE_MSR_BITMAPS
+            | CpuCtrl::SECONDARY_CONTROLS
+            | CpuCtrl::USE_TSC_OFFSETTING;
+        let mut clear =
+            CpuCtrl::CR3_LOAD_EXITING | CpuCtrl::CR3_STORE_EXITING | CpuCtrl::RDTSC_EXITING;
+        if self.apicv.is_empty() {
+            val |= CpuCtrl::CR8_LOAD_EXITING | CpuCtrl::CR8_STORE_EXITING;
+        } else {
+            val |= CpuCtrl::USE_TPR_SHADOW;
+            clear |= CpuCtrl::CR8_LOAD_EXITING | CpuCtrl::CR8_STORE_EXITING;
+        }
+        let mwait_exiting = CpuCtrl::MONITOR_EXITING | CpuCtrl::MWAIT_EXITING;
+        match self.mwait {
+            MwaitMode::Passthrough => clear |= mwait_exiting,
+            MwaitMode::Halt => val |= mwait_exiting,
+        }
+        if self.pv_ops.contains(PvOpsFeatures::SPINLOCK_KICK) {
+            // A kick may come before the waiter halts, see `handle_hlt`.
+            val |= CpuCtrl::HLT_EXITING;
+        }
         vmcs::set_control(
             VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS,
             Msr::IA32_VMX_TRUE_PROCBASED_CTLS,
             Msr::IA32_VMX_PROCBASED_CTLS.read() as u32,
-            (CpuCtrl::USE_IO_BITMAPS | CpuCtrl::USE_MSR_BITMAPS | CpuCtrl::SECONDARY_CONTROLS)
-                .bits(),
-            (CpuCtrl::CR3_LOAD_EXITING
-                | CpuCtrl::CR3_STORE_EXITING
-                | CpuCtrl::CR8_LOAD_EXITING
-                | CpuCtrl::CR8_STORE_EXITING)
-                .bits(),
+            val.bits(),
+            clear.bits(),
         )?;
 
-        // Enable EPT, RDTSCP, INVPCID, and unrestricted guest.
+        // Enable EPT, RDTSCP, INVPCID, and unrestricted guest if supported, and the
+        // APIC-virtualization controls for the APIC mode. In xAPIC mode, MMIO
+        // accesses cause APIC-access VM exits and are emulated.
         use SecondaryControls as CpuCtrl2;
-        let mut val =
-            // CpuCtrl2::VIRTUALIZE_APIC | 
-            CpuCtrl2::ENABLE_EPT | CpuCtrl2::UNRESTRICTED_GUEST;
+        let mut val = CpuCtrl2::ENABLE_EPT | self.apic_controls();
+        if self.unrestricted_guest {
+            val |= CpuCtrl2::UNRESTRICTED_GUEST;
+        }
+        if self.tsc.is_scaled() {
+            val |= CpuCtrl2::USE_TSC_SCALING;
+        }
+        if self.umwait.is_some() {
+            // UMWAIT and TPAUSE run in the guest, RDTSC exiting being 0.
+            val |= CpuCtrl2::ENABLE_USER_WAIT_PAUSE;
+        }
+        if let Some(ple) = &self.ple {
+            val |= CpuCtrl2::PAUSE_LOOP_EXITING;
+            VmcsControl32::PLE_GAP.write(ple.gap())?;
+            VmcsControl32::PLE_WINDOW.write(ple.window())?;
+        }
         if let Some(features) = raw_cpuid.get_extended_processor_and_feature_identifiers() {
             if features.has_rdtscp() {
                 val |= CpuCtrl2::ENABLE_RDTSCP;
@@ -747,17 +1466,17 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
             0,
         )?;
 
+        // Load guest IA32_PAT/IA32_EFER on VM entry.
+        use EntryControls as EntryCtrl;
         let mut val = EntryCtrl::LOAD_IA32_PAT | EntryCtrl::LOAD_IA32_EFER;
 
-        if !is_guest {
+        if long_mode {
             // IA-32e mode guest
             // On processors that support Intel 64 architecture, this control determines whether the logical processor is in IA-32e mode after VM entry.
             // Its value is loaded into IA32_EFER.LMA as part of VM entry.
             val |= EntryCtrl::IA32E_MODE_GUEST;
         }
 
-        // Load guest IA32_PAT/IA32_EFER on VM entry.
-        use EntryControls as EntryCtrl;
         vmcs::set_control(
             VmcsControl32::VMENTRY_CONTROLS,
             Msr::IA32_VMX_TRUE_ENTRY_CTLS,
@@ -786,9 +1505,34 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
         VmcsControl64::IO_BITMAP_B_ADDR.write(self.io_bitmap.phys_addr().1.as_usize() as _)?;
         VmcsControl64::MSR_BITMAPS_ADDR.write(self.msr_bitmap.phys_addr().as_usize() as _)?;
 
-        // VmcsControl64::APIC_ACCESS_ADDR.write(
-        //     EmulatedLocalApic::<H::MmHal, DummyHal>::virtual_apic_access_addr().as_usize() as _,
-        // )?;
+        VmcsControl64::TSC_OFFSET.write(self.tsc.offset())?;
+        if self.tsc.is_scaled() {
+            VmcsControl64::TSC_MULTIPLIER.write(self.tsc.multiplier())?;
+        }
+
+        if !self.apicv.is_empty() {
+            VmcsControl64::VIRT_APIC_ADDR
+                .write(self.vlapic.virtual_apic_page_addr().as_usize() as _)?;
+            VmcsControl32::TPR_THRESHOLD.write(0)?;
+        }
+        if self.apicv.contains(CpuCtrl2::VIRTUAL_INTERRUPT_DELIVERY) {
+            // No EOI exits until `set_eoi_exit` asks for them.
+            for field in EOI_EXIT_BITMAP {
+                field.write(0)?;
+            }
+            VmcsGuest16::INTERRUPT_STATUS.write(0)?;
+        }
+        if let Some(vector) = self.posted_interrupt_vector.filter(|_| posted_interrupts) {
+            VmcsControl16::POSTED_INTERRUPT_NOTIFICATION_VECTOR.write(vector as _)?;
+            VmcsControl64::POSTED_INTERRUPT_DESC_ADDR
+                .write(self.posted_interrupts.phys_addr::<H::MmHal>().as_usize() as _)?;
+        }
+
+        if Self::supports_apic_accesses() {
+            // The guest xAPIC page must be mapped to this page in the EPT.
+            VmcsControl64::APIC_ACCESS_ADDR
+                .write(EmulatedLocalApic::virtual_apic_access_addr().as_usize() as _)?;
+        }
         Ok(())
     }
 
@@ -817,59 +1561,228 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
 // Implementaton for type1.5 hypervisor
 // #[cfg(feature = "type1_5")]
 impl<H: AxVCpuHal> VmxVcpu<H> {
-    fn set_cr(&mut self, cr_idx: usize, val: u64) {
-        (|| -> AxResult {
-            // debug!("set guest CR{} to val {:#x}", cr_idx, val);
-            match cr_idx {
-                0 => {
-                    // Retrieve/validate restrictions on CR0
-                    //
-                    // In addition to what the VMX MSRs tell us, make sure that
-                    // - NW and CD are kept off as they are not updated on VM exit and we
-                    //   don't want them enabled for performance reasons while in root mode
-                    // - PE and PG can be freely chosen (by the guest) because we demand
-                    //   unrestricted guest mode support anyway
-                    // - ET is ignored
-                    let must0 = Msr::IA32_VMX_CR0_FIXED1.read()
-                        & !(Cr0Flags::NOT_WRITE_THROUGH | Cr0Flags::CACHE_DISABLE).bits();
-                    let must1 = Msr::IA32_VMX_CR0_FIXED0.read()
-                        & !(Cr0Flags::PAGING | Cr0Flags::PROTECTED_MODE_ENABLE).bits();
-                    VmcsGuestNW::CR0.write(((val & must0) | must1) as _)?;
-                    VmcsControlNW::CR0_READ_SHADOW.write(val as _)?;
-                    VmcsControlNW::CR0_GUEST_HOST_MASK.write((must1 | !must0) as _)?;
+    fn set_cr(&mut self, cr_idx: usize, val: u64) -> AxResult {
+        // debug!("set guest CR{} to val {:#x}", cr_idx, val);
+        match cr_idx {
+            0 => {
+                // Retrieve/validate restrictions on CR0
+                //
+                // In addition to what the VMX MSRs tell us, make sure that
+                // - NW and CD are kept off as they are not updated on VM exit and we
+                //   don't want them enabled for performance reasons while in root mode
+                // - PE and PG can be freely chosen (by the guest) with unrestricted
+                //   guest mode support, otherwise they stay on in the real CR0 and
+                //   the guest runs in the legacy-mode interpreter until it turns on
+                //   both of them
+                // - ET is ignored
+                let must0 = Msr::IA32_VMX_CR0_FIXED1.read()
+                    & !(Cr0Flags::NOT_WRITE_THROUGH | Cr0Flags::CACHE_DISABLE).bits();
+                let mut must1 = Msr::IA32_VMX_CR0_FIXED0.read();
+                if self.unrestricted_guest {
+                    must1 &= !(Cr0Flags::PAGING | Cr0Flags::PROTECTED_MODE_ENABLE).bits();
+                }
+                VmcsGuestNW::CR0.write(((val & must0) | must1) as _)?;
+                VmcsControlNW::CR0_READ_SHADOW.write(val as _)?;
+                VmcsControlNW::CR0_GUEST_HOST_MASK.write((must1 | !must0) as _)?;
+            }
+            3 => VmcsGuestNW::CR3.write(val as _)?,
+            4 => {
+                // Retrieve/validate restrictions on CR4
+                let must0 = Msr::IA32_VMX_CR4_FIXED1.read();
+                let must1 = Msr::IA32_VMX_CR4_FIXED0.read();
+                let val = val | Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS.bits();
+                VmcsGuestNW::CR4.write(((val & must0) | must1) as _)?;
+                VmcsControlNW::CR4_READ_SHADOW.write(val as _)?;
+                VmcsControlNW::CR4_GUEST_HOST_MASK.write((must1 | !must0) as _)?;
+            }
+            _ => return ax_err!(InvalidInput, format_args!("no such register CR{}", cr_idx)),
+        };
+        Ok(())
+    }
+
+    fn cr(&self, cr_idx: usize) -> AxResult<usize> {
+        Ok(match cr_idx {
+            0 => VmcsGuestNW::CR0.read()?,
+            3 => VmcsGuestNW::CR3.read()?,
+            4 => {
+                let host_mask = VmcsControlNW::CR4_GUEST_HOST_MASK.read()?;
+                (VmcsControlNW::CR4_READ_SHADOW.read()? & host_mask)
+                    | (VmcsGuestNW::CR4.read()? & !host_mask)
+            }
+            _ => return ax_err!(InvalidInput, format_args!("no such register CR{}", cr_idx)),
+        })
+    }
+}
+
+// Legacy-mode emulation when unrestricted guest is not supported
+impl<H: AxVCpuHal> VmxVcpu<H> {
+    /// Whether the guest is in a mode that the hardware can not run, i.e. real
+    /// mode or protected mode without paging on a processor lacking the
+    /// unrestricted-guest control.
+    fn needs_legacy_emulation(&self) -> AxResult<bool> {
+        if self.unrestricted_guest {
+            return Ok(false);
+        }
+        let cr0 = Cr0Flags::from_bits_truncate(VmcsControlNW::CR0_READ_SHADOW.read()? as u64);
+        Ok(!cr0.contains(Cr0Flags::PROTECTED_MODE_ENABLE | Cr0Flags::PAGING))
+    }
+
+    fn load_legacy_state(&self) -> AxResult<LegacyCpuState> {
+        macro_rules! read_guest_segment {
+            ($seg: ident) => {{
+                use VmcsGuest16::*;
+                use VmcsGuest32::*;
+                use VmcsGuestNW::*;
+                paste::paste! {
+                    SegmentCache {
+                        selector: [<$seg _SELECTOR>].read()?,
+                        base: [<$seg _BASE>].read()? as u64,
+                        limit: [<$seg _LIMIT>].read()?,
+                        access_rights: [<$seg _ACCESS_RIGHTS>].read()?,
+                    }
                 }
-                3 => VmcsGuestNW::CR3.write(val as _)?,
-                4 => {
-                    // Retrieve/validate restrictions on CR4
-                    let must0 = Msr::IA32_VMX_CR4_FIXED1.read();
-                    let must1 = Msr::IA32_VMX_CR4_FIXED0.read();
-                    let val = val | Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS.bits();
-                    VmcsGuestNW::CR4.write(((val & must0) | must1) as _)?;
-                    VmcsControlNW::CR4_READ_SHADOW.write(val as _)?;
-                    VmcsControlNW::CR4_GUEST_HOST_MASK.write((must1 | !must0) as _)?;
+            }};
+        }
+
+        Ok(LegacyCpuState {
+            regs: self.guest_regs,
+            rsp: VmcsGuestNW::RSP.read()? as u64,
+            rip: VmcsGuestNW::RIP.read()? as u64,
+            rflags: VmcsGuestNW::RFLAGS.read()? as u64,
+            segs: [
+                read_guest_segment!(ES),
+                read_guest_segment!(CS),
+                read_guest_segment!(SS),
+                read_guest_segment!(DS),
+                read_guest_segment!(FS),
+                read_guest_segment!(GS),
+            ],
+            ldtr: read_guest_segment!(LDTR),
+            tr: read_guest_segment!(TR),
+            cr0: VmcsControlNW::CR0_READ_SHADOW.read()? as u64,
+            cr3: VmcsGuestNW::CR3.read()? as u64,
+            cr4: VmcsControlNW::CR4_READ_SHADOW.read()? as u64,
+            efer: VmcsGuest64::IA32_EFER.read()?,
+            gdtr_base: VmcsGuestNW::GDTR_BASE.read()? as u64,
+            gdtr_limit: VmcsGuest32::GDTR_LIMIT.read()?,
+            idtr_base: VmcsGuestNW::IDTR_BASE.read()? as u64,
+            idtr_limit: VmcsGuest32::IDTR_LIMIT.read()?,
+            pending_input: self.legacy_pending_input,
+        })
+    }
+
+    fn store_legacy_state(&mut self, cpu: &LegacyCpuState) -> AxResult {
+        macro_rules! write_guest_segment {
+            ($seg: ident, $cache: expr) => {{
+                use VmcsGuest16::*;
+                use VmcsGuest32::*;
+                use VmcsGuestNW::*;
+                let cache: &SegmentCache = $cache;
+                paste::paste! {
+                    [<$seg _SELECTOR>].write(cache.selector)?;
+                    [<$seg _BASE>].write(cache.base as _)?;
+                    [<$seg _LIMIT>].write(cache.limit)?;
+                    [<$seg _ACCESS_RIGHTS>].write(cache.access_rights)?;
                 }
-                _ => unreachable!(),
-            };
-            Ok(())
-        })()
-        .expect("Failed to write guest control register")
-    }
-
-    #[allow(dead_code)]
-    fn cr(&self, cr_idx: usize) -> usize {
-        (|| -> AxResult<usize> {
-            Ok(match cr_idx {
-                0 => VmcsGuestNW::CR0.read()?,
-                3 => VmcsGuestNW::CR3.read()?,
-                4 => {
-                    let host_mask = VmcsControlNW::CR4_GUEST_HOST_MASK.read()?;
-                    (VmcsControlNW::CR4_READ_SHADOW.read()? & host_mask)
-                        | (VmcsGuestNW::CR4.read()? & !host_mask)
+            }};
+        }
+
+        self.guest_regs = cpu.regs;
+        VmcsGuestNW::RSP.write(cpu.rsp as _)?;
+        VmcsGuestNW::RIP.write(cpu.rip as _)?;
+        VmcsGuestNW::RFLAGS.write(cpu.rflags as _)?;
+        write_guest_segment!(ES, &cpu.segs[SEG_ES]);
+        write_guest_segment!(CS, &cpu.segs[SEG_CS]);
+        write_guest_segment!(SS, &cpu.segs[SEG_SS]);
+        write_guest_segment!(DS, &cpu.segs[SEG_DS]);
+        write_guest_segment!(FS, &cpu.segs[SEG_FS]);
+        write_guest_segment!(GS, &cpu.segs[SEG_GS]);
+        write_guest_segment!(LDTR, &cpu.ldtr);
+        write_guest_segment!(TR, &cpu.tr);
+        self.set_cr(0, cpu.cr0)?;
+        self.set_cr(3, cpu.cr3)?;
+        self.set_cr(4, cpu.cr4)?;
+        VmcsGuest64::IA32_EFER.write(cpu.efer)?;
+        VmcsGuestNW::GDTR_BASE.write(cpu.gdtr_base as _)?;
+        VmcsGuest32::GDTR_LIMIT.write(cpu.gdtr_limit)?;
+        VmcsGuestNW::IDTR_BASE.write(cpu.idtr_base as _)?;
+        VmcsGuest32::IDTR_LIMIT.write(cpu.idtr_limit)?;
+        self.legacy_pending_input = cpu.pending_input;
+        Ok(())
+    }
+
+    /// Run the guest in the legacy-mode interpreter instead of entering it.
+    fn run_legacy(&mut self) -> AxResult<AxVCpuExitReason> {
+        let ept_root = self
+            .ept_root
+            .ok_or_else(|| ax_err_type!(BadState, "EPT root is not set"))?;
+        let mut mem = EptGuestMemory::<H::MmHal>::new(ept_root);
+        let mut cpu = self.load_legacy_state()?;
+
+        // Pending events go through the IVT or the IDT, external interrupts
+        // only if enabled, and wait for an `INS` to get its input.
+        let event = if cpu.pending_input.is_none() {
+            self.events
+                .pop(&EventWindow::open(cpu.interrupts_enabled()))
+        } else {
+            None
+        };
+        if let Some(event) = event {
+            let delivered = cpu.deliver_interrupt(&mut mem, event.vector, event.err_code);
+            if delivered.is_err() {
+                // It goes first again on the next run.
+                self.events.push_interrupted(event);
+            }
+            delivered?;
+        }
+
+        let exit = cpu.run(&mut mem, LEGACY_EMULATION_BUDGET);
+        self.store_legacy_state(&cpu)?;
+
+        Ok(match exit? {
+            None => AxVCpuExitReason::Nothing,
+            Some(LegacyExit::Halt) => AxVCpuExitReason::Halt,
+            Some(LegacyExit::IoRead { port, width }) => self.port_io_exit(port, width, true),
+            Some(LegacyExit::IoWrite { port, width, data }) => {
+                match self.port_io_exit(port, width, false) {
+                    // `OUTS` writes from memory rather than from the accumulator.
+                    AxVCpuExitReason::IoWrite { port, width, .. } => {
+                        AxVCpuExitReason::IoWrite { port, width, data }
+                    }
+                    reason => reason,
+                }
+            }
+            Some(exit @ (LegacyExit::Cpuid | LegacyExit::MsrRead | LegacyExit::MsrWrite)) => {
+                // Complete them as their VM exits, `RIP` is at the 2-byte instruction.
+                let exit_reason = match exit {
+                    LegacyExit::Cpuid => VmxExitReason::CPUID,
+                    LegacyExit::MsrRead => VmxExitReason::MSR_READ,
+                    _ => VmxExitReason::MSR_WRITE,
+                };
+                let exit_info = VmxExitInfo {
+                    entry_failure: false,
+                    exit_reason,
+                    exit_instruction_length: 2,
+                    guest_rip: cpu.rip as _,
+                    exit_qualification: 0,
+                    exit_instruction_info: 0,
+                };
+                match self.dispatch_exit(exit_info)? {
+                    Some(exit_info) => self.msr_exit(exit_info.exit_reason),
+                    None => AxVCpuExitReason::Nothing,
+                }
+            }
+            Some(LegacyExit::PagingEnabled) => {
+                debug!(
+                    "VmxVcpu leaves legacy-mode emulation at {:#x}:{:#x}",
+                    cpu.segs[SEG_CS].selector, cpu.rip
+                );
+                if EferFlags::from_bits_truncate(cpu.efer).contains(EferFlags::LONG_MODE_ENABLE) {
+                    vmcs::update_efer()?;
                 }
-                _ => unreachable!(),
-            })
-        })()
-        .expect("Failed to read guest control register")
+                AxVCpuExitReason::Nothing
+            }
+        })
     }
 }
 
@@ -882,10 +1795,13 @@ macro_rules! vmx_entry_with {
             "mov    rsp, rdi",                      // set RSP to guest regs area
             restore_regs_from_stack!(),             // restore guest status
             $instr,                                 // let's go!
-            "jmp    {failed}",
+            // VM entry failed, the guest registers are untouched and RSP points to `host_stack_top`.
+            save_regs_to_stack!(),                  // save guest status back, rsp points to the `VmxVcpu`
+            "mov    rsp, [rsp + {host_stack_size}]", // set RSP to Vcpu::host_stack_top
+            restore_regs_from_stack!(),             // restore host status
+            "mov    eax, 1",                        // report the failure
+            "ret",
             host_stack_size = const size_of::<GeneralRegisters>(),
-            failed = sym Self::vmx_entry_failed,
-            // options(noreturn),
         )
     }
 }
@@ -896,9 +1812,9 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
     ///
     /// `#[naked]` is essential here, without it the rust compiler will think `&mut self` is not used and won't give us correct %rdi.
     ///
-    /// This function itself never returns, but [`Self::vmx_exit`] will do the return for this.
+    /// On success this function itself never returns, but [`Self::vmx_exit`] will do the return for this.
     ///
-    /// The return value is a dummy value.
+    /// Returns 0 after a VM exit, or 1 if the VM entry failed (VMfailValid or VMfailInvalid).
     unsafe extern "C" fn vmx_launch(&mut self) -> usize {
         vmx_entry_with!("vmlaunch")
     }
@@ -916,79 +1832,251 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
     ///
     /// NEVER call this function directly.
     ///
-    /// The return value is a dummy value.
+    /// Always returns 0, see [`Self::vmx_launch`].
     unsafe extern "C" fn vmx_exit(&mut self) -> usize {
         naked_asm!(
             save_regs_to_stack!(),                  // save guest status, after this, rsp points to the `VmxVcpu`
             "mov    rsp, [rsp + {host_stack_top}]", // set RSP to Vcpu::host_stack_top
             restore_regs_from_stack!(),             // restore host status
+            "xor    eax, eax",                      // VM entry succeeded
             "ret",
             host_stack_top = const size_of::<GeneralRegisters>(),
         );
     }
 
-    fn vmx_entry_failed() -> ! {
-        panic!("{}", vmcs::instruction_error().as_str())
+    /// The virtual-APIC page holding the interrupt state of the emulated local APIC.
+    fn virtual_apic_page(&self) -> VirtualApicPage {
+        let vaddr = H::MmHal::phys_to_virt(self.vlapic.virtual_apic_page_addr());
+        // The page is owned by `self.vlapic` and lives as long as it does.
+        unsafe { VirtualApicPage::new(vaddr.as_mut_ptr()) }
     }
 
-    /// Whether the guest interrupts are blocked. (SDM Vol. 3C, Section 24.4.2, Table 24-3)
-    fn allow_interrupt(&self) -> bool {
-        let rflags = VmcsGuestNW::RFLAGS.read().unwrap();
-        let block_state = VmcsGuest32::INTERRUPTIBILITY_STATE.read().unwrap();
-        rflags as u64 & x86_64::registers::rflags::RFlags::INTERRUPT_FLAG.bits() != 0
-            && block_state == 0
+    /// Which events the guest can accept at the next VM entry.
+    /// (SDM Vol. 3C, Section 24.4.2 and 26.3.1.5)
+    fn event_window(&self) -> AxResult<EventWindow> {
+        let rflags = VmcsGuestNW::RFLAGS.read()?;
+        let activity = VmcsGuest32::ACTIVITY_STATE.read()?;
+        Ok(EventWindow {
+            interrupt_flag: rflags as u64 & RFlags::INTERRUPT_FLAG.bits() != 0,
+            interruptibility: InterruptibilityState::from_bits_truncate(
+                VmcsGuest32::INTERRUPTIBILITY_STATE.read()?,
+            ),
+            activity: VmxActivityState::try_from(activity)
+                .map_err(|_| ax_err_type!(BadState, "invalid guest activity state"))?,
+            entry_event_pending: VmcsControl32::VMENTRY_INTERRUPTION_INFO_FIELD
+                .read()?
+                .get_bit(31),
+        })
     }
 
     /// Try to inject a pending event before next VM entry.
     fn inject_pending_events(&mut self) -> AxResult {
-        if let Some(event) = self.pending_events.front() {
-            // trace!(
-            //     "pending event vector {:#x} allow_int {}",
-            //     event.0,
-            //     self.allow_interrupt()
-            // );
-            if event.0 < 32 || self.allow_interrupt() {
-                // if it's an exception, or an interrupt that is not blocked, inject it directly.
-                vmcs::inject_event(event.0, event.1)?;
-                self.pending_events.pop_front();
-            } else {
-                // interrupts are blocked, enable interrupt-window exiting.
-                self.set_interrupt_window(true)?;
+        if let Some(clock) = self.hv_clock() {
+            let posted = self.posted_interrupts.descriptor();
+            self.hyperv.poll_timers(clock.reference_time(), |vector| {
+                posted.post(vector);
+            });
+        }
+        let window = self.event_window()?;
+        let mut apic_page = self.virtual_apic_page();
+        if let Some(requests) = self.posted_interrupts.descriptor().take_requests() {
+            // Posted while the processor could not process them.
+            apic_page.request_interrupts(&requests);
+        }
+        let virtual_interrupt_delivery = self
+            .apicv
+            .contains(vmcs::controls::SecondaryControls::VIRTUAL_INTERRUPT_DELIVERY);
+        let mut next = self.events.pop(&window);
+        if next.is_none() && !virtual_interrupt_delivery && window.interrupt_allowed() {
+            // Nothing else goes first, deliver what the vLAPIC accepts.
+            next = apic_page
+                .accept_interrupt()
+                .map(|vector| PendingEvent::new(vector, None));
+        }
+        if let Some(event) = next {
+            if window.activity == VmxActivityState::Hlt {
+                // The event wakes the guest up, and most exceptions cannot be
+                // injected in the HLT state. (SDM Vol. 3C, Section 26.3.1.5)
+                VmcsGuest32::ACTIVITY_STATE.write(VmxActivityState::Active as u32)?;
+            }
+            vmcs::inject_event(&event)?;
+            if let Some(fault_addr) = event.fault_addr {
+                // Loaded into CR2 right before the VM entry.
+                self.guest_cr2 = fault_addr as usize;
             }
         }
+        if self.events.has_nmi() && self.virtual_nmi {
+            // NMIs are blocked or another event goes first, enable NMI-window exiting.
+            self.set_nmi_window(true)?;
+        }
+        if virtual_interrupt_delivery {
+            // The processor delivers vLAPIC interrupts itself.
+            Self::update_guest_interrupt_status(&apic_page)?;
+        } else if !self.apicv.is_empty() {
+            Self::update_tpr_threshold(&apic_page)?;
+        }
+        if self.events.has_interrupt()
+            || (!virtual_interrupt_delivery && apic_page.pending_interrupt().is_some())
+        {
+            // interrupts are blocked or another event goes first, enable interrupt-window exiting.
+            self.set_interrupt_window(true)?;
+        }
         Ok(())
     }
 
-    /// Handle vm-exits than can and should be handled by [`VmxVcpu`] itself.
-    ///
-    /// Return the result or None if the vm-exit was not handled.
-    fn builtin_vmexit_handler(&mut self, exit_info: &VmxExitInfo) -> Option<AxResult> {
-        const X2APIC_MSR_BASE: u32 = 0x800;
-        const X2APIC_MSR_END: u32 = 0x8ff; // SDM says 0x8ff, but actually 0x83f, we respect the SDM here.
-        // Following vm-exits are handled here:
+    /// Request the highest vLAPIC interrupt (RVI) and report the highest
+    /// in-service one (SVI), the processor evaluates and delivers it on VM
+    /// entry. (SDM Vol. 3C, Section 29.2.1)
+    fn update_guest_interrupt_status(apic_page: &VirtualApicPage) -> AxResult {
+        let rvi = apic_page.highest_irr().unwrap_or(0) as u16;
+        let svi = apic_page.highest_isr().unwrap_or(0) as u16;
+        VmcsGuest16::INTERRUPT_STATUS.write(svi << 8 | rvi)
+    }
+
+    /// Exit with `TPR_BELOW_THRESHOLD` once the guest lowers its TPR enough to
+    /// unmask the highest requested vLAPIC interrupt. The threshold must not
+    /// exceed the priority class of the TPR. (SDM Vol. 3C, Section 26.2.1.1 and 29.1.2)
+    fn update_tpr_threshold(apic_page: &VirtualApicPage) -> AxResult {
+        let threshold = apic_page
+            .highest_irr()
+            .map_or(0, |vector| (vector >> 4).min(apic_page.tpr() >> 4));
+        VmcsControl32::TPR_THRESHOLD.write(threshold as u32)
+    }
+
+    /// Block NMIs again if the VM exit interrupted an IRET that had already
+    /// unblocked them, the guest executes the IRET again after the VM exit.
+    /// (SDM Vol. 3C, Section 27.2.3 and 28.3)
+    fn restore_nmi_blocking(&self, exit_info: &VmxExitInfo) -> AxResult {
+        const NMI_UNBLOCKING: usize = 12;
+
+        if !self.virtual_nmi {
+            return Ok(());
+        }
+        let unblocked = match exit_info.exit_reason {
+            VmxExitReason::EXCEPTION_NMI => {
+                let int_info = vmcs::raw_interrupt_exit_info()?;
+                // Undefined for a #DF.
+                int_info.get_bit(NMI_UNBLOCKING)
+                    && int_info.get_bits(0..8) as u8 != x86::irq::DOUBLE_FAULT_VECTOR
+            }
+            VmxExitReason::EPT_VIOLATION | VmxExitReason::PML_FULL | VmxExitReason::SPP_EVENT => {
+                exit_info.exit_qualification.get_bit(NMI_UNBLOCKING)
+            }
+            _ => false,
+        };
+        if unblocked {
+            let state = VmcsGuest32::INTERRUPTIBILITY_STATE.read()?;
+            VmcsGuest32::INTERRUPTIBILITY_STATE
+                .write(state | InterruptibilityState::BLOCKING_BY_NMI.bits())?;
+        }
+        Ok(())
+    }
+
+    /// Handle vm-exits than can and should be handled by [`VmxVcpu`] itself.
+    ///
+    /// Return the result or None if the vm-exit was not handled.
+    fn builtin_vmexit_handler(&mut self, exit_info: &VmxExitInfo) -> Option<VcpuResult> {
+        const X2APIC_MSR_BASE: u32 = 0x800;
+        const X2APIC_MSR_END: u32 = 0x8ff; // SDM says 0x8ff, but actually 0x83f, we respect the SDM here.
+        // Following vm-exits are handled here:
         // - interrupt window: turn off interrupt window;
+        // - nmi window: turn off nmi window;
         // - xsetbv: set guest xcr;
-        // - cr access: just panic;
-        match exit_info.exit_reason {
-            VmxExitReason::INTERRUPT_WINDOW => Some(self.set_interrupt_window(false)),
-            VmxExitReason::PREEMPTION_TIMER => Some(self.handle_vmx_preemption_timer()),
-            VmxExitReason::XSETBV => Some(self.handle_xsetbv()),
-            VmxExitReason::CR_ACCESS => Some(self.handle_cr()),
-            VmxExitReason::CPUID => Some(self.handle_cpuid()),
+        // - cr access: emulate writes to cr0 and cr4;
+        // An invalid guest action ends in an exception injected into the guest.
+        let result = match exit_info.exit_reason {
+            VmxExitReason::INTERRUPT_WINDOW => self.set_interrupt_window(false),
+            VmxExitReason::NMI_WINDOW => self.set_nmi_window(false),
+            VmxExitReason::PREEMPTION_TIMER => self.handle_vmx_preemption_timer(),
+            VmxExitReason::XSETBV => return Some(self.handle_xsetbv()),
+            VmxExitReason::CR_ACCESS => return Some(self.handle_cr()),
+            VmxExitReason::CPUID => self.handle_cpuid(),
+            msr_rw @ (VmxExitReason::MSR_READ | VmxExitReason::MSR_WRITE)
+                if self.pvclock.is_some()
+                    && matches!(
+                        self.regs().rcx as u32,
+                        pvclock::MSR_KVM_WALL_CLOCK_NEW | pvclock::MSR_KVM_SYSTEM_TIME_NEW
+                    ) =>
+            {
+                self.handle_kvmclock_msr_access(msr_rw == VmxExitReason::MSR_WRITE)
+            }
+            msr_rw @ (VmxExitReason::MSR_READ | VmxExitReason::MSR_WRITE)
+                if self.hyperv.handles_msr(self.regs().rcx as u32) =>
+            {
+                return Some(self.handle_hyperv_msr_access(msr_rw == VmxExitReason::MSR_WRITE));
+            }
+            msr_rw @ (VmxExitReason::MSR_READ | VmxExitReason::MSR_WRITE)
+                if self.hyperv.features().contains(HyperVFeatures::APIC_ACCESS)
+                    && (hyperv::HV_X64_MSR_EOI..=hyperv::HV_X64_MSR_TPR)
+                        .contains(&(self.regs().rcx as u32)) =>
+            {
+                return Some(
+                    self.handle_hyperv_apic_msr_access(msr_rw == VmxExitReason::MSR_WRITE),
+                );
+            }
+            VmxExitReason::VMCALL => return self.handle_vmcall(),
+            VmxExitReason::HLT | VmxExitReason::MWAIT_INSTRUCTION => {
+                return self.handle_hlt(exit_info);
+            }
+            // With `MwaitMode::Halt`, there is nothing to monitor.
+            VmxExitReason::MONITOR_INSTRUCTION => {
+                self.advance_rip(exit_info.exit_instruction_length as _)
+            }
+            msr_rw @ (VmxExitReason::MSR_READ | VmxExitReason::MSR_WRITE)
+                if self.regs().rcx as u32 == Msr::IA32_UMWAIT_CONTROL as u32 =>
+            {
+                return Some(self.handle_umwait_control_access(msr_rw == VmxExitReason::MSR_WRITE));
+            }
+            VmxExitReason::PAUSE_INSTRUCTION => self.handle_pause_loop(),
+            VmxExitReason::RDTSC => self.handle_rdtsc(false),
+            VmxExitReason::RDTSCP => self.handle_rdtsc(true),
+            msr_rw @ (VmxExitReason::MSR_READ | VmxExitReason::MSR_WRITE)
+                if matches!(
+                    self.regs().rcx as u32,
+                    x86::msr::IA32_TIME_STAMP_COUNTER
+                        | x86::msr::IA32_TSC_ADJUST
+                        | x86::msr::IA32_TSC_DEADLINE
+                ) =>
+            {
+                self.handle_tsc_msr_access(msr_rw == VmxExitReason::MSR_WRITE)
+            }
+            msr_rw @ (VmxExitReason::MSR_READ | VmxExitReason::MSR_WRITE)
+                if self.regs().rcx as u32 == x86::msr::IA32_APIC_BASE =>
+            {
+                return Some(self.handle_apic_base_access(msr_rw == VmxExitReason::MSR_WRITE));
+            }
             msr_rw @ (VmxExitReason::MSR_READ | VmxExitReason::MSR_WRITE)
                 if {
                     let msr = self.regs().rcx as u32;
                     msr >= X2APIC_MSR_BASE && msr <= X2APIC_MSR_END
                 } =>
             {
-                Some(self.handle_apic_msr_access(
+                if self.apic_base.mode() != ApicMode::X2Apic {
+                    return Some(Err(VcpuError::gp("x2APIC MSR access outside x2APIC mode")));
+                }
+                self.handle_apic_msr_access(
                     msr_rw == VmxExitReason::MSR_WRITE,
                     self.regs().rcx as u32,
-                ))
+                )
             }
-            VmxExitReason::APIC_ACCESS => Some(self.handle_apic_access(exit_info)),
-            _ => None,
-        }
+            VmxExitReason::APIC_ACCESS => self.handle_apic_access(exit_info),
+            // The unmasked interrupt is injected before the next VM entry.
+            VmxExitReason::TPR_BELOW_THRESHOLD => Ok(()),
+            VmxExitReason::APIC_WRITE => {
+                self.handle_apic_write(exit_info.exit_qualification.get_bits(0..12) as usize)
+            }
+            VmxExitReason::VIRTUALIZED_EOI => {
+                // The EOI has been virtualized already, only forward it.
+                let vector = exit_info.exit_qualification.get_bits(0..8) as u8;
+                trace!("VmxVcpu: virtualized EOI of vector {:#x}", vector);
+                if let Some(handler) = &self.eoi_handler {
+                    handler(vector);
+                }
+                Ok(())
+            }
+            _ => return None,
+        };
+        Some(result.map_err(VcpuError::from))
     }
 
     /// Read a 64-bit value from EDX:EAX.
@@ -1002,6 +2090,313 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
         self.regs_mut().rdx = val >> 32;
     }
 
+    /// Emulate `RDMSR` and `WRMSR` of `IA32_APIC_BASE`, switching the APIC mode
+    /// on a write.
+    fn handle_apic_base_access(&mut self, write: bool) -> VcpuResult {
+        const VMEXIT_INSTR_LEN_RDMSR_WRMSR: u8 = 2;
+
+        if write {
+            let cpuid = CpuId::new();
+            let x2apic = cpuid.get_feature_info().is_some_and(|f| f.has_x2apic());
+            let phys_bits = cpuid
+                .get_processor_capacity_feature_info()
+                .map_or(36, |f| f.physical_address_bits());
+            let new = self
+                .apic_base
+                .write(self.read_edx_eax(), phys_bits, x2apic)?;
+            let old = core::mem::replace(&mut self.apic_base, new);
+            debug!(
+                "VmxVcpu: IA32_APIC_BASE {:#x} -> {:#x}",
+                old.bits(),
+                new.bits()
+            );
+            if old.mode() != new.mode() {
+                self.switch_apic_mode()?;
+            }
+        } else {
+            self.write_edx_eax(self.apic_base.bits());
+        }
+        self.advance_rip(VMEXIT_INSTR_LEN_RDMSR_WRMSR)?;
+        Ok(())
+    }
+
+    /// Emulate `RDMSR` and `WRMSR` of the kvmclock MSRs.
+    fn handle_kvmclock_msr_access(&mut self, write: bool) -> AxResult {
+        const VMEXIT_INSTR_LEN_RDMSR_WRMSR: u8 = 2;
+
+        let msr = self.regs().rcx as u32;
+        let value = self.read_edx_eax();
+        let Some(clock) = self.pvclock.as_mut() else {
+            return ax_err!(BadState, "kvmclock is not offered");
+        };
+        match (write, msr) {
+            (true, pvclock::MSR_KVM_WALL_CLOCK_NEW) => {
+                trace!("kvmclock: wall clock at {:#x}", value);
+                let ept_root = self
+                    .ept_root
+                    .ok_or_else(|| ax_err_type!(BadState, "EPT root is not set"))?;
+                let mut mem = EptGuestMemory::<H::MmHal>::new(ept_root);
+                clock.write_wall_clock(&mut mem, GuestPhysAddr::from(value as usize))?;
+            }
+            (true, _) => {
+                trace!("kvmclock: system time at {:#x}", value);
+                if clock.set_system_time_msr(value) {
+                    self.update_pvclock()?;
+                }
+            }
+            (false, pvclock::MSR_KVM_WALL_CLOCK_NEW) => {
+                let value = clock.wall_clock_msr();
+                self.write_edx_eax(value);
+            }
+            (false, _) => {
+                let value = clock.system_time_msr();
+                self.write_edx_eax(value);
+            }
+        }
+        self.advance_rip(VMEXIT_INSTR_LEN_RDMSR_WRMSR)
+    }
+
+    /// Complete a hypercall with its in-vCPU handler. Returns `None` to pass
+    /// it to the VMM.
+    fn handle_vmcall(&mut self) -> Option<VcpuResult> {
+        const VM_EXIT_INSTR_LEN_VMCALL: u8 = 3;
+
+        let cpl = match VmcsGuest32::SS_ACCESS_RIGHTS.read() {
+            Ok(ar) => ar.get_bits(5..7),
+            Err(err) => return Some(Err(err.into())),
+        };
+        if cpl != 0 && self.hypercalls.deny_user() {
+            return Some(Err(VcpuError::ud("hypercall at CPL > 0")));
+        }
+        let long_mode = self.get_cpu_mode() == VmCpuMode::Mode64;
+        let call = self.hypercalls.decode(self.regs(), long_mode);
+        match self.hypercalls.dispatch(&call) {
+            HypercallAction::Return(value) => {
+                trace!("hypercall {:#x} handled in the vCPU: {:#x}", call.nr, value);
+                let mut regs = *self.regs();
+                self.hypercalls.write_result(&mut regs, long_mode, value);
+                *self.regs_mut() = regs;
+                Some(
+                    self.advance_rip(VM_EXIT_INSTR_LEN_VMCALL)
+                        .map_err(VcpuError::from),
+                )
+            }
+            HypercallAction::Exit => None,
+        }
+    }
+
+    /// Complete `HLT`, or `MWAIT` with `MwaitMode::Halt`, at once if the vCPU
+    /// has been kicked with `KVM_HC_KICK_CPU` since it last halted, so that a
+    /// kick before the waiter halts is not lost. Returns `None` to report the
+    /// halt to the VMM.
+    fn handle_hlt(&mut self, exit_info: &VmxExitInfo) -> Option<VcpuResult> {
+        if !self.pv_ops_handle.take_unhalt() {
+            return None;
+        }
+        Some(
+            self.advance_rip(exit_info.exit_instruction_length as _)
+                .map_err(VcpuError::from),
+        )
+    }
+
+    /// Emulate `RDMSR` and `WRMSR` of `IA32_UMWAIT_CONTROL`.
+    fn handle_umwait_control_access(&mut self, write: bool) -> VcpuResult {
+        const VMEXIT_INSTR_LEN_RDMSR_WRMSR: u8 = 2;
+
+        let value = self.read_edx_eax();
+        let Some(umwait) = &mut self.umwait else {
+            return Err(VcpuError::gp("IA32_UMWAIT_CONTROL access without WAITPKG"));
+        };
+        if write {
+            trace!("handle_umwait_control_write: value={:#x}", value);
+            umwait.write(value)?;
+        } else {
+            let value = umwait.guest();
+            self.write_edx_eax(value);
+        }
+        self.advance_rip(VMEXIT_INSTR_LEN_RDMSR_WRMSR)?;
+        Ok(())
+    }
+
+    /// Emulate `RDMSR` and `WRMSR` of the Hyper-V synthetic MSRs.
+    fn handle_hyperv_msr_access(&mut self, write: bool) -> VcpuResult {
+        const VMEXIT_INSTR_LEN_RDMSR_WRMSR: u8 = 2;
+
+        let msr = self.regs().rcx as u32;
+        // The clock-related MSRs are only offered with a known TSC frequency.
+        let clock = self.hv_clock().unwrap_or(HvClock {
+            tsc_hz: 1,
+            tsc_adjust: 0,
+            guest_tsc: 0,
+        });
+        if write {
+            let value = self.read_edx_eax();
+            trace!(
+                "handle_hyperv_msr_write: msr={:#x}, value={:#x}",
+                msr, value
+            );
+            let ept_root = self
+                .ept_root
+                .ok_or_else(|| ax_err_type!(BadState, "EPT root is not set"))?;
+            let mut mem = EptGuestMemory::<H::MmHal>::new(ept_root);
+            self.hyperv.write_msr(&mut mem, msr, value, &clock)?;
+        } else {
+            let value = self.hyperv.read_msr(msr, &clock)?;
+            trace!("handle_hyperv_msr_read: msr={:#x}, value={:#x}", msr, value);
+            self.write_edx_eax(value);
+        }
+        self.advance_rip(VMEXIT_INSTR_LEN_RDMSR_WRMSR)?;
+        Ok(())
+    }
+
+    /// Emulate the Hyper-V EOI, ICR and TPR MSRs through the vLAPIC, in any
+    /// APIC mode.
+    fn handle_hyperv_apic_msr_access(&mut self, write: bool) -> VcpuResult {
+        const VMEXIT_INSTR_LEN_RDMSR_WRMSR: u8 = 2;
+
+        let msr = self.regs().rcx as u32;
+        if self.apic_base.mode() == ApicMode::Disabled {
+            return Err(VcpuError::gp(
+                "Hyper-V APIC MSR access with the APIC disabled",
+            ));
+        }
+        match (write, msr) {
+            (true, hyperv::HV_X64_MSR_EOI) => self.vlapic_write(APIC_EOI, 0)?,
+            (true, hyperv::HV_X64_MSR_ICR) => self.vlapic_write(APIC_ICR, self.read_edx_eax())?,
+            (true, _) => {
+                let tpr = self.read_edx_eax();
+                if tpr >> 8 != 0 {
+                    return Err(VcpuError::gp("reserved bits set in the Hyper-V TPR MSR"));
+                }
+                self.set_vlapic_tpr(tpr as u8)?;
+            }
+            (false, hyperv::HV_X64_MSR_EOI) => {
+                return Err(VcpuError::gp("read of the write-only Hyper-V EOI MSR"));
+            }
+            (false, hyperv::HV_X64_MSR_ICR) => {
+                let icr = self.vlapic_read(APIC_ICR)?;
+                self.write_edx_eax(icr);
+            }
+            (false, _) => {
+                let tpr = self.virtual_apic_page().tpr();
+                self.write_edx_eax(tpr as u64);
+            }
+        }
+        self.advance_rip(VMEXIT_INSTR_LEN_RDMSR_WRMSR)?;
+        Ok(())
+    }
+
+    /// Write the vLAPIC register at `offset` through the interface of the
+    /// current APIC mode. The ICR is written as a whole, high half first.
+    fn vlapic_write(&mut self, offset: usize, value: u64) -> AxResult {
+        const X2APIC_MSR_BASE: usize = 0x800;
+
+        if self.apic_base.mode() == ApicMode::X2Apic {
+            return <EmulatedLocalApic as BaseDeviceOps<SysRegAddrRange>>::handle_write(
+                &self.vlapic,
+                SysRegAddr::new(X2APIC_MSR_BASE + (offset >> 4)),
+                AccessWidth::Qword,
+                value as usize,
+            );
+        }
+        let write = |offset: usize, value: u64| {
+            <EmulatedLocalApic as BaseDeviceOps<GuestPhysAddrRange>>::handle_write(
+                &self.vlapic,
+                GuestPhysAddr::from(self.apic_base.base() + offset),
+                AccessWidth::Dword,
+                value as usize & 0xffff_ffff,
+            )
+        };
+        if offset == APIC_ICR {
+            write(APIC_ICR + 0x10, value >> 32)?;
+        }
+        write(offset, value)
+    }
+
+    /// Read the vLAPIC register at `offset` through the interface of the
+    /// current APIC mode. The ICR is read as a whole.
+    fn vlapic_read(&self, offset: usize) -> AxResult<u64> {
+        const X2APIC_MSR_BASE: usize = 0x800;
+
+        if self.apic_base.mode() == ApicMode::X2Apic {
+            return Ok(
+                <EmulatedLocalApic as BaseDeviceOps<SysRegAddrRange>>::handle_read(
+                    &self.vlapic,
+                    SysRegAddr::new(X2APIC_MSR_BASE + (offset >> 4)),
+                    AccessWidth::Qword,
+                )? as u64,
+            );
+        }
+        let read = |offset: usize| {
+            <EmulatedLocalApic as BaseDeviceOps<GuestPhysAddrRange>>::handle_read(
+                &self.vlapic,
+                GuestPhysAddr::from(self.apic_base.base() + offset),
+                AccessWidth::Dword,
+            )
+            .map(|value| value as u64 & 0xffff_ffff)
+        };
+        let mut value = read(offset)?;
+        if offset == APIC_ICR {
+            value |= read(APIC_ICR + 0x10)? << 32;
+        }
+        Ok(value)
+    }
+
+    /// Emulate `RDTSC` and `RDTSCP`, which only exit if RDTSC exiting is on.
+    fn handle_rdtsc(&mut self, rdtscp: bool) -> AxResult {
+        const VM_EXIT_INSTR_LEN_RDTSC: u8 = 2;
+        const VM_EXIT_INSTR_LEN_RDTSCP: u8 = 3;
+
+        self.write_edx_eax(self.guest_tsc());
+        if rdtscp {
+            // The guest IA32_TSC_AUX is not switched on VM exits.
+            self.regs_mut().rcx = Msr::IA32_TSC_AUX.read() & 0xffff_ffff;
+            self.advance_rip(VM_EXIT_INSTR_LEN_RDTSCP)
+        } else {
+            self.advance_rip(VM_EXIT_INSTR_LEN_RDTSC)
+        }
+    }
+
+    /// Emulate `RDMSR` and `WRMSR` of the TSC, `IA32_TSC_ADJUST` and
+    /// `IA32_TSC_DEADLINE`. The deadline is converted to the host TSC and
+    /// passed to the vLAPIC timer.
+    fn handle_tsc_msr_access(&mut self, write: bool) -> AxResult {
+        use x86::msr::{IA32_TIME_STAMP_COUNTER, IA32_TSC_ADJUST, IA32_TSC_DEADLINE};
+        const VMEXIT_INSTR_LEN_RDMSR_WRMSR: u8 = 2;
+
+        let msr = self.regs().rcx as u32;
+        let host_tsc = unsafe { x86::time::rdtsc() };
+        if write {
+            let value = self.read_edx_eax();
+            trace!("handle_tsc_msr_write: msr={:#x}, value={:#x}", msr, value);
+            match msr {
+                IA32_TIME_STAMP_COUNTER => self.tsc.write_tsc(host_tsc, value),
+                IA32_TSC_ADJUST => self.tsc.write_tsc_adjust(value),
+                _ => {
+                    let deadline = self.tsc.write_tsc_deadline(value);
+                    <EmulatedLocalApic as BaseDeviceOps<SysRegAddrRange>>::handle_write(
+                        &self.vlapic,
+                        SysRegAddr::new(IA32_TSC_DEADLINE as usize),
+                        AccessWidth::Qword,
+                        deadline as usize,
+                    )?;
+                }
+            }
+            VmcsControl64::TSC_OFFSET.write(self.tsc.offset())?;
+            if msr != IA32_TSC_DEADLINE {
+                self.update_pv_clocks()?;
+            }
+        } else {
+            let value = match msr {
+                IA32_TIME_STAMP_COUNTER => self.tsc.guest_tsc(host_tsc),
+                IA32_TSC_ADJUST => self.tsc.tsc_adjust(),
+                _ => self.tsc.tsc_deadline(host_tsc),
+            };
+            self.write_edx_eax(value);
+        }
+        self.advance_rip(VMEXIT_INSTR_LEN_RDMSR_WRMSR)
+    }
+
     fn handle_apic_msr_access(&mut self, write: bool, msr: u32) -> AxResult {
         const VMEXIT_INSTR_LEN_RDMSR_WRMSR: u8 = 2;
 
@@ -1036,10 +2431,13 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
         }
     }
 
-    fn handle_apic_access(&mut self, exit_info: &VmxExitInfo) -> AxResult {
+    /// Emulate a guest access to the xAPIC registers. The access is fault-like,
+    /// the instruction is decoded to find the register operand and its length.
+    /// (SDM Vol. 3C, Section 29.4)
+    fn handle_apic_access(&mut self, _exit_info: &VmxExitInfo) -> AxResult {
         let apic_access_exit_info = self.apic_access_exit_info()?;
 
-        let _write = match apic_access_exit_info.access_type {
+        let write = match apic_access_exit_info.access_type {
             ApicAccessExitType::LinearDataWrite => true,
             ApicAccessExitType::LinearDataRead => false,
             _ => {
@@ -1051,25 +2449,213 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
             }
         };
 
-        unimplemented!("apic access");
-        // TODO
+        let insn = self.decode_mmio_instruction()?;
+        if insn.write != write {
+            return ax_err!(BadState, "APIC access does not match the instruction");
+        }
+        let addr =
+            GuestPhysAddr::from(self.apic_base.base() + apic_access_exit_info.offset as usize);
+        let width = AccessWidth::try_from(insn.size)
+            .map_err(|_| ax_err_type!(InvalidInput, "invalid APIC access width"))?;
 
-        self.advance_rip(exit_info.exit_instruction_length as _)
+        if write {
+            let value = match insn.operand {
+                MmioOperand::Register { index, .. } => insn.write_value(self.gpr(index)),
+                MmioOperand::Immediate(_) => insn.write_value(0),
+            };
+            trace!("handle_apic_access: write {:#x} to {:?}", value, addr);
+            <EmulatedLocalApic as BaseDeviceOps<GuestPhysAddrRange>>::handle_write(
+                &self.vlapic,
+                addr,
+                width,
+                value as usize,
+            )?;
+        } else {
+            let value = <EmulatedLocalApic as BaseDeviceOps<GuestPhysAddrRange>>::handle_read(
+                &self.vlapic,
+                addr,
+                width,
+            )? as u64;
+            trace!("handle_apic_access: read {:#x} from {:?}", value, addr);
+            if let MmioOperand::Register { index, .. } = insn.operand {
+                let result = insn.read_result(self.gpr(index), value);
+                self.set_gpr_of_index(index, result);
+            }
+        }
+        self.advance_rip(insn.len as u8)
+    }
+
+    /// Decode the instruction at the guest `RIP` as an MMIO access.
+    fn decode_mmio_instruction(&self) -> AxResult<MmioInstruction> {
+        let ept_root = self
+            .ept_root
+            .ok_or_else(|| ax_err_type!(BadState, "VmxVcpu: EPT root is not set"))?;
+        let mem = EptGuestMemory::<H::MmHal>::new(ept_root);
+        let ptw = self.get_ptw_info();
+        let rip = self.gla2gva(GuestVirtAddr::from(self.rip())).as_usize() as u64;
+
+        // The bytes after the instruction may be on a page that is not mapped.
+        let mut bytes = [0u8; MAX_INSN_LEN];
+        let first = (PAGE_SIZE_4K - rip as usize % PAGE_SIZE_4K).min(MAX_INSN_LEN);
+        read_linear(&mem, &ptw, rip, &mut bytes[..first])?;
+        let len = if first < MAX_INSN_LEN
+            && read_linear(&mem, &ptw, rip + first as u64, &mut bytes[first..]).is_err()
+        {
+            first
+        } else {
+            MAX_INSN_LEN
+        };
+
+        let long_mode = self.get_cpu_mode() == VmCpuMode::Mode64;
+        let default_size = if VmcsGuest32::CS_ACCESS_RIGHTS.read()?.get_bit(14) {
+            4
+        } else {
+            2
+        };
+        MmioInstruction::decode(&bytes[..len], long_mode, default_size)
+    }
+
+    /// The general-purpose register `index`, in instruction encoding order.
+    fn gpr(&self, index: u8) -> u64 {
+        if index == 4 {
+            self.stack_pointer() as u64
+        } else {
+            self.guest_regs.get_reg_of_index(index)
+        }
+    }
+
+    /// Set the general-purpose register `index`, in instruction encoding order.
+    fn set_gpr_of_index(&mut self, index: u8, value: u64) {
+        if index == 4 {
+            self.set_stack_pointer(value as usize)
+        } else {
+            self.guest_regs.set_reg_of_index(index, value)
+        }
+    }
+
+    /// Set the TPR of the emulated local APIC, as a guest `MOV to CR8` does.
+    fn set_vlapic_tpr(&mut self, tpr: u8) -> AxResult {
+        self.virtual_apic_page().write(APIC_TPR, tpr as u32);
+        if self.apic_base.mode() == ApicMode::Disabled {
+            return Ok(());
+        }
+        self.handle_apic_write(APIC_TPR)
+    }
+
+    /// Complete a trap-like write to the virtual-APIC page: the value is
+    /// already in the page, the emulated local APIC performs the side effects.
+    /// (SDM Vol. 3C, Section 29.4.3.3)
+    fn handle_apic_write(&mut self, offset: usize) -> AxResult {
+        const X2APIC_MSR_BASE: usize = 0x800;
+
+        let apic_page = self.virtual_apic_page();
+        let offset = offset & !0xf;
+        let mut value = apic_page.read(offset) as usize;
+        if offset == APIC_ICR {
+            // The x2APIC ICR is a single 64-bit register.
+            value |= (apic_page.read(APIC_ICR + 0x10) as usize) << 32;
+        }
+        trace!(
+            "handle_apic_write: offset={:#x}, value={:#x}",
+            offset, value
+        );
+
+        if self.apic_base.mode() == ApicMode::XApic {
+            return <EmulatedLocalApic as BaseDeviceOps<GuestPhysAddrRange>>::handle_write(
+                &self.vlapic,
+                GuestPhysAddr::from(self.apic_base.base() + offset),
+                AccessWidth::Dword,
+                value & 0xffff_ffff,
+            );
+        }
+        <EmulatedLocalApic as BaseDeviceOps<SysRegAddrRange>>::handle_write(
+            &self.vlapic,
+            SysRegAddr::new(X2APIC_MSR_BASE + (offset >> 4)),
+            AccessWidth::Qword,
+            value,
+        )
+    }
+
+    /// A PAUSE-loop exit: skip the `PAUSE`, widen the window and hint the
+    /// caller of `run_with_deadline` to run another vCPU.
+    fn handle_pause_loop(&mut self) -> AxResult {
+        const VM_EXIT_INSTR_LEN_PAUSE: u8 = 2;
+
+        if let Some(window) = self.ple.as_mut().and_then(PauseLoopExiting::grow) {
+            VmcsControl32::PLE_WINDOW.write(window)?;
+        }
+        self.yield_hint = true;
+        self.advance_rip(VM_EXIT_INSTR_LEN_PAUSE)
     }
 
     fn handle_vmx_preemption_timer(&mut self) -> AxResult {
-        /*
-        The VMX-preemption timer counts down at rate proportional to that of the timestamp counter (TSC).
-        Specifically, the timer counts down by 1 every time bit X in the TSC changes due to a TSC increment.
-        The value of X is in the range 0–31 and can be determined by consulting the VMX capability MSR IA32_VMX_MISC (see Appendix A.6).
-         */
-        VmcsGuest32::VMX_PREEMPTION_TIMER_VALUE.write(VMX_PREEMPTION_TIMER_SET_VALUE)?;
+        // Either the time slice of `run_with_deadline` is over, or a Hyper-V
+        // synthetic timer fires, before the next VM entry.
+        self.timer_expired = self.slice_ticks == Some(0);
+        Ok(())
+    }
+
+    fn supports_preemption_timer() -> bool {
+        vmcs::control_allowed1(
+            Msr::IA32_VMX_TRUE_PINBASED_CTLS,
+            vmcs::controls::PinbasedControls::VMX_PREEMPTION_TIMER.bits(),
+        ) && vmcs::control_allowed1(
+            Msr::IA32_VMX_TRUE_EXIT_CTLS,
+            vmcs::controls::ExitControls::SAVE_VMX_PREEMPTION_TIMER.bits(),
+        )
+    }
+
+    /// Arm the VMX-preemption timer for the earlier of the end of the time
+    /// slice and the next Hyper-V synthetic timer, saving the remaining value
+    /// on VM exit, or disarm it.
+    fn arm_preemption_timer(&mut self) -> AxResult {
+        use vmcs::controls::{ExitControls as ExitCtrl, PinbasedControls as PinCtrl};
+
+        let stimer = self
+            .hyperv_timer_deadline()
+            .filter(|_| Self::supports_preemption_timer())
+            .map(|deadline| {
+                let cycles = deadline.saturating_sub(unsafe { x86::time::rdtsc() });
+                preemption::timer_ticks(cycles, preemption::timer_rate_shift())
+            });
+        let ticks = match (self.slice_ticks, stimer) {
+            (Some(slice), Some(stimer)) => Some(slice.min(stimer)),
+            (slice, stimer) => slice.or(stimer),
+        };
+        if ticks.is_none() && self.preemption_timer_armed.is_none() {
+            return Ok(());
+        }
+        let pin_bits = PinCtrl::VMX_PREEMPTION_TIMER.bits();
+        let exit_bits = ExitCtrl::SAVE_VMX_PREEMPTION_TIMER.bits();
+        let mut pin = VmcsControl32::PINBASED_EXEC_CONTROLS.read()?;
+        let mut exit = VmcsControl32::VMEXIT_CONTROLS.read()?;
+        if let Some(ticks) = ticks {
+            VmcsGuest32::VMX_PREEMPTION_TIMER_VALUE.write(ticks)?;
+            pin |= pin_bits;
+            exit |= exit_bits;
+        } else {
+            pin &= !pin_bits;
+            exit &= !exit_bits;
+        }
+        VmcsControl32::PINBASED_EXEC_CONTROLS.write(pin)?;
+        VmcsControl32::VMEXIT_CONTROLS.write(exit)?;
+        self.preemption_timer_armed = ticks;
+        Ok(())
+    }
+
+    /// Charge the guest run time since the VM entry to the time slice.
+    fn charge_time_slice(&mut self) -> AxResult {
+        if let (Some(loaded), Some(slice)) = (self.preemption_timer_armed, &mut self.slice_ticks) {
+            let left = VmcsGuest32::VMX_PREEMPTION_TIMER_VALUE.read()?;
+            *slice = slice.saturating_sub(loaded.saturating_sub(left));
+        }
         Ok(())
     }
 
     #[allow(clippy::single_match)]
-    fn handle_cr(&mut self) -> AxResult {
-        const VM_EXIT_INSTR_LEN_MV_TO_CR: u8 = 3;
+    fn handle_cr(&mut self) -> VcpuResult {
+        // 3 bytes, or 4 with a REX prefix.
+        let instr_len = VmcsReadOnly32::VMEXIT_INSTRUCTION_LEN.read()? as u8;
 
         let cr_access_info = vmcs::cr_access_info()?;
 
@@ -1085,23 +2671,83 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
                     self.guest_regs.get_reg_of_index(reg)
                 };
                 if cr == 0 || cr == 4 {
-                    self.advance_rip(VM_EXIT_INSTR_LEN_MV_TO_CR)?;
-                    /* TODO: check for #GP reasons */
-                    self.set_cr(cr as usize, val);
+                    self.check_cr_write(cr, val)?;
+                    self.advance_rip(instr_len)?;
+                    self.set_cr(cr as usize, val)?;
 
                     if cr == 0 && Cr0Flags::from_bits_truncate(val).contains(Cr0Flags::PAGING) {
                         vmcs::update_efer()?;
                     }
                     return Ok(());
                 }
+                if cr == 8 {
+                    // CR8[3:0] is TPR[7:4]. (SDM Vol. 3A, Section 10.8.6.1)
+                    if val >> 4 != 0 {
+                        return Err(VcpuError::gp("reserved CR8 bits set"));
+                    }
+                    self.set_vlapic_tpr((val << 4) as u8)?;
+                    self.advance_rip(instr_len)?;
+                    return Ok(());
+                }
+            }
+            /* move from cr */
+            1 if cr == 8 => {
+                let tpr = self.virtual_apic_page().tpr();
+                self.set_gpr_of_index(reg, (tpr >> 4) as u64);
+                self.advance_rip(instr_len)?;
+                return Ok(());
             }
             _ => {}
         };
 
-        panic!(
-            "Guest's access to cr not allowed: {:#x?}, {:#x?}",
-            self, cr_access_info
-        );
+        Err(ax_err_type!(
+            Unsupported,
+            format_args!("Guest's access to cr not allowed: {:#x?}", cr_access_info)
+        )
+        .into())
+    }
+
+    /// The #GP(0) conditions of MOV to CR0 and CR4.
+    /// (SDM Vol. 2B, MOV—Move to/from Control Registers)
+    fn check_cr_write(&self, cr: u8, val: u64) -> VcpuResult {
+        let efer = EferFlags::from_bits_truncate(VmcsGuest64::IA32_EFER.read()?);
+        match cr {
+            0 => {
+                let cr0 = Cr0Flags::from_bits_truncate(val);
+                let cr4 = Cr4Flags::from_bits_truncate(self.cr(4)? as u64);
+                if val >> 32 != 0 {
+                    return Err(VcpuError::gp("reserved CR0 bits set"));
+                }
+                if cr0.contains(Cr0Flags::PAGING) && !cr0.contains(Cr0Flags::PROTECTED_MODE_ENABLE)
+                {
+                    return Err(VcpuError::gp("CR0.PG set without CR0.PE"));
+                }
+                if cr0.contains(Cr0Flags::NOT_WRITE_THROUGH)
+                    && !cr0.contains(Cr0Flags::CACHE_DISABLE)
+                {
+                    return Err(VcpuError::gp("CR0.NW set without CR0.CD"));
+                }
+                if cr0.contains(Cr0Flags::PAGING)
+                    && efer.contains(EferFlags::LONG_MODE_ENABLE)
+                    && !cr4.contains(Cr4Flags::PHYSICAL_ADDRESS_EXTENSION)
+                {
+                    return Err(VcpuError::gp("long mode paging without CR4.PAE"));
+                }
+            }
+            4 => {
+                if val & !Msr::IA32_VMX_CR4_FIXED1.read() != 0 {
+                    return Err(VcpuError::gp("reserved CR4 bits set"));
+                }
+                if efer.contains(EferFlags::LONG_MODE_ACTIVE)
+                    && !Cr4Flags::from_bits_truncate(val)
+                        .contains(Cr4Flags::PHYSICAL_ADDRESS_EXTENSION)
+                {
+                    return Err(VcpuError::gp("CR4.PAE cleared in IA-32e mode"));
+                }
+            }
+            _ => {}
+        }
+        Ok(())
     }
 
     fn handle_cpuid(&mut self) -> AxResult {
@@ -1111,6 +2757,7 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
         const LEAF_FEATURE_INFO: u32 = 0x1;
         const LEAF_STRUCTURED_EXTENDED_FEATURE_FLAGS_ENUMERATION: u32 = 0x7;
         const LEAF_PROCESSOR_EXTENDED_STATE_ENUMERATION: u32 = 0xd;
+        const LEAF_TSC_INFO: u32 = 0x15;
         const EAX_FREQUENCY_INFO: u32 = 0x16;
         const LEAF_HYPERVISOR_INFO: u32 = 0x4000_0000;
         const LEAF_HYPERVISOR_FEATURE: u32 = 0x4000_0001;
@@ -1119,12 +2766,39 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
 
         let regs_clone = self.regs_mut().clone();
         let function = regs_clone.rax as u32;
-        let res = match function {
+        // With Hyper-V, its leaves come first and the KVM or own leaves move
+        // up by 0x100.
+        let hyperv = !self.hyperv.features().is_empty();
+        let kvm_base = if hyperv {
+            LEAF_HYPERVISOR_INFO + 0x100
+        } else {
+            LEAF_HYPERVISOR_INFO
+        };
+        let hyperv_leaf = if hyperv {
+            self.hyperv.cpuid(function)
+        } else {
+            None
+        };
+        let leaf = if function & !0xff == kvm_base {
+            function - kvm_base + LEAF_HYPERVISOR_INFO
+        } else {
+            function
+        };
+        let res = match leaf {
+            _ if let Some(res) = hyperv_leaf => res,
+            _ if let Some([eax, ebx, ecx, edx]) = self.pv_ops.cpuid(function) => {
+                CpuIdResult { eax, ebx, ecx, edx }
+            }
             LEAF_FEATURE_INFO => {
                 const FEATURE_VMX: u32 = 1 << 5;
                 const FEATURE_HYPERVISOR: u32 = 1 << 31;
                 const FEATURE_MCE: u32 = 1 << 7;
+                const FEATURE_MONITOR: u32 = 1 << 3;
                 let mut res = cpuid!(regs_clone.rax, regs_clone.rcx);
+                if self.mwait == MwaitMode::Halt {
+                    // Emulated, whatever the processor supports.
+                    res.ecx |= FEATURE_MONITOR;
+                }
                 res.ecx &= !FEATURE_VMX;
                 res.ecx |= FEATURE_HYPERVISOR;
                 res.eax &= !FEATURE_MCE;
@@ -1134,8 +2808,8 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
             LEAF_STRUCTURED_EXTENDED_FEATURE_FLAGS_ENUMERATION => {
                 let mut res = cpuid!(regs_clone.rax, regs_clone.rcx);
                 if regs_clone.rcx == 0 {
-                    // Bit 05: WAITPKG.
-                    res.ecx.set_bit(5, false); // clear waitpkg
+                    // Bit 05: WAITPKG, only with an emulated IA32_UMWAIT_CONTROL.
+                    res.ecx.set_bit(5, self.umwait.is_some());
                     // Bit 16: LA57. Supports 57-bit linear addresses and five-level paging if 1.
                     res.ecx.set_bit(16, false); // clear LA57
                 }
@@ -1149,29 +2823,54 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
 
                 res
             }
-            LEAF_HYPERVISOR_INFO => CpuIdResult {
-                eax: LEAF_HYPERVISOR_FEATURE,
-                ebx: vendor_regs[0],
-                ecx: vendor_regs[1],
-                edx: vendor_regs[2],
-            },
+            // With kvmclock or the spinlock kick, Linux looks for the KVM
+            // signature to find them.
+            LEAF_HYPERVISOR_INFO => {
+                let vendor_regs = if self.pvclock.is_some() || self.pv_ops.kvm_features() != 0 {
+                    &pvclock::KVM_SIGNATURE
+                } else {
+                    vendor_regs
+                };
+                CpuIdResult {
+                    eax: kvm_base + 1,
+                    ebx: vendor_regs[0],
+                    ecx: vendor_regs[1],
+                    edx: vendor_regs[2],
+                }
+            }
             LEAF_HYPERVISOR_FEATURE => CpuIdResult {
-                eax: 0,
+                eax: if self.pvclock.is_some() {
+                    pvclock::KVM_FEATURE_CLOCKSOURCE2 | pvclock::KVM_FEATURE_CLOCKSOURCE_STABLE_BIT
+                } else {
+                    0
+                } | self.pv_ops.kvm_features(),
                 ebx: 0,
                 ecx: 0,
                 edx: 0,
             },
+            // TSC = crystal clock (ECX) * EBX / EAX. With TSC scaling, keep the
+            // crystal clock, used by Linux for the APIC timer, and report the
+            // guest rate in kHz.
+            LEAF_TSC_INFO => {
+                let mut res = cpuid!(regs_clone.rax, regs_clone.rcx);
+                if let Some(guest_hz) = self.tsc.guest_hz().filter(|_| self.tsc.is_scaled()) {
+                    if res.ecx >= 1000 {
+                        res.eax = res.ecx / 1000;
+                        res.ebx = (guest_hz / 1000) as u32;
+                    } else {
+                        res.eax = 0;
+                        res.ebx = 0;
+                    }
+                }
+                res
+            }
+            // The base frequency in MHz, used by Linux as the TSC frequency if
+            // leaf 0x15 does not enumerate it.
             EAX_FREQUENCY_INFO => {
-                /// Timer interrupt frequencyin Hz.
-                /// Todo: this should be the same as `axconfig::TIMER_FREQUENCY` defined in ArceOS's config file.
-                const TIMER_FREQUENCY_MHZ: u32 = 3_000;
                 let mut res = cpuid!(regs_clone.rax, regs_clone.rcx);
-                if res.eax == 0 {
-                    warn!(
-                        "handle_cpuid: Failed to get TSC frequency by CPUID, default to {} MHz",
-                        TIMER_FREQUENCY_MHZ
-                    );
-                    res.eax = TIMER_FREQUENCY_MHZ;
+                let report = res.eax == 0 || self.tsc.is_scaled();
+                if let Some(guest_hz) = self.tsc.guest_hz().filter(|_| report) {
+                    res.eax = (guest_hz / 1_000_000) as u32;
                 }
                 res
             }
@@ -1193,50 +2892,88 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
         Ok(())
     }
 
-    fn handle_xsetbv(&mut self) -> AxResult {
+    /// Emulate XSETBV, raising #GP(0) for the values the processor would reject.
+    /// (SDM Vol. 2C, XSETBV—Set Extended Control Register)
+    fn handle_xsetbv(&mut self) -> VcpuResult {
+        use raw_cpuid::cpuid;
+
         const XCR_XCR0: u64 = 0;
         const VM_EXIT_INSTR_LEN_XSETBV: u8 = 3;
+        const LEAF_PROCESSOR_EXTENDED_STATE_ENUMERATION: u32 = 0xd;
 
         let index = self.guest_regs.rcx.get_bits(0..32);
         let value = self.guest_regs.rdx.get_bits(0..32) << 32 | self.guest_regs.rax.get_bits(0..32);
 
-        // TODO: get host-supported xcr0 mask by cpuid and reject any guest-xsetbv violating that
-        if index == XCR_XCR0 {
-            Xcr0::from_bits(value)
-                .and_then(|x| {
-                    if !x.contains(Xcr0::XCR0_FPU_MMX_STATE) {
-                        return None;
-                    }
+        // xcr0 only
+        if index != XCR_XCR0 {
+            return Err(VcpuError::gp("XSETBV to an unsupported XCR"));
+        }
 
-                    if x.contains(Xcr0::XCR0_AVX_STATE) && !x.contains(Xcr0::XCR0_SSE_STATE) {
-                        return None;
-                    }
+        let host_supported = {
+            let res = cpuid!(LEAF_PROCESSOR_EXTENDED_STATE_ENUMERATION, 0);
+            (res.edx as u64) << 32 | res.eax as u64
+        };
+        if value & !host_supported != 0 {
+            return Err(VcpuError::gp(
+                "XCR0 enables state components the processor lacks",
+            ));
+        }
 
-                    if x.contains(Xcr0::XCR0_BNDCSR_STATE) ^ x.contains(Xcr0::XCR0_BNDREG_STATE) {
-                        return None;
-                    }
+        let x = Xcr0::from_bits(value).ok_or(VcpuError::gp("reserved XCR0 bits set"))?;
+        if !x.contains(Xcr0::XCR0_FPU_MMX_STATE) {
+            return Err(VcpuError::gp("XCR0.X87 cleared"));
+        }
+        if x.contains(Xcr0::XCR0_AVX_STATE) && !x.contains(Xcr0::XCR0_SSE_STATE) {
+            return Err(VcpuError::gp("XCR0.AVX set without XCR0.SSE"));
+        }
+        if x.contains(Xcr0::XCR0_BNDCSR_STATE) != x.contains(Xcr0::XCR0_BNDREG_STATE) {
+            return Err(VcpuError::gp("XCR0.BNDREG and XCR0.BNDCSR differ"));
+        }
+        let avx512 =
+            Xcr0::XCR0_OPMASK_STATE | Xcr0::XCR0_ZMM_HI256_STATE | Xcr0::XCR0_HI16_ZMM_STATE;
+        if x.intersects(avx512) && (!x.contains(avx512) || !x.contains(Xcr0::XCR0_AVX_STATE)) {
+            return Err(VcpuError::gp("partial or AVX-less AVX-512 state in XCR0"));
+        }
 
-                    if x.contains(Xcr0::XCR0_OPMASK_STATE)
-                        || x.contains(Xcr0::XCR0_ZMM_HI256_STATE)
-                        || x.contains(Xcr0::XCR0_HI16_ZMM_STATE)
-                        || !x.contains(Xcr0::XCR0_AVX_STATE)
-                        || !x.contains(Xcr0::XCR0_OPMASK_STATE)
-                        || !x.contains(Xcr0::XCR0_ZMM_HI256_STATE)
-                        || !x.contains(Xcr0::XCR0_HI16_ZMM_STATE)
-                    {
-                        return None;
-                    }
+        self.xstate.guest_xcr0 = x.bits();
+        self.advance_rip(VM_EXIT_INSTR_LEN_XSETBV)?;
+        Ok(())
+    }
+
+    /// Exit reason for a port I/O access, the data of `OUT` is taken from `RAX`.
+    /// The exit reason of an `RDMSR` or `WRMSR` left to the caller.
+    fn msr_exit(&self, exit_reason: VmxExitReason) -> AxVCpuExitReason {
+        if exit_reason == VmxExitReason::MSR_WRITE {
+            AxVCpuExitReason::SysRegWrite {
+                addr: SysRegAddr::new(self.regs().rcx as _),
+                value: self.read_edx_eax(),
+            }
+        } else {
+            // `reg` is unused here.
+            AxVCpuExitReason::SysRegRead {
+                addr: SysRegAddr::new(self.regs().rcx as _),
+                reg: 0,
+            }
+        }
+    }
 
-                    Some(x)
-                })
-                .ok_or(ax_err_type!(InvalidInput))
-                .and_then(|x| {
-                    self.xstate.guest_xcr0 = x.bits();
-                    self.advance_rip(VM_EXIT_INSTR_LEN_XSETBV)
-                })
+    fn port_io_exit(&self, port: u16, width: AccessWidth, is_in: bool) -> AxVCpuExitReason {
+        if is_in {
+            AxVCpuExitReason::IoRead {
+                port: Port(port),
+                width,
+            }
+        } else if port == QEMU_EXIT_PORT
+            && width == AccessWidth::Word
+            && self.regs().rax == QEMU_EXIT_MAGIC
+        {
+            AxVCpuExitReason::SystemDown
         } else {
-            // xcr0 only
-            ax_err!(Unsupported, "only xcr0 is supported")
+            AxVCpuExitReason::IoWrite {
+                port: Port(port),
+                width,
+                data: self.regs().rax.get_bits(width.bits_range()),
+            }
         }
     }
 
@@ -1251,7 +2988,13 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
 
 impl<H: AxVCpuHal> Drop for VmxVcpu<H> {
     fn drop(&mut self) {
-        unsafe { vmx::vmclear(self.vmcs.phys_addr().as_usize() as u64).unwrap() };
+        if let Err(err) = unsafe { vmx::vmclear(self.vmcs.phys_addr().as_usize() as u64) } {
+            warn!(
+                "[HV] failed to clear VMCS {:#x}: {:?}",
+                self.vmcs.phys_addr(),
+                err
+            );
+        }
         info!("[HV] dropped VmxVcpu(vmcs: {:#x})", self.vmcs.phys_addr());
     }
 }
@@ -1274,10 +3017,11 @@ fn get_tr_base(tr: SegmentSelector, gdt: &DescriptorTablePointer<u64>) -> u64 {
 
 impl<H: AxVCpuHal> Debug for VmxVcpu<H> {
     fn fmt(&self, f: &mut Formatter) -> Result {
-        (|| -> AxResult<Result> {
-            Ok(f.debug_struct("VmxVcpu")
-                .field("guest_regs", &self.guest_regs)
-                .field("rip", &VmcsGuestNW::RIP.read()?)
+        let mut s = f.debug_struct("VmxVcpu");
+        s.field("guest_regs", &self.guest_regs);
+        // The guest state is only readable while the VMCS is current on this CPU.
+        let _ = (|| -> AxResult {
+            s.field("rip", &VmcsGuestNW::RIP.read()?)
                 .field("rsp", &VmcsGuestNW::RSP.read()?)
                 .field("rflags", &VmcsGuestNW::RFLAGS.read()?)
                 .field("cr0", &VmcsGuestNW::CR0.read()?)
@@ -1286,17 +3030,17 @@ impl<H: AxVCpuHal> Debug for VmxVcpu<H> {
                 .field("cs", &VmcsGuest16::CS_SELECTOR.read()?)
                 .field("fs_base", &VmcsGuestNW::FS_BASE.read()?)
                 .field("gs_base", &VmcsGuestNW::GS_BASE.read()?)
-                .field("tss", &VmcsGuest16::TR_SELECTOR.read()?)
-                .finish())
-        })()
-        .unwrap()
+                .field("tss", &VmcsGuest16::TR_SELECTOR.read()?);
+            Ok(())
+        })();
+        s.finish()
     }
 }
 
 impl<H: AxVCpuHal> AxArchVCpu for VmxVcpu<H> {
     type CreateConfig = ();
 
-    type SetupConfig = ();
+    type SetupConfig = VmxSetupConfig;
 
     fn new(vm_id: VMId, vcpu_id: VCpuId, _config: Self::CreateConfig) -> AxResult<Self> {
         Self::new(vm_id, vcpu_id)
@@ -1312,35 +3056,59 @@ impl<H: AxVCpuHal> AxArchVCpu for VmxVcpu<H> {
         Ok(())
     }
 
-    fn setup(&mut self, _config: Self::SetupConfig) -> AxResult {
-        self.setup_vmcs(self.entry.unwrap(), self.ept_root.unwrap())
+    fn setup(&mut self, config: Self::SetupConfig) -> AxResult {
+        let entry = self
+            .entry
+            .ok_or_else(|| ax_err_type!(BadState, "entry is not set"))?;
+        let ept_root = self
+            .ept_root
+            .ok_or_else(|| ax_err_type!(BadState, "EPT root is not set"))?;
+        self.setup_vmcs(entry, ept_root, &config)
     }
 
     fn run(&mut self) -> AxResult<AxVCpuExitReason> {
-        match self.inner_run() {
+        if self.events.take_triple_fault() {
+            warn!("VmxVcpu: guest triple fault, shutting down");
+            return Ok(AxVCpuExitReason::SystemDown);
+        }
+        if self.needs_legacy_emulation()? {
+            return self.run_legacy();
+        }
+        match self.inner_run()? {
             Some(exit_info) => Ok(if exit_info.entry_failure {
+                // SDM Vol. 3C, Section 26.8: the exit qualification tells which
+                // check failed for exit reasons 33 and 34 (the MSR index for 34).
+                let qualification = VmcsReadOnlyNW::EXIT_QUALIFICATION.read()?;
+                match entry_check::check_current_vmcs() {
+                    Err(check) => warn!(
+                        "VM entry failed ({:?}, qualification {:#x}): {}",
+                        exit_info.exit_reason, qualification, check
+                    ),
+                    Ok(()) => warn!(
+                        "VM entry failed ({:?}, qualification {:#x})",
+                        exit_info.exit_reason, qualification
+                    ),
+                }
                 AxVCpuExitReason::FailEntry {
-                    // Todo: get `hardware_entry_failure_reason` somehow.
-                    hardware_entry_failure_reason: 0,
+                    hardware_entry_failure_reason: qualification as u64,
                 }
             } else {
                 match exit_info.exit_reason {
                     VmxExitReason::VMCALL => {
                         self.advance_rip(exit_info.exit_instruction_length as _)?;
+                        let long_mode = self.get_cpu_mode() == VmCpuMode::Mode64;
+                        let call = self.hypercalls.decode(self.regs(), long_mode);
                         AxVCpuExitReason::Hypercall {
-                            nr: self.regs().rax,
-                            args: [
-                                self.regs().rdi,
-                                self.regs().rsi,
-                                self.regs().rdx,
-                                self.regs().rcx,
-                                self.regs().r8,
-                                self.regs().r9,
-                            ],
+                            nr: call.nr,
+                            args: call.args,
                         }
                     }
+                    VmxExitReason::HLT | VmxExitReason::MWAIT_INSTRUCTION => {
+                        self.advance_rip(exit_info.exit_instruction_length as _)?;
+                        AxVCpuExitReason::Halt
+                    }
                     VmxExitReason::IO_INSTRUCTION => {
-                        let io_info = self.io_exit_info().unwrap();
+                        let io_info = self.io_exit_info()?;
                         self.advance_rip(exit_info.exit_instruction_length as _)?;
 
                         let port = io_info.port;
@@ -1365,46 +3133,27 @@ impl<H: AxVCpuHal> AxArchVCpu for VmxVcpu<H> {
                                 }
                             };
 
-                            if io_info.is_in {
-                                AxVCpuExitReason::IoRead {
-                                    port: Port(port),
-                                    width,
-                                }
-                            } else if port == QEMU_EXIT_PORT
-                                && width == AccessWidth::Word
-                                && self.regs().rax == QEMU_EXIT_MAGIC
-                            {
-                                AxVCpuExitReason::SystemDown
-                            } else {
-                                AxVCpuExitReason::IoWrite {
-                                    port: Port(port),
-                                    width,
-                                    data: self.regs().rax.get_bits(width.bits_range()),
-                                }
-                            }
+                            self.port_io_exit(port, width, io_info.is_in)
                         }
                     }
+                    VmxExitReason::TRIPLE_FAULT => {
+                        warn!("VmxVcpu: guest triple fault, shutting down");
+                        AxVCpuExitReason::SystemDown
+                    }
                     VmxExitReason::EXTERNAL_INTERRUPT => {
                         let int_info = self.interrupt_exit_info()?;
-                        assert!(int_info.valid);
+                        if !int_info.valid {
+                            return ax_err!(
+                                BadState,
+                                "external-interrupt exit without valid interruption information"
+                            );
+                        }
                         AxVCpuExitReason::ExternalInterrupt {
                             vector: int_info.vector as _,
                         }
                     }
-                    VmxExitReason::MSR_READ => {
-                        // `reg` is unused here.
-                        AxVCpuExitReason::SysRegRead {
-                            addr: SysRegAddr::new(self.regs().rcx as _),
-                            reg: 0,
-                        }
-                    }
-                    VmxExitReason::MSR_WRITE => {
-                        let value = (self.regs().rax & 0xffff_ffff)
-                            | ((self.regs().rdx & 0xffff_ffff) << 32);
-                        AxVCpuExitReason::SysRegWrite {
-                            addr: SysRegAddr::new(self.regs().rcx as _),
-                            value,
-                        }
+                    msr_rw @ (VmxExitReason::MSR_READ | VmxExitReason::MSR_WRITE) => {
+                        self.msr_exit(msr_rw)
                     }
                     _ => {
                         warn!("VMX unsupported VM-Exit: {:#x?}", exit_info);
@@ -1431,13 +3180,9 @@ impl<H: AxVCpuHal> AxArchVCpu for VmxVcpu<H> {
     }
 
     fn inject_interrupt(&mut self, vector: usize) -> AxResult {
-        if vector != 0 {
-            // warn!("interrupt queued in inject_interrupt: vector {:#x}", vector);
-        } else {
-            warn!("interrupt queued in inject_interrupt: vector 0");
-            panic!()
-        }
-        Ok(self.queue_event(vector as u8, None))
+        let vector = u8::try_from(vector)
+            .map_err(|_| ax_err_type!(InvalidInput, "interrupt vector out of range"))?;
+        self.queue_event(vector, None)
     }
 
     fn set_return_value(&mut self, val: usize) {
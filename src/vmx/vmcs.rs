@@ -7,11 +7,13 @@ use bit_field::BitField;
 use x86::bits64::vmx;
 
 use axaddrspace::{GuestPhysAddr, HostPhysAddr, NestedPageFaultInfo};
-use axerrno::{AxResult, ax_err};
-use page_table_entry::MappingFlags;
+use axerrno::{AxResult, ax_err, ax_err_type};
 
 use super::as_axerr;
 use super::definitions::{VmxExitReason, VmxInstructionError, VmxInterruptionType};
+use super::error::{VcpuError, VcpuResult};
+use super::events::PendingEvent;
+use super::qualification::{EptViolationQualification, ExitQualification, VmxInstructionInfo};
 use crate::msr::Msr;
 
 // HYGIENE: These macros are only used in this file, so we can use `as_axerr` directly.
@@ -217,6 +219,7 @@ pub enum VmcsControlNW {
 define_vmcs_fields_rw!(VmcsControlNW, usize);
 
 /// 16-Bit Guest-State Fields. (SDM Vol. 3D, Appendix B.1.2)
+#[derive(Clone, Copy, Debug)]
 pub enum VmcsGuest16 {
     /// Guest ES selector.
     ES_SELECTOR = 0x800,
@@ -497,6 +500,26 @@ pub struct VmxExitInfo {
     pub exit_instruction_length: u32,
     /// Guest `RIP` where the VM exit occurs.
     pub guest_rip: usize,
+    /// Raw exit qualification, see [`Self::qualification`].
+    pub exit_qualification: u64,
+    /// Raw VM-exit instruction information, see [`Self::instruction_info`].
+    pub exit_instruction_info: u32,
+}
+
+impl VmxExitInfo {
+    /// The decoded exit qualification.
+    pub fn qualification(&self) -> ExitQualification {
+        ExitQualification::decode(self.exit_reason, self.exit_qualification)
+    }
+
+    /// The decoded VM-exit instruction information, if the exit reason reports it.
+    pub fn instruction_info(&self) -> Option<VmxInstructionInfo> {
+        VmxInstructionInfo::decode(
+            self.exit_reason,
+            self.exit_instruction_info,
+            self.exit_qualification,
+        )
+    }
 }
 
 /// VM-Entry/VM-Exit Interruption-Information Field. (SDM Vol. 3C, Section 24.8.3, 24.9.2)
@@ -548,6 +571,19 @@ pub struct VmxIoExitInfo {
     pub port: u16,
 }
 
+impl VmxIoExitInfo {
+    /// Decode from the raw exit qualification.
+    pub fn from_qualification(qualification: u64) -> Self {
+        Self {
+            access_size: qualification.get_bits(0..3) as u8 + 1,
+            is_in: qualification.get_bit(3),
+            is_string: qualification.get_bit(4),
+            is_repeat: qualification.get_bit(5),
+            port: qualification.get_bits(16..32) as u16,
+        }
+    }
+}
+
 /// Exit Qualification for Control Register Accesses. (SDM Vol. 3C, Section 28.2.1, Table 28-5)
 #[derive(Debug)]
 pub struct CrAccessInfo {
@@ -578,7 +614,20 @@ pub struct CrAccessInfo {
     /// [31:16]
     /// For LMSW, the LMSW source data
     /// For CLTS and MOV CR, cleared to 0
-    pub lmsw_source_data: u8,
+    pub lmsw_source_data: u16,
+}
+
+impl CrAccessInfo {
+    /// Decode from the raw exit qualification.
+    pub fn from_qualification(qualification: u64) -> Self {
+        Self {
+            cr_number: qualification.get_bits(0..4) as u8,
+            access_type: qualification.get_bits(4..6) as u8,
+            lmsw_op_type: qualification.get_bits(6..7) as u8,
+            gpr: qualification.get_bits(8..12) as u8,
+            lmsw_source_data: qualification.get_bits(16..32) as u16,
+        }
+    }
 }
 
 /// Type of APIC-access, used in Exit Qualification for APIC Accesses. (SDM Vol. 3C, Section 28.2.2, Table 28-6)
@@ -631,6 +680,17 @@ pub struct ApicAccessExitInfo {
     pub non_event_delivery_asynchronous: bool,
 }
 
+impl ApicAccessExitInfo {
+    /// Decode from the raw exit qualification, `None` for an undefined access type.
+    pub fn from_qualification(qualification: u64) -> Option<Self> {
+        Some(Self {
+            offset: qualification.get_bits(0..12) as u16,
+            access_type: ApicAccessExitType::try_from(qualification.get_bits(12..16) as u8).ok()?,
+            non_event_delivery_asynchronous: qualification.get_bit(16),
+        })
+    }
+}
+
 pub mod controls {
     pub use x86::vmx::vmcs::control::{EntryControls, ExitControls};
     pub use x86::vmx::vmcs::control::{PinbasedControls, PrimaryControls, SecondaryControls};
@@ -646,7 +706,15 @@ pub fn set_control(
     let cap = capability_msr.read();
     let allowed0 = cap as u32;
     let allowed1 = (cap >> 32) as u32;
-    assert_eq!(allowed0 & allowed1, allowed0);
+    if allowed0 & allowed1 != allowed0 {
+        return ax_err!(
+            BadState,
+            format_args!(
+                "inconsistent capability MSR {:?}: {:#x}",
+                capability_msr, cap
+            )
+        );
+    }
     debug!(
         "set {:?}: {:#x} (+{:#x}, -{:#x})",
         control, old_value, set, clear
@@ -680,6 +748,13 @@ pub fn set_control(
     Ok(())
 }
 
+/// Whether all of `bits` may be set to 1 in the control reported by `capability_msr`.
+/// (SDM Vol. 3D, Appendix A.3)
+pub fn control_allowed1(capability_msr: Msr, bits: u32) -> bool {
+    let allowed1 = (capability_msr.read() >> 32) as u32;
+    allowed1 & bits == bits
+}
+
 pub fn set_ept_pointer(pml4_paddr: HostPhysAddr) -> AxResult {
     use super::instructions::{InvEptType, invept};
     let eptp = super::structs::EPTPointer::from_table_phys(pml4_paddr).bits();
@@ -688,20 +763,43 @@ pub fn set_ept_pointer(pml4_paddr: HostPhysAddr) -> AxResult {
     Ok(())
 }
 
-pub fn instruction_error() -> VmxInstructionError {
-    VmcsReadOnly32::VM_INSTRUCTION_ERROR.read().unwrap().into()
+/// Invalidate the TLB entries and paging-structure caches of the guest: those
+/// tagged with its VPID if VPIDs are enabled, derived from its EPT otherwise.
+/// (SDM Vol. 3C, Section 28.3.3)
+pub fn flush_guest_tlb() -> AxResult {
+    use super::instructions::{InvEptType, InvVpidType, invept, invvpid};
+    use controls::SecondaryControls as CpuCtrl2;
+    let ctrl2 =
+        CpuCtrl2::from_bits_truncate(VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS.read()?);
+    if ctrl2.contains(CpuCtrl2::ENABLE_VPID) {
+        let vpid = VmcsControl16::VPID.read()?;
+        unsafe { invvpid(InvVpidType::SingleContext, vpid, 0).map_err(as_axerr)? };
+    } else if ctrl2.contains(CpuCtrl2::ENABLE_EPT) {
+        let eptp = VmcsControl64::EPTP.read()?;
+        unsafe { invept(InvEptType::SingleContext, eptp).map_err(as_axerr)? };
+    }
+    Ok(())
 }
 
-pub fn exit_info() -> AxResult<VmxExitInfo> {
+pub fn instruction_error() -> AxResult<VmxInstructionError> {
+    // Not through `as_axerr`, which reads this field itself to describe the failure.
+    unsafe { vmx::vmread(VmcsReadOnly32::VM_INSTRUCTION_ERROR as u32) }
+        .map(|error| VmxInstructionError::from(error as u32))
+        .map_err(|_| ax_err_type!(BadState, "VMCS pointer is not valid"))
+}
+
+pub fn exit_info() -> VcpuResult<VmxExitInfo> {
     let full_reason = VmcsReadOnly32::EXIT_REASON.read()?;
+    let basic_reason = full_reason.get_bits(0..16);
     Ok(VmxExitInfo {
-        exit_reason: full_reason
-            .get_bits(0..16)
+        exit_reason: basic_reason
             .try_into()
-            .expect("Unknown VM-exit reason"),
+            .map_err(|_| VcpuError::UnknownExit(basic_reason))?,
         entry_failure: full_reason.get_bit(31),
         exit_instruction_length: VmcsReadOnly32::VMEXIT_INSTRUCTION_LEN.read()?,
         guest_rip: VmcsGuestNW::RIP.read()?,
+        exit_qualification: VmcsReadOnlyNW::EXIT_QUALIFICATION.read()? as u64,
+        exit_instruction_info: VmcsReadOnly32::VMEXIT_INSTRUCTION_INFO.read()?,
     })
 }
 
@@ -714,7 +812,8 @@ pub fn interrupt_exit_info() -> AxResult<VmxInterruptInfo> {
     let info = VmcsReadOnly32::VMEXIT_INTERRUPTION_INFO.read()?;
     Ok(VmxInterruptInfo {
         vector: info.get_bits(0..8) as u8,
-        int_type: VmxInterruptionType::try_from(info.get_bits(8..11) as u8).unwrap(),
+        int_type: VmxInterruptionType::try_from(info.get_bits(8..11) as u8)
+            .map_err(|_| ax_err_type!(BadState, "invalid interruption type"))?,
         err_code: if info.get_bit(11) {
             Some(VmcsReadOnly32::VMEXIT_INTERRUPTION_ERR_CODE.read()?)
         } else {
@@ -724,53 +823,58 @@ pub fn interrupt_exit_info() -> AxResult<VmxInterruptInfo> {
     })
 }
 
-pub fn inject_event(vector: u8, err_code: Option<u32>) -> AxResult {
+pub fn inject_event(event: &PendingEvent) -> AxResult {
+    use x86_64::registers::control::Cr0Flags;
+
     // SDM Vol. 3C, Section 24.8.3
-    let err_code = if VmxInterruptionType::vector_has_error_code(vector) {
-        err_code.or_else(|| Some(VmcsReadOnly32::VMEXIT_INTERRUPTION_ERR_CODE.read().unwrap()))
-    } else {
-        None
+    // Exceptions in real mode push no error code. (SDM Vol. 3C, Section 26.2.1.3)
+    let protected_mode =
+        VmcsGuestNW::CR0.read()? & Cr0Flags::PROTECTED_MODE_ENABLE.bits() as usize != 0;
+    let int_info = VmxInterruptInfo {
+        vector: event.vector,
+        int_type: event.int_type,
+        err_code: event.err_code.filter(|_| protected_mode),
+        valid: true,
     };
-    let int_info = VmxInterruptInfo::from(vector, err_code);
     if let Some(err_code) = int_info.err_code {
         VmcsControl32::VMENTRY_EXCEPTION_ERR_CODE.write(err_code)?;
     }
     if int_info.int_type.is_soft() {
-        VmcsControl32::VMENTRY_INSTRUCTION_LEN
-            .write(VmcsReadOnly32::VMEXIT_INSTRUCTION_LEN.read()?)?;
+        let instr_len = event.instr_len.ok_or_else(|| {
+            ax_err_type!(InvalidInput, "software event without instruction length")
+        })?;
+        VmcsControl32::VMENTRY_INSTRUCTION_LEN.write(instr_len)?;
     }
     VmcsControl32::VMENTRY_INTERRUPTION_INFO_FIELD.write(int_info.bits())?;
     Ok(())
 }
 
+/// The event whose delivery was interrupted by the last VM exit. (SDM Vol. 3C, Section 27.2.4)
+pub fn idt_vectoring_event() -> AxResult<Option<PendingEvent>> {
+    let info = VmcsReadOnly32::IDT_VECTORING_INFO.read()?;
+    if !info.get_bit(31) {
+        return Ok(None);
+    }
+    Ok(PendingEvent::from_idt_vectoring(
+        info,
+        VmcsReadOnly32::IDT_VECTORING_ERR_CODE.read()?,
+        VmcsReadOnly32::VMEXIT_INSTRUCTION_LEN.read()?,
+    ))
+}
+
 pub fn io_exit_info() -> AxResult<VmxIoExitInfo> {
     // SDM Vol. 3C, Section 27.2.1, Table 27-5
     let qualification = VmcsReadOnlyNW::EXIT_QUALIFICATION.read()?;
-    Ok(VmxIoExitInfo {
-        access_size: qualification.get_bits(0..3) as u8 + 1,
-        is_in: qualification.get_bit(3),
-        is_string: qualification.get_bit(4),
-        is_repeat: qualification.get_bit(5),
-        port: qualification.get_bits(16..32) as u16,
-    })
+    Ok(VmxIoExitInfo::from_qualification(qualification as u64))
 }
 
 pub fn ept_violation_info() -> AxResult<NestedPageFaultInfo> {
     // SDM Vol. 3C, Section 27.2.1, Table 27-7
     let qualification = VmcsReadOnlyNW::EXIT_QUALIFICATION.read()?;
     let fault_guest_paddr = VmcsReadOnly64::GUEST_PHYSICAL_ADDR.read()? as usize;
-    let mut access_flags = MappingFlags::empty();
-    if qualification.get_bit(0) {
-        access_flags |= MappingFlags::READ;
-    }
-    if qualification.get_bit(1) {
-        access_flags |= MappingFlags::WRITE;
-    }
-    if qualification.get_bit(2) {
-        access_flags |= MappingFlags::EXECUTE;
-    }
     Ok(NestedPageFaultInfo {
-        access_flags,
+        access_flags: EptViolationQualification::from_bits_retain(qualification as u64)
+            .access_flags(),
         fault_guest_paddr: GuestPhysAddr::from(fault_guest_paddr),
     })
 }
@@ -814,22 +918,13 @@ pub fn cr_access_info() -> AxResult<CrAccessInfo> {
     let qualification = VmcsReadOnlyNW::EXIT_QUALIFICATION.read()?;
     // debug!("cr_access_info qualification {:#x}", qualification);
 
-    Ok(CrAccessInfo {
-        cr_number: qualification.get_bits(0..4) as u8,
-        access_type: qualification.get_bits(4..6) as u8,
-        lmsw_op_type: qualification.get_bits(6..7) as u8,
-        gpr: qualification.get_bits(8..12) as u8,
-        lmsw_source_data: qualification.get_bits(16..32) as u8,
-    })
+    Ok(CrAccessInfo::from_qualification(qualification as u64))
 }
 
 pub fn apic_access_exit_info() -> AxResult<ApicAccessExitInfo> {
     let qualification = VmcsReadOnlyNW::EXIT_QUALIFICATION.read()?;
     // debug!("apic_access_info qualification {:#x}", qualification);
 
-    Ok(ApicAccessExitInfo {
-        offset: qualification.get_bits(0..12) as u16,
-        access_type: ApicAccessExitType::try_from(qualification.get_bits(12..16) as u8).unwrap(),
-        non_event_delivery_asynchronous: qualification.get_bit(16),
-    })
+    ApicAccessExitInfo::from_qualification(qualification as u64)
+        .ok_or_else(|| ax_err_type!(BadState, "invalid APIC-access type"))
 }